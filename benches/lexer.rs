@@ -0,0 +1,97 @@
+// Times building the lexer, generating each backend and tokenizing the
+// matching input in benches/inputs for the grammars in benches/grammars, from
+// a small alphabet (json) to a large one (unicode).
+// Run with `cargo bench`, or `cargo bench -- c/` to only run the c grammar.
+
+use std::{fs, path::Path};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use parge::{
+    codegen::{
+        c::{self, CConfig},
+        cpp::{self, CppConfig},
+        dot, go,
+        go::GoConfig,
+        java::{self, JavaConfig},
+        js::{self, JsConfig},
+        json,
+        python::{self, PythonConfig},
+        rust::{self, RustConfig},
+    },
+    error::Result,
+    parse_file, Lexer,
+};
+
+const GRAMMARS: [&str; 4] = ["json", "c", "keywords", "unicode"];
+
+fn generate(gen: impl Fn(&mut Vec<u8>) -> Result<()>) -> Vec<u8> {
+    let mut out = Vec::new();
+    gen(&mut out).unwrap();
+    out
+}
+
+fn benches(criterion: &mut Criterion) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+    for name in GRAMMARS {
+        let grammar = parse_file(dir.join(format!("grammars/{}.pgrules", name))).unwrap();
+        let mut group = criterion.benchmark_group(name);
+        group.bench_function("lexer", |b| {
+            b.iter(|| Lexer::from_grammar(&grammar).unwrap())
+        });
+        let lexer = Lexer::from_grammar(&grammar).unwrap();
+        let cpp_config = CppConfig::default();
+        let table_config = CppConfig {
+            table_driven: true,
+            ..CppConfig::default()
+        };
+        group.bench_function("cpp", |b| {
+            b.iter(|| {
+                generate(|out| {
+                    cpp::gen_header_lexer(&lexer, &cpp_config, out)?;
+                    cpp::gen_body_lexer(&lexer, &cpp_config, out)
+                })
+            })
+        });
+        group.bench_function("cpp-table", |b| {
+            b.iter(|| generate(|out| cpp::gen_body_lexer(&lexer, &table_config, out)))
+        });
+        group.bench_function("java", |b| {
+            b.iter(|| generate(|out| java::gen_lexer(&lexer, &JavaConfig::default(), out)))
+        });
+        group.bench_function("c", |b| {
+            b.iter(|| {
+                generate(|out| {
+                    c::gen_header_lexer(&lexer, out)?;
+                    c::gen_body_lexer(&lexer, &CConfig::default(), out)
+                })
+            })
+        });
+        group.bench_function("rust", |b| {
+            b.iter(|| generate(|out| rust::gen_lexer(&lexer, &RustConfig::default(), out)))
+        });
+        group.bench_function("python", |b| {
+            b.iter(|| generate(|out| python::gen_lexer(&lexer, &PythonConfig::default(), out)))
+        });
+        group.bench_function("js", |b| {
+            b.iter(|| generate(|out| js::gen_lexer(&lexer, &JsConfig::default(), out)))
+        });
+        group.bench_function("go", |b| {
+            b.iter(|| generate(|out| go::gen_lexer(&lexer, &GoConfig::default(), out)))
+        });
+        group.bench_function("json", |b| {
+            b.iter(|| generate(|out| json::gen_json(&lexer, out)))
+        });
+        group.bench_function("dot", |b| {
+            b.iter(|| generate(|out| dot::gen_dot(&lexer, out)))
+        });
+        // About 100 KB of input, so the time is spent lexing and not in setup.
+        let sample = fs::read_to_string(dir.join(format!("inputs/{}.txt", name))).unwrap();
+        let input = sample.repeat(100_000 / sample.len() + 1);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function("tokenize", |b| b.iter(|| lexer.tokenize(&input).unwrap()));
+        group.finish();
+    }
+}
+
+criterion_group!(lexer, benches);
+criterion_main!(lexer);
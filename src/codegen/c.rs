@@ -0,0 +1,466 @@
+use std::io::Write;
+
+use crate::{
+    codegen::{escape_string, tables},
+    error::Result,
+    lexer::Lexer,
+    rules::MatchMode,
+};
+
+macro_rules! write_line {
+    ($indent:expr,$writer:expr,$($arg:tt)*) => {
+        for _ in 0..$indent {
+            write!($writer, "    ")?;
+        }
+        write!($writer, $($arg)*)?;
+    };
+}
+
+#[derive(Default)]
+pub struct CConfig {
+    pub bulk_errors: bool,
+    pub normalize_newlines: bool,
+    pub bytes: bool,
+}
+
+pub fn gen_header_lexer<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+    let tables = tables(lexer);
+    write!(
+        writer,
+        r#"#ifndef LEXER_H
+#define LEXER_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+
+typedef enum
+{{
+"#
+    )?;
+    for token in &tables.tokens {
+        write_line!(1, writer, "TOKEN_{},\n", token);
+    }
+    writeln!(writer, "}} Token;")?;
+    let equivalences = lexer.get_equivalences();
+    if !equivalences.is_empty() {
+        write!(writer, "\ntypedef enum\n{{\n")?;
+        write_line!(1, writer, "TOKEN_CLASS__NONE,\n");
+        for (name, _) in equivalences {
+            write_line!(1, writer, "TOKEN_CLASS_{},\n", name);
+        }
+        write!(
+            writer,
+            "}} TokenClass;\n\nTokenClass token_class(Token token);\n"
+        )?;
+    }
+    write!(writer, "\nconst char *token_name(Token token);\n")?;
+    if lexer.has_displays() {
+        writeln!(writer, "const char *token_display(Token token);")?;
+    }
+    write!(
+        writer,
+        r#"
+/* Returns the next input byte, or -1 at the end of the input. */
+typedef int (*LexerRead)(void *data);
+
+typedef struct
+{{
+    LexerRead read;
+    void *data;
+    unsigned char *buf;
+    size_t buf_len;
+    size_t buf_cap;
+    size_t consumed;
+    char *text;
+    size_t text_cap;
+}} Lexer;
+
+void lexer_init(Lexer *lexer, LexerRead read, void *data);
+void lexer_init_file(Lexer *lexer, FILE *file);
+void lexer_free(Lexer *lexer);
+/* The text stays valid until the next call. */
+Token lexer_next(Lexer *lexer, const char **text, size_t *len);
+
+#endif
+"#
+    )?;
+    Ok(())
+}
+
+pub fn gen_body_lexer<W: Write>(lexer: &Lexer, config: &CConfig, writer: &mut W) -> Result<()> {
+    let tables = tables(lexer);
+    write!(
+        writer,
+        "#include \"lexer.h\"\n\n#include <stdlib.h>\n#include <string.h>\n"
+    )?;
+    write!(
+        writer,
+        "\n#define TRAP {}\n\nstatic const uint32_t ALPHABET[{}] = {{\n",
+        tables.trap,
+        tables.alphabet.len()
+    )?;
+    for (_, last) in &tables.alphabet {
+        write_line!(1, writer, "{},\n", last);
+    }
+    write!(
+        writer,
+        "}};\n\nstatic const size_t TRANSITIONS[{}][{}] = {{\n",
+        tables.transitions.len(),
+        tables.alphabet.len()
+    )?;
+    for row in &tables.transitions {
+        let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+        write_line!(1, writer, "{{{}}},\n", row.join(", "));
+    }
+    write!(
+        writer,
+        "}};\n\n/* The token accepted in each state, or -1. */\nstatic const int ACCEPTS[{}] = {{\n",
+        tables.accepts.len()
+    )?;
+    for accept in &tables.accepts {
+        match accept {
+            Some(token) => {
+                write_line!(1, writer, "TOKEN_{},\n", tables.tokens[*token]);
+            }
+            None => {
+                write_line!(1, writer, "-1,\n");
+            }
+        }
+    }
+    writeln!(writer, "}};")?;
+    if !lexer.get_equivalences().is_empty() {
+        gen_token_class(lexer, writer)?;
+    }
+    write!(writer, "\nconst char *token_name(Token token)\n{{\n")?;
+    write_line!(1, writer, "switch (token)\n");
+    write_line!(1, writer, "{{\n");
+    for token in &tables.tokens {
+        write_line!(1, writer, "case TOKEN_{}:\n", token);
+        write_line!(2, writer, "return \"{}\";\n", token);
+    }
+    write_line!(1, writer, "}}\n");
+    write_line!(1, writer, "return \"\";\n");
+    writeln!(writer, "}}")?;
+    if lexer.has_displays() {
+        write!(writer, "\nconst char *token_display(Token token)\n{{\n")?;
+        write_line!(1, writer, "switch (token)\n");
+        write_line!(1, writer, "{{\n");
+        for token in &tables.tokens {
+            write_line!(1, writer, "case TOKEN_{}:\n", token);
+            write_line!(
+                2,
+                writer,
+                "return \"{}\";\n",
+                escape_string(&lexer.display(token))
+            );
+        }
+        write_line!(1, writer, "}}\n");
+        write_line!(1, writer, "return \"\";\n");
+        writeln!(writer, "}}")?;
+    }
+    write!(
+        writer,
+        r#"
+static int read_file(void *data)
+{{
+    int c = fgetc((FILE *)data);
+    return c == EOF ? -1 : c;
+}}
+
+void lexer_init(Lexer *lexer, LexerRead read, void *data)
+{{
+    memset(lexer, 0, sizeof(*lexer));
+    lexer->read = read;
+    lexer->data = data;
+}}
+
+void lexer_init_file(Lexer *lexer, FILE *file)
+{{
+    lexer_init(lexer, read_file, file);
+}}
+
+void lexer_free(Lexer *lexer)
+{{
+    free(lexer->buf);
+    free(lexer->text);
+    lexer->buf = NULL;
+    lexer->text = NULL;
+}}
+
+static int fill(Lexer *lexer, size_t n)
+{{
+    while (lexer->buf_len < n)
+    {{
+        int c = lexer->read(lexer->data);
+        if (c < 0)
+            return 0;
+        if (lexer->buf_len == lexer->buf_cap)
+        {{
+            size_t cap = lexer->buf_cap ? lexer->buf_cap * 2 : 4096;
+            unsigned char *buf = realloc(lexer->buf, cap);
+            if (!buf)
+                return 0;
+            lexer->buf = buf;
+            lexer->buf_cap = cap;
+        }}
+        lexer->buf[lexer->buf_len++] = (unsigned char)c;
+    }}
+    return 1;
+}}
+"#
+    )?;
+    if config.bytes {
+        write!(
+            writer,
+            r#"
+static uint32_t next_chr(Lexer *lexer, int *e, size_t pos, size_t *len)
+{{
+    *e = 0;
+    *len = 0;
+    if (!fill(lexer, pos + 1))
+        return 0;
+    *len = 1;
+    return lexer->buf[pos];
+}}
+"#
+        )?;
+    } else {
+        write!(
+            writer,
+            r#"
+/* taken from: https://github.com/skeeto/branchless-utf8 */
+static uint32_t next_chr(Lexer *lexer, int *e, size_t pos, size_t *len)
+{{
+    static const char lengths[] = {{
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 3, 3, 4, 0}};
+    static const int masks[] = {{0x00, 0x7f, 0x1f, 0x0f, 0x07}};
+    static const uint32_t mins[] = {{4194304, 0, 128, 2048, 65536}};
+    static const int shiftc[] = {{0, 18, 12, 6, 0}};
+    static const int shifte[] = {{0, 6, 4, 2, 0}};
+
+    unsigned char s[4] = {{0}};
+    uint32_t c;
+    size_t i;
+    *len = 0;
+    if (!fill(lexer, pos + 1))
+    {{
+        *e = 0;
+        return 0;
+    }}
+    s[0] = lexer->buf[pos];
+    *len = lengths[s[0] >> 3];
+    if (!*len)
+    {{
+        /* Stray continuation or invalid lead byte. */
+        *len = 1;
+        *e = 1;
+        return s[0];
+    }}
+    fill(lexer, pos + *len);
+    for (i = 1; i < *len && pos + i < lexer->buf_len; i++)
+        s[i] = lexer->buf[pos + i];
+
+    /* Assume a four-byte character and load four bytes. Unused bits are
+     * shifted out.
+     */
+    c = (uint32_t)(s[0] & masks[*len]) << 18;
+    c |= (uint32_t)(s[1] & 0x3f) << 12;
+    c |= (uint32_t)(s[2] & 0x3f) << 6;
+    c |= (uint32_t)(s[3] & 0x3f) << 0;
+    c >>= shiftc[*len];
+
+    /* Accumulate the various error conditions. */
+    *e = (c < mins[*len]) << 6;      /* non-canonical encoding */
+    *e |= ((c >> 11) == 0x1b) << 7;  /* surrogate half? */
+    *e |= (c > 0x10FFFF) << 8;       /* out of range? */
+    *e |= (s[1] & 0xc0) >> 2;
+    *e |= (s[2] & 0xc0) >> 4;
+    *e |= (s[3]) >> 6;
+    *e ^= 0x2a; /* top two bits of each tail byte correct? */
+    *e >>= shifte[*len];
+
+    /* A truncated sequence at the end of the input. */
+    if (pos + *len > lexer->buf_len)
+        *len = lexer->buf_len - pos;
+
+    return c;
+}}
+"#
+        )?;
+    }
+    write!(
+        writer,
+        r#"
+static size_t to_alphabet(uint32_t ch)
+{{
+    size_t low = 0;
+    size_t high = {};
+    while (low < high)
+    {{
+        size_t mid = (low + high) / 2;
+        if (ALPHABET[mid] < ch)
+            low = mid + 1;
+        else
+            high = mid;
+    }}
+    return low;
+}}
+
+static int longest(Lexer *lexer, size_t pos, Token *token, size_t *end)
+{{
+    size_t state = 0;
+    int found = 0;
+    while (1)
+    {{
+        int error = 0;
+        size_t chlen = 0;
+        uint32_t ch = next_chr(lexer, &error, pos, &chlen);
+        if (!chlen || error)
+            break;
+        state = TRANSITIONS[state][to_alphabet(ch)];
+        if (state == TRAP)
+            break;
+        pos += chlen;
+        if (ACCEPTS[state] >= 0)
+        {{
+            *token = (Token)ACCEPTS[state];
+            *end = pos;
+            found = 1;
+"#,
+        tables.alphabet.len() - 1
+    )?;
+    if lexer.get_match_mode() == MatchMode::Shortest {
+        write_line!(3, writer, "break;\n");
+    }
+    write!(
+        writer,
+        r#"        }}
+    }}
+    return found;
+}}
+
+static int set_text(Lexer *lexer, size_t len)
+{{
+    if (len + 1 > lexer->text_cap)
+    {{
+        char *text = realloc(lexer->text, len + 1);
+        if (!text)
+            return 0;
+        lexer->text = text;
+        lexer->text_cap = len + 1;
+    }}
+    memcpy(lexer->text, lexer->buf, len);
+    lexer->text[len] = '\0';
+    return 1;
+}}
+
+Token lexer_next(Lexer *lexer, const char **text, size_t *len)
+{{
+    while (1)
+    {{
+        Token token;
+        size_t end = 0;
+        int error = 0;
+
+        if (lexer->consumed)
+        {{
+            memmove(lexer->buf, lexer->buf + lexer->consumed, lexer->buf_len - lexer->consumed);
+            lexer->buf_len -= lexer->consumed;
+            lexer->consumed = 0;
+        }}
+
+        if (!fill(lexer, 1))
+        {{
+            *text = "";
+            *len = 0;
+            return TOKEN__EOF;
+        }}
+        if (!longest(lexer, 0, &token, &end))
+        {{
+            next_chr(lexer, &error, 0, &end);
+"#
+    )?;
+    if config.bulk_errors {
+        write_line!(3, writer, "while (fill(lexer, end + 1))\n");
+        write_line!(3, writer, "{{\n");
+        write_line!(4, writer, "Token next;\n");
+        write_line!(4, writer, "size_t next_end, chlen;\n");
+        write_line!(4, writer, "if (longest(lexer, end, &next, &next_end))\n");
+        write_line!(5, writer, "break;\n");
+        write_line!(4, writer, "next_chr(lexer, &error, end, &chlen);\n");
+        write_line!(4, writer, "end += chlen;\n");
+        write_line!(3, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"            token = TOKEN__ERR;
+        }}
+        lexer->consumed = end;
+"#
+    )?;
+    let skipped: Vec<String> = tables
+        .tokens
+        .iter()
+        .zip(&tables.skipped)
+        .filter(|(_, skipped)| **skipped)
+        .map(|(token, _)| format!("token == TOKEN_{}", token))
+        .collect();
+    if !skipped.is_empty() {
+        write_line!(2, writer, "if ({})\n", skipped.join(" || "));
+        write_line!(3, writer, "continue;\n");
+    }
+    write!(
+        writer,
+        r#"        if (!set_text(lexer, end))
+        {{
+            *text = "";
+            *len = 0;
+            return TOKEN__ERR;
+        }}
+"#
+    )?;
+    if config.normalize_newlines {
+        write_line!(2, writer, "if (token != TOKEN__ERR)\n");
+        write_line!(2, writer, "{{\n");
+        write_line!(3, writer, "size_t i, j = 0;\n");
+        write_line!(3, writer, "for (i = 0; i < end; i++)\n");
+        write_line!(
+            4,
+            writer,
+            "if (lexer->text[i] != '\\r' || lexer->text[i + 1] != '\\n')\n"
+        );
+        write_line!(5, writer, "lexer->text[j++] = lexer->text[i];\n");
+        write_line!(3, writer, "lexer->text[j] = '\\0';\n");
+        write_line!(3, writer, "end = j;\n");
+        write_line!(2, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"        *text = lexer->text;
+        *len = end;
+        return token;
+    }}
+}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_token_class<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+    write!(writer, "\nTokenClass token_class(Token token)\n{{\n")?;
+    write_line!(1, writer, "switch (token)\n");
+    write_line!(1, writer, "{{\n");
+    for (name, members) in lexer.get_equivalences() {
+        for member in members {
+            write_line!(1, writer, "case TOKEN_{}:\n", member);
+        }
+        write_line!(2, writer, "return TOKEN_CLASS_{};\n", name);
+    }
+    write_line!(1, writer, "default:\n");
+    write_line!(2, writer, "return TOKEN_CLASS__NONE;\n");
+    write_line!(1, writer, "}}\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
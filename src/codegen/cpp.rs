@@ -3,243 +3,2430 @@ use std::{
     io::Write,
 };
 
-use color_eyre::Result;
 use smol_str::SmolStr;
 
-use crate::lexer::Lexer;
+use crate::{
+    codegen::{
+        alphabet_ids, committed_states, escape_string, folded_keywords, keyword_hash,
+        keyword_table, rule_origin, tables, tables_contract, CodeWriter, Committed, Layout, Names,
+    },
+    error::{bail, Result},
+    lexer::Lexer,
+    rules::{MatchMode, ModeAction},
+};
 
 macro_rules! write_line {
     ($indent:expr,$writer:expr,$($arg:tt)*) => {
-        for _ in 0..$indent {
-            write!($writer, "    ")?;
-        }
+        $writer.indent($indent)?;
         write!($writer, $($arg)*)?;
     };
 }
 
-pub fn gen_header_lexer<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
-    let tokens: BTreeSet<SmolStr> = lexer
-        .get_states()
-        .iter()
-        .filter(|s| s.is_some())
-        .map(|s| s.unwrap().clone())
+#[derive(Default)]
+pub struct CppConfig {
+    pub error_context: bool,
+    pub keyword_hash: bool,
+    pub fold_keywords: bool,
+    pub bulk_errors: bool,
+    pub max_error_run: Option<usize>,
+    pub recover: bool,
+    pub lookahead: Option<usize>,
+    pub validate_utf8: bool,
+    pub normalize_newlines: bool,
+    pub positions: bool,
+    pub table_driven: bool,
+    pub utf16: bool,
+    pub bytes: bool,
+    pub strip_bom: bool,
+    pub buffer_capacity: Option<usize>,
+    pub source_comments: bool,
+    pub file_name: Option<String>,
+    pub token_type: Option<String>,
+    pub stable_token_ids: bool,
+    pub token_counts: bool,
+    pub names: Names,
+    pub layout: Layout,
+}
+
+fn public_tokens(lexer: &Lexer) -> BTreeSet<SmolStr> {
+    [lexer.get_eof().as_str(), "_ERR"]
+        .into_iter()
+        .map(SmolStr::new)
+        .chain(
+            lexer
+                .get_states()
+                .iter()
+                .filter(|s| s.is_some())
+                .map(|s| s.unwrap().clone())
+                .filter(|s| s != "_TRAP" && !lexer.is_internal(s)),
+        )
+        .chain(
+            lexer
+                .get_folded()
+                .iter()
+                .map(|(keyword, _)| keyword.clone()),
+        )
+        .collect()
+}
+
+// The value bits of the token enum's underlying type, int when none is given.
+fn token_bits(config: &CppConfig) -> u32 {
+    let Some(token_type) = &config.token_type else {
+        return 31;
+    };
+    let bits: u32 = token_type
+        .trim_start_matches('u')
+        .trim_start_matches("int")
+        .trim_end_matches("_t")
+        .parse()
+        .unwrap();
+    if token_type.starts_with('u') {
+        bits
+    } else {
+        bits - 1
+    }
+}
+
+// The enumerators of the token enum. Stable ids are derived from the token
+// name alone, so adding or removing tokens doesn't renumber the others.
+fn token_enumerators(lexer: &Lexer, config: &CppConfig) -> Result<Vec<String>> {
+    let eof = lexer.get_eof();
+    let tokens: Vec<SmolStr> = [eof.clone(), SmolStr::new("_ERR")]
+        .into_iter()
+        .chain(
+            public_tokens(lexer)
+                .into_iter()
+                .filter(|t| t != eof && t != "_ERR"),
+        )
         .collect();
+    let bits = token_bits(config);
+    if !config.stable_token_ids {
+        if bits < 32 && tokens.len() as u64 > 1 << bits {
+            bail!(
+                Grammar,
+                "{} tokens don't fit in the token type {}",
+                tokens.len(),
+                config.token_type.as_deref().unwrap_or("int")
+            );
+        }
+        return Ok(tokens.iter().map(|t| t.to_string()).collect());
+    }
+    let mask = if bits < 32 { (1 << bits) - 1 } else { u32::MAX };
+    let mut ids: BTreeMap<u32, &SmolStr> = BTreeMap::new();
+    let mut enumerators = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let id = if i < 2 {
+            i as u32
+        } else {
+            keyword_hash(2166136261, token) & mask
+        };
+        if let Some(other) = ids.insert(id, token) {
+            bail!(
+                Grammar,
+                "The stable ids of tokens '{}' and '{}' collide, a wider token type might avoid it",
+                other,
+                token
+            );
+        }
+        enumerators.push(format!("{} = {}", token, id));
+    }
+    Ok(enumerators)
+}
+
+fn unit_type(config: &CppConfig) -> &'static str {
+    if config.utf16 {
+        "char16_t"
+    } else {
+        "char"
+    }
+}
+
+fn string_type(config: &CppConfig) -> &'static str {
+    if config.utf16 {
+        "std::u16string"
+    } else {
+        "std::string"
+    }
+}
+
+pub fn gen_header_lexer<W: Write>(lexer: &Lexer, config: &CppConfig, writer: &mut W) -> Result<()> {
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let ind = writer.unit();
+    let folded;
+    let lexer = if config.fold_keywords {
+        folded = lexer.fold_keywords()?;
+        &folded
+    } else {
+        lexer
+    };
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let string = string_type(config);
+    let unit = unit_type(config);
+    let capacity = config.buffer_capacity.unwrap_or(4096);
     write!(
         writer,
-        r#"#include <cstdint>
+        r#"#pragma once
+
+#include <cstdint>
 #include <string>
 #include <istream>
-#include <sstream>
+"#
+    )?;
+    let typed = !value_types(lexer).is_empty();
+    if typed {
+        writeln!(writer, "#include <variant>")?;
+    }
+    if config.lookahead.is_some() {
+        write!(
+            writer,
+            "#include <stdexcept>\n#include <utility>\n#include <vector>\n"
+        )?;
+    } else if !lexer.get_modes().is_empty() {
+        writeln!(writer, "#include <vector>")?;
+    }
+    if let Some(namespace) = &config.names.namespace {
+        write!(writer, "\nnamespace {}\n{{\n", namespace)?;
+    }
+    write!(
+        writer,
+        r#"
+enum class {token_name}{}
+{{
+{ind}{}
+}};
+"#,
+        match &config.token_type {
+            Some(token_type) => format!(" : {}", token_type),
+            None => String::new(),
+        },
+        token_enumerators(lexer, config)?.join(&format!(",\n{ind}"))
+    )?;
+    write!(writer, "\nconst char *token_name({token_name} token);\n")?;
+    if lexer.has_displays() {
+        writeln!(writer, "const char *token_display({token_name} token);")?;
+    }
+    let equivalences = lexer.get_equivalences();
+    if !equivalences.is_empty() {
+        write!(
+            writer,
+            r#"
+enum class {token_name}Class
+{{
+{ind}_NONE,
+{ind}{}
+}};
 
-enum class Token
+{token_name}Class token_class({token_name} token);
+"#,
+            equivalences
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<&str>>()
+                .join(&format!(",\n{ind}"))
+        )?;
+    }
+    if typed {
+        write!(
+            writer,
+            "\nusing {token_name}Value = std::variant<std::monostate, int32_t, int64_t, float, double>;\n"
+        )?;
+    }
+    if config.positions {
+        write!(
+            writer,
+            r#"
+struct {token_name}Position
 {{
-    _EOF,
-    _ERR,
-    {}
+{ind}size_t line;
+{ind}size_t column;
+{ind}size_t offset;
+{ind}size_t length;
 }};
 
-class Lexer
+// A token with its text, byte offsets [start, end) and where it starts.
+struct {token_name}Span
+{{
+{ind}{token_name} kind;
+{ind}{string} text;
+{ind}size_t start;
+{ind}size_t end;
+{ind}size_t line;
+{ind}size_t column;
+}};
+"#
+        )?;
+    }
+    if config.error_context {
+        write!(
+            writer,
+            r#"
+struct LexError
+{{
+{ind}size_t line;
+{ind}size_t column;
+{ind}{string} line_text;
+}};
+"#
+        )?;
+    }
+    write!(
+        writer,
+        r#"
+// All mutable state lives in the instance, so separate lexers can be used
+// concurrently. A single lexer isn't safe to share between threads.
+class {lexer_name}
 {{
 private:
-    std::stringstream buf;
-    std::istream &contents;
-    uint32_t next_chr(int *err, bool &use_buf);
-    void read(bool &use_buf, char *dst, size_t n);
-
+{ind}{string} buf;
+{ind}std::istream *contents = nullptr;
+{ind}const {unit} *data = nullptr;
+{ind}size_t data_left = 0;
+{ind}void clear();
+{ind}bool fill(size_t n);
+{ind}uint32_t next_chr(int *err, size_t pos, size_t &len);
+{ind}static int to_alphabet(uint32_t ch);
+"#
+    )?;
+    for (token, _) in lexer.get_lookaheads() {
+        write_line!(1, writer, "bool rejects_{}(size_t pos);\n", token);
+    }
+    if !lexer.get_modes().is_empty() {
+        write_line!(1, writer, "std::vector<size_t> modes;\n");
+    }
+    if !lexer.get_line_starts().is_empty() {
+        write_line!(1, writer, "bool at_line_start = true;\n");
+    }
+    if dynamic_start(lexer) {
+        write_line!(1, writer, "size_t start_state() const;\n");
+    }
+    if config.strip_bom {
+        write_line!(1, writer, "bool at_start = true;\n");
+    }
+    if config.positions {
+        write!(
+            writer,
+            r#"{ind}{token_name}Position cursor = {{1, 1, 0, 0}};
+{ind}{token_name}Position start_position = {{1, 1, 0, 0}};
+{ind}void track(const {string} &text);
+"#
+        )?;
+    }
+    if config.error_context {
+        write!(
+            writer,
+            r#"{ind}size_t line = 1;
+{ind}size_t column = 1;
+{ind}{string} current_line;
+{ind}LexError last_error;
+{ind}void advance(const {string} &text);
+{ind}void fail(size_t pos);
+"#
+        )?;
+    }
+    if let Some(lookahead) = config.lookahead {
+        write_line!(
+            1,
+            writer,
+            "std::pair<{token_name}, {string}> lookahead[{}];\n",
+            lookahead
+        );
+        write_line!(1, writer, "size_t lookahead_start = 0;\n");
+        write_line!(1, writer, "size_t lookahead_count = 0;\n");
+        write_line!(1, writer, "{string} scan({token_name} &token);\n");
+    }
+    if config.token_counts {
+        let inner = if config.lookahead.is_some() {
+            "take"
+        } else {
+            "scan"
+        };
+        write_line!(1, writer, "{string} {inner}({token_name} &token);\n");
+        write_line!(
+            1,
+            writer,
+            "size_t token_counts[{}] = {{}};\n",
+            public_tokens(lexer).len()
+        );
+    }
+    if typed {
+        write_line!(1, writer, "{token_name}Value last_value;\n");
+        write_line!(
+            1,
+            writer,
+            "bool parse_value({token_name} token, const std::string &text);\n"
+        );
+    }
+    if !lexer.get_folded().is_empty() {
+        write_line!(
+            1,
+            writer,
+            "static {token_name} fold_keyword({token_name} found, const std::string &text);\n"
+        );
+    }
+    write!(
+        writer,
+        r#"
 public:
-    Lexer(std::istream &contents);
-    std::string next(Token &token);
-}};
-"#,
-        tokens
-            .into_iter()
-            .collect::<Vec<SmolStr>>()
-            .join(",\r\n    ")
+{ind}{lexer_name}(std::istream &contents, size_t capacity = {capacity});
+{ind}// Lexes the text in place, which has to outlive the lexer.
+{ind}{lexer_name}(const {unit} *data, size_t size);
+{ind}explicit {lexer_name}(const {string} &text);
+{ind}explicit {lexer_name}({string} &&text) = delete;
+{ind}void reset(std::istream &contents);
+{ind}void reset(const {unit} *data, size_t size);
+{ind}void reset(const {string} &text);
+{ind}void reset({string} &&text) = delete;
+{ind}{string} next({token_name} &token);
+"#
     )?;
+    if config.lookahead.is_some() {
+        write_line!(1, writer, "{string} peek({token_name} &token);\n");
+        write_line!(
+            1,
+            writer,
+            "std::vector<std::pair<{token_name}, {string}>> peek_n(size_t n);\n"
+        );
+    }
+    if config.validate_utf8 {
+        write_line!(1, writer, "bool validate_utf8(size_t &offset);\n");
+    }
+    if config.error_context {
+        write_line!(1, writer, "const LexError &error() const;\n");
+    }
+    if config.positions {
+        write_line!(1, writer, "const {token_name}Position &position() const;\n");
+        write_line!(1, writer, "{token_name}Span next_span();\n");
+    }
+    if typed {
+        write_line!(1, writer, "const {token_name}Value &value() const;\n");
+    }
+    if config.token_counts {
+        write_line!(
+            1,
+            writer,
+            "// How often next returned each token, kept across reset.\n"
+        );
+        write_line!(1, writer, "size_t token_count({token_name} token) const;\n");
+        write_line!(1, writer, "void reset_token_counts();\n");
+    }
+    if config.keyword_hash {
+        write_line!(
+            1,
+            writer,
+            "static {token_name} keyword(const std::string &text);\n"
+        );
+    }
+    writeln!(writer, "}};")?;
+    if let Some(namespace) = &config.names.namespace {
+        write!(writer, "\n}} // namespace {}\n", namespace)?;
+    }
     Ok(())
 }
 
-pub fn gen_body_lexer<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
-    let trap = lexer
-        .get_states()
-        .iter()
-        .position(|s| match s {
-            Some(s) if s == &"_TRAP" => true,
-            _ => false,
-        })
-        .unwrap();
+pub fn file_stem(config: &CppConfig) -> String {
+    match &config.file_name {
+        Some(name) => name.clone(),
+        None => config.names.lexer.to_lowercase(),
+    }
+}
+
+// The header followed by the definitions, which are made inline so the
+// header can be included from several translation units.
+pub fn gen_single_header<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    writer: &mut W,
+) -> Result<()> {
+    gen_header_lexer(lexer, config, writer)?;
+    let mut body = Vec::new();
+    gen_body_lexer(lexer, config, &mut body)?;
+    let body = String::from_utf8(body).unwrap();
+    let include = format!(
+        "#include \"{}.h\"{}",
+        file_stem(config),
+        config.layout.newline
+    );
+    let lines: Vec<&str> = body[include.len()..].split_inclusive('\n').collect();
+    for (i, line) in lines.iter().enumerate() {
+        // Definitions start in the first column with the brace on the next line.
+        let definition = line.starts_with(|c: char| c.is_ascii_alphabetic())
+            && !line.starts_with("namespace ")
+            && lines.get(i + 1).is_some_and(|next| next.starts_with('{'));
+        if definition {
+            write!(writer, "inline ")?;
+        }
+        write!(writer, "{}", line)?;
+    }
+    Ok(())
+}
+
+pub fn gen_body_lexer<W: Write>(lexer: &Lexer, config: &CppConfig, writer: &mut W) -> Result<()> {
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let ind = writer.unit();
+    let folded;
+    let lexer = if config.fold_keywords {
+        folded = lexer.fold_keywords()?;
+        &folded
+    } else {
+        lexer
+    };
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
+    let string = string_type(config);
+    let prefix = if config.utf16 { "u" } else { "" };
+    let trap = lexer.get_trap();
+
+    writeln!(writer, "#include \"{}.h\"", file_stem(config))?;
+    if !value_types(lexer).is_empty() {
+        write!(
+            writer,
+            "#include <cerrno>\n#include <cstdlib>\n#include <limits>\n"
+        )?;
+    }
+    if let Some(namespace) = &config.names.namespace {
+        write!(writer, "\nnamespace {}\n{{", namespace)?;
+    }
+    gen_fill(config, writer)?;
+    if config.utf16 {
+        gen_utf16_input(&config.names, writer)?;
+    }
+    if config.bytes {
+        write!(
+            writer,
+            r#"
+uint32_t {lexer_name}::next_chr(int *e, size_t pos, size_t &len)
+{{
+{ind}*e = 0;
+{ind}len = 0;
+{ind}if (!this->fill(pos + 1))
+{ind}{ind}return 0;
+{ind}len = 1;
+{ind}return (unsigned char)this->buf[pos];
+}}
+"#
+        )?;
+    } else if !config.utf16 {
+        write!(
+            writer,
+            r#"
+// taken from: https://github.com/skeeto/branchless-utf8
+uint32_t {lexer_name}::next_chr(int *e, size_t pos, size_t &len)
+{{
+{ind}uint32_t ch = 0;
+{ind}uint32_t *c = &ch;
+{ind}static const char lengths[] = {{
+{ind}{ind}1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+{ind}{ind}0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 3, 3, 4, 0}};
+{ind}static const int masks[] = {{0x00, 0x7f, 0x1f, 0x0f, 0x07}};
+{ind}static const uint32_t mins[] = {{4194304, 0, 128, 2048, 65536}};
+{ind}static const int shiftc[] = {{0, 18, 12, 6, 0}};
+{ind}static const int shifte[] = {{0, 6, 4, 2, 0}};
+
+{ind}unsigned char s[4] = {{0}};
+{ind}len = 0;
+{ind}if (!this->fill(pos + 1))
+{ind}{{
+{ind}{ind}*e = 0;
+{ind}{ind}return 0;
+{ind}}}
+{ind}s[0] = this->buf[pos];
+{ind}len = lengths[s[0] >> 3];
+{ind}if (!len)
+{ind}{{
+{ind}{ind}/* Stray continuation or invalid lead byte. */
+{ind}{ind}len = 1;
+{ind}{ind}*e = 1;
+{ind}{ind}return s[0];
+{ind}}}
+{ind}this->fill(pos + len);
+{ind}for (size_t i = 1; i < len && pos + i < this->buf.size(); i++)
+{ind}{ind}s[i] = this->buf[pos + i];
 
+{ind}/* Assume a four-byte character and load four bytes. Unused bits are
+{ind} * shifted out.
+{ind} */
+{ind}*c = (uint32_t)(s[0] & masks[len]) << 18;
+{ind}*c |= (uint32_t)(s[1] & 0x3f) << 12;
+{ind}*c |= (uint32_t)(s[2] & 0x3f) << 6;
+{ind}*c |= (uint32_t)(s[3] & 0x3f) << 0;
+{ind}*c >>= shiftc[len];
+
+{ind}/* Accumulate the various error conditions. */
+{ind}*e = (*c < mins[len]) << 6;      // non-canonical encoding
+{ind}*e |= ((*c >> 11) == 0x1b) << 7; // surrogate half?
+{ind}*e |= (*c > 0x10FFFF) << 8;      // out of range?
+{ind}*e |= (s[1] & 0xc0) >> 2;
+{ind}*e |= (s[2] & 0xc0) >> 4;
+{ind}*e |= (s[3]) >> 6;
+{ind}*e ^= 0x2a; // top two bits of each tail byte correct?
+{ind}*e >>= shifte[len];
+
+{ind}return ch;
+}}
+"#
+        )?;
+    }
     write!(
         writer,
-        r#"#include "lexer.h"
-#include <system_error>
-#include <sstream>
+        r#"
+{lexer_name}::{lexer_name}(std::istream &contents, size_t capacity)
+{{
+{ind}this->buf.reserve(capacity);
+{ind}this->reset(contents);
+}}
 
-void Lexer::read(bool &use_buf, char *dst, size_t n)
+{lexer_name}::{lexer_name}(const {unit} *data, size_t size)
 {{
-    if (n == 0)
-        return;
-    if (use_buf)
-    {{
-        size_t read = this->buf.readsome(dst, n);
-        if (read < n || !this->buf.rdbuf()->in_avail())
-        {{
-            use_buf = false;
-            this->contents.read(dst + read, n - read);
-        }}
-    }}
-    else
-    {{
-        this->contents.read(dst, n);
-    }}
+{ind}this->reset(data, size);
 }}
 
-// taken from: https://github.com/skeeto/branchless-utf8
-uint32_t Lexer::next_chr(int *e, bool &use_buf)
-{{
-    uint32_t ch = 0;
-    uint32_t *c = &ch;
-    static const char lengths[] = {{
-        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-        0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 3, 3, 4, 0}};
-    static const int masks[] = {{0x00, 0x7f, 0x1f, 0x0f, 0x07}};
-    static const uint32_t mins[] = {{4194304, 0, 128, 2048, 65536}};
-    static const int shiftc[] = {{0, 18, 12, 6, 0}};
-    static const int shifte[] = {{0, 6, 4, 2, 0}};
-
-    char s[4] = {{0}};
-    this->read(use_buf, s, 1);
-    int len = lengths[s[0] >> 3];
-    if (len)
-        this->read(use_buf, s + 1, len - 1);
-
-    /* Assume a four-byte character and load four bytes. Unused bits are
-     * shifted out.
-     */
-    *c = (uint32_t)(s[0] & masks[len]) << 18;
-    *c |= (uint32_t)(s[1] & 0x3f) << 12;
-    *c |= (uint32_t)(s[2] & 0x3f) << 6;
-    *c |= (uint32_t)(s[3] & 0x3f) << 0;
-    *c >>= shiftc[len];
-
-    /* Accumulate the various error conditions. */
-    *e = (*c < mins[len]) << 6;      // non-canonical encoding
-    *e |= ((*c >> 11) == 0x1b) << 7; // surrogate half?
-    *e |= (*c > 0x10FFFF) << 8;      // out of range?
-    *e |= (s[1] & 0xc0) >> 2;
-    *e |= (s[2] & 0xc0) >> 4;
-    *e |= (s[3]) >> 6;
-    *e ^= 0x2a; // top two bits of each tail byte correct?
-    *e >>= shifte[len];
-
-    return ch;
-}}
-
-int push_utf8(std::ostream &s, uint32_t cp)
-{{
-    int len = 4 - ((cp < 0x80) + (cp < 0x800) + (cp < 0x10000));
-    char chs[4] = {{0}};
-    chs[(len - 3 >= 0) * len - 3] = (0b10000000 | ((cp >> 12) & 0x3f));
-    chs[(len - 2 >= 0) * len - 2] = (0b10000000 | ((cp >> 6) & 0x3f));
-    chs[len - 1] = (0b10000000 | (cp & 0x3f));
-    chs[0] = cp * (len == 1) +
-             (0b11000000 | ((cp >> 6) & 0x1f)) * (len == 2) +
-             (0b11100000 | ((cp >> 12) & 0xf)) * (len == 3) +
-             (0b11110000 | ((cp >> 18) & 0x7)) * (len == 4);
-    s.write(chs, len);
-    return len;
-}}
-
-Lexer::Lexer(std::istream &contents) : contents(contents) {{}}
-
-std::string Lexer::next(Token &token)
-{{
-    Token found = Token::_TRAP;
-    size_t found_pos = 0;
-
-    size_t pos = 0;
-    size_t state = 0;
-    bool use_buf = this->buf.rdbuf()->in_avail();
-    while (1)
-    {{
-        if (state == {}) {{
-            std::string s(found_pos, '\0');
-            this->buf.read(&s[0], found_pos);
-            token = found;
-            return s;
-        }}
-
-        int error = 0;
-        uint32_t ch = this->next_chr(&error, use_buf);
-        if (error) {{
-            token = Token::_ERR;
-            return "";
-        }}
-        int chlen = push_utf8(this->buf, ch);
-
-        switch (state) {{
+{lexer_name}::{lexer_name}(const {string} &text) : {lexer_name}(text.data(), text.size())
+{{
+}}
+
+void {lexer_name}::reset(std::istream &contents)
+{{
+{ind}this->contents = &contents;
+{ind}this->data = nullptr;
+{ind}this->data_left = 0;
+{ind}this->clear();
+}}
+
+void {lexer_name}::reset(const {unit} *data, size_t size)
+{{
+{ind}this->contents = nullptr;
+{ind}this->data = data;
+{ind}this->data_left = size;
+{ind}this->clear();
+}}
+
+void {lexer_name}::reset(const {string} &text)
+{{
+{ind}this->reset(text.data(), text.size());
+}}
+"#,
+        unit = unit_type(config)
+    )?;
+    gen_clear(lexer, config, writer)?;
+    if config.table_driven {
+        gen_transition_tables(lexer, config, writer)?;
+    } else {
+        gen_to_alphabet(lexer, &config.names, writer)?;
+    }
+    if dynamic_start(lexer) {
+        gen_start_state(lexer, &config.names, writer)?;
+    }
+    for (token, lookahead) in lexer.get_lookaheads() {
+        gen_rejects(token, lookahead, &config.names, writer)?;
+    }
+    if config.positions {
+        gen_positions(config, writer)?;
+    }
+    if config.error_context {
+        gen_error_context(config, writer)?;
+    }
+    if config.validate_utf8 {
+        gen_validate_utf8(&config.names, writer)?;
+    }
+    if !value_types(lexer).is_empty() {
+        gen_parse_value(lexer, &config.names, writer)?;
+    }
+    if config.keyword_hash {
+        gen_keyword(lexer, &config.names, writer)?;
+    }
+    if !lexer.get_folded().is_empty() {
+        gen_fold_keyword(lexer, &config.names, writer)?;
+    }
+    if !lexer.get_equivalences().is_empty() {
+        gen_token_class(lexer, &config.names, writer)?;
+    }
+    gen_token_name(lexer, &config.names, writer)?;
+    if lexer.has_displays() {
+        gen_token_display(lexer, &config.names, writer)?;
+    }
+    if let Some(lookahead) = config.lookahead {
+        gen_lookahead(lookahead, config, writer)?;
+    }
+    if config.token_counts {
+        gen_token_counts(config, writer)?;
+    }
+    write!(
+        writer,
+        r#"
+{string} {lexer_name}::{}({token_name} &token)
+{{
+{ind}{token_name} found = {token_name}::{eof};
+{ind}size_t found_pos = 0;
+
+{ind}size_t pos = 0;
+{ind}size_t state = {};
+"#,
+        if config.lookahead.is_some() || config.token_counts {
+            "scan"
+        } else {
+            "next"
+        },
+        start_state(lexer)
+    )?;
+    if config.strip_bom {
+        gen_strip_bom(config, writer)?;
+    }
+    if config.bulk_errors {
+        write_line!(1, writer, "size_t start = 0;\n");
+    }
+    if config.positions {
+        write_line!(1, writer, "this->start_position = this->cursor;\n");
+    }
+    write!(
+        writer,
+        r#"{ind}while (1)
+{ind}{{
+{ind}{ind}if (state == {}) {{
 "#,
         trap
     )?;
+    if config.bulk_errors {
+        write_line!(3, writer, "if (found == {token_name}::{eof}) {{\n");
+        gen_skip_error(lexer, config, 4, writer)?;
+        write_line!(3, writer, "}}\n");
+        gen_return_error_run(lexer, config, "start > 0", 3, writer)?;
+    } else if config.recover {
+        write_line!(3, writer, "if (found == {token_name}::{eof}) {{\n");
+        gen_recover(lexer, config, 4, writer)?;
+        write_line!(3, writer, "}}\n");
+    } else if config.error_context {
+        write_line!(3, writer, "if (found == {token_name}::{eof}) {{\n");
+        write_line!(4, writer, "this->fail(0);\n");
+        write_line!(4, writer, "token = {token_name}::_ERR;\n");
+        write_line!(4, writer, "return {prefix}\"\";\n");
+        write_line!(3, writer, "}}\n");
+    } else {
+        write_line!(3, writer, "if (found == {token_name}::{eof}) {{\n");
+        write_line!(4, writer, "token = {token_name}::_ERR;\n");
+        write_line!(4, writer, "return {prefix}\"\";\n");
+        write_line!(3, writer, "}}\n");
+    }
+    gen_return_found(lexer, config, 3, writer)?;
+    write!(
+        writer,
+        r#"{ind}{ind}}}
+
+{ind}{ind}int error = 0;
+{ind}{ind}size_t chlen = 0;
+{ind}{ind}uint32_t ch = this->next_chr(&error, pos, chlen);
+{ind}{ind}if (error) {{
+"#
+    )?;
+    // A token matched before the bad unit is still returned, and without
+    // recovery the unmatched text up to and including the bad unit is
+    // consumed as the error, so the next call starts after it.
+    gen_accept_current(lexer, config, trap, writer)?;
+    if config.recover || config.bulk_errors {
+        write_line!(3, writer, "state = {};\n", trap);
+        write_line!(3, writer, "continue;\n");
+    } else {
+        write_line!(3, writer, "if (found != {token_name}::{eof}) {{\n");
+        write_line!(4, writer, "state = {};\n", trap);
+        write_line!(4, writer, "continue;\n");
+        write_line!(3, writer, "}}\n");
+        if config.error_context {
+            write_line!(3, writer, "this->fail(pos);\n");
+        }
+        write_line!(3, writer, "{string} s = this->buf.substr(0, pos + 1);\n");
+        gen_track_line_start(lexer, config, "pos + 1", 3, writer)?;
+        write_line!(3, writer, "this->buf.erase(0, pos + 1);\n");
+        if config.positions {
+            write_line!(3, writer, "this->track(s);\n");
+        }
+        if config.error_context {
+            write_line!(3, writer, "this->advance(s);\n");
+        }
+        write_line!(3, writer, "token = {token_name}::_ERR;\n");
+        write_line!(3, writer, "return s;\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}}}
+
+{ind}{ind}int ach = {lexer_name}::to_alphabet(ch);
+
+"#
+    )?;
+    if config.bytes {
+        writeln!(writer, "{ind}{ind}if (chlen == 0)\n{ind}{ind}{{")?;
+        gen_accept_current(lexer, config, trap, writer)?;
+    } else {
+        gen_step(lexer, config, trap, writer)?;
+        writeln!(writer, "\n{ind}{ind}if (ch == 0)\n{ind}{ind}{{")?;
+    }
+    if config.bulk_errors {
+        write_line!(
+            3,
+            writer,
+            "if (found == {token_name}::{eof} && pos > start)\n"
+        );
+        write_line!(3, writer, "{{\n");
+        gen_skip_error(lexer, config, 4, writer)?;
+        write_line!(3, writer, "}}\n");
+        gen_return_error_run(lexer, config, "start > 0", 3, writer)?;
+    } else if config.recover {
+        write_line!(3, writer, "if (found == {token_name}::{eof} && pos > 0)\n");
+        write_line!(3, writer, "{{\n");
+        gen_recover(lexer, config, 4, writer)?;
+        write_line!(3, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}{ind}if (found == {token_name}::{eof})
+{ind}{ind}{ind}{{
+{ind}{ind}{ind}{ind}token = {token_name}::{eof};
+{ind}{ind}{ind}{ind}return {prefix}"";
+{ind}{ind}{ind}}}
+
+"#
+    )?;
+    gen_return_found(lexer, config, 3, writer)?;
+    writeln!(writer, "{ind}{ind}}}")?;
+    if config.bytes {
+        writeln!(writer)?;
+        gen_step(lexer, config, trap, writer)?;
+    }
+    write!(
+        writer,
+        r#"
+{ind}{ind}pos += chlen;
+{ind}}}
+}}"#
+    )?;
+    if let Some(namespace) = &config.names.namespace {
+        write!(writer, "\n\n}} // namespace {}\n", namespace)?;
+    }
+    Ok(())
+}
+
+fn gen_clear<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    write!(writer, "\nvoid {lexer_name}::clear()\n{{\n")?;
+    write_line!(1, writer, "this->buf.clear();\n");
+    if !lexer.get_modes().is_empty() {
+        write_line!(1, writer, "this->modes.clear();\n");
+    }
+    if config.strip_bom {
+        write_line!(1, writer, "this->at_start = true;\n");
+    }
+    if !lexer.get_line_starts().is_empty() {
+        write_line!(1, writer, "this->at_line_start = true;\n");
+    }
+    if config.positions {
+        write_line!(
+            1,
+            writer,
+            "this->cursor = {token_name}Position{{1, 1, 0, 0}};\n"
+        );
+        write_line!(1, writer, "this->start_position = this->cursor;\n");
+    }
+    if config.error_context {
+        write_line!(1, writer, "this->line = 1;\n");
+        write_line!(1, writer, "this->column = 1;\n");
+        write_line!(1, writer, "this->current_line.clear();\n");
+        write_line!(1, writer, "this->last_error = LexError();\n");
+    }
+    if config.lookahead.is_some() {
+        write_line!(1, writer, "this->lookahead_start = 0;\n");
+        write_line!(1, writer, "this->lookahead_count = 0;\n");
+    }
+    if !value_types(lexer).is_empty() {
+        write_line!(1, writer, "this->last_value = std::monostate();\n");
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_fill<W: Write>(config: &CppConfig, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    write!(
+        writer,
+        r#"
+
+bool {lexer_name}::fill(size_t n)
+{{
+{ind}if (!this->contents)
+{ind}{{
+{ind}{ind}size_t count = n > this->buf.size() ? n - this->buf.size() : 0;
+{ind}{ind}if (count > this->data_left)
+{ind}{ind}{ind}count = this->data_left;
+{ind}{ind}this->buf.append(this->data, count);
+{ind}{ind}this->data += count;
+{ind}{ind}this->data_left -= count;
+{ind}{ind}return this->buf.size() >= n;
+{ind}}}
+{ind}while (this->buf.size() < n)
+{ind}{{
+"#
+    )?;
+    if config.utf16 {
+        write!(
+            writer,
+            r#"{ind}{ind}int lo = this->contents->get();
+{ind}{ind}int hi = this->contents->get();
+{ind}{ind}if (hi == std::char_traits<char>::eof())
+{ind}{ind}{ind}return false;
+{ind}{ind}this->buf.push_back((char16_t)(lo | hi << 8));
+"#
+        )?;
+    } else {
+        write!(
+            writer,
+            r#"{ind}{ind}int c = this->contents->get();
+{ind}{ind}if (c == std::char_traits<char>::eof())
+{ind}{ind}{ind}return false;
+{ind}{ind}this->buf.push_back((char)c);
+"#
+        )?;
+    }
+    write!(
+        writer,
+        r#"{ind}}}
+{ind}return true;
+}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_utf16_input<W: Write>(names: &Names, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    write!(
+        writer,
+        r#"
+uint32_t {lexer_name}::next_chr(int *e, size_t pos, size_t &len)
+{{
+{ind}*e = 0;
+{ind}len = 0;
+{ind}if (!this->fill(pos + 1))
+{ind}{ind}return 0;
+{ind}uint32_t ch = this->buf[pos];
+{ind}len = 1;
+{ind}if (ch >= 0xd800 && ch <= 0xdbff)
+{ind}{{
+{ind}{ind}if (this->fill(pos + 2) && (this->buf[pos + 1] & 0xfc00) == 0xdc00)
+{ind}{ind}{{
+{ind}{ind}{ind}len = 2;
+{ind}{ind}{ind}return 0x10000 + ((ch - 0xd800) << 10) + (this->buf[pos + 1] - 0xdc00);
+{ind}{ind}}}
+{ind}{ind}/* Unpaired high surrogate. */
+{ind}{ind}*e = 1;
+{ind}}}
+{ind}else if (ch >= 0xdc00 && ch <= 0xdfff)
+{ind}{{
+{ind}{ind}/* Unpaired low surrogate. */
+{ind}{ind}*e = 1;
+{ind}}}
+{ind}return ch;
+}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_strip_bom<W: Write>(config: &CppConfig, writer: &mut CodeWriter<W>) -> Result<()> {
+    write_line!(1, writer, "if (this->at_start)\n");
+    write_line!(1, writer, "{{\n");
+    write_line!(2, writer, "this->at_start = false;\n");
+    if config.utf16 {
+        write_line!(2, writer, "if (this->fill(1) && this->buf[0] == 0xfeff)\n");
+        write_line!(3, writer, "this->buf.erase(0, 1);\n");
+    } else {
+        write_line!(
+            2,
+            writer,
+            "if (this->fill(1) && this->buf[0] == '\\xef' && this->fill(3) && this->buf.compare(0, 3, \"\\xef\\xbb\\xbf\") == 0)\n"
+        );
+        write_line!(3, writer, "this->buf.erase(0, 3);\n");
+    }
+    write_line!(1, writer, "}}\n");
+    Ok(())
+}
+
+fn gen_validate_utf8<W: Write>(names: &Names, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    write!(
+        writer,
+        r#"
+bool {lexer_name}::validate_utf8(size_t &offset)
+{{
+{ind}size_t pos = 0;
+{ind}while (1)
+{ind}{{
+{ind}{ind}int error = 0;
+{ind}{ind}size_t chlen = 0;
+{ind}{ind}this->next_chr(&error, pos, chlen);
+{ind}{ind}if (error)
+{ind}{ind}{{
+{ind}{ind}{ind}offset = pos;
+{ind}{ind}{ind}return false;
+{ind}{ind}}}
+{ind}{ind}if (chlen == 0)
+{ind}{ind}{ind}return true;
+{ind}{ind}pos += chlen;
+{ind}}}
+}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_step<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    trap: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    if config.table_driven {
+        gen_table_step(lexer, config, trap, writer)
+    } else {
+        gen_switch_step(lexer, config, trap, writer)
+    }
+}
+
+// At the end of the input, records the token accepted by the current state
+// instead of stepping the DFA on a NUL that is real input in byte mode.
+fn gen_accept_current<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    trap: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
+    if config.table_driven {
+        write_line!(3, writer, "if (ACCEPTS[state] != {token_name}::{eof}) {{\n");
+        write_line!(4, writer, "found_pos = pos;\n");
+        write_line!(4, writer, "found = ACCEPTS[state];\n");
+        write_line!(3, writer, "}}\n");
+        return Ok(());
+    }
+    write_line!(3, writer, "switch (state) {{\n");
     for (i, acc) in lexer.get_states().iter().enumerate() {
-        if i != trap {
-            write_line!(3, writer, "case {}:\r\n", i);
-            write_line!(4, writer, "switch (ch) {{\r\n");
-            let mut results: BTreeMap<usize, Vec<(u32, u32)>> = BTreeMap::new();
+        if let Some(acc) = acc.filter(|_| i != trap) {
+            write_line!(
+                3,
+                writer,
+                "case {}:{}\n",
+                i,
+                source_comment(lexer, config, acc)
+            );
+            gen_found(lexer, config, acc, "pos", "", 4, writer)?;
+            write_line!(4, writer, "break;\n");
+        }
+    }
+    write_line!(3, writer, "}}\n");
+    Ok(())
+}
+
+// Unless it reads bytes, the lexer reads a NUL as the end of the input, so
+// it must not step into a state that e.g. a `.` accepts there.
+fn steps_on(config: &CppConfig, r0: u32, r1: u32) -> bool {
+    config.bytes || (r0, r1) != (0, 0)
+}
+
+fn gen_switch_step<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    trap: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &config.names.token;
+    writeln!(writer, "{ind}{ind}switch (state) {{")?;
+    let states = lexer.get_states();
+    let alphabet_ids = alphabet_ids(lexer);
+    let committed = committed_states(lexer);
+    for (i, acc) in states.iter().enumerate() {
+        if i != trap && !committed.finals.contains(&i) {
+            let comment = match acc {
+                Some(acc) => source_comment(lexer, config, acc),
+                None => String::new(),
+            };
+            write_line!(3, writer, "case {}:{}\n", i, comment);
+            if let (Some(acc), MatchMode::Shortest) = (acc, lexer.get_match_mode()) {
+                write_line!(4, writer, "found_pos = pos;\n");
+                write_line!(
+                    4,
+                    writer,
+                    "found = {token_name}::{};\n",
+                    lexer.reported_token(acc)
+                );
+                write_line!(4, writer, "state = {};\n", trap);
+                write_line!(4, writer, "break;\n");
+                continue;
+            }
+            write_line!(4, writer, "switch (ach) {{\n");
+            let mut results: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
             for (r0, r1, result) in lexer.get_connections(i) {
-                if let Some(result) = results.get_mut(&result) {
-                    result.push((r0, r1));
-                } else {
-                    results.insert(result, vec![(r0, r1)]);
+                if !steps_on(config, r0, r1) {
+                    continue;
                 }
+                results
+                    .entry(result)
+                    .or_default()
+                    .push(alphabet_ids[&(r0, r1)]);
             }
-            for (result, ranges) in results {
+            // End of input isn't in the alphabet and has to reach the trap.
+            results.entry(trap).or_default();
+            for (result, alphabet_ids) in results {
                 if result == trap {
-                    write_line!(5, writer, "default:\r\n");
+                    write_line!(5, writer, "default:\n");
                 } else {
-                    for (r0, r1) in ranges {
-                        if r0 == r1 {
-                            write_line!(5, writer, "case {}:\r\n", r0);
-                        } else {
-                            write_line!(5, writer, "case {} ... {}:\r\n", r0, r1);
-                        }
+                    for alphabet_id in alphabet_ids {
+                        write_line!(5, writer, "case {}:\n", alphabet_id);
                     }
                 }
-                if let Some(acc) = acc {
-                    write_line!(6, writer, "found_pos = pos;\r\n");
-                    write_line!(6, writer, "found = Token::{};\r\n", acc);
-                    write_line!(6, writer, "state = {};\r\n", result);
-                    write_line!(6, writer, "break;\r\n");
-                } else {
-                    write_line!(6, writer, "state = {};\r\n", result);
-                    write_line!(6, writer, "break;\r\n");
-                }
+                // A committed state recorded its token when it was entered.
+                let acc = acc.filter(|_| !committed.states.contains(&i));
+                gen_transition(lexer, &committed, acc, result, trap, 6, config, writer)?;
             }
-            write_line!(4, writer, "}}\r\n");
-            write_line!(4, writer, "break;\r\n");
+            write_line!(4, writer, "}}\n");
+            write_line!(4, writer, "break;\n");
         }
     }
-    write!(
-        writer,
-        r#"        }}
-
-        if (ch == 0)
-        {{
-            if (found == Token::_TRAP)
-            {{
-                token = Token::_EOF;
-                return "";
-            }}
+    writeln!(writer, "{ind}{ind}}}")?;
+    Ok(())
+}
 
-            std::string s(found_pos, '\0');
-            this->buf.read(&s[0], found_pos);
-            token = found;
-            return s;
-        }}
+fn gen_table_step<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    trap: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
+    match lexer.get_match_mode() {
+        MatchMode::Longest => {
+            write_line!(2, writer, "size_t next = TRANSITIONS[state][ach];\n");
+            if !committed_states(lexer).finals.is_empty() {
+                write_line!(2, writer, "if (FINAL[next]) {{\n");
+                write_line!(3, writer, "found_pos = pos + chlen;\n");
+                write_line!(3, writer, "found = ACCEPTS[next];\n");
+                write_line!(3, writer, "next = {};\n", trap);
+                write!(writer, "{ind}{ind}}} else ")?;
+            } else {
+                write!(writer, "{ind}{ind}")?;
+            }
+            writeln!(writer, "if (ACCEPTS[state] != {token_name}::{eof}) {{")?;
+            write_line!(3, writer, "found_pos = pos;\n");
+            write_line!(3, writer, "found = ACCEPTS[state];\n");
+            write_line!(2, writer, "}}\n");
+            write_line!(2, writer, "state = next;\n");
+        }
+        MatchMode::Shortest => {
+            write_line!(2, writer, "if (ACCEPTS[state] != {token_name}::{eof}) {{\n");
+            write_line!(3, writer, "found_pos = pos;\n");
+            write_line!(3, writer, "found = ACCEPTS[state];\n");
+            write_line!(3, writer, "state = {};\n", trap);
+            write_line!(2, writer, "}} else {{\n");
+            write_line!(3, writer, "state = TRANSITIONS[state][ach];\n");
+            write_line!(2, writer, "}}\n");
+        }
+    }
+    Ok(())
+}
 
-        pos += chlen;
-    }}
-}}"#
+fn gen_transition_tables<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
+    let mut tables = tables(lexer);
+    if let Some(nul) = (tables.alphabet.iter()).position(|&(r0, r1)| !steps_on(config, r0, r1)) {
+        for row in &mut tables.transitions {
+            row[nul] = tables.trap;
+        }
+    }
+    let states = lexer.get_states();
+    let state_type = if states.len() <= 1 << 8 {
+        "uint8_t"
+    } else if states.len() <= 1 << 16 {
+        "uint16_t"
+    } else {
+        "uint32_t"
+    };
+    write!(
+        writer,
+        "\nstatic const uint32_t ALPHABET[{}] = {{\n",
+        tables.alphabet.len()
     )?;
-    Ok(())
+    for (_, last) in &tables.alphabet {
+        write_line!(1, writer, "{},\n", last);
+    }
+    write!(
+        writer,
+        "}};\n\nstatic const {} TRANSITIONS[{}][{}] = {{\n",
+        state_type,
+        tables.transitions.len(),
+        tables.alphabet.len()
+    )?;
+    for row in &tables.transitions {
+        let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+        write_line!(1, writer, "{{{}}},\n", row.join(", "));
+    }
+    write!(
+        writer,
+        "}};\n\nstatic const {token_name} ACCEPTS[{}] = {{\n",
+        states.len()
+    )?;
+    for (i, state) in states.iter().enumerate() {
+        match state {
+            Some(token) if i != tables.trap => {
+                write_line!(
+                    1,
+                    writer,
+                    "{token_name}::{},{}\n",
+                    lexer.reported_token(token),
+                    source_comment(lexer, config, token)
+                );
+            }
+            _ => {
+                write_line!(1, writer, "{token_name}::{eof},\n");
+            }
+        }
+    }
+    writeln!(writer, "}};")?;
+    let finals = committed_states(lexer).finals;
+    if !finals.is_empty() {
+        write!(writer, "\nstatic const bool FINAL[{}] = {{\n", states.len())?;
+        for i in 0..states.len() {
+            write_line!(1, writer, "{},\n", finals.contains(&i));
+        }
+        writeln!(writer, "}};")?;
+    }
+    write!(
+        writer,
+        r#"
+int {lexer_name}::to_alphabet(uint32_t ch)
+{{
+{ind}int low = 0;
+{ind}int high = {};
+{ind}while (low < high)
+{ind}{{
+{ind}{ind}int mid = (low + high) / 2;
+{ind}{ind}if (ALPHABET[mid] < ch)
+{ind}{ind}{ind}low = mid + 1;
+{ind}{ind}else
+{ind}{ind}{ind}high = mid;
+{ind}}}
+{ind}return low;
+}}
+"#,
+        tables.alphabet.len() - 1
+    )?;
+    Ok(())
+}
+
+fn gen_to_alphabet<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    write!(
+        writer,
+        "\nint {lexer_name}::to_alphabet(uint32_t ch)\n{{\n{ind}switch (ch) {{\n"
+    )?;
+    for (i, (r0, r1)) in lexer.get_alphabet().iter().enumerate() {
+        if r0 == r1 {
+            write_line!(2, writer, "case {}:\n", r0);
+            write_line!(3, writer, "return {};\n", i);
+        }
+    }
+    write_line!(1, writer, "}}\n");
+    write!(writer, "{ind}")?;
+    for (i, (r0, r1)) in lexer.get_alphabet().iter().enumerate() {
+        if r0 != r1 {
+            writeln!(writer, "if (ch >= {} && ch <= {}) {{", r0, r1)?;
+            write_line!(2, writer, "return {};\n", i);
+            write_line!(1, writer, "}} else ");
+        }
+    }
+    write!(writer, "{{\n{ind}{ind}return -1;\n{ind}}}\n}}\n")?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn gen_transition<W: Write>(
+    lexer: &Lexer,
+    committed: &Committed,
+    acc: Option<&SmolStr>,
+    result: usize,
+    trap: usize,
+    indent: usize,
+    config: &CppConfig,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let states = lexer.get_states();
+    let entered = states[result].filter(|_| committed.states.contains(&result));
+    if let Some(token) = entered {
+        let comment = source_comment(lexer, config, token);
+        // The state being left is only passed over when the committed token
+        // is accepted, which its lookahead can still prevent.
+        if let Some(acc) = acc.filter(|_| lexer.get_lookahead(token).is_some()) {
+            gen_found(lexer, config, acc, "pos", "", indent, writer)?;
+        }
+        gen_found(
+            lexer,
+            config,
+            token,
+            "pos + chlen",
+            &comment,
+            indent,
+            writer,
+        )?;
+        if committed.finals.contains(&result) {
+            write_line!(indent, writer, "state = {};\n", trap);
+        } else {
+            write_line!(indent, writer, "state = {};\n", result);
+        }
+    } else if let Some(acc) = acc {
+        gen_found(lexer, config, acc, "pos", "", indent, writer)?;
+        write_line!(indent, writer, "state = {};\n", result);
+    } else {
+        write_line!(indent, writer, "state = {};\n", result);
+    }
+    write_line!(indent, writer, "break;\n");
+    Ok(())
+}
+
+// Records the token accepted by a rule ending at end, unless the rule's
+// negative lookahead matches what follows.
+#[allow(clippy::too_many_arguments)]
+fn gen_found<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    acc: &SmolStr,
+    end: &str,
+    comment: &str,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &config.names.token;
+    let guarded = lexer.get_lookahead(acc).is_some();
+    if guarded {
+        write_line!(indent, writer, "if (!this->rejects_{}({})) {{\n", acc, end);
+    }
+    let inner = indent + guarded as usize;
+    write_line!(inner, writer, "found_pos = {};\n", end);
+    write_line!(
+        inner,
+        writer,
+        "found = {token_name}::{};{}\n",
+        lexer.reported_token(acc),
+        comment
+    );
+    if guarded {
+        write_line!(indent, writer, "}}\n");
+    }
+    Ok(())
+}
+
+// Walks the lookahead's DFA from pos, which stops at its first accepting
+// state. Running out of input or hitting a malformed unit doesn't reject.
+fn gen_rejects<W: Write>(
+    token: &SmolStr,
+    lookahead: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    let trap = lookahead.get_trap();
+    write!(
+        writer,
+        r#"
+bool {lexer_name}::rejects_{token}(size_t pos)
+{{
+{ind}size_t state = {};
+{ind}while (1)
+{ind}{{
+{ind}{ind}int error = 0;
+{ind}{ind}size_t chlen = 0;
+{ind}{ind}uint32_t ch = this->next_chr(&error, pos, chlen);
+{ind}{ind}if (error || chlen == 0)
+{ind}{ind}{ind}return false;
+{ind}{ind}switch (state) {{
+"#,
+        lookahead.start_state()
+    )?;
+    for (i, _) in lookahead.get_states().iter().enumerate() {
+        if i == trap || lookahead.accept(i).is_some() {
+            continue;
+        }
+        write_line!(2, writer, "case {}:\n", i);
+        for (r0, r1, result) in lookahead.get_connections(i) {
+            if result == trap {
+                continue;
+            }
+            write_line!(3, writer, "if (ch >= {} && ch <= {}) {{\n", r0, r1);
+            if lookahead.accept(result).is_some() {
+                write_line!(4, writer, "return true;\n");
+            } else {
+                write_line!(4, writer, "state = {};\n", result);
+                write_line!(4, writer, "break;\n");
+            }
+            write_line!(3, writer, "}}\n");
+        }
+        write_line!(3, writer, "return false;\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}}}
+{ind}{ind}pos += chlen;
+{ind}}}
+}}
+"#
+    )?;
+    Ok(())
+}
+
+fn source_comment(lexer: &Lexer, config: &CppConfig, token: &SmolStr) -> String {
+    if config.source_comments {
+        format!(" // {}", rule_origin(lexer, token))
+    } else {
+        String::new()
+    }
+}
+
+fn dynamic_start(lexer: &Lexer) -> bool {
+    !lexer.get_modes().is_empty() || !lexer.get_line_starts().is_empty()
+}
+
+fn start_state(lexer: &Lexer) -> &'static str {
+    if dynamic_start(lexer) {
+        "this->start_state()"
+    } else {
+        "0"
+    }
+}
+
+fn gen_track_line_start<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    end: &str,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    if !lexer.get_line_starts().is_empty() {
+        write_line!(
+            indent,
+            writer,
+            "this->at_line_start = this->buf[{} - 1] == {}'\\n';\n",
+            end,
+            if config.utf16 { "u" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+fn gen_skip_error<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    write_line!(indent, writer, "int skip_error = 0;\n");
+    write_line!(indent, writer, "size_t skip = 0;\n");
+    write_line!(
+        indent,
+        writer,
+        "this->next_chr(&skip_error, start, skip);\n"
+    );
+    // A malformed sequence only counts its first unit, the rest is lexed again.
+    write_line!(indent, writer, "if (skip_error)\n");
+    write_line!(indent + 1, writer, "skip = 1;\n");
+    write_line!(indent, writer, "start += skip;\n");
+    gen_track_line_start(lexer, config, "start", indent, writer)?;
+    // A long run is returned in pieces instead of staying in the buffer.
+    if let Some(max_error_run) = config.max_error_run {
+        let condition = format!("start >= {}", max_error_run);
+        gen_return_error_run(lexer, config, &condition, indent, writer)?;
+    }
+    write_line!(indent, writer, "pos = start;\n");
+    write_line!(indent, writer, "state = {};\n", start_state(lexer));
+    write_line!(indent, writer, "continue;\n");
+    Ok(())
+}
+
+fn gen_recover<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    write_line!(indent, writer, "int skip_error = 0;\n");
+    write_line!(indent, writer, "size_t start = 0;\n");
+    write_line!(indent, writer, "this->next_chr(&skip_error, 0, start);\n");
+    write_line!(indent, writer, "if (skip_error)\n");
+    write_line!(indent + 1, writer, "start = 1;\n");
+    gen_return_error_run(lexer, config, "start > 0", indent, writer)
+}
+
+fn gen_return_error_run<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    condition: &str,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &config.names.token;
+    write_line!(indent, writer, "if ({}) {{\n", condition);
+    if config.error_context {
+        write_line!(indent + 1, writer, "this->fail(0);\n");
+    }
+    write_line!(
+        indent + 1,
+        writer,
+        "{} s = this->buf.substr(0, start);\n",
+        string_type(config)
+    );
+    gen_track_line_start(lexer, config, "start", indent + 1, writer)?;
+    write_line!(indent + 1, writer, "this->buf.erase(0, start);\n");
+    if config.positions {
+        write_line!(indent + 1, writer, "this->track(s);\n");
+    }
+    if config.error_context {
+        write_line!(indent + 1, writer, "this->advance(s);\n");
+    }
+    write_line!(indent + 1, writer, "token = {token_name}::_ERR;\n");
+    write_line!(indent + 1, writer, "return s;\n");
+    write_line!(indent, writer, "}}\n");
+    Ok(())
+}
+
+fn value_types(lexer: &Lexer) -> Vec<(&SmolStr, &'static str)> {
+    lexer
+        .get_terminals()
+        .iter()
+        .filter_map(|rule| {
+            let value_type = match rule.value_type.as_deref()? {
+                "int" => "int32_t",
+                "long" => "int64_t",
+                "float" => "float",
+                _ => "double",
+            };
+            Some((&rule.name, value_type))
+        })
+        .collect()
+}
+
+fn gen_return_found<W: Write>(
+    lexer: &Lexer,
+    config: &CppConfig,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
+    write_line!(
+        indent,
+        writer,
+        "{} s = this->buf.substr(0, found_pos);\n",
+        string_type(config)
+    );
+    if !lexer.get_folded().is_empty() {
+        write_line!(indent, writer, "found = fold_keyword(found, s);\n");
+    }
+    gen_track_line_start(lexer, config, "found_pos", indent, writer)?;
+    write_line!(indent, writer, "this->buf.erase(0, found_pos);\n");
+    if config.positions {
+        write_line!(indent, writer, "this->track(s);\n");
+    }
+    if config.error_context {
+        write_line!(indent, writer, "this->advance(s);\n");
+    }
+    gen_switch_mode(lexer, &config.names, indent, writer)?;
+    let skipped = lexer.get_skipped();
+    if !skipped.is_empty() {
+        let condition: Vec<String> = skipped
+            .iter()
+            .map(|token| format!("found == {token_name}::{}", token))
+            .collect();
+        write_line!(indent, writer, "if ({}) {{\n", condition.join(" || "));
+        write_line!(indent + 1, writer, "found = {token_name}::{eof};\n");
+        write_line!(indent + 1, writer, "found_pos = 0;\n");
+        write_line!(indent + 1, writer, "pos = 0;\n");
+        write_line!(indent + 1, writer, "state = {};\n", start_state(lexer));
+        if config.bulk_errors {
+            write_line!(indent + 1, writer, "start = 0;\n");
+        }
+        if config.positions {
+            write_line!(indent + 1, writer, "this->start_position = this->cursor;\n");
+        }
+        write_line!(indent + 1, writer, "continue;\n");
+        write_line!(indent, writer, "}}\n");
+    }
+    if config.normalize_newlines {
+        write_line!(
+            indent,
+            writer,
+            "for (size_t nl = s.find({0}\"\\r\\n\"); nl != {1}::npos; nl = s.find({0}\"\\r\\n\", nl))\n",
+            if config.utf16 { "u" } else { "" },
+            string_type(config)
+        );
+        write_line!(indent + 1, writer, "s.erase(nl, 1);\n");
+    }
+    write_line!(indent, writer, "token = found;\n");
+    if !value_types(lexer).is_empty() {
+        write_line!(indent, writer, "if (!this->parse_value(token, s))\n");
+        write_line!(indent + 1, writer, "token = {token_name}::_ERR;\n");
+    }
+    write_line!(indent, writer, "return s;\n");
+    Ok(())
+}
+
+fn gen_switch_mode<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &names.token;
+    let modes = lexer.get_modes();
+    let mut branch = "if";
+    for rule in lexer.get_terminals() {
+        match &rule.action {
+            Some(ModeAction::Push(mode)) => {
+                let (_, start) = modes.iter().find(|(name, _)| *name == mode).unwrap();
+                write_line!(
+                    indent,
+                    writer,
+                    "{} (found == {token_name}::{})\n",
+                    branch,
+                    rule.name
+                );
+                write_line!(
+                    indent + 1,
+                    writer,
+                    "this->modes.push_back({}); // {}\n",
+                    start,
+                    mode
+                );
+            }
+            Some(ModeAction::Pop) => {
+                write_line!(
+                    indent,
+                    writer,
+                    "{} (found == {token_name}::{} && !this->modes.empty())\n",
+                    branch,
+                    rule.name
+                );
+                write_line!(indent + 1, writer, "this->modes.pop_back();\n");
+            }
+            None => continue,
+        }
+        branch = "else if";
+    }
+    Ok(())
+}
+
+fn gen_start_state<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let lexer_name = &names.lexer;
+    write!(writer, "\nsize_t {lexer_name}::start_state() const\n{{\n")?;
+    let line_starts = lexer.get_line_starts();
+    if line_starts.is_empty() {
+        write_line!(
+            1,
+            writer,
+            "return this->modes.empty() ? 0 : this->modes.back();\n"
+        );
+        writeln!(writer, "}}")?;
+        return Ok(());
+    }
+    if lexer.get_modes().is_empty() {
+        write_line!(
+            1,
+            writer,
+            "return this->at_line_start ? {} : 0;\n",
+            line_starts[0].1
+        );
+        writeln!(writer, "}}")?;
+        return Ok(());
+    }
+    write_line!(
+        1,
+        writer,
+        "size_t state = this->modes.empty() ? 0 : this->modes.back();\n"
+    );
+    write_line!(1, writer, "if (!this->at_line_start)\n");
+    write_line!(2, writer, "return state;\n");
+    write_line!(1, writer, "switch (state)\n");
+    write_line!(1, writer, "{{\n");
+    for (start, line_start) in line_starts {
+        write_line!(1, writer, "case {}:\n", start);
+        write_line!(2, writer, "return {};\n", line_start);
+    }
+    write_line!(1, writer, "}}\n");
+    write_line!(1, writer, "return state;\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_parse_value<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    let token_name = &names.token;
+    write!(
+        writer,
+        r#"
+bool {lexer_name}::parse_value({token_name} token, const std::string &text)
+{{
+{ind}char *end = nullptr;
+{ind}errno = 0;
+{ind}this->last_value = std::monostate();
+{ind}switch (token)
+{ind}{{
+"#
+    )?;
+    for (token, value_type) in value_types(lexer) {
+        write_line!(1, writer, "case {token_name}::{}:\n", token);
+        write_line!(1, writer, "{{\n");
+        match value_type {
+            "int32_t" | "int64_t" => {
+                write_line!(
+                    2,
+                    writer,
+                    "long long v = std::strtoll(text.c_str(), &end, 10);\n"
+                );
+                write_line!(
+                    2,
+                    writer,
+                    "if (errno == ERANGE || *end || v < std::numeric_limits<{0}>::min() || v > std::numeric_limits<{0}>::max())\n",
+                    value_type
+                );
+            }
+            "float" => {
+                write_line!(2, writer, "float v = std::strtof(text.c_str(), &end);\n");
+                write_line!(2, writer, "if (errno == ERANGE || *end)\n");
+            }
+            _ => {
+                write_line!(2, writer, "double v = std::strtod(text.c_str(), &end);\n");
+                write_line!(2, writer, "if (errno == ERANGE || *end)\n");
+            }
+        }
+        write_line!(3, writer, "return false;\n");
+        write_line!(2, writer, "this->last_value = ({})v;\n", value_type);
+        write_line!(2, writer, "return true;\n");
+        write_line!(1, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"{ind}default:
+{ind}{ind}return true;
+{ind}}}
+}}
+
+const {token_name}Value &{lexer_name}::value() const
+{{
+{ind}return this->last_value;
+}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_lookahead<W: Write>(
+    lookahead: usize,
+    config: &CppConfig,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let string = string_type(config);
+    write!(
+        writer,
+        r#"
+{string} {lexer_name}::{next}({token_name} &token)
+{{
+{ind}if (this->lookahead_count == 0)
+{ind}{ind}return this->scan(token);
+{ind}std::pair<{token_name}, {string}> &entry = this->lookahead[this->lookahead_start];
+{ind}token = entry.first;
+{ind}{string} s = std::move(entry.second);
+{ind}this->lookahead_start = (this->lookahead_start + 1) % {0};
+{ind}this->lookahead_count--;
+{ind}return s;
+}}
+
+{string} {lexer_name}::peek({token_name} &token)
+{{
+{ind}std::pair<{token_name}, {string}> &entry = this->lookahead[this->lookahead_start];
+{ind}if (this->lookahead_count == 0)
+{ind}{{
+{ind}{ind}entry.second = this->scan(entry.first);
+{ind}{ind}this->lookahead_count = 1;
+{ind}}}
+{ind}token = entry.first;
+{ind}return entry.second;
+}}
+
+std::vector<std::pair<{token_name}, {string}>> {lexer_name}::peek_n(size_t n)
+{{
+{ind}if (n > {0})
+{ind}{ind}throw std::out_of_range("peek_n exceeds the maximum lookahead of {0}");
+{ind}while (this->lookahead_count < n)
+{ind}{{
+{ind}{ind}std::pair<{token_name}, {string}> &entry =
+{ind}{ind}{ind}this->lookahead[(this->lookahead_start + this->lookahead_count) % {0}];
+{ind}{ind}entry.second = this->scan(entry.first);
+{ind}{ind}this->lookahead_count++;
+{ind}}}
+{ind}std::vector<std::pair<{token_name}, {string}>> tokens;
+{ind}for (size_t i = 0; i < n; i++)
+{ind}{ind}tokens.push_back(this->lookahead[(this->lookahead_start + i) % {0}]);
+{ind}return tokens;
+}}
+"#,
+        lookahead,
+        next = if config.token_counts { "take" } else { "next" }
+    )?;
+    Ok(())
+}
+
+// next counts what the lexer proper, or the lookahead queue, hands out.
+fn gen_token_counts<W: Write>(config: &CppConfig, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    write!(
+        writer,
+        r#"
+{string} {lexer_name}::next({token_name} &token)
+{{
+{ind}{string} s = this->{inner}(token);
+{ind}this->token_counts[static_cast<size_t>(token)]++;
+{ind}return s;
+}}
+
+size_t {lexer_name}::token_count({token_name} token) const
+{{
+{ind}return this->token_counts[static_cast<size_t>(token)];
+}}
+
+void {lexer_name}::reset_token_counts()
+{{
+{ind}for (size_t &count : this->token_counts)
+{ind}{ind}count = 0;
+}}
+"#,
+        string = string_type(config),
+        inner = if config.lookahead.is_some() {
+            "take"
+        } else {
+            "scan"
+        }
+    )?;
+    Ok(())
+}
+
+fn gen_token_class<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &names.token;
+    write!(
+        writer,
+        "\n{token_name}Class token_class({token_name} token)\n{{\n"
+    )?;
+    write_line!(1, writer, "switch (token)\n");
+    write_line!(1, writer, "{{\n");
+    for (name, members) in lexer.get_equivalences() {
+        for member in members {
+            write_line!(1, writer, "case {token_name}::{}:\n", member);
+        }
+        write_line!(2, writer, "return {token_name}Class::{};\n", name);
+    }
+    write_line!(1, writer, "default:\n");
+    write_line!(2, writer, "return {token_name}Class::_NONE;\n");
+    write_line!(1, writer, "}}\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_token_name<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &names.token;
+    write!(writer, "\nconst char *token_name({token_name} token)\n{{\n")?;
+    write_line!(1, writer, "switch (token)\n");
+    write_line!(1, writer, "{{\n");
+    for token in public_tokens(lexer) {
+        write_line!(1, writer, "case {token_name}::{}:\n", token);
+        write_line!(2, writer, "return \"{}\";\n", token);
+    }
+    write_line!(1, writer, "}}\n");
+    write_line!(1, writer, "return \"\";\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_token_display<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &names.token;
+    write!(
+        writer,
+        "\nconst char *token_display({token_name} token)\n{{\n"
+    )?;
+    write_line!(1, writer, "switch (token)\n");
+    write_line!(1, writer, "{{\n");
+    for token in public_tokens(lexer) {
+        write_line!(1, writer, "case {token_name}::{}:\n", token);
+        write_line!(
+            2,
+            writer,
+            "return \"{}\";\n",
+            escape_string(&lexer.display(&token))
+        );
+    }
+    write_line!(1, writer, "}}\n");
+    write_line!(1, writer, "return \"\";\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_keyword<W: Write>(lexer: &Lexer, names: &Names, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    let token_name = &names.token;
+    let table = keyword_table(&lexer.get_keywords());
+    write!(
+        writer,
+        "\n{token_name} {lexer_name}::keyword(const std::string &text)\n{{\n"
+    )?;
+    write_line!(
+        1,
+        writer,
+        "static const char *const texts[{}] = {{\n",
+        table.slots.len()
+    );
+    for slot in &table.slots {
+        match slot {
+            Some((_, lit)) => {
+                write_line!(2, writer, "\"{}\",\n", escape_string(lit));
+            }
+            None => {
+                write_line!(2, writer, "nullptr,\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n");
+    write_line!(
+        1,
+        writer,
+        "static const {token_name} tokens[{}] = {{\n",
+        table.slots.len()
+    );
+    for slot in &table.slots {
+        match slot {
+            Some((name, _)) => {
+                write_line!(2, writer, "{token_name}::{},\n", name);
+            }
+            None => {
+                write_line!(2, writer, "{token_name}::_ERR,\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n");
+    write!(
+        writer,
+        r#"{ind}uint32_t hash = {}u;
+{ind}for (unsigned char c : text)
+{ind}{{
+{ind}{ind}hash ^= c;
+{ind}{ind}hash *= 16777619u;
+{ind}}}
+{ind}size_t slot = hash & {};
+{ind}if (texts[slot] && text == texts[slot])
+{ind}{ind}return tokens[slot];
+{ind}return {token_name}::_ERR;
+}}
+"#,
+        table.basis,
+        table.slots.len() - 1
+    )?;
+    Ok(())
+}
+
+// Folded keywords are matched as their host token, and only told apart
+// here by their text.
+fn gen_fold_keyword<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    let token_name = &names.token;
+    let table = keyword_table(&folded_keywords(lexer));
+    let hosts: Vec<&SmolStr> = lexer.get_folded().iter().map(|(_, host)| host).collect();
+    write!(
+        writer,
+        "\n{token_name} {lexer_name}::fold_keyword({token_name} found, const std::string &text)\n{{\n"
+    )?;
+    let condition: Vec<String> = hosts
+        .iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|host| format!("found != {token_name}::{}", host))
+        .collect();
+    write_line!(1, writer, "if ({})\n", condition.join(" && "));
+    write_line!(2, writer, "return found;\n");
+    write_line!(
+        1,
+        writer,
+        "static const char *const texts[{}] = {{\n",
+        table.slots.len()
+    );
+    for slot in &table.slots {
+        match slot {
+            Some((_, lit)) => {
+                write_line!(2, writer, "\"{}\",\n", escape_string(lit));
+            }
+            None => {
+                write_line!(2, writer, "nullptr,\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n");
+    write_line!(
+        1,
+        writer,
+        "static const {token_name} tokens[{}][2] = {{\n",
+        table.slots.len()
+    );
+    for slot in &table.slots {
+        match slot {
+            Some((name, _)) => {
+                let (_, host) = lexer
+                    .get_folded()
+                    .iter()
+                    .find(|(keyword, _)| keyword == name)
+                    .unwrap();
+                write_line!(
+                    2,
+                    writer,
+                    "{{{token_name}::{}, {token_name}::{}}},\n",
+                    host,
+                    name
+                );
+            }
+            None => {
+                write_line!(2, writer, "{{{token_name}::_ERR, {token_name}::_ERR}},\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n");
+    write!(
+        writer,
+        r#"{ind}uint32_t hash = {}u;
+{ind}for (unsigned char c : text)
+{ind}{{
+{ind}{ind}hash ^= c;
+{ind}{ind}hash *= 16777619u;
+{ind}}}
+{ind}size_t slot = hash & {};
+{ind}if (texts[slot] && tokens[slot][0] == found && text == texts[slot])
+{ind}{ind}return tokens[slot][1];
+{ind}return found;
+}}
+"#,
+        table.basis,
+        table.slots.len() - 1
+    )?;
+    Ok(())
+}
+
+// The first code unit of a character, as opposed to a UTF-8 continuation
+// byte or a UTF-16 low surrogate.
+fn starts_char(config: &CppConfig, unit: &str) -> String {
+    if config.bytes {
+        "true".to_string()
+    } else if config.utf16 {
+        format!("({} & 0xfc00) != 0xdc00", unit)
+    } else {
+        format!("({} & 0xc0) != 0x80", unit)
+    }
+}
+
+fn gen_positions<W: Write>(config: &CppConfig, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let string = string_type(config);
+    let unit = unit_type(config);
+    let starts_c = starts_char(config, "c");
+    write!(
+        writer,
+        r#"
+void {lexer_name}::track(const {string} &text)
+{{
+{ind}this->cursor.offset += text.size();
+{ind}this->start_position.length = this->cursor.offset - this->start_position.offset;
+{ind}for ({unit} c : text)
+{ind}{{
+{ind}{ind}if (c == '\n')
+{ind}{ind}{{
+{ind}{ind}{ind}this->cursor.line++;
+{ind}{ind}{ind}this->cursor.column = 1;
+{ind}{ind}}}
+{ind}{ind}else if ({starts_c})
+{ind}{ind}{{
+{ind}{ind}{ind}this->cursor.column++;
+{ind}{ind}}}
+{ind}}}
+}}
+
+const {token_name}Position &{lexer_name}::position() const
+{{
+{ind}return this->start_position;
+}}
+
+{token_name}Span {lexer_name}::next_span()
+{{
+{ind}{token_name}Span span;
+{ind}span.text = this->next(span.kind);
+{ind}span.start = this->start_position.offset;
+{ind}span.end = this->start_position.offset + this->start_position.length;
+{ind}span.line = this->start_position.line;
+{ind}span.column = this->start_position.column;
+{ind}return span;
+}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_error_context<W: Write>(config: &CppConfig, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    let string = string_type(config);
+    let unit = unit_type(config);
+    let starts_c = starts_char(config, "c");
+    let starts_buf = starts_char(config, "this->buf[i]");
+    let prefix = if config.utf16 { "u" } else { "" };
+    write!(
+        writer,
+        r#"
+void {lexer_name}::advance(const {string} &text)
+{{
+{ind}for ({unit} c : text)
+{ind}{{
+{ind}{ind}if (c == '\n')
+{ind}{ind}{{
+{ind}{ind}{ind}this->line++;
+{ind}{ind}{ind}this->column = 1;
+{ind}{ind}{ind}this->current_line.clear();
+{ind}{ind}}}
+{ind}{ind}else
+{ind}{ind}{{
+{ind}{ind}{ind}if ({starts_c})
+{ind}{ind}{ind}{ind}this->column++;
+{ind}{ind}{ind}this->current_line.push_back(c);
+{ind}{ind}}}
+{ind}}}
+}}
+
+void {lexer_name}::fail(size_t pos)
+{{
+{ind}size_t line = this->line;
+{ind}size_t column = this->column;
+{ind}size_t start = 0;
+{ind}for (size_t i = 0; i < pos; i++)
+{ind}{{
+{ind}{ind}if (this->buf[i] == '\n')
+{ind}{ind}{{
+{ind}{ind}{ind}line++;
+{ind}{ind}{ind}column = 1;
+{ind}{ind}{ind}start = i + 1;
+{ind}{ind}}}
+{ind}{ind}else if ({starts_buf})
+{ind}{ind}{{
+{ind}{ind}{ind}column++;
+{ind}{ind}}}
+{ind}}}
+{ind}size_t end = pos;
+{ind}while (this->fill(end + 1) && this->buf[end] != '\n' && this->buf[end] != '\r')
+{ind}{ind}end++;
+{ind}this->last_error.line = line;
+{ind}this->last_error.column = column;
+{ind}this->last_error.line_text = (start == 0 ? this->current_line : {prefix}"") + this->buf.substr(start, end - start);
+}}
+
+const LexError &{lexer_name}::error() const
+{{
+{ind}return this->last_error;
+}}
+"#
+    )?;
+    Ok(())
+}
+
+pub fn gen_tables<W: Write>(lexer: &Lexer, config: &CppConfig, writer: &mut W) -> Result<()> {
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let tables = tables(lexer);
+    write!(writer, "#pragma once\n\n")?;
+    for line in tables_contract(lexer) {
+        writeln!(
+            writer,
+            "//{}{}",
+            if line.is_empty() { "" } else { " " },
+            line
+        )?;
+    }
+    write!(writer, "\n#include <cstddef>\n#include <cstdint>\n\n")?;
+    writeln!(writer, "static const size_t INITIAL_STATE = 0;")?;
+    writeln!(writer, "static const size_t TRAP_STATE = {};", tables.trap)?;
+    writeln!(
+        writer,
+        "static const size_t STATE_COUNT = {};",
+        tables.transitions.len()
+    )?;
+    writeln!(
+        writer,
+        "static const size_t CLASS_COUNT = {};",
+        tables.alphabet.len()
+    )?;
+    write!(
+        writer,
+        "static const size_t TOKEN_COUNT = {};\n\n",
+        tables.tokens.len()
+    )?;
+    writeln!(
+        writer,
+        "static const uint32_t ALPHABET[CLASS_COUNT][2] = {{"
+    )?;
+    for (first, last) in &tables.alphabet {
+        write_line!(1, writer, "{{{}, {}}},\n", first, last);
+    }
+    write!(writer, "}};\n\n")?;
+    writeln!(
+        writer,
+        "static const uint32_t TRANSITIONS[STATE_COUNT][CLASS_COUNT] = {{"
+    )?;
+    for row in &tables.transitions {
+        let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+        write_line!(1, writer, "{{{}}},\n", row.join(", "));
+    }
+    write!(writer, "}};\n\n")?;
+    let accepts: Vec<String> = tables
+        .accepts
+        .iter()
+        .map(|a| a.map_or_else(|| String::from("-1"), |a| a.to_string()))
+        .collect();
+    write!(
+        writer,
+        "static const int32_t ACCEPTS[STATE_COUNT] = {{{}}};\n\n",
+        accepts.join(", ")
+    )?;
+    writeln!(
+        writer,
+        "static const char *const TOKEN_NAMES[TOKEN_COUNT] = {{"
+    )?;
+    for token in &tables.tokens {
+        write_line!(1, writer, "\"{}\",\n", token);
+    }
+    write!(writer, "}};\n\n")?;
+    let skipped: Vec<&str> = tables
+        .skipped
+        .iter()
+        .map(|s| if *s { "true" } else { "false" })
+        .collect();
+    writeln!(
+        writer,
+        "static const bool TOKEN_SKIPPED[TOKEN_COUNT] = {{{}}};",
+        skipped.join(", ")
+    )?;
+    Ok(())
+}
+
+pub fn gen_driver<W: Write>(lexer: &Lexer, config: &CppConfig, writer: &mut W) -> Result<()> {
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
+    write!(
+        writer,
+        "#include \"{}.h\"\n\n#include <iostream>\n",
+        file_stem(config)
+    )?;
+    if let Some(namespace) = &config.names.namespace {
+        write!(writer, "\nusing namespace {};\n", namespace)?;
+    }
+    write!(
+        writer,
+        r#"
+int main()
+{{
+{ind}{lexer_name} lexer(std::cin);
+{ind}{token_name} token;
+{ind}std::string text = lexer.next(token);
+{ind}while (token != {token_name}::{eof})
+{ind}{{
+{ind}{ind}std::cout << token_name(token) << ": " << text << "\n";
+"#
+    )?;
+    // Unless it recovers, the lexer doesn't get past an error.
+    if !config.recover && !config.bulk_errors {
+        write_line!(2, writer, "if (token == {token_name}::_ERR)\n");
+        write_line!(2, writer, "{{\n");
+        write_line!(3, writer, "break;\n");
+        write_line!(2, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}text = lexer.next(token);
+{ind}}}
+{ind}return 0;
+}}
+"#
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_enum(src: &str) -> Vec<String> {
+        token_enum_with(src, &CppConfig::default())
+    }
+
+    fn token_enum_with(src: &str, config: &CppConfig) -> Vec<String> {
+        let lexer = Lexer::from_source(src).unwrap();
+        let mut out = Vec::new();
+        gen_header_lexer(&lexer, config, &mut out).unwrap();
+        let header = String::from_utf8(out).unwrap();
+        let start = header.find("enum class Token").unwrap();
+        let body = &header[start..];
+        let body = &body[body.find('{').unwrap() + 1..body.find('}').unwrap()];
+        body.split(',')
+            .map(|name| name.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn token_enum_has_no_trap() {
+        assert_eq!(
+            token_enum("token NAME = ([a-z])+;\ntoken NL = \"\\n\";\ntoken WS = \" \";\n"),
+            ["_EOF", "_ERR", "NAME", "NL", "WS"]
+        );
+    }
+
+    #[test]
+    fn only_exported_tokens_are_in_the_enum() {
+        assert_eq!(
+            token_enum("export token NAME = ([a-z])+;\ntoken NUM = ([0-9])+;\n"),
+            ["_EOF", "_ERR", "NAME"]
+        );
+    }
+
+    #[test]
+    fn stable_token_ids() {
+        let config = CppConfig {
+            token_type: Some(String::from("uint16_t")),
+            stable_token_ids: true,
+            ..Default::default()
+        };
+        let before = token_enum_with("token B = \"b\";\ntoken D = \"d\";\n", &config);
+        let after = token_enum_with(
+            "token A = \"a\";\ntoken B = \"b\";\ntoken C = \"c\";\ntoken D = \"d\";\n",
+            &config,
+        );
+        assert_eq!(&before[..2], ["_EOF = 0", "_ERR = 1"]);
+        for enumerator in &before {
+            assert!(after.contains(enumerator), "{:?} {:?}", before, after);
+        }
+        assert_eq!(after.len(), before.len() + 2);
+        for enumerator in &after[2..] {
+            let id: u32 = enumerator.split(" = ").nth(1).unwrap().parse().unwrap();
+            assert!(id <= u16::MAX as u32, "{}", enumerator);
+        }
+
+        let lexer = Lexer::from_source("token B = \"b\";\n").unwrap();
+        let mut out = Vec::new();
+        gen_header_lexer(&lexer, &config, &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("enum class Token : uint16_t"));
+    }
+
+    #[test]
+    fn eof_can_be_renamed() {
+        assert_eq!(
+            token_enum("eof = END;\ntoken NAME = ([a-z])+;\n"),
+            ["END", "_ERR", "NAME"]
+        );
+    }
+
+    #[test]
+    fn source_comments_name_each_accepting_state() {
+        let lexer = Lexer::from_source(
+            "token IF = \"if\";\n\ntoken NAME = ([a-z])+;\ntoken NUM = ([0-9])+;\n",
+        )
+        .unwrap();
+        let body = |source_comments| {
+            let config = CppConfig {
+                source_comments,
+                ..CppConfig::default()
+            };
+            let mut out = Vec::new();
+            gen_body_lexer(&lexer, &config, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let commented = body(true);
+        let trap = lexer.get_trap();
+        for (i, acc) in lexer.get_states().iter().enumerate() {
+            let Some(acc) = acc.filter(|_| i != trap) else {
+                continue;
+            };
+            let line = match acc.as_str() {
+                "IF" => 1,
+                "NAME" => 3,
+                _ => 4,
+            };
+            let comment = format!("case {}: // {} (line {})", i, acc, line);
+            assert!(commented.contains(&comment), "{}", comment);
+        }
+        assert!(!body(false).contains("(line "));
+    }
 }
@@ -0,0 +1,250 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{codegen::escape_string, error::Result, lexer::Lexer};
+
+pub fn gen_dot<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+    let states = lexer.get_states();
+    writeln!(writer, "digraph lexer {{")?;
+    writeln!(writer, "    rankdir=LR;")?;
+    writeln!(writer, "    node [shape=circle];")?;
+    for (i, state) in states.iter().enumerate() {
+        match state {
+            Some(token) if *token == "_TRAP" => {
+                writeln!(
+                    writer,
+                    "    {} [label=\"{}\\n_TRAP\", shape=box, style=dashed, color=gray];",
+                    i, i
+                )?;
+            }
+            Some(token) => {
+                writeln!(
+                    writer,
+                    "    {} [label=\"{}\\n{}\", shape=doublecircle];",
+                    i,
+                    i,
+                    escape_string(token)
+                )?;
+            }
+            None => {
+                writeln!(writer, "    {} [label=\"{}\"];", i, i)?;
+            }
+        }
+    }
+    for i in 0..states.len() {
+        let mut connections = lexer.get_connections(i);
+        connections.sort();
+        let mut targets: BTreeMap<usize, Vec<(u32, u32)>> = BTreeMap::new();
+        for (r0, r1, result) in connections {
+            if matches!(states[result], Some(token) if token == "_TRAP") {
+                continue;
+            }
+            let ranges = targets.entry(result).or_default();
+            match ranges.last_mut() {
+                Some(last) if last.1 + 1 == r0 => last.1 = r1,
+                _ => ranges.push((r0, r1)),
+            }
+        }
+        for (result, ranges) in targets {
+            let label: Vec<String> = ranges.into_iter().map(show_range).collect();
+            writeln!(
+                writer,
+                "    {} -> {} [label=\"{}\"];",
+                i,
+                result,
+                escape_string(&label.join(", "))
+            )?;
+        }
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+pub fn gen_nfa_dot<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+    let nfa = lexer.nfa_graph()?;
+    writeln!(writer, "digraph nfa {{")?;
+    writeln!(writer, "    rankdir=LR;")?;
+    writeln!(writer, "    node [shape=circle];")?;
+    for (i, state) in nfa.states.iter().enumerate() {
+        let style = if nfa.entries.contains(&i) {
+            ", style=bold"
+        } else {
+            ""
+        };
+        match state {
+            Some(token) => {
+                writeln!(
+                    writer,
+                    "    {} [label=\"{}\\n{}\", shape=doublecircle{}];",
+                    i,
+                    i,
+                    escape_string(token),
+                    style
+                )?;
+            }
+            None => {
+                writeln!(writer, "    {} [label=\"{}\"{}];", i, i, style)?;
+            }
+        }
+    }
+    for (start, end) in nfa.epsilon {
+        writeln!(
+            writer,
+            "    {} -> {} [label=\"ε\", style=dashed];",
+            start, end
+        )?;
+    }
+    let mut targets: BTreeMap<(usize, usize), Vec<(u32, u32)>> = BTreeMap::new();
+    for (start, range, end) in nfa.ranges {
+        targets.entry((start, end)).or_default().push(range);
+    }
+    for ((start, end), mut ranges) in targets {
+        ranges.sort();
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (r0, r1) in ranges {
+            match merged.last_mut() {
+                Some(last) if last.1 + 1 == r0 => last.1 = r1,
+                _ => merged.push((r0, r1)),
+            }
+        }
+        let label: Vec<String> = merged.into_iter().map(show_range).collect();
+        writeln!(
+            writer,
+            "    {} -> {} [label=\"{}\"];",
+            start,
+            end,
+            escape_string(&label.join(", "))
+        )?;
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn show_range((r0, r1): (u32, u32)) -> String {
+    let show =
+        |c: u32| char::from_u32(c).map_or_else(|| format!("U+{:04X}", c), |c| format!("{:?}", c));
+    if r0 == r1 {
+        show(r0)
+    } else {
+        format!("{}-{}", show(r0), show(r1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the statement syntax gen_dot writes and returns the node and
+    // edge statements.
+    fn parse<'a>(dot: &'a str, graph: &str) -> (Vec<&'a str>, Vec<&'a str>) {
+        let mut lines = dot.lines();
+        assert_eq!(lines.next(), Some(format!("digraph {} {{", graph).as_str()));
+        let mut nodes = Vec::new();
+        let mut edges = Vec::new();
+        for line in lines {
+            if line == "}" {
+                break;
+            }
+            let statement = line.trim().strip_suffix(';').unwrap();
+            let Some((head, attributes)) = statement.split_once(" [") else {
+                assert!(statement.starts_with("rankdir="), "{}", line);
+                continue;
+            };
+            assert!(attributes.ends_with(']'), "{}", line);
+            assert_eq!(attributes.matches('"').count() % 2, 0, "{}", line);
+            match head.split_once(" -> ") {
+                Some((from, to)) => {
+                    assert!(from.parse::<usize>().is_ok() && to.parse::<usize>().is_ok());
+                    edges.push(statement);
+                }
+                None if head == "node" => {}
+                None => {
+                    assert!(head.parse::<usize>().is_ok(), "{}", line);
+                    nodes.push(statement);
+                }
+            }
+        }
+        (nodes, edges)
+    }
+
+    #[test]
+    fn dot_has_a_node_per_state() {
+        let lexer = Lexer::from_source("token AB = \"ab\";\ntoken D = ([0-9])+;\n").unwrap();
+        let mut out = Vec::new();
+        gen_dot(&lexer, &mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        let (nodes, edges) = parse(&dot, "lexer");
+        assert_eq!(nodes.len(), lexer.get_states().len());
+        assert_eq!(
+            nodes
+                .iter()
+                .filter(|node| node.contains("doublecircle"))
+                .count(),
+            2
+        );
+        assert_eq!(
+            nodes.iter().filter(|node| node.contains("_TRAP")).count(),
+            1
+        );
+        // Both digit transitions are one edge with one merged label.
+        assert_eq!(
+            edges
+                .iter()
+                .filter(|edge| edge.contains("label=\"'0'-'9'\""))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn nfa_dot_skips_optional_elements_by_epsilon() {
+        let lexer = Lexer::from_source("token A = \"b\" (\"a\")?;\n").unwrap();
+        let mut out = Vec::new();
+        gen_nfa_dot(&lexer, &mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        let (nodes, edges) = parse(&dot, "nfa");
+        let node = |statement: &str| statement.split(' ').next().unwrap().to_string();
+        let entry = node(nodes.iter().find(|n| n.contains("style=bold")).unwrap());
+        let accepting = node(nodes.iter().find(|n| n.contains("doublecircle")).unwrap());
+        let edge = |statement: &&str| {
+            let (from, rest) = statement.split_once(" -> ").unwrap();
+            (from.to_string(), node(rest))
+        };
+        let epsilon: Vec<(String, String)> = edges
+            .iter()
+            .filter(|e| e.ends_with("[label=\"ε\", style=dashed]"))
+            .map(edge)
+            .collect();
+        let range = |label: &str| -> Vec<(String, String)> {
+            let label = format!("[label=\"'{}'\"]", label);
+            edges
+                .iter()
+                .filter(|e| e.ends_with(&label))
+                .map(edge)
+                .collect()
+        };
+        let (b, a) = (range("b"), range("a"));
+        assert_eq!((b.len(), a.len()), (1, 1), "{}", dot);
+        assert_eq!(epsilon.len() + 2, edges.len(), "{}", dot);
+        // After the 'b' the accepting state can be reached both without
+        // reading anything and across the 'a' edge.
+        let closure = |from: &str| {
+            let mut reached = vec![from.to_string()];
+            let mut i = 0;
+            while i < reached.len() {
+                for (start, end) in &epsilon {
+                    if *start == reached[i] && !reached.contains(end) {
+                        reached.push(end.clone());
+                    }
+                }
+                i += 1;
+            }
+            reached
+        };
+        assert!(closure(&entry).contains(&b[0].0), "{}", dot);
+        let skipped = closure(&b[0].1);
+        assert!(skipped.contains(&accepting), "{}", dot);
+        let (before, after) = &a[0];
+        assert!(skipped.contains(before), "{}", dot);
+        assert!(closure(after).contains(&accepting), "{}", dot);
+    }
+}
@@ -0,0 +1,32 @@
+use std::io::Write;
+
+use crate::{
+    error::Result,
+    lexer::eof_name,
+    parser::first_follow,
+    rules::{Grammar, Rule},
+};
+
+pub fn gen_first_follow<W: Write>(grammar: &Grammar, writer: &mut W) -> Result<()> {
+    let rules: Vec<Rule> = grammar
+        .rules
+        .iter()
+        .filter(|rule| !rule.is_terminal)
+        .cloned()
+        .collect();
+    let (first, follow) = first_follow(&rules, &eof_name(grammar));
+    for (i, rule) in rules.iter().enumerate() {
+        if i > 0 {
+            writeln!(writer)?;
+        }
+        let (tokens, nullable) = first.get(&rule.name);
+        let mut tokens: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+        if nullable {
+            tokens.push("ε");
+        }
+        writeln!(writer, "FIRST({}) = {{{}}}", rule.name, tokens.join(", "))?;
+        let tokens: Vec<&str> = follow[&rule.name].iter().map(|t| t.as_str()).collect();
+        writeln!(writer, "FOLLOW({}) = {{{}}}", rule.name, tokens.join(", "))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use std::io::Write;
+
+use crate::{
+    codegen::{escape_string, tables},
+    error::Result,
+    lexer::Lexer,
+    rules::MatchMode,
+};
+
+macro_rules! write_line {
+    ($indent:expr,$writer:expr,$($arg:tt)*) => {
+        for _ in 0..$indent {
+            write!($writer, "\t")?;
+        }
+        write!($writer, $($arg)*)?;
+    };
+}
+
+#[derive(Default)]
+pub struct GoConfig {
+    pub bulk_errors: bool,
+    pub normalize_newlines: bool,
+}
+
+pub fn gen_lexer<W: Write>(lexer: &Lexer, config: &GoConfig, writer: &mut W) -> Result<()> {
+    let tables = tables(lexer);
+
+    write!(writer, "package lexer\n\nimport (\n")?;
+    write_line!(1, writer, "\"io\"\n");
+    write_line!(1, writer, "\"sort\"\n");
+    if config.normalize_newlines {
+        write_line!(1, writer, "\"strings\"\n");
+    }
+    write!(writer, ")\n\ntype Token int\n\nconst (\n")?;
+    for (i, token) in tables.tokens.iter().enumerate() {
+        if i == 0 {
+            write_line!(1, writer, "Token{} Token = iota\n", token);
+        } else {
+            write_line!(1, writer, "Token{}\n", token);
+        }
+    }
+    write!(writer, ")\n\nvar tokenNames = [...]string{{\n")?;
+    for token in &tables.tokens {
+        write_line!(1, writer, "\"{}\",\n", token);
+    }
+    write!(
+        writer,
+        "}}\n\nfunc (t Token) String() string {{\n\treturn tokenNames[t]\n}}\n"
+    )?;
+    if lexer.has_displays() {
+        write!(writer, "\nvar tokenDisplays = [...]string{{\n")?;
+        for token in &tables.tokens {
+            write_line!(1, writer, "\"{}\",\n", escape_string(&lexer.display(token)));
+        }
+        write!(
+            writer,
+            "}}\n\nfunc (t Token) Display() string {{\n\treturn tokenDisplays[t]\n}}\n"
+        )?;
+    }
+    if !lexer.get_equivalences().is_empty() {
+        gen_token_class(lexer, writer)?;
+    }
+
+    write!(writer, "\nconst trap = {}\n", tables.trap)?;
+    write!(writer, "\nvar alphabet = []rune{{\n")?;
+    for (_, last) in &tables.alphabet {
+        write_line!(1, writer, "{},\n", last);
+    }
+    write!(writer, "}}\n\nvar transitions = [][]int{{\n")?;
+    for row in &tables.transitions {
+        let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+        write_line!(1, writer, "{{{}}},\n", row.join(", "));
+    }
+    write!(writer, "}}\n\nvar accepts = []Token{{\n")?;
+    for accept in &tables.accepts {
+        match accept {
+            Some(token) => {
+                write_line!(1, writer, "Token{},\n", tables.tokens[*token]);
+            }
+            None => {
+                write_line!(1, writer, "-1,\n");
+            }
+        }
+    }
+    write!(writer, "}}\n\nvar skipped = [...]bool{{\n")?;
+    for skipped in &tables.skipped {
+        write_line!(1, writer, "{},\n", skipped);
+    }
+    write!(
+        writer,
+        r#"}}
+
+func toAlphabet(ch rune) int {{
+	return sort.Search(len(alphabet), func(i int) bool {{ return alphabet[i] >= ch }})
+}}
+
+type Lexer struct {{
+	reader io.RuneReader
+	buf    []rune
+	eof    bool
+	err    error
+}}
+
+func NewLexer(reader io.RuneReader) *Lexer {{
+	return &Lexer{{reader: reader}}
+}}
+
+// Err returns the first read error other than io.EOF, after which Next
+// reports _EOF.
+func (l *Lexer) Err() error {{
+	return l.err
+}}
+
+func (l *Lexer) peek(pos int) (rune, bool) {{
+	for pos >= len(l.buf) && !l.eof {{
+		ch, _, err := l.reader.ReadRune()
+		if err != nil {{
+			if err != io.EOF {{
+				l.err = err
+			}}
+			l.eof = true
+			break
+		}}
+		l.buf = append(l.buf, ch)
+	}}
+	if pos < len(l.buf) {{
+		return l.buf[pos], true
+	}}
+	return 0, false
+}}
+
+func (l *Lexer) take(n int) string {{
+	text := string(l.buf[:n])
+	l.buf = l.buf[n:]
+	return text
+}}
+
+func (l *Lexer) longest(pos int) (Token, int, bool) {{
+	state := 0
+	found, end, ok := Token_EOF, 0, false
+	for {{
+		ch, more := l.peek(pos)
+		if !more {{
+			break
+		}}
+		state = transitions[state][toAlphabet(ch)]
+		if state == trap {{
+			break
+		}}
+		pos++
+		if accepts[state] >= 0 {{
+			found, end, ok = accepts[state], pos, true
+"#
+    )?;
+    if lexer.get_match_mode() == MatchMode::Shortest {
+        write_line!(3, writer, "break\n");
+    }
+    write!(
+        writer,
+        r#"		}}
+	}}
+	return found, end, ok
+}}
+
+func (l *Lexer) Next() (Token, string) {{
+	for {{
+		if _, more := l.peek(0); !more {{
+			return Token_EOF, ""
+		}}
+		token, end, ok := l.longest(0)
+		if !ok {{
+			end = 1
+"#
+    )?;
+    if config.bulk_errors {
+        write_line!(3, writer, "for {{\n");
+        write_line!(4, writer, "if _, more := l.peek(end); !more {{\n");
+        write_line!(5, writer, "break\n");
+        write_line!(4, writer, "}}\n");
+        write_line!(4, writer, "if _, _, ok := l.longest(end); ok {{\n");
+        write_line!(5, writer, "break\n");
+        write_line!(4, writer, "}}\n");
+        write_line!(4, writer, "end++\n");
+        write_line!(3, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"			return Token_ERR, l.take(end)
+		}}
+		text := l.take(end)
+		if skipped[token] {{
+			continue
+		}}
+"#
+    )?;
+    if config.normalize_newlines {
+        write_line!(
+            2,
+            writer,
+            "return token, strings.ReplaceAll(text, \"\\r\\n\", \"\\n\")\n"
+        );
+    } else {
+        write_line!(2, writer, "return token, text\n");
+    }
+    write!(writer, "\t}}\n}}\n")?;
+    Ok(())
+}
+
+fn gen_token_class<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+    write!(writer, "\ntype TokenClass int\n\nconst (\n")?;
+    write_line!(1, writer, "TokenClass_NONE TokenClass = iota\n");
+    for (name, _) in lexer.get_equivalences() {
+        write_line!(1, writer, "TokenClass{}\n", name);
+    }
+    write!(writer, ")\n\nfunc (t Token) Class() TokenClass {{\n")?;
+    write_line!(1, writer, "switch t {{\n");
+    for (name, members) in lexer.get_equivalences() {
+        let members: Vec<String> = members
+            .iter()
+            .map(|member| format!("Token{}", member))
+            .collect();
+        write_line!(1, writer, "case {}:\n", members.join(", "));
+        write_line!(2, writer, "return TokenClass{}\n", name);
+    }
+    write_line!(1, writer, "}}\n");
+    write_line!(1, writer, "return TokenClass_NONE\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
@@ -3,215 +3,1511 @@ use std::{
     io::Write,
 };
 
-use color_eyre::Result;
 use smol_str::SmolStr;
 
-use crate::lexer::Lexer;
+use crate::{
+    codegen::{
+        alphabet_ids, committed_states, escape_string, folded_keywords, keyword_table, tables,
+        tables_contract, CodeWriter, Layout, Names,
+    },
+    error::Result,
+    lexer::Lexer,
+    rules::MatchMode,
+};
 
 macro_rules! write_line {
     ($indent:expr,$writer:expr,$($arg:tt)*) => {
-        for _ in 0..$indent {
-            write!($writer, "    ")?;
-        }
+        $writer.indent($indent)?;
         write!($writer, $($arg)*)?;
     };
 }
 
-pub fn gen_lexer<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+#[derive(Default)]
+pub struct JavaConfig {
+    pub indentation: Option<usize>,
+    pub error_context: bool,
+    pub keyword_hash: bool,
+    pub fold_keywords: bool,
+    pub bulk_errors: bool,
+    pub max_error_run: Option<usize>,
+    pub recover: bool,
+    pub lookahead: Option<usize>,
+    pub normalize_newlines: bool,
+    pub positions: bool,
+    pub bytes: bool,
+    pub strip_bom: bool,
+    pub buffer_capacity: Option<usize>,
+    pub names: Names,
+    pub layout: Layout,
+}
+
+pub fn gen_lexer<W: Write>(lexer: &Lexer, config: &JavaConfig, writer: &mut W) -> Result<()> {
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let ind = writer.unit();
+    let folded;
+    let lexer = if config.fold_keywords {
+        folded = lexer.fold_keywords()?;
+        &folded
+    } else {
+        lexer
+    };
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
     let tokens: BTreeSet<SmolStr> = lexer
         .get_states()
         .iter()
         .filter(|s| s.is_some())
         .map(|s| s.unwrap().clone())
+        .filter(|s| s != "_TRAP" && !lexer.is_internal(s))
+        .chain(
+            lexer
+                .get_folded()
+                .iter()
+                .map(|(keyword, _)| keyword.clone()),
+        )
         .collect();
 
-    let trap = lexer
-        .get_states()
-        .iter()
-        .position(|s| match s {
-            Some(s) if s == &"_TRAP" => true,
-            _ => false,
-        })
-        .unwrap();
+    let trap = lexer.get_trap();
+    let wrapped = config.indentation.is_some() || config.lookahead.is_some();
 
+    if let Some(package) = &config.names.namespace {
+        write!(writer, "package {};\n\n", package)?;
+    }
     write!(
         writer,
         r#"import java.io.InputStream;
 import java.io.BufferedReader;
 import java.io.IOException;
 import java.io.InputStreamReader;
+import java.io.UncheckedIOException;
 import java.io.UnsupportedEncodingException;
+import java.util.Iterator;
+import java.util.NoSuchElementException;
+"#
+    )?;
+    if config.indentation.is_some() {
+        writeln!(writer, "import java.util.ArrayDeque;")?;
+    }
+    if config.indentation.is_some() || config.lookahead.is_some() {
+        writeln!(writer, "import java.util.ArrayList;")?;
+    }
+    if config.lookahead.is_some() {
+        writeln!(writer, "import java.util.List;")?;
+    }
+    write!(
+        writer,
+        r#"
+public class {lexer_name} implements Iterable<{lexer_name}.TextToken> {{
 
-public class Lexer {{
+{ind}private BufferedReader reader;
+{ind}private final StringBuffer buf;
+"#
+    )?;
+    if config.strip_bom {
+        write_line!(1, writer, "private boolean atStart = true;\n");
+    }
+    if config.indentation.is_some() {
+        write!(
+            writer,
+            r#"{ind}private final ArrayDeque<TextToken> pending = new ArrayDeque<>();
+{ind}private final ArrayList<Integer> indents = new ArrayList<>();
+{ind}private boolean atLineStart = true;
+{ind}private int width = 0;
+"#
+        )?;
+    }
+    if let Some(lookahead) = config.lookahead {
+        write!(
+            writer,
+            r#"{ind}private final TextToken[] lookahead = new TextToken[{}];
+{ind}private int lookaheadStart = 0;
+{ind}private int lookaheadCount = 0;
+"#,
+            lookahead
+        )?;
+    }
+    if config.positions {
+        write!(
+            writer,
+            r#"{ind}private int cursorLine = 1;
+{ind}private int cursorColumn = 1;
+{ind}private int cursorOffset = 0;
+{ind}private int startLine = 1;
+{ind}private int startColumn = 1;
+{ind}private int startOffset = 0;
+"#
+        )?;
+    }
+    if config.error_context {
+        write!(
+            writer,
+            r#"{ind}private int line = 1;
+{ind}private int column = 1;
+{ind}private final StringBuilder currentLine = new StringBuilder();
+"#
+        )?;
+    }
+    let capacity = config.buffer_capacity.unwrap_or(4096);
+    write!(
+        writer,
+        r#"
+{ind}public {lexer_name}(InputStream is) {{
+{ind}{ind}this(is, {capacity});
+{ind}}}
 
-    private final BufferedReader reader;
-    private final StringBuffer buf;
+{ind}public {lexer_name}(InputStream is, int capacity) {{
+{ind}{ind}this.buf = new StringBuffer(capacity);
+{ind}{ind}this.reset(is);
+{ind}}}
 
-    public Lexer(InputStream is) {{
-        BufferedReader reader = null;
-        try {{
-            reader = new BufferedReader(new InputStreamReader(is, "utf-8"));
-        }} catch (UnsupportedEncodingException e) {{
-        }}
-        this.reader = reader;
-        this.buf = new StringBuffer();
-    }}
+{ind}public void reset(InputStream is) {{
+{ind}{ind}BufferedReader reader = null;
+{ind}{ind}try {{
+{ind}{ind}{ind}reader = new BufferedReader(new InputStreamReader(is, "{}"));
+{ind}{ind}}} catch (UnsupportedEncodingException e) {{
+{ind}{ind}}}
+{ind}{ind}this.reader = reader;
+{ind}{ind}this.buf.setLength(0);
+"#,
+        if config.bytes { "ISO-8859-1" } else { "utf-8" }
+    )?;
+    gen_reset(config, writer)?;
+    write!(
+        writer,
+        r#"{ind}}}
 
-    private int toAlphabet(int ch) {{
-        switch (ch) {{
+{ind}private int toAlphabet(int ch) {{
+{ind}{ind}switch (ch) {{
 "#
     )?;
     for (i, (r0, r1)) in lexer.get_alphabet().iter().enumerate() {
         if r0 == r1 {
-            write_line!(3, writer, "case {}:\r\n", r0);
-            write_line!(4, writer, "return {};\r\n", i);
+            write_line!(3, writer, "case {}:\n", r0);
+            write_line!(4, writer, "return {};\n", i);
         }
     }
-    write_line!(2, writer, "}}\r\n");
-    write!(writer, "        ")?;
+    write_line!(2, writer, "}}\n");
+    write!(writer, "{ind}{ind}")?;
     for (i, (r0, r1)) in lexer.get_alphabet().iter().enumerate() {
         if r0 != r1 {
-            write!(writer, "if (ch >= {} && ch <= {}) {{\r\n", r0, r1)?;
-            write_line!(3, writer, "return {};\r\n", i);
+            writeln!(writer, "if (ch >= {} && ch <= {}) {{", r0, r1)?;
+            write_line!(3, writer, "return {};\n", i);
             write_line!(2, writer, "}} else ");
         }
     }
-    write!(writer, "{{\r\n            return -1;\r\n        }}\r\n")?;
+    write!(writer, "{{\n{ind}{ind}{ind}return -1;\n{ind}{ind}}}\n")?;
     write!(
         writer,
-        r#"    }}
-
-    private int read() throws IOException {{
-        int ch = this.reader.read();
-        return ch;
-    }}
-
-    public TextToken next() throws IOException {{
-        Token found = Token._TRAP;
-        int found_pos = 0;
+        r#"{ind}}}
 
-        int pos = 0;
-        int state = 0;
-        while (true) {{
-            if (state == {}) {{
-                String s = this.buf.substring(0, found_pos);
-                this.buf.delete(0, found_pos);
-                return new TextToken(found, s);
-            }}
+{ind}private int read() throws IOException {{
+{ind}{ind}int ch = this.reader.read();
+"#
+    )?;
+    if config.strip_bom {
+        write_line!(2, writer, "if (this.atStart) {{\n");
+        write_line!(3, writer, "this.atStart = false;\n");
+        write_line!(3, writer, "if (ch == 0xFEFF) ch = this.reader.read();\n");
+        write_line!(2, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}if (ch != -1 && Character.isHighSurrogate((char) ch)) {{
+{ind}{ind}{ind}this.reader.mark(1);
+{ind}{ind}{ind}int low = this.reader.read();
+{ind}{ind}{ind}if (low != -1 && Character.isLowSurrogate((char) low)) {{
+{ind}{ind}{ind}{ind}return Character.toCodePoint((char) ch, (char) low);
+{ind}{ind}{ind}}}
+{ind}{ind}{ind}this.reader.reset();
+{ind}{ind}}}
+{ind}{ind}return ch;
+{ind}}}
 
-            int ch;
-            if (pos < this.buf.length()) {{
-                ch = this.buf.charAt(pos);
-            }} else {{
-                ch = this.read();
-                if (ch != -1) this.buf.appendCodePoint(ch);
-            }}
-            int ach = this.toAlphabet(ch);
+{ind}{} TextToken {}() throws IOException {{
+{ind}{ind}{token_name} found = null;
+{ind}{ind}int found_pos = 0;
 
-            switch (state) {{
+{ind}{ind}int pos = 0;
+{ind}{ind}int state = 0;
+"#,
+        if wrapped || config.positions {
+            "private"
+        } else {
+            "public"
+        },
+        if config.positions {
+            "scanToken"
+        } else if wrapped {
+            "nextToken"
+        } else {
+            "next"
+        },
+    )?;
+    if config.positions {
+        write_line!(2, writer, "this.markStart();\n");
+    }
+    if config.bulk_errors {
+        write_line!(2, writer, "int start = 0;\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}while (true) {{
+{ind}{ind}{ind}if (state == {}) {{
 "#,
         trap
     )?;
-    for (i, acc) in lexer.get_states().iter().enumerate() {
-        if i != trap {
-            write_line!(4, writer, "case {}:\r\n", i);
-            write_line!(5, writer, "switch (ach) {{\r\n");
+    if config.bulk_errors {
+        write_line!(4, writer, "if (found == null) {{\n");
+        gen_skip_error(config, 5, writer)?;
+        write_line!(4, writer, "}}\n");
+        gen_return_error_run(config, "start > 0", 4, writer)?;
+    } else if config.recover {
+        write_line!(4, writer, "if (found == null) {{\n");
+        gen_recover(config, 5, writer)?;
+        write_line!(4, writer, "}}\n");
+    } else if config.error_context {
+        write_line!(4, writer, "if (found == null) {{\n");
+        write_line!(5, writer, "return this.fail(\"\");\n");
+        write_line!(4, writer, "}}\n");
+    } else {
+        write_line!(4, writer, "if (found == null) {{\n");
+        write_line!(
+            5,
+            writer,
+            "return new TextToken({token_name}._ERR, \"\");\n"
+        );
+        write_line!(4, writer, "}}\n");
+    }
+    gen_return_found(lexer, config, 4, writer)?;
+    write!(
+        writer,
+        r#"{ind}{ind}{ind}}}
+
+{ind}{ind}{ind}int ch;
+{ind}{ind}{ind}if (pos < this.buf.length()) {{
+{ind}{ind}{ind}{ind}ch = this.buf.codePointAt(pos);
+{ind}{ind}{ind}}} else {{
+{ind}{ind}{ind}{ind}ch = this.read();
+{ind}{ind}{ind}{ind}if (ch != -1) this.buf.appendCodePoint(ch);
+{ind}{ind}{ind}}}
+{ind}{ind}{ind}int ach = this.toAlphabet(ch);
+
+{ind}{ind}{ind}switch (state) {{
+"#
+    )?;
+    let states = lexer.get_states();
+    let alphabet_ids = alphabet_ids(lexer);
+    let committed = committed_states(lexer);
+    for (i, acc) in states.iter().enumerate() {
+        if i != trap && !committed.finals.contains(&i) {
+            write_line!(4, writer, "case {}:\n", i);
+            if let (Some(acc), MatchMode::Shortest) = (acc, lexer.get_match_mode()) {
+                write_line!(5, writer, "found_pos = pos;\n");
+                write_line!(
+                    5,
+                    writer,
+                    "found = {token_name}.{};\n",
+                    lexer.reported_token(acc)
+                );
+                write_line!(5, writer, "state = {};\n", trap);
+                write_line!(5, writer, "break;\n");
+                continue;
+            }
+            write_line!(5, writer, "switch (ach) {{\n");
             let mut results: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
             for (r0, r1, result) in lexer.get_connections(i) {
-                if let Some(result) = results.get_mut(&result) {
-                    result.push(
-                        lexer
-                            .get_alphabet()
-                            .iter()
-                            .position(|a| a == &(r0, r1))
-                            .unwrap(),
-                    );
-                } else {
-                    results.insert(
-                        result,
-                        vec![lexer
-                            .get_alphabet()
-                            .iter()
-                            .position(|a| a == &(r0, r1))
-                            .unwrap()],
-                    );
-                }
+                results
+                    .entry(result)
+                    .or_default()
+                    .push(alphabet_ids[&(r0, r1)]);
             }
+            // End of input isn't in the alphabet and has to reach the trap.
+            results.entry(trap).or_default();
+            // A committed state recorded its token when it was entered.
+            let acc = acc.filter(|_| !committed.states.contains(&i));
             for (result, ranges) in results {
                 if result == trap {
-                    write_line!(6, writer, "default:\r\n");
+                    write_line!(6, writer, "default:\n");
                 } else {
                     for alphabet_id in ranges {
-                        write_line!(6, writer, "case {}:\r\n", alphabet_id);
+                        write_line!(6, writer, "case {}:\n", alphabet_id);
                     }
                 }
-                if let Some(acc) = acc {
-                    write_line!(7, writer, "found_pos = pos;\r\n");
-                    write_line!(7, writer, "found = Token.{};\r\n", acc);
-                    write_line!(7, writer, "state = {};\r\n", result);
-                    write_line!(7, writer, "break;\r\n");
+                let entered = states[result].filter(|_| committed.states.contains(&result));
+                if let Some(token) = entered {
+                    write_line!(7, writer, "found_pos = pos + Character.charCount(ch);\n");
+                    write_line!(
+                        7,
+                        writer,
+                        "found = {token_name}.{};\n",
+                        lexer.reported_token(token)
+                    );
+                    if committed.finals.contains(&result) {
+                        write_line!(7, writer, "state = {};\n", trap);
+                    } else {
+                        write_line!(7, writer, "state = {};\n", result);
+                    }
+                    write_line!(7, writer, "break;\n");
+                } else if let Some(acc) = acc {
+                    write_line!(7, writer, "found_pos = pos;\n");
+                    write_line!(
+                        7,
+                        writer,
+                        "found = {token_name}.{};\n",
+                        lexer.reported_token(acc)
+                    );
+                    write_line!(7, writer, "state = {};\n", result);
+                    write_line!(7, writer, "break;\n");
                 } else {
-                    write_line!(7, writer, "state = {};\r\n", result);
-                    write_line!(7, writer, "break;\r\n");
+                    write_line!(7, writer, "state = {};\n", result);
+                    write_line!(7, writer, "break;\n");
                 }
             }
-            write_line!(5, writer, "}}\r\n");
-            write_line!(5, writer, "break;\r\n");
+            write_line!(5, writer, "}}\n");
+            write_line!(5, writer, "break;\n");
         }
     }
     write!(
         writer,
-        r#"            }}
+        r#"{ind}{ind}{ind}}}
+
+{ind}{ind}{ind}if (ch == -1)
+{ind}{ind}{ind}{{
+"#
+    )?;
+    if config.bulk_errors {
+        write_line!(4, writer, "if (found == null && pos > start)\n");
+        write_line!(4, writer, "{{\n");
+        gen_skip_error(config, 5, writer)?;
+        write_line!(4, writer, "}}\n");
+        gen_return_error_run(config, "start > 0", 4, writer)?;
+    } else if config.recover {
+        write_line!(4, writer, "if (found == null && pos > 0)\n");
+        write_line!(4, writer, "{{\n");
+        gen_recover(config, 5, writer)?;
+        write_line!(4, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}{ind}{ind}if (found == null)
+{ind}{ind}{ind}{ind}{{
+{ind}{ind}{ind}{ind}{ind}return new TextToken({token_name}.{eof}, "");
+{ind}{ind}{ind}{ind}}}
+
+"#
+    )?;
+    gen_return_found(lexer, config, 4, writer)?;
+    write!(
+        writer,
+        r#"{ind}{ind}{ind}}}
+
+{ind}{ind}{ind}pos += Character.charCount(ch);
+{ind}{ind}}}
+{ind}}}
+"#
+    )?;
+    if config.positions {
+        gen_positions(config, wrapped, writer)?;
+    }
+    if config.error_context {
+        gen_error_context(writer)?;
+    }
+    if !value_types(lexer).is_empty() {
+        gen_make_token(lexer, &config.names, writer)?;
+    }
+    if let Some(tab_width) = config.indentation {
+        gen_indentation(
+            tab_width,
+            config.lookahead.is_some(),
+            lexer.get_eof(),
+            &config.names,
+            writer,
+        )?;
+    }
+    if let Some(lookahead) = config.lookahead {
+        let source = if config.indentation.is_some() {
+            "nextIndented"
+        } else {
+            "nextToken"
+        };
+        gen_lookahead(lookahead, source, writer)?;
+    }
+    gen_iterator(lexer, config, writer)?;
+    if config.keyword_hash {
+        gen_keyword(lexer, &config.names, writer)?;
+    }
+    if !lexer.get_folded().is_empty() {
+        gen_fold_keyword(lexer, &config.names, writer)?;
+    }
+    if !lexer.get_equivalences().is_empty() {
+        gen_token_class(lexer, &config.names, writer)?;
+    }
+    if lexer.has_displays() {
+        gen_token_display(lexer, &tokens, &config.names, writer)?;
+    }
+    write!(
+        writer,
+        r#"
+{ind}public static class TextToken {{
+{ind}{ind}private final {token_name} token;
+{ind}{ind}private final String text;
+"#
+    )?;
+    if config.positions {
+        write!(
+            writer,
+            r#"{ind}{ind}private int line;
+{ind}{ind}private int column;
+{ind}{ind}private int offset;
+{ind}{ind}private int length;
+"#
+        )?;
+    }
+    write!(
+        writer,
+        r#"
+{ind}{ind}public TextToken({token_name} token, String text) {{
+{ind}{ind}{ind}this.token = token;
+{ind}{ind}{ind}this.text = text;
+{ind}{ind}}}
+
+{ind}{ind}public {token_name} getToken() {{
+{ind}{ind}{ind}return this.token;
+{ind}{ind}}}
+
+{ind}{ind}public String getText() {{
+{ind}{ind}{ind}return this.text;
+{ind}{ind}}}
+"#
+    )?;
+    if config.positions {
+        write!(
+            writer,
+            r#"
+{ind}{ind}public int getLine() {{
+{ind}{ind}{ind}return this.line;
+{ind}{ind}}}
+
+{ind}{ind}public int getColumn() {{
+{ind}{ind}{ind}return this.column;
+{ind}{ind}}}
+
+{ind}{ind}public int getOffset() {{
+{ind}{ind}{ind}return this.offset;
+{ind}{ind}}}
 
-            if (ch == -1)
-            {{
-                if (found == Token._TRAP)
-                {{
-                    return new TextToken(Token._EOF, "");
-                }}
+{ind}{ind}public int getLength() {{
+{ind}{ind}{ind}return this.length;
+{ind}{ind}}}
+
+{ind}{ind}public int getEnd() {{
+{ind}{ind}{ind}return this.offset + this.length;
+{ind}{ind}}}
+"#
+        )?;
+    }
+    writeln!(writer, "{ind}}}")?;
+    if !value_types(lexer).is_empty() {
+        write!(
+            writer,
+            r#"
+{ind}public static class ValueToken extends TextToken {{
+{ind}{ind}private final Number value;
 
-                String s = this.buf.substring(0, found_pos);
-                this.buf.delete(0, found_pos);
-                return new TextToken(found, s);
-            }}
+{ind}{ind}public ValueToken({token_name} token, String text, Number value) {{
+{ind}{ind}{ind}super(token, text);
+{ind}{ind}{ind}this.value = value;
+{ind}{ind}}}
 
-            pos++;
-        }}
-    }}
+{ind}{ind}public Number getValue() {{
+{ind}{ind}{ind}return this.value;
+{ind}{ind}}}
+{ind}}}
+"#
+        )?;
+    }
+    if config.error_context {
+        write!(
+            writer,
+            r#"
+{ind}public static class ErrorToken extends TextToken {{
+{ind}{ind}private final int line;
+{ind}{ind}private final int column;
+{ind}{ind}private final String lineText;
 
-    public static class TextToken {{
-        private final Token token;
-        private final String text;
+{ind}{ind}public ErrorToken(String text, int line, int column, String lineText) {{
+{ind}{ind}{ind}super({token_name}._ERR, text);
+{ind}{ind}{ind}this.line = line;
+{ind}{ind}{ind}this.column = column;
+{ind}{ind}{ind}this.lineText = lineText;
+{ind}{ind}}}
 
-        public TextToken(Token token, String text) {{
-            this.token = token;
-            this.text = text;
-        }}
+{ind}{ind}public int getLine() {{
+{ind}{ind}{ind}return this.line;
+{ind}{ind}}}
 
-        public Token getToken() {{
-            return this.token;
-        }}
+{ind}{ind}public int getColumn() {{
+{ind}{ind}{ind}return this.column;
+{ind}{ind}}}
 
-        public String getText() {{
-            return this.text;
-        }}
-    }}
+{ind}{ind}public String getLineText() {{
+{ind}{ind}{ind}return this.lineText;
+{ind}{ind}}}
+{ind}}}
+"#
+        )?;
+    }
+    write!(
+        writer,
+        r#"
 
-    public static enum Token {{
-        _EOF,
-        _ERR,
+{ind}public static enum {token_name} {{
+{ind}{ind}{eof},
+{ind}{ind}_ERR,
 "#
     )?;
+    if config.indentation.is_some() {
+        write!(writer, "{ind}{ind}INDENT,\n{ind}{ind}DEDENT,\n")?;
+    }
 
     for token in tokens {
-        write!(writer, "        {},\r\n", token)?;
+        writeln!(writer, "{ind}{ind}{},", token)?;
     }
     write!(
         writer,
-        r#"        ;
-    }}
+        r#"{ind}{ind};
+{ind}}}
 
 }}"#
     )?;
     Ok(())
 }
+
+fn gen_token_class<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &names.token;
+    write!(
+        writer,
+        r#"
+{ind}public static enum {token_name}Class {{
+{ind}{ind}_NONE,
+"#
+    )?;
+    for (name, _) in lexer.get_equivalences() {
+        write_line!(2, writer, "{},\n", name);
+    }
+    write_line!(2, writer, ";\n");
+    write_line!(1, writer, "}}\n\n");
+    write_line!(
+        1,
+        writer,
+        "public static {token_name}Class tokenClass({token_name} token) {{\n"
+    );
+    write_line!(2, writer, "switch (token) {{\n");
+    for (name, members) in lexer.get_equivalences() {
+        for member in members {
+            write_line!(3, writer, "case {}:\n", member);
+        }
+        write_line!(4, writer, "return {token_name}Class.{};\n", name);
+    }
+    write_line!(3, writer, "default:\n");
+    write_line!(4, writer, "return {token_name}Class._NONE;\n");
+    write_line!(2, writer, "}}\n");
+    write_line!(1, writer, "}}\n");
+    Ok(())
+}
+
+fn gen_token_display<W: Write>(
+    lexer: &Lexer,
+    tokens: &BTreeSet<SmolStr>,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &names.token;
+    write!(
+        writer,
+        "\n{ind}public static String tokenDisplay({token_name} token) {{\n"
+    )?;
+    write_line!(2, writer, "switch (token) {{\n");
+    for token in tokens {
+        write_line!(3, writer, "case {}:\n", token);
+        write_line!(
+            4,
+            writer,
+            "return \"{}\";\n",
+            escape_string(&lexer.display(token))
+        );
+    }
+    write_line!(3, writer, "default:\n");
+    write_line!(4, writer, "return token.name();\n");
+    write_line!(2, writer, "}}\n");
+    write_line!(1, writer, "}}\n");
+    Ok(())
+}
+
+fn gen_keyword<W: Write>(lexer: &Lexer, names: &Names, writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &names.token;
+    let table = keyword_table(&lexer.get_keywords());
+    write!(
+        writer,
+        "\n{ind}private static final String[] KEYWORD_TEXTS = {{\n"
+    )?;
+    for slot in &table.slots {
+        match slot {
+            Some((_, lit)) => {
+                write_line!(2, writer, "\"{}\",\n", escape_string(lit));
+            }
+            None => {
+                write_line!(2, writer, "null,\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n\n");
+    write_line!(
+        1,
+        writer,
+        "private static final {token_name}[] KEYWORD_TOKENS = {{\n"
+    );
+    for slot in &table.slots {
+        match slot {
+            Some((name, _)) => {
+                write_line!(2, writer, "{token_name}.{},\n", name);
+            }
+            None => {
+                write_line!(2, writer, "{token_name}._ERR,\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n");
+    write!(
+        writer,
+        r#"
+{ind}public static {token_name} keyword(String text) {{
+{ind}{ind}int hash = {};
+{ind}{ind}for (byte b : text.getBytes(java.nio.charset.StandardCharsets.UTF_8)) {{
+{ind}{ind}{ind}hash ^= b & 0xff;
+{ind}{ind}{ind}hash *= 16777619;
+{ind}{ind}}}
+{ind}{ind}int slot = hash & {};
+{ind}{ind}if (KEYWORD_TEXTS[slot] != null && KEYWORD_TEXTS[slot].equals(text)) {{
+{ind}{ind}{ind}return KEYWORD_TOKENS[slot];
+{ind}{ind}}}
+{ind}{ind}return {token_name}._ERR;
+{ind}}}
+"#,
+        table.basis as i32,
+        table.slots.len() - 1
+    )?;
+    Ok(())
+}
+
+// Folded keywords are matched as their host token, and only told apart
+// here by their text.
+fn gen_fold_keyword<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &names.token;
+    let table = keyword_table(&folded_keywords(lexer));
+    let host = |name: &SmolStr| {
+        lexer
+            .get_folded()
+            .iter()
+            .find(|(keyword, _)| keyword == name)
+            .map(|(_, host)| host.clone())
+            .unwrap()
+    };
+    write!(
+        writer,
+        "\n{ind}private static final String[] FOLDED_TEXTS = {{\n"
+    )?;
+    for slot in &table.slots {
+        match slot {
+            Some((_, lit)) => {
+                write_line!(2, writer, "\"{}\",\n", escape_string(lit));
+            }
+            None => {
+                write_line!(2, writer, "null,\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n\n");
+    write_line!(
+        1,
+        writer,
+        "private static final {token_name}[][] FOLDED_TOKENS = {{\n"
+    );
+    for slot in &table.slots {
+        match slot {
+            Some((name, _)) => {
+                write_line!(
+                    2,
+                    writer,
+                    "{{{token_name}.{}, {token_name}.{}}},\n",
+                    host(name),
+                    name
+                );
+            }
+            None => {
+                write_line!(2, writer, "{{{token_name}._ERR, {token_name}._ERR}},\n");
+            }
+        }
+    }
+    write_line!(1, writer, "}};\n");
+    write!(
+        writer,
+        r#"
+{ind}private static {token_name} foldKeyword({token_name} found, String text) {{
+{ind}{ind}int hash = {};
+{ind}{ind}for (byte b : text.getBytes(java.nio.charset.StandardCharsets.UTF_8)) {{
+{ind}{ind}{ind}hash ^= b & 0xff;
+{ind}{ind}{ind}hash *= 16777619;
+{ind}{ind}}}
+{ind}{ind}int slot = hash & {};
+{ind}{ind}if (FOLDED_TOKENS[slot][0] == found && FOLDED_TEXTS[slot].equals(text)) {{
+{ind}{ind}{ind}return FOLDED_TOKENS[slot][1];
+{ind}{ind}}}
+{ind}{ind}return found;
+{ind}}}
+"#,
+        table.basis as i32,
+        table.slots.len() - 1
+    )?;
+    Ok(())
+}
+
+fn gen_skip_error<W: Write>(
+    config: &JavaConfig,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    write_line!(
+        indent,
+        writer,
+        "start += Character.charCount(this.buf.codePointAt(start));\n"
+    );
+    // A long run is returned in pieces instead of staying in the buffer.
+    if let Some(max_error_run) = config.max_error_run {
+        let condition = format!("start >= {}", max_error_run);
+        gen_return_error_run(config, &condition, indent, writer)?;
+    }
+    write_line!(indent, writer, "pos = start;\n");
+    write_line!(indent, writer, "state = 0;\n");
+    write_line!(indent, writer, "continue;\n");
+    Ok(())
+}
+
+fn gen_recover<W: Write>(
+    config: &JavaConfig,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    write_line!(
+        indent,
+        writer,
+        "int start = Character.charCount(this.buf.codePointAt(0));\n"
+    );
+    gen_return_error_run(config, "start > 0", indent, writer)
+}
+
+fn gen_return_error_run<W: Write>(
+    config: &JavaConfig,
+    condition: &str,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &config.names.token;
+    write_line!(indent, writer, "if ({}) {{\n", condition);
+    write_line!(
+        indent + 1,
+        writer,
+        "String s = this.buf.substring(0, start);\n"
+    );
+    if config.positions {
+        write_line!(indent + 1, writer, "this.track(s);\n");
+    }
+    if config.error_context {
+        write_line!(indent + 1, writer, "ErrorToken error = this.fail(s);\n");
+        write_line!(indent + 1, writer, "this.buf.delete(0, start);\n");
+        write_line!(indent + 1, writer, "this.advance(s);\n");
+        write_line!(indent + 1, writer, "return error;\n");
+    } else {
+        write_line!(indent + 1, writer, "this.buf.delete(0, start);\n");
+        write_line!(
+            indent + 1,
+            writer,
+            "return new TextToken({token_name}._ERR, s);\n"
+        );
+    }
+    write_line!(indent, writer, "}}\n");
+    Ok(())
+}
+
+fn gen_reset<W: Write>(config: &JavaConfig, writer: &mut CodeWriter<W>) -> Result<()> {
+    if config.strip_bom {
+        write_line!(2, writer, "this.atStart = true;\n");
+    }
+    if config.indentation.is_some() {
+        write_line!(2, writer, "this.pending.clear();\n");
+        write_line!(2, writer, "this.indents.clear();\n");
+        write_line!(2, writer, "this.indents.add(0);\n");
+        write_line!(2, writer, "this.atLineStart = true;\n");
+        write_line!(2, writer, "this.width = 0;\n");
+    }
+    if config.lookahead.is_some() {
+        write_line!(2, writer, "java.util.Arrays.fill(this.lookahead, null);\n");
+        write_line!(2, writer, "this.lookaheadStart = 0;\n");
+        write_line!(2, writer, "this.lookaheadCount = 0;\n");
+    }
+    if config.positions {
+        write_line!(2, writer, "this.cursorLine = 1;\n");
+        write_line!(2, writer, "this.cursorColumn = 1;\n");
+        write_line!(2, writer, "this.cursorOffset = 0;\n");
+        write_line!(2, writer, "this.startLine = 1;\n");
+        write_line!(2, writer, "this.startColumn = 1;\n");
+        write_line!(2, writer, "this.startOffset = 0;\n");
+    }
+    if config.error_context {
+        write_line!(2, writer, "this.line = 1;\n");
+        write_line!(2, writer, "this.column = 1;\n");
+        write_line!(2, writer, "this.currentLine.setLength(0);\n");
+    }
+    Ok(())
+}
+
+fn gen_lookahead<W: Write>(
+    lookahead: usize,
+    source: &str,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    write!(
+        writer,
+        r#"
+{ind}public TextToken next() throws IOException {{
+{ind}{ind}if (this.lookaheadCount == 0) {{
+{ind}{ind}{ind}return this.{1}();
+{ind}{ind}}}
+{ind}{ind}TextToken token = this.lookahead[this.lookaheadStart];
+{ind}{ind}this.lookahead[this.lookaheadStart] = null;
+{ind}{ind}this.lookaheadStart = (this.lookaheadStart + 1) % {0};
+{ind}{ind}this.lookaheadCount--;
+{ind}{ind}return token;
+{ind}}}
+
+{ind}public TextToken peek() throws IOException {{
+{ind}{ind}if (this.lookaheadCount == 0) {{
+{ind}{ind}{ind}this.lookahead[this.lookaheadStart] = this.{1}();
+{ind}{ind}{ind}this.lookaheadCount = 1;
+{ind}{ind}}}
+{ind}{ind}return this.lookahead[this.lookaheadStart];
+{ind}}}
+
+{ind}public List<TextToken> peekN(int n) throws IOException {{
+{ind}{ind}if (n > {0}) {{
+{ind}{ind}{ind}throw new IllegalArgumentException("peekN exceeds the maximum lookahead of {0}");
+{ind}{ind}}}
+{ind}{ind}while (this.lookaheadCount < n) {{
+{ind}{ind}{ind}this.lookahead[(this.lookaheadStart + this.lookaheadCount) % {0}] = this.{1}();
+{ind}{ind}{ind}this.lookaheadCount++;
+{ind}{ind}}}
+{ind}{ind}List<TextToken> tokens = new ArrayList<>(n);
+{ind}{ind}for (int i = 0; i < n; i++) {{
+{ind}{ind}{ind}tokens.add(this.lookahead[(this.lookaheadStart + i) % {0}]);
+{ind}{ind}}}
+{ind}{ind}return tokens;
+{ind}}}
+"#,
+        lookahead, source
+    )?;
+    Ok(())
+}
+
+fn gen_iterator<W: Write>(
+    lexer: &Lexer,
+    config: &JavaConfig,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    let token_name = &config.names.token;
+    let eof = lexer.get_eof();
+    // Without error recovery, next() keeps returning the same error instead of
+    // advancing, so iteration ends after yielding it once.
+    let stuck = !config.recover && !config.bulk_errors;
+    write!(
+        writer,
+        r#"
+{ind}@Override
+{ind}public Iterator<TextToken> iterator() {{
+{ind}{ind}return new Iterator<TextToken>() {{
+{ind}{ind}{ind}private TextToken pending;
+"#
+    )?;
+    if stuck {
+        write_line!(3, writer, "private boolean failed;\n");
+    }
+    write!(
+        writer,
+        r#"
+{ind}{ind}{ind}@Override
+{ind}{ind}{ind}public boolean hasNext() {{
+"#
+    )?;
+    if stuck {
+        write_line!(4, writer, "if (this.failed) {{\n");
+        write_line!(5, writer, "return false;\n");
+        write_line!(4, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}{ind}{ind}if (this.pending == null) {{
+{ind}{ind}{ind}{ind}{ind}try {{
+{ind}{ind}{ind}{ind}{ind}{ind}this.pending = {lexer_name}.this.next();
+{ind}{ind}{ind}{ind}{ind}}} catch (IOException e) {{
+{ind}{ind}{ind}{ind}{ind}{ind}throw new UncheckedIOException(e);
+{ind}{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}return this.pending.getToken() != {token_name}.{eof};
+{ind}{ind}{ind}}}
+
+{ind}{ind}{ind}@Override
+{ind}{ind}{ind}public TextToken next() {{
+{ind}{ind}{ind}{ind}if (!this.hasNext()) {{
+{ind}{ind}{ind}{ind}{ind}throw new NoSuchElementException();
+{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}TextToken token = this.pending;
+{ind}{ind}{ind}{ind}this.pending = null;
+"#
+    )?;
+    if stuck {
+        write_line!(
+            4,
+            writer,
+            "this.failed = token.getToken() == {token_name}._ERR;\n"
+        );
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}{ind}{ind}return token;
+{ind}{ind}{ind}}}
+{ind}{ind}}};
+{ind}}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_indentation<W: Write>(
+    tab_width: usize,
+    wrapped: bool,
+    eof: &str,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &names.token;
+    write!(
+        writer,
+        r#"
+{ind}private static boolean isBlank(String text) {{
+{ind}{ind}for (int i = 0; i < text.length(); i++) {{
+{ind}{ind}{ind}char ch = text.charAt(i);
+{ind}{ind}{ind}if (ch != ' ' && ch != '\t' && ch != '\r' && ch != '\n') {{
+{ind}{ind}{ind}{ind}return false;
+{ind}{ind}{ind}}}
+{ind}{ind}}}
+{ind}{ind}return !text.isEmpty();
+{ind}}}
+
+{ind}{1} TextToken {2}() throws IOException {{
+{ind}{ind}while (this.pending.isEmpty()) {{
+{ind}{ind}{ind}TextToken token = this.nextToken();
+{ind}{ind}{ind}String text = token.getText();
+{ind}{ind}{ind}if (token.getToken() == {token_name}.{eof}) {{
+{ind}{ind}{ind}{ind}while (this.indents.size() > 1) {{
+{ind}{ind}{ind}{ind}{ind}this.indents.remove(this.indents.size() - 1);
+{ind}{ind}{ind}{ind}{ind}this.pending.add(new TextToken({token_name}.DEDENT, ""));
+{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}}} else if (isBlank(text)) {{
+{ind}{ind}{ind}{ind}int nl = text.lastIndexOf('\n');
+{ind}{ind}{ind}{ind}if (nl != -1) {{
+{ind}{ind}{ind}{ind}{ind}this.atLineStart = true;
+{ind}{ind}{ind}{ind}{ind}this.width = 0;
+{ind}{ind}{ind}{ind}{ind}text = text.substring(nl + 1);
+{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}if (this.atLineStart) {{
+{ind}{ind}{ind}{ind}{ind}for (int i = 0; i < text.length(); i++) {{
+{ind}{ind}{ind}{ind}{ind}{ind}char ch = text.charAt(i);
+{ind}{ind}{ind}{ind}{ind}{ind}if (ch == '\t') {{
+{ind}{ind}{ind}{ind}{ind}{ind}{ind}this.width = (this.width / {0} + 1) * {0};
+{ind}{ind}{ind}{ind}{ind}{ind}}} else if (ch != '\r') {{
+{ind}{ind}{ind}{ind}{ind}{ind}{ind}this.width++;
+{ind}{ind}{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}}} else {{
+{ind}{ind}{ind}{ind}if (this.atLineStart) {{
+{ind}{ind}{ind}{ind}{ind}int top = this.indents.get(this.indents.size() - 1);
+{ind}{ind}{ind}{ind}{ind}if (this.width > top) {{
+{ind}{ind}{ind}{ind}{ind}{ind}this.indents.add(this.width);
+{ind}{ind}{ind}{ind}{ind}{ind}this.pending.add(new TextToken({token_name}.INDENT, ""));
+{ind}{ind}{ind}{ind}{ind}}} else {{
+{ind}{ind}{ind}{ind}{ind}{ind}while (this.width < this.indents.get(this.indents.size() - 1)) {{
+{ind}{ind}{ind}{ind}{ind}{ind}{ind}this.indents.remove(this.indents.size() - 1);
+{ind}{ind}{ind}{ind}{ind}{ind}{ind}this.pending.add(new TextToken({token_name}.DEDENT, ""));
+{ind}{ind}{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}{ind}{ind}if (this.width != this.indents.get(this.indents.size() - 1)) {{
+{ind}{ind}{ind}{ind}{ind}{ind}{ind}this.pending.add(new TextToken({token_name}._ERR, ""));
+{ind}{ind}{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}{ind}this.atLineStart = false;
+{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}{ind}if (text.endsWith("\n")) {{
+{ind}{ind}{ind}{ind}{ind}this.atLineStart = true;
+{ind}{ind}{ind}{ind}{ind}this.width = 0;
+{ind}{ind}{ind}{ind}}}
+{ind}{ind}{ind}}}
+{ind}{ind}{ind}this.pending.add(token);
+{ind}{ind}}}
+{ind}{ind}return this.pending.poll();
+{ind}}}
+"#,
+        tab_width,
+        if wrapped { "private" } else { "public" },
+        if wrapped { "nextIndented" } else { "next" },
+    )?;
+    Ok(())
+}
+
+fn gen_return_found<W: Write>(
+    lexer: &Lexer,
+    config: &JavaConfig,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let token_name = &config.names.token;
+    write_line!(
+        indent,
+        writer,
+        "String s = this.buf.substring(0, found_pos);\n"
+    );
+    if !lexer.get_folded().is_empty() {
+        write_line!(indent, writer, "found = foldKeyword(found, s);\n");
+    }
+    write_line!(indent, writer, "this.buf.delete(0, found_pos);\n");
+    if config.positions {
+        write_line!(indent, writer, "this.track(s);\n");
+    }
+    if config.error_context {
+        write_line!(indent, writer, "this.advance(s);\n");
+    }
+    let skipped = lexer.get_skipped();
+    if !skipped.is_empty() {
+        let condition: Vec<String> = skipped
+            .iter()
+            .map(|token| format!("found == {token_name}.{}", token))
+            .collect();
+        write_line!(indent, writer, "if ({}) {{\n", condition.join(" || "));
+        write_line!(indent + 1, writer, "found = null;\n");
+        write_line!(indent + 1, writer, "found_pos = 0;\n");
+        write_line!(indent + 1, writer, "pos = 0;\n");
+        write_line!(indent + 1, writer, "state = 0;\n");
+        if config.bulk_errors {
+            write_line!(indent + 1, writer, "start = 0;\n");
+        }
+        if config.positions {
+            write_line!(indent + 1, writer, "this.markStart();\n");
+        }
+        write_line!(indent + 1, writer, "continue;\n");
+        write_line!(indent, writer, "}}\n");
+    }
+    if config.normalize_newlines {
+        write_line!(indent, writer, "s = s.replace(\"\\r\\n\", \"\\n\");\n");
+    }
+    if value_types(lexer).is_empty() {
+        write_line!(indent, writer, "return new TextToken(found, s);\n");
+    } else {
+        write_line!(indent, writer, "return this.makeToken(found, s);\n");
+    }
+    Ok(())
+}
+
+fn value_types(lexer: &Lexer) -> Vec<(&SmolStr, &'static str, &'static str)> {
+    lexer
+        .get_terminals()
+        .iter()
+        .filter_map(|rule| {
+            let (value_type, parse) = match rule.value_type.as_deref()? {
+                "int" => ("int", "Integer.parseInt"),
+                "long" => ("long", "Long.parseLong"),
+                "float" => ("float", "Float.parseFloat"),
+                _ => ("double", "Double.parseDouble"),
+            };
+            Some((&rule.name, value_type, parse))
+        })
+        .collect()
+}
+
+fn gen_make_token<W: Write>(
+    lexer: &Lexer,
+    names: &Names,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let token_name = &names.token;
+    write!(
+        writer,
+        r#"
+{ind}private TextToken makeToken({token_name} token, String text) {{
+{ind}{ind}try {{
+{ind}{ind}{ind}switch (token) {{
+"#
+    )?;
+    for (token, value_type, parse) in value_types(lexer) {
+        write_line!(4, writer, "case {}: {{\n", token);
+        write_line!(5, writer, "{} v = {}(text);\n", value_type, parse);
+        if value_type == "float" || value_type == "double" {
+            write_line!(5, writer, "if (Double.isInfinite(v)) {{\n");
+            write_line!(
+                6,
+                writer,
+                "return new TextToken({token_name}._ERR, text);\n"
+            );
+            write_line!(5, writer, "}}\n");
+        }
+        write_line!(5, writer, "return new ValueToken(token, text, v);\n");
+        write_line!(4, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"{ind}{ind}{ind}{ind}default:
+{ind}{ind}{ind}{ind}{ind}return new TextToken(token, text);
+{ind}{ind}{ind}}}
+{ind}{ind}}} catch (NumberFormatException e) {{
+{ind}{ind}{ind}return new TextToken({token_name}._ERR, text);
+{ind}{ind}}}
+{ind}}}
+"#
+    )?;
+    Ok(())
+}
+
+fn gen_positions<W: Write>(
+    config: &JavaConfig,
+    wrapped: bool,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    let ind = writer.unit();
+    let width = if config.bytes {
+        "1"
+    } else {
+        "ch < 0x80 ? 1 : ch < 0x800 ? 2 : ch < 0x10000 ? 3 : 4"
+    };
+    write!(
+        writer,
+        r#"
+{ind}private void markStart() {{
+{ind}{ind}this.startLine = this.cursorLine;
+{ind}{ind}this.startColumn = this.cursorColumn;
+{ind}{ind}this.startOffset = this.cursorOffset;
+{ind}}}
+
+{ind}private void track(String text) {{
+{ind}{ind}for (int i = 0; i < text.length(); ) {{
+{ind}{ind}{ind}int ch = text.codePointAt(i);
+{ind}{ind}{ind}i += Character.charCount(ch);
+{ind}{ind}{ind}this.cursorOffset += {width};
+{ind}{ind}{ind}if (ch == '\n') {{
+{ind}{ind}{ind}{ind}this.cursorLine++;
+{ind}{ind}{ind}{ind}this.cursorColumn = 1;
+{ind}{ind}{ind}}} else {{
+{ind}{ind}{ind}{ind}this.cursorColumn++;
+{ind}{ind}{ind}}}
+{ind}{ind}}}
+{ind}}}
+
+{ind}{} TextToken {}() throws IOException {{
+{ind}{ind}TextToken token = this.scanToken();
+{ind}{ind}token.line = this.startLine;
+{ind}{ind}token.column = this.startColumn;
+{ind}{ind}token.offset = this.startOffset;
+{ind}{ind}token.length = this.cursorOffset - this.startOffset;
+{ind}{ind}return token;
+{ind}}}
+"#,
+        if wrapped { "private" } else { "public" },
+        if wrapped { "nextToken" } else { "next" },
+    )?;
+    Ok(())
+}
+
+fn gen_error_context<W: Write>(writer: &mut CodeWriter<W>) -> Result<()> {
+    let ind = writer.unit();
+    write!(
+        writer,
+        r#"
+{ind}private void advance(String text) {{
+{ind}{ind}for (int i = 0; i < text.length(); i++) {{
+{ind}{ind}{ind}char ch = text.charAt(i);
+{ind}{ind}{ind}if (ch == '\n') {{
+{ind}{ind}{ind}{ind}this.line++;
+{ind}{ind}{ind}{ind}this.column = 1;
+{ind}{ind}{ind}{ind}this.currentLine.setLength(0);
+{ind}{ind}{ind}}} else {{
+{ind}{ind}{ind}{ind}this.column++;
+{ind}{ind}{ind}{ind}this.currentLine.append(ch);
+{ind}{ind}{ind}}}
+{ind}{ind}}}
+{ind}}}
+
+{ind}private ErrorToken fail(String text) throws IOException {{
+{ind}{ind}int end = 0;
+{ind}{ind}while (true) {{
+{ind}{ind}{ind}if (end >= this.buf.length()) {{
+{ind}{ind}{ind}{ind}int ch = this.read();
+{ind}{ind}{ind}{ind}if (ch == -1) break;
+{ind}{ind}{ind}{ind}this.buf.appendCodePoint(ch);
+{ind}{ind}{ind}}}
+{ind}{ind}{ind}char ch = this.buf.charAt(end);
+{ind}{ind}{ind}if (ch == '\n' || ch == '\r') break;
+{ind}{ind}{ind}end++;
+{ind}{ind}}}
+{ind}{ind}return new ErrorToken(text, this.line, this.column, this.currentLine.toString() + this.buf.substring(0, end));
+{ind}}}
+"#
+    )?;
+    Ok(())
+}
+
+pub fn gen_tables<W: Write>(lexer: &Lexer, config: &JavaConfig, writer: &mut W) -> Result<()> {
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let tables = tables(lexer);
+    writeln!(writer, "/**")?;
+    for line in tables_contract(lexer) {
+        writeln!(
+            writer,
+            " *{}{}",
+            if line.is_empty() { "" } else { " " },
+            line
+        )?;
+    }
+    write!(writer, " */\npublic final class LexerTables {{\n")?;
+    write_line!(1, writer, "public static final int INITIAL_STATE = 0;\n");
+    write_line!(
+        1,
+        writer,
+        "public static final int TRAP_STATE = {};\n\n",
+        tables.trap
+    );
+    write_line!(1, writer, "public static final int[][] ALPHABET = {{\n");
+    for (first, last) in &tables.alphabet {
+        write_line!(2, writer, "{{{}, {}}},\n", first, last);
+    }
+    write_line!(1, writer, "}};\n\n");
+    write_line!(1, writer, "public static final int[][] TRANSITIONS = {{\n");
+    for row in &tables.transitions {
+        let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+        write_line!(2, writer, "{{{}}},\n", row.join(", "));
+    }
+    write_line!(1, writer, "}};\n\n");
+    let accepts: Vec<String> = tables
+        .accepts
+        .iter()
+        .map(|a| a.map_or_else(|| String::from("-1"), |a| a.to_string()))
+        .collect();
+    write_line!(
+        1,
+        writer,
+        "public static final int[] ACCEPTS = {{{}}};\n\n",
+        accepts.join(", ")
+    );
+    write_line!(1, writer, "public static final String[] TOKEN_NAMES = {{\n");
+    for token in &tables.tokens {
+        write_line!(2, writer, "\"{}\",\n", token);
+    }
+    write_line!(1, writer, "}};\n\n");
+    let skipped: Vec<&str> = tables
+        .skipped
+        .iter()
+        .map(|s| if *s { "true" } else { "false" })
+        .collect();
+    write_line!(
+        1,
+        writer,
+        "public static final boolean[] TOKEN_SKIPPED = {{{}}};\n",
+        skipped.join(", ")
+    );
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+pub fn gen_driver<W: Write>(config: &JavaConfig, writer: &mut W) -> Result<()> {
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let ind = writer.unit();
+    let lexer_name = &config.names.lexer;
+    if let Some(package) = &config.names.namespace {
+        write!(writer, "package {};\n\n", package)?;
+    }
+    write!(
+        writer,
+        r#"public class Main {{
+{ind}public static void main(String[] args) {{
+{ind}{ind}{lexer_name} lexer = new {lexer_name}(System.in);
+{ind}{ind}for ({lexer_name}.TextToken token : lexer) {{
+{ind}{ind}{ind}System.out.println(token.getToken() + ": " + token.getText());
+{ind}{ind}}}
+{ind}}}
+}}
+"#
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAMMAR: &str = r#"
+token NAME = ([a-z])+;
+token NL = "\n";
+token WS = ([ \t])+;
+"#;
+
+    fn generate(src: &str, config: &JavaConfig) -> String {
+        let lexer = Lexer::from_source(src).unwrap();
+        let mut out = Vec::new();
+        gen_lexer(&lexer, config, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn indentation_is_opt_in() {
+        let plain = generate(GRAMMAR, &JavaConfig::default());
+        assert!(!plain.contains("INDENT"));
+        assert!(plain.contains("public TextToken next() throws IOException"));
+
+        let indented = generate(
+            GRAMMAR,
+            &JavaConfig {
+                indentation: Some(4),
+                ..Default::default()
+            },
+        );
+        assert!(indented.contains("        INDENT,"));
+        assert!(indented.contains("        DEDENT,"));
+        assert!(indented.contains("private TextToken nextToken() throws IOException"));
+        assert!(indented.contains("public TextToken next() throws IOException"));
+        assert!(indented.contains("this.width = (this.width / 4 + 1) * 4;"));
+    }
+
+    #[test]
+    fn shortest_match_stops_at_first_accept() {
+        let src = "match = shortest;\ntoken A = \"a\";\ntoken AB = \"ab\";\n";
+        let code = generate(src, &JavaConfig::default());
+        // The accepting case records the token and goes straight to the
+        // trap instead of switching on the next character.
+        let case: Vec<&str> = code
+            .lines()
+            .skip_while(|line| line.trim() != "found = Token.A;")
+            .take(3)
+            .map(str::trim)
+            .collect();
+        assert_eq!(case[0], "found = Token.A;");
+        assert!(case[1].starts_with("state = "), "{:?}", case);
+        assert_eq!(case[2], "break;");
+    }
+
+    #[test]
+    fn internal_tokens_stay_out_of_the_enum() {
+        let src = "internal token RESERVED = \"goto\";\ntoken NAME = ([a-z])+;\n";
+        let code = generate(src, &JavaConfig::default());
+        assert!(code.contains("        NAME,"));
+        assert!(!code.contains("RESERVED"));
+    }
+
+    #[test]
+    fn eof_can_be_renamed() {
+        let code = generate(
+            "eof = END;\ntoken NAME = ([a-z])+;\n",
+            &JavaConfig::default(),
+        );
+        assert!(code.contains("        END,"));
+        assert!(code.contains("return new TextToken(Token.END, \"\");"));
+        assert!(!code.contains("_EOF"));
+    }
+
+    #[test]
+    fn tables_only_has_no_driver() {
+        let lexer = Lexer::from_source(GRAMMAR).unwrap();
+        let mut out = Vec::new();
+        gen_tables(&lexer, &JavaConfig::default(), &mut out).unwrap();
+        let tables = String::from_utf8(out).unwrap();
+        for table in ["ALPHABET", "TRANSITIONS", "ACCEPTS", "TOKEN_NAMES"] {
+            assert!(tables.contains(&format!("{} = {{", table)), "{}", table);
+        }
+        assert!(tables.contains("TRAP_STATE"));
+        assert!(!tables.contains("next("));
+        assert!(!tables.contains("class Lexer "));
+    }
+
+    #[test]
+    fn token_enum_has_no_trap() {
+        let code = generate(GRAMMAR, &JavaConfig::default());
+        let start = code.find("public static enum Token {").unwrap();
+        let end = start + code[start..].find('}').unwrap();
+        let names: Vec<&str> = code[start..end]
+            .lines()
+            .skip(1)
+            .map(|line| line.trim().trim_end_matches([',', ';']))
+            .filter(|line| !line.is_empty())
+            .collect();
+        assert_eq!(names, ["_EOF", "_ERR", "NAME", "NL", "WS"]);
+    }
+
+    #[test]
+    fn large_alphabet() {
+        let mut src = String::new();
+        for i in 0..200 {
+            let c = 0x400 + i * 4;
+            src.push_str(&format!(
+                "token T{} = [\\u{{{:x}}}-\\u{{{:x}}}] ([a-z])*;\n",
+                i,
+                c,
+                c + 2
+            ));
+        }
+        let lexer = Lexer::from_source(&src).unwrap();
+        assert!(lexer.get_alphabet().len() > 600);
+        let start = std::time::Instant::now();
+        let mut first = Vec::new();
+        gen_lexer(&lexer, &JavaConfig::default(), &mut first).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed.as_secs() < 5, "took {:?}", elapsed);
+        let mut second = Vec::new();
+        gen_lexer(&lexer, &JavaConfig::default(), &mut second).unwrap();
+        assert_eq!(first, second);
+        // Every class of the alphabet is mapped exactly once.
+        let code = String::from_utf8(first).unwrap();
+        for (i, (r0, r1)) in lexer.get_alphabet().iter().enumerate() {
+            let mapping = if r0 == r1 {
+                format!("case {}:\n                return {};", r0, i)
+            } else {
+                format!(
+                    "if (ch >= {} && ch <= {}) {{\n            return {};",
+                    r0, r1, i
+                )
+            };
+            assert_eq!(code.matches(&mapping).count(), 1, "{}", mapping);
+        }
+    }
+}
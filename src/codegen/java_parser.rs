@@ -0,0 +1,403 @@
+use std::{collections::BTreeSet, io::Write};
+
+use smol_str::SmolStr;
+
+use crate::{
+    codegen::{java::JavaConfig, CodeWriter},
+    error::{ensure, Result},
+    parser::{FieldKind, FieldType, Parser},
+    rules::Element,
+};
+
+macro_rules! write_line {
+    ($indent:expr,$writer:expr,$($arg:tt)*) => {
+        $writer.indent($indent)?;
+        write!($writer, $($arg)*)?;
+    };
+}
+
+fn java_type(field: &FieldType) -> String {
+    let kind = match &field.kind {
+        FieldKind::Token => "String",
+        FieldKind::Node(name) => name.as_str(),
+        FieldKind::AnyNode => "Node",
+        FieldKind::Any => "Object",
+    };
+    if field.list {
+        format!("List<{}>", kind)
+    } else {
+        kind.to_string()
+    }
+}
+
+fn condition(first: &BTreeSet<SmolStr>, token_type: &str) -> String {
+    let checks: Vec<String> = first
+        .iter()
+        .map(|token| format!("this.peek() == {token_type}.{}", token))
+        .collect();
+    checks.join(" || ")
+}
+
+fn expected(first: &BTreeSet<SmolStr>, token_type: &str) -> String {
+    let tokens: Vec<String> = first
+        .iter()
+        .map(|token| format!("{token_type}.{}", token))
+        .collect();
+    tokens.join(", ")
+}
+
+pub fn gen_parser<W: Write>(parser: &Parser, config: &JavaConfig, writer: &mut W) -> Result<()> {
+    let names = &config.names;
+    let writer = &mut CodeWriter::new(writer, &config.layout);
+    let ind = writer.unit();
+    let lexer_name = &names.lexer;
+    let token_type = format!("{}.{}", names.lexer, names.token);
+    if let Some(package) = &names.namespace {
+        write!(writer, "package {};\n\n", package)?;
+    }
+    write!(
+        writer,
+        r#"import java.io.IOException;
+import java.util.ArrayList;
+import java.util.Arrays;
+import java.util.List;
+
+public class Parser {{
+
+{ind}public interface Node {{
+{ind}}}
+
+{ind}public static class ParseException extends Exception {{
+{ind}{ind}private static final long serialVersionUID = 1L;
+
+{ind}{ind}private final {lexer_name}.TextToken found;
+
+{ind}{ind}public ParseException({lexer_name}.TextToken found, {token_type}... expected) {{
+{ind}{ind}{ind}super("Expected one of " + Arrays.toString(expected) + ", found " + found.getToken() + " '" + found.getText() + "'");
+{ind}{ind}{ind}this.found = found;
+{ind}{ind}}}
+
+{ind}{ind}public {lexer_name}.TextToken getFound() {{
+{ind}{ind}{ind}return this.found;
+{ind}{ind}}}
+{ind}}}
+"#
+    )?;
+    for node in parser.get_nodes() {
+        write!(
+            writer,
+            "\n{ind}public static class {} implements Node {{\n",
+            node.name
+        )?;
+        for (name, field) in &node.fields {
+            write_line!(2, writer, "public final {} {};\n", java_type(field), name);
+        }
+        let params: Vec<String> = node
+            .fields
+            .iter()
+            .map(|(name, field)| format!("{} {}", java_type(field), name))
+            .collect();
+        writeln!(writer)?;
+        write_line!(
+            2,
+            writer,
+            "public {}({}) {{\n",
+            node.name,
+            params.join(", ")
+        );
+        for (name, _) in &node.fields {
+            write_line!(3, writer, "this.{} = {};\n", name, name);
+        }
+        write_line!(2, writer, "}}\n\n");
+        write_line!(2, writer, "@Override\n");
+        write_line!(2, writer, "public String toString() {{\n");
+        let fields: Vec<String> = node
+            .fields
+            .iter()
+            .map(|(name, _)| format!("\"{}=\" + this.{}", name, name))
+            .collect();
+        if fields.is_empty() {
+            write_line!(3, writer, "return \"{}()\";\n", node.name);
+        } else {
+            write_line!(
+                3,
+                writer,
+                "return \"{}(\" + {} + \")\";\n",
+                node.name,
+                fields.join(" + \", \" + ")
+            );
+        }
+        write_line!(2, writer, "}}\n");
+        write_line!(1, writer, "}}\n");
+    }
+
+    for rule in parser.get_rules() {
+        ensure!(
+            !rule.has_attribute("inline") || parser.bindings(rule).is_empty(),
+            Grammar,
+            "'{}' is marked @inline, but binds variables its callers couldn't see",
+            rule.name
+        );
+    }
+
+    let start = parser.get_start();
+    let eof = parser.get_eof();
+    write!(
+        writer,
+        r#"
+{ind}private final {lexer_name} lexer;
+{ind}private {lexer_name}.TextToken current;
+
+{ind}public Parser({lexer_name} lexer) throws IOException {{
+{ind}{ind}this.lexer = lexer;
+{ind}{ind}this.current = lexer.next();
+{ind}}}
+
+{ind}private {token_type} peek() {{
+{ind}{ind}return this.current.getToken();
+{ind}}}
+
+{ind}private String expect({token_type} token) throws IOException, ParseException {{
+{ind}{ind}if (this.peek() != token) {{
+{ind}{ind}{ind}throw new ParseException(this.current, token);
+{ind}{ind}}}
+{ind}{ind}String text = this.current.getText();
+{ind}{ind}this.current = this.lexer.next();
+{ind}{ind}return text;
+{ind}}}
+
+{ind}public {} parse() throws IOException, ParseException {{
+{ind}{ind}{} result = this.parse{}();
+{ind}{ind}this.expect({token_type}.{eof});
+{ind}{ind}return result;
+{ind}}}
+"#,
+        parser.node_of(&start.name),
+        parser.node_of(&start.name),
+        start.name
+    )?;
+
+    for rule in parser.get_rules() {
+        let node_name = parser.node_of(&rule.name);
+        let node = parser
+            .get_nodes()
+            .iter()
+            .find(|node| &node.name == node_name)
+            .unwrap();
+        write!(
+            writer,
+            "\n{ind}public {} parse{}() throws IOException, ParseException {{\n",
+            node_name, rule.name
+        )?;
+        for (var, field) in parser.bindings(rule) {
+            let field = node
+                .fields
+                .iter()
+                .find(|(name, _)| name == &var)
+                .map_or(field, |(_, field)| field.clone());
+            let init = if field.list {
+                "new ArrayList<>()"
+            } else {
+                "null"
+            };
+            write_line!(2, writer, "{} {} = {};\n", java_type(&field), var, init);
+        }
+        let list_vars: BTreeSet<SmolStr> = parser
+            .bindings(rule)
+            .into_iter()
+            .filter(|(_, field)| field.list)
+            .map(|(var, _)| var)
+            .collect();
+        let mut inlining = vec![rule.name.clone()];
+        gen_element(
+            parser,
+            &token_type,
+            &rule.element,
+            &list_vars,
+            &mut inlining,
+            2,
+            writer,
+        )?;
+        let args: Vec<&str> = rule
+            .constructor_vars
+            .iter()
+            .flatten()
+            .map(|var| var.as_str())
+            .collect();
+        write_line!(
+            2,
+            writer,
+            "return new {}({});\n",
+            node_name,
+            args.join(", ")
+        );
+        write_line!(1, writer, "}}\n");
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_element<W: Write>(
+    parser: &Parser,
+    token_type: &str,
+    element: &Element,
+    list_vars: &BTreeSet<SmolStr>,
+    inlining: &mut Vec<SmolStr>,
+    indent: usize,
+    writer: &mut CodeWriter<W>,
+) -> Result<()> {
+    match element {
+        Element::Rule { var, name } => {
+            // An unbound call to an @inline rule parses its body in place, the
+            // method is still generated for the calls that keep the node.
+            let inline = parser
+                .get_rules()
+                .iter()
+                .find(|rule| &rule.name == name && rule.has_attribute("inline"))
+                .filter(|_| var.is_none() && !inlining.contains(name));
+            if let Some(rule) = inline {
+                inlining.push(name.clone());
+                gen_element(
+                    parser,
+                    token_type,
+                    &rule.element,
+                    list_vars,
+                    inlining,
+                    indent,
+                    writer,
+                )?;
+                inlining.pop();
+                return Ok(());
+            }
+            let call = if parser.is_token(name) {
+                format!("this.expect({token_type}.{})", name)
+            } else {
+                format!("this.parse{}()", name)
+            };
+            match var {
+                Some(var) if list_vars.contains(var) => {
+                    write_line!(indent, writer, "{}.add({});\n", var, call);
+                }
+                Some(var) => {
+                    write_line!(indent, writer, "{} = {};\n", var, call);
+                }
+                None => {
+                    write_line!(indent, writer, "{};\n", call);
+                }
+            }
+        }
+        Element::Group { subelems } => {
+            for subelem in subelems {
+                gen_element(
+                    parser, token_type, subelem, list_vars, inlining, indent, writer,
+                )?;
+            }
+        }
+        Element::Alternatives { subelems } => {
+            write_line!(indent, writer, "switch (this.peek()) {{\n");
+            let mut default = None;
+            for subelem in subelems {
+                if parser.nullable(subelem) {
+                    default = Some(subelem);
+                    continue;
+                }
+                for token in parser.first(subelem) {
+                    write_line!(indent + 1, writer, "case {}:\n", token);
+                }
+                gen_element(
+                    parser,
+                    token_type,
+                    subelem,
+                    list_vars,
+                    inlining,
+                    indent + 2,
+                    writer,
+                )?;
+                write_line!(indent + 2, writer, "break;\n");
+            }
+            if let Some(subelem) = default {
+                for token in parser.first(subelem) {
+                    write_line!(indent + 1, writer, "case {}:\n", token);
+                }
+                write_line!(indent + 1, writer, "default:\n");
+                gen_element(
+                    parser,
+                    token_type,
+                    subelem,
+                    list_vars,
+                    inlining,
+                    indent + 2,
+                    writer,
+                )?;
+                write_line!(indent + 2, writer, "break;\n");
+            } else {
+                write_line!(indent + 1, writer, "default:\n");
+                write_line!(
+                    indent + 2,
+                    writer,
+                    "throw new ParseException(this.current, {});\n",
+                    expected(&parser.first(element), token_type)
+                );
+            }
+            write_line!(indent, writer, "}}\n");
+        }
+        Element::Optional { inner } => {
+            write_line!(
+                indent,
+                writer,
+                "if ({}) {{\n",
+                condition(&parser.first(inner), token_type)
+            );
+            gen_element(
+                parser,
+                token_type,
+                inner,
+                list_vars,
+                inlining,
+                indent + 1,
+                writer,
+            )?;
+            write_line!(indent, writer, "}}\n");
+        }
+        Element::ZeroOrMore { inner } => {
+            write_line!(
+                indent,
+                writer,
+                "while ({}) {{\n",
+                condition(&parser.first(inner), token_type)
+            );
+            gen_element(
+                parser,
+                token_type,
+                inner,
+                list_vars,
+                inlining,
+                indent + 1,
+                writer,
+            )?;
+            write_line!(indent, writer, "}}\n");
+        }
+        Element::OneOrMore { inner } => {
+            write_line!(indent, writer, "do {{\n");
+            gen_element(
+                parser,
+                token_type,
+                inner,
+                list_vars,
+                inlining,
+                indent + 1,
+                writer,
+            )?;
+            write_line!(
+                indent,
+                writer,
+                "}} while ({});\n",
+                condition(&parser.first(inner), token_type)
+            );
+        }
+        Element::Literal { .. } | Element::Set { .. } | Element::NegatedSet { .. } => {
+            unreachable!("nonterminals only reference rules")
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,267 @@
+use std::io::Write;
+
+use crate::{
+    codegen::{escape_string, tables},
+    error::Result,
+    lexer::Lexer,
+    rules::MatchMode,
+};
+
+macro_rules! write_line {
+    ($indent:expr,$writer:expr,$($arg:tt)*) => {
+        for _ in 0..$indent {
+            write!($writer, "    ")?;
+        }
+        write!($writer, $($arg)*)?;
+    };
+}
+
+#[derive(Default)]
+pub struct JsConfig {
+    pub typescript: bool,
+    pub bulk_errors: bool,
+    pub normalize_newlines: bool,
+}
+
+pub fn gen_lexer<W: Write>(lexer: &Lexer, config: &JsConfig, writer: &mut W) -> Result<()> {
+    let tables = tables(lexer);
+    let ts = |annotation: &'static str| if config.typescript { annotation } else { "" };
+
+    let tokens: Vec<&str> = tables.tokens.iter().map(|token| token.as_str()).collect();
+    gen_enum("Token", &tokens, config, writer)?;
+    if !lexer.get_equivalences().is_empty() {
+        gen_token_class(lexer, config, writer)?;
+    }
+    if lexer.has_displays() {
+        write!(
+            writer,
+            "\nexport function tokenDisplay(token{}){} {{\n",
+            ts(": Token"),
+            ts(": string")
+        )?;
+        write_line!(1, writer, "switch (token) {{\n");
+        for token in &tables.tokens {
+            write_line!(2, writer, "case Token.{}:\n", token);
+            write_line!(
+                3,
+                writer,
+                "return \"{}\";\n",
+                escape_string(&lexer.display(token))
+            );
+        }
+        write_line!(1, writer, "}}\n");
+        writeln!(writer, "}}")?;
+    }
+
+    write!(writer, "\nconst TRAP = {};\n", tables.trap)?;
+    write!(writer, "\nconst ALPHABET{} = [\n", ts(": number[]"))?;
+    for (_, last) in &tables.alphabet {
+        write_line!(1, writer, "{},\n", last);
+    }
+    write!(
+        writer,
+        "];\n\nconst TRANSITIONS{} = [\n",
+        ts(": number[][]")
+    )?;
+    for row in &tables.transitions {
+        let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+        write_line!(1, writer, "[{}],\n", row.join(", "));
+    }
+    write!(
+        writer,
+        "];\n\nconst ACCEPTS{} = [\n",
+        ts(": (Token | null)[]")
+    )?;
+    for accept in &tables.accepts {
+        match accept {
+            Some(token) => {
+                write_line!(1, writer, "Token.{},\n", tables.tokens[*token]);
+            }
+            None => {
+                write_line!(1, writer, "null,\n");
+            }
+        }
+    }
+    let skipped: Vec<String> = tables
+        .tokens
+        .iter()
+        .zip(&tables.skipped)
+        .filter(|(_, skipped)| **skipped)
+        .map(|(token, _)| format!("Token.{}", token))
+        .collect();
+    write!(
+        writer,
+        "];\n\nconst SKIPPED{} = new Set([{}]);\n",
+        ts(": Set<Token>"),
+        skipped.join(", ")
+    )?;
+
+    write!(
+        writer,
+        r#"
+function toAlphabet(ch{}){} {{
+    let low = 0;
+    let high = ALPHABET.length - 1;
+    while (low < high) {{
+        const mid = (low + high) >> 1;
+        if (ALPHABET[mid] < ch) {{
+            low = mid + 1;
+        }} else {{
+            high = mid;
+        }}
+    }}
+    return low;
+}}
+
+export class Lexer {{
+"#,
+        ts(": number"),
+        ts(": number")
+    )?;
+    if config.typescript {
+        write_line!(1, writer, "private readonly text: string;\n");
+        write_line!(1, writer, "private pos: number;\n\n");
+    }
+    write!(
+        writer,
+        r#"    constructor(text{}) {{
+        this.text = text;
+        this.pos = 0;
+    }}
+
+    {}longest(pos{}){} {{
+        let state = 0;
+        let found{} = null;
+        while (pos < this.text.length) {{
+            const ch = this.text.codePointAt(pos){};
+            state = TRANSITIONS[state][toAlphabet(ch)];
+            if (state === TRAP) {{
+                break;
+            }}
+            pos += ch > 0xffff ? 2 : 1;
+            const token = ACCEPTS[state];
+            if (token !== null) {{
+                found = [token, pos];
+"#,
+        ts(": string"),
+        ts("private "),
+        ts(": number"),
+        ts(": [Token, number] | null"),
+        ts(": [Token, number] | null"),
+        ts("!"),
+    )?;
+    if lexer.get_match_mode() == MatchMode::Shortest {
+        write_line!(4, writer, "break;\n");
+    }
+    write!(
+        writer,
+        r#"            }}
+        }}
+        return found;
+    }}
+
+    next(){} {{
+        while (true) {{
+            if (this.pos >= this.text.length) {{
+                return [Token._EOF, ""];
+            }}
+            const found = this.longest(this.pos);
+            if (found === null) {{
+                const start = this.pos;
+                this.pos += this.text.codePointAt(this.pos){} > 0xffff ? 2 : 1;
+"#,
+        ts(": [Token, string]"),
+        ts("!"),
+    )?;
+    if config.bulk_errors {
+        write_line!(
+            4,
+            writer,
+            "while (this.pos < this.text.length && this.longest(this.pos) === null) {{\n"
+        );
+        write_line!(
+            5,
+            writer,
+            "this.pos += this.text.codePointAt(this.pos){} > 0xffff ? 2 : 1;\n",
+            ts("!")
+        );
+        write_line!(4, writer, "}}\n");
+    }
+    write!(
+        writer,
+        r#"                return [Token._ERR, this.text.slice(start, this.pos)];
+            }}
+            const [token, end] = found;
+            const text = this.text.slice(this.pos, end);
+            this.pos = end;
+            if (SKIPPED.has(token)) {{
+                continue;
+            }}
+"#
+    )?;
+    if config.normalize_newlines {
+        write_line!(
+            3,
+            writer,
+            "return [token, text.replace(/\\r\\n/g, \"\\n\")];\n"
+        );
+    } else {
+        write_line!(3, writer, "return [token, text];\n");
+    }
+    write!(writer, "        }}\n    }}\n}}\n")?;
+    Ok(())
+}
+
+fn gen_enum<W: Write>(
+    name: &str,
+    variants: &[&str],
+    config: &JsConfig,
+    writer: &mut W,
+) -> Result<()> {
+    if config.typescript {
+        writeln!(writer, "export enum {} {{", name)?;
+        for variant in variants {
+            write_line!(1, writer, "{} = \"{}\",\n", variant, variant);
+        }
+        writeln!(writer, "}}")?;
+    } else {
+        writeln!(writer, "export const {} = Object.freeze({{", name)?;
+        for variant in variants {
+            write_line!(1, writer, "{}: \"{}\",\n", variant, variant);
+        }
+        writeln!(writer, "}});")?;
+    }
+    Ok(())
+}
+
+fn gen_token_class<W: Write>(lexer: &Lexer, config: &JsConfig, writer: &mut W) -> Result<()> {
+    let mut classes = vec!["_NONE"];
+    classes.extend(
+        lexer
+            .get_equivalences()
+            .iter()
+            .map(|(name, _)| name.as_str()),
+    );
+    writeln!(writer)?;
+    gen_enum("TokenClass", &classes, config, writer)?;
+    if config.typescript {
+        write!(
+            writer,
+            "\nexport function tokenClass(token: Token): TokenClass {{\n"
+        )?;
+    } else {
+        write!(writer, "\nexport function tokenClass(token) {{\n")?;
+    }
+    write_line!(1, writer, "switch (token) {{\n");
+    for (name, members) in lexer.get_equivalences() {
+        for member in members {
+            write_line!(2, writer, "case Token.{}:\n", member);
+        }
+        write_line!(3, writer, "return TokenClass.{};\n", name);
+    }
+    write_line!(2, writer, "default:\n");
+    write_line!(3, writer, "return TokenClass._NONE;\n");
+    write_line!(1, writer, "}}\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use std::io::Write;
+
+use crate::{codegen::escape_string, error::Result, lexer::Lexer};
+
+pub fn gen_json<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+    let alphabet: Vec<String> = lexer
+        .get_alphabet()
+        .iter()
+        .map(|(first, last)| format!("\n    [{}, {}]", first, last))
+        .collect();
+    write!(
+        writer,
+        "{{\n  \"alphabet\": [{}\n  ],\n",
+        alphabet.join(",")
+    )?;
+    let states = lexer.get_states();
+    let names: Vec<String> = states
+        .iter()
+        .map(|state| match state {
+            Some(name) => format!("\n    \"{}\"", escape_string(name)),
+            None => "\n    null".to_string(),
+        })
+        .collect();
+    write!(writer, "  \"states\": [{}\n  ],\n", names.join(","))?;
+    let connections: Vec<String> = (0..states.len())
+        .flat_map(|start| {
+            lexer
+                .get_connections(start)
+                .into_iter()
+                .map(move |(first, last, end)| {
+                    format!(
+                        "\n    {{\"start\": {}, \"first\": {}, \"last\": {}, \"end\": {}}}",
+                        start, first, last, end
+                    )
+                })
+        })
+        .collect();
+    write!(
+        writer,
+        "  \"connections\": [{}\n  ]\n}}\n",
+        connections.join(",")
+    )?;
+    Ok(())
+}
@@ -1,2 +1,350 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::Write,
+};
+
+use smol_str::SmolStr;
+
+use crate::{lexer::Lexer, rules::MatchMode};
+
+pub mod c;
 pub mod cpp;
-pub mod java;
\ No newline at end of file
+pub mod dot;
+pub mod first_follow;
+pub mod go;
+pub mod java;
+pub mod java_parser;
+pub mod js;
+pub mod json;
+pub mod python;
+pub mod rules_json;
+pub mod rust;
+pub mod rust_ast;
+
+#[derive(Clone)]
+pub struct Names {
+    pub namespace: Option<String>,
+    pub lexer: String,
+    pub token: String,
+}
+
+impl Default for Names {
+    fn default() -> Names {
+        Names {
+            namespace: None,
+            lexer: "Lexer".to_string(),
+            token: "Token".to_string(),
+        }
+    }
+}
+
+pub struct KeywordTable {
+    pub basis: u32,
+    pub slots: Vec<Option<(SmolStr, SmolStr)>>,
+}
+
+pub fn keyword_hash(basis: u32, text: &str) -> u32 {
+    let mut hash = basis;
+    for byte in text.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+// Keywords whose literal an earlier keyword already takes can never be
+// matched, so only the first of them gets a slot; two equal literals would
+// always collide.
+pub fn keyword_table(keywords: &[(&SmolStr, &SmolStr)]) -> KeywordTable {
+    let mut keywords = keywords.to_vec();
+    let mut seen = HashSet::new();
+    keywords.retain(|(_, lit)| seen.insert(*lit));
+    let mut size = keywords.len().next_power_of_two();
+    loop {
+        for seed in 0..1024 {
+            let basis = 2166136261 ^ seed;
+            let mut slots = vec![None; size];
+            let mut collision = false;
+            for (name, lit) in &keywords {
+                let slot = &mut slots[keyword_hash(basis, lit) as usize & (size - 1)];
+                if slot.is_some() {
+                    collision = true;
+                    break;
+                }
+                *slot = Some(((*name).clone(), (*lit).clone()));
+            }
+            if !collision {
+                return KeywordTable { basis, slots };
+            }
+        }
+        size *= 2;
+    }
+}
+
+pub fn folded_keywords(lexer: &Lexer) -> Vec<(&SmolStr, &SmolStr)> {
+    lexer
+        .get_keywords()
+        .into_iter()
+        .filter(|(name, _)| {
+            lexer
+                .get_folded()
+                .iter()
+                .any(|(keyword, _)| keyword == *name)
+        })
+        .collect()
+}
+
+// The rule a state accepts for, and its line in the grammar when it's known.
+pub fn rule_origin(lexer: &Lexer, token: &SmolStr) -> String {
+    match lexer.get_rule(token).and_then(|rule| rule.line) {
+        Some(line) => format!("{} (line {})", token, line),
+        None => token.to_string(),
+    }
+}
+
+pub fn escape_string(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\000"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// How the cpp and java backends lay out the code they generate: the text of
+// one indentation level and the line ending.
+#[derive(Clone)]
+pub struct Layout {
+    pub indent: String,
+    pub newline: &'static str,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout {
+            indent: String::from("    "),
+            newline: "\n",
+        }
+    }
+}
+
+// The writer the emitters generate code through. write_line! indents with
+// the layout's unit, and every '\n' is written as the layout's line ending.
+pub struct CodeWriter<'a, W: Write> {
+    writer: &'a mut W,
+    layout: &'a Layout,
+}
+
+impl<'a, W: Write> CodeWriter<'a, W> {
+    pub fn new(writer: &'a mut W, layout: &'a Layout) -> Self {
+        CodeWriter { writer, layout }
+    }
+
+    pub fn unit(&self) -> String {
+        self.layout.indent.clone()
+    }
+
+    pub fn indent(&mut self, levels: usize) -> std::io::Result<()> {
+        for _ in 0..levels {
+            self.writer.write_all(self.layout.indent.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for CodeWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.layout.newline == "\n" {
+            return self.writer.write(buf);
+        }
+        let mut lines = buf.split(|&b| b == b'\n');
+        if let Some(first) = lines.next() {
+            self.writer.write_all(first)?;
+        }
+        for line in lines {
+            self.writer.write_all(self.layout.newline.as_bytes())?;
+            self.writer.write_all(line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub fn alphabet_ids(lexer: &Lexer) -> HashMap<(u32, u32), usize> {
+    lexer
+        .get_alphabet()
+        .iter()
+        .enumerate()
+        .map(|(i, range)| (*range, i))
+        .collect()
+}
+
+// The committed states of a longest-match lexer, which record their token
+// when they are entered instead of when they are left, and the ones among
+// them that can't go on, where entering them ends the token.
+pub struct Committed {
+    pub states: BTreeSet<usize>,
+    pub finals: BTreeSet<usize>,
+}
+
+pub fn committed_states(lexer: &Lexer) -> Committed {
+    if lexer.get_match_mode() == MatchMode::Shortest {
+        return Committed {
+            states: BTreeSet::new(),
+            finals: BTreeSet::new(),
+        };
+    }
+    let trap = lexer.get_trap();
+    let states = lexer.get_committed_states();
+    let finals = states
+        .iter()
+        .copied()
+        .filter(|&i| lexer.accept(i).is_some())
+        .filter(|&i| lexer.get_connections(i).iter().all(|c| c.2 == trap))
+        .collect();
+    Committed { states, finals }
+}
+
+pub struct Tables {
+    pub alphabet: Vec<(u32, u32)>,
+    pub transitions: Vec<Vec<usize>>,
+    pub accepts: Vec<Option<usize>>,
+    pub tokens: Vec<SmolStr>,
+    pub skipped: Vec<bool>,
+    pub trap: usize,
+}
+
+pub fn tables(lexer: &Lexer) -> Tables {
+    let mut alphabet = lexer.get_alphabet().clone();
+    let states = lexer.get_states();
+    let trap = lexer.get_trap();
+    let mut tokens: Vec<SmolStr> = vec![lexer.get_eof().clone(), SmolStr::new("_ERR")];
+    for state in states.iter().flatten() {
+        let token = lexer.reported_token(state);
+        if token != "_TRAP" && !tokens.contains(token) {
+            tokens.push(token.clone());
+        }
+    }
+    for (keyword, _) in lexer.get_folded() {
+        tokens.push(keyword.clone());
+    }
+    tokens[2..].sort();
+    let mut transitions = lexer.dfa_table();
+    // Lookups don't check the upper end of a compact alphabet, so one more
+    // class catches everything above it and only leads to the trap.
+    if let Some(&(_, last)) = alphabet.last().filter(|_| !lexer.is_full_alphabet()) {
+        alphabet.push((last + 1, char::MAX as u32));
+        for row in &mut transitions {
+            row.push(trap);
+        }
+    }
+    let accepts = states
+        .iter()
+        .enumerate()
+        .map(|(i, state)| {
+            let token = lexer.reported_token(state.filter(|_| i != trap)?);
+            tokens.iter().position(|t| t == token)
+        })
+        .collect();
+    let skipped_tokens = lexer.get_skipped();
+    let skipped = tokens
+        .iter()
+        .map(|token| skipped_tokens.contains(&token))
+        .collect();
+    Tables {
+        alphabet,
+        transitions,
+        accepts,
+        tokens,
+        skipped,
+        trap,
+    }
+}
+
+pub fn tables_contract(lexer: &Lexer) -> Vec<&'static str> {
+    let mut contract = vec![
+        "Lexer tables generated by parge. To find the next token:",
+        "",
+        "1. Decode the input into Unicode code points.",
+        "2. Map each code point to its character class, the index of the",
+        "   ALPHABET range [first, last] containing it. The ranges are sorted",
+        "   and cover every code point.",
+        "3. Start in INITIAL_STATE and follow TRANSITIONS[state][class] for",
+        "   each code point until the state is TRAP_STATE or the input ends.",
+        "4. ACCEPTS[state] is the index into TOKEN_NAMES of the token accepted",
+        "   after entering that state, or -1 if the state doesn't accept.",
+    ];
+    match lexer.get_match_mode() {
+        MatchMode::Longest => contract.extend([
+            "5. Remember the last accepting state and its end position. When",
+            "   walking stops, emit that token and resume after its end.",
+        ]),
+        MatchMode::Shortest => contract.extend([
+            "5. Stop at the first accepting state, emit its token and resume",
+            "   after the code point that entered it.",
+        ]),
+    }
+    contract.push("6. If no state accepted, the next code point is an error (_ERR).");
+    if lexer.get_eof() == "_EOF" {
+        contract.push("   At the end of the input, emit _EOF.");
+    } else {
+        contract.push("   At the end of the input, emit TOKEN_NAMES[0].");
+    }
+    contract.push("7. Tokens marked in TOKEN_SKIPPED are matched but not emitted.");
+    contract
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyword_table_has_no_collisions() {
+        let words = [
+            "auto", "break", "case", "char", "const", "continue", "default", "do", "double",
+            "else", "enum", "extern", "float", "for", "goto", "if", "int", "long", "return",
+        ];
+        let names: Vec<SmolStr> = words
+            .iter()
+            .map(|w| SmolStr::new(w.to_uppercase()))
+            .collect();
+        let lits: Vec<SmolStr> = words.iter().map(SmolStr::new).collect();
+        let keywords: Vec<(&SmolStr, &SmolStr)> = names.iter().zip(&lits).collect();
+        let table = keyword_table(&keywords);
+        assert!(table.slots.len().is_power_of_two());
+        assert_eq!(table.slots.iter().flatten().count(), words.len());
+        let lookup = |text: &str| {
+            let slot = keyword_hash(table.basis, text) as usize & (table.slots.len() - 1);
+            match &table.slots[slot] {
+                Some((name, lit)) if lit == text => Some(name.clone()),
+                _ => None,
+            }
+        };
+        for (name, lit) in &keywords {
+            assert_eq!(lookup(lit).as_ref(), Some(*name));
+        }
+        for text in ["", "iff", "retur", "Auto", "x"] {
+            assert_eq!(lookup(text), None);
+        }
+    }
+
+    #[test]
+    fn keyword_table_keeps_the_first_of_equal_literals() {
+        let (a, b, c) = (SmolStr::new("A"), SmolStr::new("B"), SmolStr::new("C"));
+        let (lit, other) = (SmolStr::new("if"), SmolStr::new("else"));
+        let table = keyword_table(&[(&a, &lit), (&b, &lit), (&c, &other)]);
+        let taken: Vec<&SmolStr> = table.slots.iter().flatten().map(|(name, _)| name).collect();
+        assert_eq!(taken.len(), 2);
+        assert!(taken.contains(&&a) && taken.contains(&&c));
+    }
+}
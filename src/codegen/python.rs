@@ -0,0 +1,173 @@
+use std::io::Write;
+
+use crate::{
+    codegen::{escape_string, tables},
+    error::Result,
+    lexer::Lexer,
+    rules::MatchMode,
+};
+
+macro_rules! write_line {
+    ($indent:expr,$writer:expr,$($arg:tt)*) => {
+        for _ in 0..$indent {
+            write!($writer, "    ")?;
+        }
+        write!($writer, $($arg)*)?;
+    };
+}
+
+#[derive(Default)]
+pub struct PythonConfig {
+    pub bulk_errors: bool,
+    pub normalize_newlines: bool,
+}
+
+pub fn gen_lexer<W: Write>(lexer: &Lexer, config: &PythonConfig, writer: &mut W) -> Result<()> {
+    let tables = tables(lexer);
+
+    write!(writer, "import bisect\nimport enum\n\n\n")?;
+    writeln!(writer, "class Token(enum.Enum):")?;
+    for (i, token) in tables.tokens.iter().enumerate() {
+        write_line!(1, writer, "{} = {}\n", token, i);
+    }
+    if !lexer.get_equivalences().is_empty() {
+        writeln!(writer)?;
+        write_line!(1, writer, "def token_class(self):\n");
+        write_line!(2, writer, "return _CLASSES.get(self, TokenClass._NONE)\n");
+    }
+    if lexer.has_displays() {
+        writeln!(writer)?;
+        write_line!(1, writer, "def display(self):\n");
+        write_line!(2, writer, "return _DISPLAYS[self]\n");
+    }
+    if !lexer.get_equivalences().is_empty() {
+        gen_token_class(lexer, writer)?;
+    }
+    if lexer.has_displays() {
+        write!(writer, "\n\n_DISPLAYS = {{\n")?;
+        for token in &tables.tokens {
+            write_line!(
+                1,
+                writer,
+                "Token.{}: \"{}\",\n",
+                token,
+                escape_string(&lexer.display(token))
+            );
+        }
+        writeln!(writer, "}}")?;
+    }
+
+    write!(writer, "\n\n_TRAP = {}\n", tables.trap)?;
+    write!(writer, "\n_ALPHABET = [\n")?;
+    for (_, last) in &tables.alphabet {
+        write_line!(1, writer, "{},\n", last);
+    }
+    write!(writer, "]\n\n_TRANSITIONS = [\n")?;
+    for row in &tables.transitions {
+        let row: Vec<String> = row.iter().map(|s| s.to_string()).collect();
+        write_line!(1, writer, "[{}],\n", row.join(", "));
+    }
+    write!(writer, "]\n\n_ACCEPTS = [\n")?;
+    for accept in &tables.accepts {
+        match accept {
+            Some(token) => {
+                write_line!(1, writer, "Token.{},\n", tables.tokens[*token]);
+            }
+            None => {
+                write_line!(1, writer, "None,\n");
+            }
+        }
+    }
+    writeln!(writer, "]")?;
+    let skipped: Vec<String> = tables
+        .tokens
+        .iter()
+        .zip(&tables.skipped)
+        .filter(|(_, skipped)| **skipped)
+        .map(|(token, _)| format!("Token.{}", token))
+        .collect();
+    write!(writer, "\n_SKIPPED = {{{}}}\n", skipped.join(", "))?;
+
+    write!(
+        writer,
+        r#"
+
+class Lexer:
+    def __init__(self, text):
+        self.text = text
+        self.pos = 0
+
+    def _longest(self, pos):
+        state = 0
+        found = None
+        while pos < len(self.text):
+            state = _TRANSITIONS[state][bisect.bisect_left(_ALPHABET, ord(self.text[pos]))]
+            if state == _TRAP:
+                break
+            pos += 1
+            if _ACCEPTS[state] is not None:
+                found = (_ACCEPTS[state], pos)
+"#
+    )?;
+    if lexer.get_match_mode() == MatchMode::Shortest {
+        write_line!(4, writer, "break\n");
+    }
+    write!(
+        writer,
+        r#"        return found
+
+    def next(self):
+        while True:
+            if self.pos >= len(self.text):
+                return Token._EOF, ""
+            found = self._longest(self.pos)
+            if found is None:
+                start = self.pos
+                self.pos += 1
+"#
+    )?;
+    if config.bulk_errors {
+        write_line!(
+            4,
+            writer,
+            "while self.pos < len(self.text) and self._longest(self.pos) is None:\n"
+        );
+        write_line!(5, writer, "self.pos += 1\n");
+    }
+    write!(
+        writer,
+        r#"                return Token._ERR, self.text[start:self.pos]
+            token, end = found
+            text = self.text[self.pos:end]
+            self.pos = end
+            if token in _SKIPPED:
+                continue
+"#
+    )?;
+    if config.normalize_newlines {
+        write_line!(
+            3,
+            writer,
+            "return token, text.replace(\"\\r\\n\", \"\\n\")\n"
+        );
+    } else {
+        write_line!(3, writer, "return token, text\n");
+    }
+    Ok(())
+}
+
+fn gen_token_class<W: Write>(lexer: &Lexer, writer: &mut W) -> Result<()> {
+    write!(writer, "\n\nclass TokenClass(enum.Enum):\n")?;
+    write_line!(1, writer, "_NONE = 0\n");
+    for (i, (name, _)) in lexer.get_equivalences().iter().enumerate() {
+        write_line!(1, writer, "{} = {}\n", name, i + 1);
+    }
+    write!(writer, "\n\n_CLASSES = {{\n")?;
+    for (name, members) in lexer.get_equivalences() {
+        for member in members {
+            write_line!(1, writer, "Token.{}: TokenClass.{},\n", member, name);
+        }
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
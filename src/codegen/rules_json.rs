@@ -0,0 +1,345 @@
+use std::io::Write;
+
+use smol_str::SmolStr;
+
+use crate::{
+    error::{bail, PargeError, Result},
+    json::{self, Json},
+    rules::{Element, Grammar, ModeAction, Rule},
+};
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_optional(text: Option<&SmolStr>) -> String {
+    text.map_or_else(|| "null".to_string(), |text| json_string(text))
+}
+
+fn json_strings<'a, I: IntoIterator<Item = &'a SmolStr>>(items: I) -> String {
+    let items: Vec<String> = items.into_iter().map(|item| json_string(item)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn json_attributes(attributes: &[(SmolStr, Option<SmolStr>)]) -> String {
+    let attributes: Vec<String> = attributes
+        .iter()
+        .map(|(name, value)| format!("[{}, {}]", json_string(name), json_optional(value.as_ref())))
+        .collect();
+    format!("[{}]", attributes.join(", "))
+}
+
+fn json_set(chars: &[char], ranges: &[(char, char)], classes: &[SmolStr]) -> String {
+    let chars: Vec<String> = chars.iter().map(|c| json_string(&c.to_string())).collect();
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(first, last)| {
+            format!(
+                "[{}, {}]",
+                json_string(&first.to_string()),
+                json_string(&last.to_string())
+            )
+        })
+        .collect();
+    format!(
+        "\"chars\": [{}], \"ranges\": [{}], \"classes\": {}",
+        chars.join(", "),
+        ranges.join(", "),
+        json_strings(classes)
+    )
+}
+
+fn json_element(element: &Element, indent: usize) -> String {
+    let pad = "  ".repeat(indent + 1);
+    let inner = |kind: &str, inner: &Element| {
+        format!(
+            "{{\"kind\": \"{}\", \"inner\": {}}}",
+            kind,
+            json_element(inner, indent)
+        )
+    };
+    let subelems = |kind: &str, subelems: &[Element]| {
+        let subelems: Vec<String> = subelems
+            .iter()
+            .map(|subelem| format!("\n{}{}", pad, json_element(subelem, indent + 1)))
+            .collect();
+        format!(
+            "{{\"kind\": \"{}\", \"subelems\": [{}\n{}]}}",
+            kind,
+            subelems.join(","),
+            "  ".repeat(indent)
+        )
+    };
+    match element {
+        Element::Rule { var, name } => format!(
+            "{{\"kind\": \"Rule\", \"var\": {}, \"name\": {}}}",
+            json_optional(var.as_ref()),
+            json_string(name)
+        ),
+        Element::Set {
+            chars,
+            ranges,
+            classes,
+        } => format!(
+            "{{\"kind\": \"Set\", {}}}",
+            json_set(chars, ranges, classes)
+        ),
+        Element::NegatedSet {
+            chars,
+            ranges,
+            classes,
+        } => format!(
+            "{{\"kind\": \"NegatedSet\", {}}}",
+            json_set(chars, ranges, classes)
+        ),
+        Element::Literal { lit } => {
+            format!("{{\"kind\": \"Literal\", \"lit\": {}}}", json_string(lit))
+        }
+        Element::OneOrMore { inner: element } => inner("OneOrMore", element),
+        Element::ZeroOrMore { inner: element } => inner("ZeroOrMore", element),
+        Element::Optional { inner: element } => inner("Optional", element),
+        Element::Alternatives { subelems: elements } => subelems("Alternatives", elements),
+        Element::Group { subelems: elements } => subelems("Group", elements),
+    }
+}
+
+pub fn gen_rules_json<W: Write>(grammar: &Grammar, writer: &mut W) -> Result<()> {
+    let rules: Vec<String> = grammar
+        .rules
+        .iter()
+        .map(|rule| {
+            let action = match &rule.action {
+                Some(ModeAction::Push(mode)) => format!("{{\"push\": {}}}", json_string(mode)),
+                Some(ModeAction::Pop) => "\"pop\"".to_string(),
+                None => "null".to_string(),
+            };
+            let fields = [
+                ("name", json_string(&rule.name)),
+                ("is_terminal", rule.is_terminal.to_string()),
+                ("fragment", rule.fragment.to_string()),
+                ("skip", rule.skip.to_string()),
+                ("priority", rule.priority.to_string()),
+                ("export", rule.export.to_string()),
+                ("case_sensitive", rule.case_sensitive.to_string()),
+                ("internal", json_optional(rule.internal.as_ref())),
+                (
+                    "constructor_name",
+                    json_optional(rule.constructor_name.as_ref()),
+                ),
+                (
+                    "constructor_vars",
+                    rule.constructor_vars
+                        .as_ref()
+                        .map_or_else(|| "null".to_string(), json_strings),
+                ),
+                ("value_type", json_optional(rule.value_type.as_ref())),
+                ("display", json_optional(rule.display.as_ref())),
+                ("mode", json_optional(rule.mode.as_ref())),
+                ("action", action),
+                ("line_start", rule.line_start.to_string()),
+                ("element", json_element(&rule.element, 2)),
+                (
+                    "lookahead",
+                    rule.lookahead
+                        .as_ref()
+                        .map_or_else(|| "null".to_string(), |e| json_element(e, 2)),
+                ),
+                ("attributes", json_attributes(&rule.attributes)),
+            ];
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(name, value)| format!("\n    \"{}\": {}", name, value))
+                .collect();
+            format!("\n  {{{}\n  }}", fields.join(","))
+        })
+        .collect();
+    write!(writer, "[{}\n]\n", rules.join(","))?;
+    Ok(())
+}
+
+fn read_char(json: &Json) -> Result<char> {
+    let text = json.as_str()?;
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => bail!(Grammar, "Expected a single character, found {:?}", text),
+    }
+}
+
+fn read_strings(json: &Json) -> Result<Vec<SmolStr>> {
+    json.as_array()?
+        .iter()
+        .map(|item| item.as_str().cloned())
+        .collect()
+}
+
+fn read_set(json: &Json, negated: bool) -> Result<Element> {
+    let chars = json
+        .get("chars")?
+        .as_array()?
+        .iter()
+        .map(read_char)
+        .collect::<Result<_>>()?;
+    let ranges = json
+        .get("ranges")?
+        .as_array()?
+        .iter()
+        .map(|range| match range.as_array()? {
+            [first, last] => Ok((read_char(first)?, read_char(last)?)),
+            _ => bail!(Grammar, "Expected a range of two characters"),
+        })
+        .collect::<Result<_>>()?;
+    let classes = read_strings(json.get("classes")?)?;
+    Ok(if negated {
+        Element::NegatedSet {
+            chars,
+            ranges,
+            classes,
+        }
+    } else {
+        Element::Set {
+            chars,
+            ranges,
+            classes,
+        }
+    })
+}
+
+fn read_element(json: &Json) -> Result<Element> {
+    let inner = || Ok::<_, PargeError>(Box::new(read_element(json.get("inner")?)?));
+    let subelems = || {
+        json.get("subelems")?
+            .as_array()?
+            .iter()
+            .map(read_element)
+            .collect::<Result<Vec<_>>>()
+    };
+    Ok(match json.get("kind")?.as_str()?.as_str() {
+        "Rule" => Element::Rule {
+            var: json.get("var")?.as_optional_str()?.cloned(),
+            name: json.get("name")?.as_str()?.clone(),
+        },
+        "Set" => read_set(json, false)?,
+        "NegatedSet" => read_set(json, true)?,
+        "Literal" => Element::Literal {
+            lit: json.get("lit")?.as_str()?.clone(),
+        },
+        "OneOrMore" => Element::OneOrMore { inner: inner()? },
+        "ZeroOrMore" => Element::ZeroOrMore { inner: inner()? },
+        "Optional" => Element::Optional { inner: inner()? },
+        "Alternatives" => Element::Alternatives {
+            subelems: subelems()?,
+        },
+        "Group" => Element::Group {
+            subelems: subelems()?,
+        },
+        kind => bail!(Grammar, "Unknown element kind '{}'", kind),
+    })
+}
+
+// Reads back what gen_rules_json wrote. Line numbers aren't part of the
+// JSON, so the rules come back without them.
+pub fn read_rules_json(src: &str) -> Result<Vec<Rule>> {
+    let optional =
+        |json: &Json, key: &str| Ok::<_, PargeError>(json.get(key)?.as_optional_str()?.cloned());
+    json::parse(src)?
+        .as_array()?
+        .iter()
+        .map(|rule| {
+            let action = match rule.get("action")? {
+                Json::Null => None,
+                Json::String(action) if action == "pop" => Some(ModeAction::Pop),
+                push => Some(ModeAction::Push(push.get("push")?.as_str()?.clone())),
+            };
+            let attributes = rule
+                .get("attributes")?
+                .as_array()?
+                .iter()
+                .map(|attribute| match attribute.as_array()? {
+                    [name, value] => {
+                        Ok((name.as_str()?.clone(), value.as_optional_str()?.cloned()))
+                    }
+                    _ => bail!(Grammar, "Expected an attribute name and value"),
+                })
+                .collect::<Result<_>>()?;
+            Ok(Rule {
+                is_terminal: rule.get("is_terminal")?.as_bool()?,
+                fragment: rule.get("fragment")?.as_bool()?,
+                skip: rule.get("skip")?.as_bool()?,
+                priority: rule.get("priority")?.as_u32()?,
+                export: rule.get("export")?.as_bool()?,
+                case_sensitive: rule.get("case_sensitive")?.as_bool()?,
+                internal: optional(rule, "internal")?,
+                name: rule.get("name")?.as_str()?.clone(),
+                element: read_element(rule.get("element")?)?,
+                constructor_name: optional(rule, "constructor_name")?,
+                constructor_vars: match rule.get("constructor_vars")? {
+                    Json::Null => None,
+                    vars => Some(read_strings(vars)?),
+                },
+                value_type: optional(rule, "value_type")?,
+                display: optional(rule, "display")?,
+                mode: optional(rule, "mode")?,
+                action,
+                line_start: rule.get("line_start")?.as_bool()?,
+                lookahead: match rule.get("lookahead")? {
+                    Json::Null => None,
+                    lookahead => Some(read_element(lookahead)?),
+                },
+                attributes,
+                line: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules;
+
+    #[test]
+    fn rules_json_round_trip() {
+        let src = r#"class DIGIT = [0-9];
+fragment token HEX = [0-9a-fA-F];
+priority(2) token IF = "if";
+@inline @doc("a name")
+token NAME = ([a-z_])+ ![0-9];
+token NUM = ([\{DIGIT}])+ -> long;
+token ESC = "\\" ("n" | "\"" | "\t") ([HEX])?;
+token NOT = [^a-z\n];
+token LPAREN = "(" display "'('";
+skip token WS = ([ ])+;
+push(STR) token OPEN = "\"";
+mode STR {
+    token TEXT = ([^"])+;
+    pop token CLOSE = "\"";
+}
+nonterm S = name:NAME (LPAREN num:NUM)* -> Call(name, num);
+"#;
+        let grammar = rules::parse_str(src).unwrap();
+        let mut json = Vec::new();
+        gen_rules_json(&grammar, &mut json).unwrap();
+        let read = read_rules_json(&String::from_utf8(json).unwrap()).unwrap();
+        let expected: Vec<Rule> = grammar
+            .rules
+            .iter()
+            .cloned()
+            .map(|rule| Rule { line: None, ..rule })
+            .collect();
+        assert_eq!(read, expected);
+    }
+}
@@ -0,0 +1,624 @@
+use std::{collections::BTreeMap, io::Write};
+
+use smol_str::SmolStr;
+
+use crate::{
+    codegen::alphabet_ids,
+    error::{ensure, Result},
+    lexer::Lexer,
+    rules::MatchMode,
+};
+
+macro_rules! write_line {
+    ($indent:expr,$writer:expr,$($arg:tt)*) => {
+        for _ in 0..$indent {
+            write!($writer, "    ")?;
+        }
+        write!($writer, $($arg)*)?;
+    };
+}
+
+#[derive(Default)]
+pub struct RustConfig {
+    pub bulk_errors: bool,
+    pub normalize_newlines: bool,
+    pub serde: bool,
+    pub display_impl: bool,
+    pub lex_trait: bool,
+    pub reverse: bool,
+}
+
+fn token_derives(config: &RustConfig) -> &'static str {
+    if config.serde {
+        "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]"
+    } else {
+        "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]"
+    }
+}
+
+pub fn gen_lexer<W: Write>(lexer: &Lexer, config: &RustConfig, writer: &mut W) -> Result<()> {
+    gen_module(lexer, config, false, writer)
+}
+
+// Several lexers in one file, each in a module of its own. They were built
+// over one alphabet, so the table is only written once.
+pub fn gen_combined<W: Write>(
+    lexers: &[(SmolStr, Lexer)],
+    config: &RustConfig,
+    writer: &mut W,
+) -> Result<()> {
+    let Some((_, first)) = lexers.first() else {
+        return Ok(());
+    };
+    writeln!(
+        writer,
+        "static ALPHABET: [(u32, u32); {}] = [",
+        first.get_alphabet().len()
+    )?;
+    gen_alphabet(first, 1, writer)?;
+    writeln!(writer, "];")?;
+    for (name, lexer) in lexers {
+        ensure!(
+            lexer.get_alphabet() == first.get_alphabet(),
+            Grammar,
+            "The lexer '{}' doesn't share the alphabet of the others",
+            name
+        );
+        let mut module = Vec::new();
+        gen_module(lexer, config, true, &mut module)?;
+        // Generated lexers have no multi-line literals, so indenting the
+        // module line by line can't change what it means.
+        write!(writer, "\npub mod {} {{\n", name)?;
+        for line in String::from_utf8(module).unwrap().split_inclusive('\n') {
+            if line.trim().is_empty() {
+                write!(writer, "{}", line)?;
+            } else {
+                write_line!(1, writer, "{}", line);
+            }
+        }
+        writeln!(writer, "}}")?;
+    }
+    Ok(())
+}
+
+fn gen_alphabet<W: Write>(lexer: &Lexer, indent: usize, writer: &mut W) -> Result<()> {
+    for (r0, r1) in lexer.get_alphabet() {
+        write_line!(indent, writer, "({}, {}),\n", r0, r1);
+    }
+    Ok(())
+}
+
+fn gen_module<W: Write>(
+    lexer: &Lexer,
+    config: &RustConfig,
+    shared: bool,
+    writer: &mut W,
+) -> Result<()> {
+    let mut tokens: Vec<SmolStr> = vec![SmolStr::new("_EOF"), SmolStr::new("_ERR")];
+    for state in lexer.get_states().into_iter().flatten() {
+        if state != "_TRAP" && !lexer.is_internal(state) && !tokens.contains(state) {
+            tokens.push(state.clone());
+        }
+    }
+    tokens[2..].sort();
+    let trap = lexer.get_trap();
+    // With --reverse, back holds the tokens known to lie between an earlier
+    // boundary and pos, so prev() can step through them without rescanning.
+    let (back_field, back_init) = if config.reverse {
+        ("\n    back: Vec<(usize, Token)>,", ", back: Vec::new()")
+    } else {
+        ("", "")
+    };
+
+    write!(
+        writer,
+        "{}\n#[allow(non_camel_case_types)]\npub enum Token {{\n",
+        token_derives(config)
+    )?;
+    for token in &tokens {
+        write_line!(1, writer, "{},\n", token);
+    }
+    writeln!(writer, "}}")?;
+    gen_token_name(&tokens, writer)?;
+    if !lexer.get_equivalences().is_empty() {
+        gen_token_class(lexer, config, writer)?;
+    }
+    if lexer.has_displays() {
+        gen_token_display(lexer, &tokens, writer)?;
+    }
+    if config.display_impl {
+        let text = if lexer.has_displays() {
+            "display"
+        } else {
+            "name"
+        };
+        write!(writer, "\nimpl std::fmt::Display for Token {{\n")?;
+        write_line!(
+            1,
+            writer,
+            "fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {{\n"
+        );
+        write_line!(2, writer, "f.write_str(self.{}())\n", text);
+        write_line!(1, writer, "}}\n");
+        writeln!(writer, "}}")?;
+    }
+
+    // Everything else is an associated item of Lexer, so the file can be
+    // include!d without adding more names to the including module.
+    write!(
+        writer,
+        r#"
+pub struct Lexer<'a> {{
+    input: &'a str,
+    pos: usize,{}
+}}
+
+impl<'a> Lexer<'a> {{
+    const TRAP: usize = {};
+"#,
+        back_field, trap
+    )?;
+    if shared {
+        write!(
+            writer,
+            r#"
+    fn class(ch: char) -> usize {{
+        super::ALPHABET.partition_point(|&(_, last)| last < ch as u32)
+    }}
+"#
+        )?;
+    } else {
+        write!(
+            writer,
+            "\n    const ALPHABET: [(u32, u32); {}] = [\n",
+            lexer.get_alphabet().len()
+        )?;
+        gen_alphabet(lexer, 2, writer)?;
+        write!(
+            writer,
+            r#"    ];
+
+    fn class(ch: char) -> usize {{
+        Self::ALPHABET.partition_point(|&(_, last)| last < ch as u32)
+    }}
+"#
+        )?;
+    }
+    write!(
+        writer,
+        r#"
+    fn step(state: usize, ch: char) -> usize {{
+        let class = Self::class(ch);
+        match state {{
+"#
+    )?;
+    let states = lexer.get_states();
+    let alphabet_ids = alphabet_ids(lexer);
+    for i in 0..states.len() {
+        if i == trap {
+            continue;
+        }
+        let mut results: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (r0, r1, result) in lexer.get_connections(i) {
+            if result != trap {
+                results
+                    .entry(result)
+                    .or_default()
+                    .push(alphabet_ids[&(r0, r1)]);
+            }
+        }
+        if results.is_empty() {
+            continue;
+        }
+        write_line!(3, writer, "{} => match class {{\n", i);
+        for (result, classes) in results {
+            let classes: Vec<String> = classes.iter().map(|c| c.to_string()).collect();
+            write_line!(4, writer, "{} => {},\n", classes.join(" | "), result);
+        }
+        write_line!(4, writer, "_ => Self::TRAP,\n");
+        write_line!(3, writer, "}},\n");
+    }
+    write!(
+        writer,
+        r#"            _ => Self::TRAP,
+        }}
+    }}
+
+    fn accept(state: usize) -> Option<Token> {{
+        match state {{
+"#
+    )?;
+    for (i, state) in states.iter().enumerate() {
+        if let Some(token) = state.filter(|_| i != trap) {
+            write_line!(
+                3,
+                writer,
+                "{} => Some(Token::{}),\n",
+                i,
+                lexer.reported_token(token)
+            );
+        }
+    }
+    write!(
+        writer,
+        r#"            _ => None,
+        }}
+    }}
+
+    fn longest(input: &str) -> Option<(Token, usize)> {{
+        let mut state = 0;
+        let mut found = None;
+        for (i, ch) in input.char_indices() {{
+            state = Self::step(state, ch);
+            if state == Self::TRAP {{
+                break;
+            }}
+            if let Some(token) = Self::accept(state) {{
+                found = Some((token, i + ch.len_utf8()));
+"#
+    )?;
+    if lexer.get_match_mode() == MatchMode::Shortest {
+        write_line!(4, writer, "break;\n");
+    }
+    write!(
+        writer,
+        r#"            }}
+        }}
+        found
+    }}
+
+    pub fn new(input: &'a str) -> Self {{
+        Lexer {{ input, pos: 0{} }}
+    }}
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> (Token, String) {{
+        loop {{
+            let rest = &self.input[self.pos..];
+            if rest.is_empty() {{
+                return (Token::_EOF, String::new());
+            }}
+            let Some((token, len)) = Self::longest(rest) else {{
+"#,
+        back_init
+    )?;
+    if config.bulk_errors {
+        write_line!(
+            4,
+            writer,
+            "let mut len = rest.chars().next().unwrap().len_utf8();\n"
+        );
+        write_line!(
+            4,
+            writer,
+            "while len < rest.len() && Self::longest(&rest[len..]).is_none() {{\n"
+        );
+        write_line!(
+            5,
+            writer,
+            "len += rest[len..].chars().next().unwrap().len_utf8();\n"
+        );
+        write_line!(4, writer, "}}\n");
+    } else {
+        write_line!(
+            4,
+            writer,
+            "let len = rest.chars().next().unwrap().len_utf8();\n"
+        );
+    }
+    if config.reverse {
+        write_line!(4, writer, "self.remember(Token::_ERR);\n");
+    }
+    write!(
+        writer,
+        r#"                self.pos += len;
+                return (Token::_ERR, rest[..len].to_string());
+            }};
+"#
+    )?;
+    if config.reverse {
+        write_line!(3, writer, "self.remember(token);\n");
+    }
+    write_line!(3, writer, "self.pos += len;\n");
+    let skipped = lexer.get_skipped();
+    if !skipped.is_empty() {
+        let pattern: Vec<String> = skipped
+            .iter()
+            .map(|token| format!("Token::{}", token))
+            .collect();
+        write_line!(
+            3,
+            writer,
+            "if matches!(token, {}) {{\n",
+            pattern.join(" | ")
+        );
+        write_line!(4, writer, "continue;\n");
+        write_line!(3, writer, "}}\n");
+    }
+    if config.normalize_newlines {
+        write_line!(
+            3,
+            writer,
+            "return (token, rest[..len].replace(\"\\r\\n\", \"\\n\"));\n"
+        );
+    } else {
+        write_line!(3, writer, "return (token, rest[..len].to_string());\n");
+    }
+    write!(writer, "        }}\n    }}\n")?;
+    if config.reverse {
+        gen_reverse(lexer, config, writer)?;
+    }
+    write!(
+        writer,
+        r#"}}
+
+// Yields the tokens up to, but not including, _EOF.
+impl<'a> Iterator for Lexer<'a> {{
+    type Item = (Token, String);
+
+    fn next(&mut self) -> Option<Self::Item> {{
+        match Lexer::next(self) {{
+            (Token::_EOF, _) => None,
+            token => Some(token),
+        }}
+    }}
+}}
+"#
+    )?;
+    if config.lex_trait {
+        write!(
+            writer,
+            r#"
+impl<'a> parge::runtime::Lex for Lexer<'a> {{
+    fn next(&mut self) -> Option<(u32, String)> {{
+        match Lexer::next(self) {{
+            (Token::_EOF, _) => None,
+            (token, text) => Some((token as u32, text)),
+        }}
+    }}
+}}
+"#
+        )?;
+    }
+    Ok(())
+}
+
+// prev() reads the token ending at the current position. The reverse DFA
+// finds every start a token could have there, and the forward DFA keeps those
+// it would have read up to exactly that position. When more than one is left,
+// only scanning from the beginning of the input can tell which one it was.
+// That scan keeps every token it passes, so walking back through the whole
+// input scans it at most once.
+fn gen_reverse<W: Write>(lexer: &Lexer, config: &RustConfig, writer: &mut W) -> Result<()> {
+    let (transitions, accepts, trap) = lexer.reverse_table();
+    write!(
+        writer,
+        r#"
+    const REVERSE_TRAP: usize = {};
+
+    fn step_back(state: usize, ch: char) -> usize {{
+        let class = Self::class(ch);
+        match state {{
+"#,
+        trap
+    )?;
+    for (i, row) in transitions.iter().enumerate() {
+        if i == trap {
+            continue;
+        }
+        let mut results: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (class, &result) in row.iter().enumerate() {
+            if result != trap {
+                results.entry(result).or_default().push(class);
+            }
+        }
+        if results.is_empty() {
+            continue;
+        }
+        write_line!(3, writer, "{} => match class {{\n", i);
+        for (result, classes) in results {
+            let classes: Vec<String> = classes.iter().map(|c| c.to_string()).collect();
+            write_line!(4, writer, "{} => {},\n", classes.join(" | "), result);
+        }
+        write_line!(4, writer, "_ => Self::REVERSE_TRAP,\n");
+        write_line!(3, writer, "}},\n");
+    }
+    let accepting: Vec<String> = (0..accepts.len())
+        .filter(|&i| accepts[i])
+        .map(|i| i.to_string())
+        .collect();
+    let accepting = if accepting.is_empty() {
+        String::from("false")
+    } else {
+        format!("matches!(state, {})", accepting.join(" | "))
+    };
+    write!(
+        writer,
+        r#"            _ => Self::REVERSE_TRAP,
+        }}
+    }}
+
+    fn accept_back(state: usize) -> bool {{
+        {}
+    }}
+
+    fn scan(input: &str, pos: usize) -> (Token, usize) {{
+        match Self::longest(&input[pos..]) {{
+            Some((token, len)) => (token, pos + len),
+            None => (Token::_ERR, pos + input[pos..].chars().next().unwrap().len_utf8()),
+        }}
+    }}
+
+    // Extends back by the token starting at pos, unless nothing is known
+    // below pos anyway.
+    fn remember(&mut self, token: Token) {{
+        if !self.back.is_empty() {{
+            self.back.push((self.pos, token));
+        }}
+    }}
+
+    fn token_before(&mut self, end: usize) -> (Token, usize) {{
+        if let Some((start, token)) = self.back.pop() {{
+            return (token, start);
+        }}
+        let before = &self.input[..end];
+        let mut starts = Vec::new();
+        let mut state = 0;
+        for (i, ch) in before.char_indices().rev() {{
+            state = Self::step_back(state, ch);
+            if state == Self::REVERSE_TRAP {{
+                break;
+            }}
+            if Self::accept_back(state) {{
+                starts.push(i);
+            }}
+        }}
+        let (last, _) = before.char_indices().next_back().unwrap();
+        if Self::longest(&self.input[last..]).is_none() {{
+            starts.push(last);
+        }}
+        let mut found = Vec::new();
+        for start in starts {{
+            let (token, next) = Self::scan(self.input, start);
+            if next == end {{
+                found.push((token, start));
+                if found.len() > 1 {{
+                    break;
+                }}
+            }}
+        }}
+        if let [found] = found[..] {{
+            return found;
+        }}
+        // The tokens scanned on the way to end are kept in back, so reading
+        // further backwards doesn't scan them again.
+        let mut start = 0;
+        loop {{
+            let (token, next) = Self::scan(self.input, start);
+            if next >= end {{
+                return (token, start);
+            }}
+            self.back.push((start, token));
+            start = next;
+        }}
+    }}
+
+    // Starts at pos, which should be a token boundary, to read in either
+    // direction from there.
+    pub fn at(input: &'a str, pos: usize) -> Self {{
+        Lexer {{ input, pos, back: Vec::new() }}
+    }}
+
+    pub fn pos(&self) -> usize {{
+        self.pos
+    }}
+
+    // Returns the token ending at the current position and moves to its
+    // start, or _EOF at the beginning of the input.
+    pub fn prev(&mut self) -> (Token, String) {{
+        loop {{
+            let end = self.pos;
+            if end == 0 {{
+                return (Token::_EOF, String::new());
+            }}
+            let (token, start) = self.token_before(end);
+            self.pos = start;
+"#,
+        accepting
+    )?;
+    let skipped = lexer.get_skipped();
+    if !skipped.is_empty() {
+        let pattern: Vec<String> = skipped
+            .iter()
+            .map(|token| format!("Token::{}", token))
+            .collect();
+        write_line!(
+            3,
+            writer,
+            "if matches!(token, {}) {{\n",
+            pattern.join(" | ")
+        );
+        write_line!(4, writer, "continue;\n");
+        write_line!(3, writer, "}}\n");
+    }
+    if config.normalize_newlines {
+        write_line!(
+            3,
+            writer,
+            "return (token, self.input[start..end].replace(\"\\r\\n\", \"\\n\"));\n"
+        );
+    } else {
+        write_line!(
+            3,
+            writer,
+            "return (token, self.input[start..end].to_string());\n"
+        );
+    }
+    write!(writer, "        }}\n    }}\n")?;
+    Ok(())
+}
+
+fn gen_token_class<W: Write>(lexer: &Lexer, config: &RustConfig, writer: &mut W) -> Result<()> {
+    write!(
+        writer,
+        "\n{}\n#[allow(non_camel_case_types)]\npub enum TokenClass {{\n",
+        token_derives(config)
+    )?;
+    write_line!(1, writer, "_NONE,\n");
+    for (name, _) in lexer.get_equivalences() {
+        write_line!(1, writer, "{},\n", name);
+    }
+    write!(writer, "}}\n\nimpl Token {{\n")?;
+    write_line!(1, writer, "pub fn class(self) -> TokenClass {{\n");
+    write_line!(2, writer, "match self {{\n");
+    for (name, members) in lexer.get_equivalences() {
+        let pattern: Vec<String> = members
+            .iter()
+            .map(|member| format!("Token::{}", member))
+            .collect();
+        write_line!(
+            3,
+            writer,
+            "{} => TokenClass::{},\n",
+            pattern.join(" | "),
+            name
+        );
+    }
+    write_line!(3, writer, "_ => TokenClass::_NONE,\n");
+    write_line!(2, writer, "}}\n");
+    write_line!(1, writer, "}}\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_token_name<W: Write>(tokens: &[SmolStr], writer: &mut W) -> Result<()> {
+    write!(writer, "\nimpl Token {{\n")?;
+    write_line!(1, writer, "pub fn name(self) -> &'static str {{\n");
+    write_line!(2, writer, "match self {{\n");
+    for token in tokens {
+        write_line!(3, writer, "Token::{} => \"{}\",\n", token, token);
+    }
+    write_line!(2, writer, "}}\n");
+    write_line!(1, writer, "}}\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn gen_token_display<W: Write>(lexer: &Lexer, tokens: &[SmolStr], writer: &mut W) -> Result<()> {
+    write!(writer, "\nimpl Token {{\n")?;
+    write_line!(1, writer, "pub fn display(self) -> &'static str {{\n");
+    write_line!(2, writer, "match self {{\n");
+    for token in tokens {
+        write_line!(
+            3,
+            writer,
+            "Token::{} => {:?},\n",
+            token,
+            lexer.display(token).as_str()
+        );
+    }
+    write_line!(2, writer, "}}\n");
+    write_line!(1, writer, "}}\n");
+    writeln!(writer, "}}")?;
+    Ok(())
+}
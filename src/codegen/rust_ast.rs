@@ -0,0 +1,78 @@
+use std::io::Write;
+
+use crate::{
+    error::Result,
+    parser::{FieldKind, FieldType, Parser},
+};
+
+macro_rules! write_line {
+    ($indent:expr,$writer:expr,$($arg:tt)*) => {
+        for _ in 0..$indent {
+            write!($writer, "    ")?;
+        }
+        write!($writer, $($arg)*)?;
+    };
+}
+
+fn rust_type(field: &FieldType) -> String {
+    let kind = match &field.kind {
+        FieldKind::Token => "String",
+        FieldKind::Node(name) => name.as_str(),
+        FieldKind::AnyNode => "Node",
+        FieldKind::Any => "Value",
+    };
+    // Single fields stay unset when their binding isn't matched, and nodes need a box to nest.
+    match (&field.kind, field.list) {
+        (_, true) => format!("Vec<{}>", kind),
+        (FieldKind::Token, false) => format!("Option<{}>", kind),
+        (_, false) => format!("Option<Box<{}>>", kind),
+    }
+}
+
+pub fn gen_ast<W: Write>(parser: &Parser, writer: &mut W) -> Result<()> {
+    write!(
+        writer,
+        "#[derive(Debug, Clone, PartialEq)]\npub enum Node {{\n"
+    )?;
+    for node in parser.get_nodes() {
+        write_line!(1, writer, "{}({}),\n", node.name, node.name);
+    }
+    writeln!(writer, "}}")?;
+    let has_any = parser
+        .get_nodes()
+        .iter()
+        .flat_map(|node| &node.fields)
+        .any(|(_, field)| field.kind == FieldKind::Any);
+    if has_any {
+        write!(
+            writer,
+            r#"
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {{
+    Token(String),
+    Node(Node),
+}}
+"#
+        )?;
+    }
+    for node in parser.get_nodes() {
+        write!(
+            writer,
+            "\n#[derive(Debug, Clone, PartialEq)]\npub struct {} {{",
+            node.name
+        )?;
+        if !node.fields.is_empty() {
+            writeln!(writer)?;
+        }
+        for (name, field) in &node.fields {
+            write_line!(1, writer, "pub {}: {},\n", name, rust_type(field));
+        }
+        writeln!(writer, "}}")?;
+        write!(writer, "\nimpl From<{}> for Node {{\n", node.name)?;
+        write_line!(1, writer, "fn from(node: {}) -> Node {{\n", node.name);
+        write_line!(2, writer, "Node::{}(node)\n", node.name);
+        write_line!(1, writer, "}}\n");
+        writeln!(writer, "}}")?;
+    }
+    Ok(())
+}
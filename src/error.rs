@@ -0,0 +1,102 @@
+use std::{fmt, io, path::PathBuf};
+
+use smol_str::SmolStr;
+
+pub type Result<T, E = PargeError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum PargeError {
+    Io(io::Error),
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        message: String,
+        location: String,
+    },
+    DuplicateName(SmolStr),
+    UnresolvedReference(Vec<(SmolStr, SmolStr)>),
+    AcceptConflict {
+        tokens: Vec<SmolStr>,
+        example: String,
+    },
+    EmptyMatch(SmolStr),
+    Grammar(String),
+    Parser(String),
+    Dfa(String),
+    NoMatch {
+        offset: usize,
+        input: String,
+    },
+    Lex(String),
+}
+
+impl fmt::Display for PargeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PargeError::Io(e) => write!(f, "{}", e),
+            PargeError::Read { path, .. } => {
+                write!(f, "Can't read rules file '{}'", path.display())
+            }
+            PargeError::Parse { message, location } => write!(f, "{}\n{}", message, location),
+            PargeError::DuplicateName(name) => write!(f, "Rule name '{}' isn't unique", name),
+            PargeError::UnresolvedReference(references) => {
+                let references: Vec<String> = references
+                    .iter()
+                    .map(|(name, rule)| format!("'{}' in rule '{}'", name, rule))
+                    .collect();
+                write!(f, "Unresolved references: {}", references.join(", "))
+            }
+            PargeError::AcceptConflict { tokens, example } => write!(
+                f,
+                "Tokens {:?} are asserted to be exclusive, but all of them match {:?}",
+                tokens, example
+            ),
+            PargeError::EmptyMatch(name) => {
+                write!(f, "Token '{}' matches the empty string", name)
+            }
+            PargeError::Grammar(message)
+            | PargeError::Parser(message)
+            | PargeError::Dfa(message)
+            | PargeError::Lex(message) => write!(f, "{}", message),
+            PargeError::NoMatch { offset, input } => write!(
+                f,
+                "No token matches the input at offset {}: {:?}",
+                offset, input
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PargeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PargeError::Io(e) => e.source(),
+            PargeError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PargeError {
+    fn from(e: io::Error) -> Self {
+        PargeError::Io(e)
+    }
+}
+
+macro_rules! bail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err($crate::error::PargeError::$kind(format!($($arg)+)))
+    };
+}
+
+macro_rules! ensure {
+    ($cond:expr, $kind:ident, $($arg:tt)+) => {
+        if !$cond {
+            $crate::error::bail!($kind, $($arg)+);
+        }
+    };
+}
+
+pub(crate) use {bail, ensure};
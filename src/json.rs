@@ -0,0 +1,156 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while_m_n},
+    character::complete::{digit1, multispace0, none_of, one_of},
+    combinator::{all_consuming, map, map_opt, map_res, value},
+    multi::{many0, separated_list0},
+    sequence::{delimited, preceded, separated_pair, terminated},
+    IResult,
+};
+use smol_str::SmolStr;
+
+use crate::error::{bail, PargeError, Result};
+
+#[derive(Debug, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(SmolStr),
+    Array(Vec<Json>),
+    Object(Vec<(SmolStr, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Result<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value)
+                .ok_or_else(|| PargeError::Dfa(format!("Missing key '{}'", key))),
+            _ => bail!(Dfa, "Expected an object with key '{}'", key),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Json]> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => bail!(Dfa, "Expected an array, found {:?}", self),
+        }
+    }
+
+    pub fn as_u32(&self) -> Result<u32> {
+        match self {
+            Json::Number(n) => u32::try_from(*n).map_err(|e| PargeError::Dfa(e.to_string())),
+            _ => bail!(Dfa, "Expected a number, found {:?}", self),
+        }
+    }
+
+    pub fn as_usize(&self) -> Result<usize> {
+        match self {
+            Json::Number(n) => usize::try_from(*n).map_err(|e| PargeError::Dfa(e.to_string())),
+            _ => bail!(Dfa, "Expected a number, found {:?}", self),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool> {
+        match self {
+            Json::Bool(b) => Ok(*b),
+            _ => bail!(Dfa, "Expected a boolean, found {:?}", self),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&SmolStr> {
+        match self {
+            Json::String(s) => Ok(s),
+            _ => bail!(Dfa, "Expected a string, found {:?}", self),
+        }
+    }
+
+    pub fn as_optional_str(&self) -> Result<Option<&SmolStr>> {
+        match self {
+            Json::Null => Ok(None),
+            Json::String(s) => Ok(Some(s)),
+            _ => bail!(Dfa, "Expected a string or null, found {:?}", self),
+        }
+    }
+}
+
+fn ws<'src, O, F>(inner: F) -> impl FnMut(&'src str) -> IResult<&'src str, O>
+where
+    F: FnMut(&'src str) -> IResult<&'src str, O>,
+{
+    delimited(multispace0, inner, multispace0)
+}
+
+fn parse_escape(src: &str) -> IResult<&str, char> {
+    preceded(
+        tag("\\"),
+        alt((
+            one_of("\"\\/"),
+            value('\u{8}', tag("b")),
+            value('\u{c}', tag("f")),
+            value('\n', tag("n")),
+            value('\r', tag("r")),
+            value('\t', tag("t")),
+            map_opt(
+                map_res(
+                    preceded(
+                        tag("u"),
+                        take_while_m_n(4, 4, |c: char| c.is_ascii_hexdigit()),
+                    ),
+                    |hex| u32::from_str_radix(hex, 16),
+                ),
+                char::from_u32,
+            ),
+        )),
+    )(src)
+}
+
+fn parse_string(src: &str) -> IResult<&str, SmolStr> {
+    map(
+        delimited(
+            tag("\""),
+            many0(alt((parse_escape, none_of("\"\\")))),
+            tag("\""),
+        ),
+        |chars| chars.into_iter().collect::<String>().into(),
+    )(src)
+}
+
+fn parse_value(src: &str) -> IResult<&str, Json> {
+    ws(alt((
+        value(Json::Null, tag("null")),
+        value(Json::Bool(true), tag("true")),
+        value(Json::Bool(false), tag("false")),
+        map(map_res(digit1, str::parse), Json::Number),
+        map(parse_string, Json::String),
+        map(
+            delimited(
+                tag("["),
+                separated_list0(tag(","), parse_value),
+                terminated(multispace0, tag("]")),
+            ),
+            Json::Array,
+        ),
+        map(
+            delimited(
+                tag("{"),
+                separated_list0(
+                    tag(","),
+                    separated_pair(ws(parse_string), tag(":"), parse_value),
+                ),
+                terminated(multispace0, tag("}")),
+            ),
+            Json::Object,
+        ),
+    )))(src)
+}
+
+pub fn parse(src: &str) -> Result<Json> {
+    match all_consuming(parse_value)(src) {
+        Ok((_, json)) => Ok(json),
+        Err(e) => bail!(Dfa, "Invalid JSON: {}", e),
+    }
+}
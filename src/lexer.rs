@@ -1,15 +1,34 @@
-use color_eyre::{eyre::ensure, Result};
 use smol_str::SmolStr;
-use std::collections::{BTreeSet, HashSet};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    path::Path,
+};
 
-use crate::rules::{Element, Rule};
+use crate::{
+    error::{bail, ensure, PargeError, Result},
+    json,
+    rules::{self, case_variants, fold_literal, Element, Grammar, MatchMode, ModeAction, Rule},
+};
 
+#[derive(Clone)]
 pub struct Lexer {
     dfa: DFA,
     alphabet: Vec<(u32, u32)>,
+    terminals: Vec<Rule>,
+    equivalences: Vec<(SmolStr, Vec<SmolStr>)>,
+    match_mode: MatchMode,
+    modes: Vec<SmolStr>,
+    folded: Vec<(SmolStr, SmolStr)>,
+    lookaheads: Vec<(SmolStr, Lexer)>,
+    eof: SmolStr,
+    // Built once from the DFA, so step and accept don't search it.
+    table: Vec<Vec<usize>>,
+    trap: usize,
+    accepts: Vec<Option<SmolStr>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct State {
     accepting: Option<SmolStr>,
 }
@@ -20,31 +39,67 @@ enum EpsilonConnection {
     Connection((u32, u32), usize, usize),
 }
 
-impl EpsilonConnection {
-    fn get_a(&self) -> usize {
-        match self {
-            EpsilonConnection::Connection(_, a, _) => *a,
-            EpsilonConnection::Epsilon(a, _) => *a,
-        }
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Connection {
     range: (u32, u32),
     start: usize,
     end: usize,
 }
 
+// The NFA a lexer's DFA is built from, with one entry per mode and line
+// start.
+pub struct NfaGraph {
+    pub states: Vec<Option<SmolStr>>,
+    pub entries: Vec<usize>,
+    pub epsilon: Vec<(usize, usize)>,
+    pub ranges: Vec<(usize, (u32, u32), usize)>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 struct NFA {
     states: Vec<State>,
-    entry: usize,
+    entries: Vec<usize>,
     connections: Vec<EpsilonConnection>,
 }
 
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
 struct DFA {
     states: Vec<State>,
     connections: Vec<Connection>,
+    starts: Vec<usize>,
+}
+
+fn is_trap(state: &State) -> bool {
+    matches!(&state.accepting, Some(name) if name == "_TRAP")
+}
+
+// The dense transition table indexed by state and alphabet class, the trap
+// state, and the token each state reports.
+fn index_dfa(
+    dfa: &DFA,
+    alphabet: &[(u32, u32)],
+    terminals: &[Rule],
+) -> (Vec<Vec<usize>>, usize, Vec<Option<SmolStr>>) {
+    let trap = dfa.states.iter().position(is_trap).unwrap();
+    let mut table = vec![vec![trap; alphabet.len()]; dfa.states.len()];
+    for c in &dfa.connections {
+        let class = alphabet.binary_search(&c.range).unwrap();
+        table[c.start][class] = c.end;
+    }
+    let accepts = dfa
+        .states
+        .iter()
+        .map(|state| {
+            let token = state.accepting.as_ref().filter(|_| !is_trap(state))?;
+            let rule = terminals.iter().find(|rule| &rule.name == token);
+            Some(
+                rule.and_then(|rule| rule.internal.clone())
+                    .unwrap_or_else(|| token.clone()),
+            )
+        })
+        .collect();
+    (table, trap, accepts)
 }
 
 impl DFA {
@@ -52,6 +107,7 @@ impl DFA {
         DFA {
             states: Vec::new(),
             connections: Vec::new(),
+            starts: vec![0],
         }
     }
 
@@ -68,6 +124,157 @@ impl DFA {
     fn connect_range(&mut self, start: usize, end: usize, range: (u32, u32)) {
         self.connections.push(Connection { range, start, end })
     }
+
+    // Hopcroft's algorithm: states start out partitioned by the token they
+    // accept, and partitions are split until all members of a partition
+    // transition into the same partitions.
+    fn minimize(&self, alphabet: &[(u32, u32)]) -> DFA {
+        let mut delta = vec![vec![0; alphabet.len()]; self.states.len()];
+        let mut inverse = vec![vec![Vec::new(); self.states.len()]; alphabet.len()];
+        for c in &self.connections {
+            let class = alphabet.binary_search(&c.range).unwrap();
+            delta[c.start][class] = c.end;
+            inverse[class][c.end].push(c.start);
+        }
+
+        let mut initial: BTreeMap<Option<&SmolStr>, Vec<usize>> = BTreeMap::new();
+        for (i, state) in self.states.iter().enumerate() {
+            initial.entry(state.accepting.as_ref()).or_default().push(i);
+        }
+        let mut blocks: Vec<Vec<usize>> = initial.into_values().collect();
+        let mut block_of = vec![0; self.states.len()];
+        for (block, members) in blocks.iter().enumerate() {
+            for &state in members {
+                block_of[state] = block;
+            }
+        }
+        let mut pending: Vec<usize> = (0..blocks.len()).collect();
+        while let Some(splitter) = pending.pop() {
+            let members = blocks[splitter].clone();
+            for predecessors in &inverse {
+                let mut touched: BTreeMap<usize, HashSet<usize>> = BTreeMap::new();
+                for &target in &members {
+                    for &state in &predecessors[target] {
+                        touched.entry(block_of[state]).or_default().insert(state);
+                    }
+                }
+                for (block, inside) in touched {
+                    if inside.len() == blocks[block].len() {
+                        continue;
+                    }
+                    let (inside, outside): (Vec<usize>, Vec<usize>) =
+                        blocks[block].iter().partition(|s| inside.contains(s));
+                    // The smaller half moves to a new block and becomes a
+                    // splitter; if the old block was still pending, its
+                    // remaining half stays pending as well.
+                    let (stay, moved) = if inside.len() < outside.len() {
+                        (outside, inside)
+                    } else {
+                        (inside, outside)
+                    };
+                    let new = blocks.len();
+                    for &state in &moved {
+                        block_of[state] = new;
+                    }
+                    blocks[block] = stay;
+                    blocks.push(moved);
+                    pending.push(new);
+                }
+            }
+        }
+
+        // Keep the start state at index 0 and the rest in order of their
+        // first original state.
+        let mut order: Vec<usize> = (0..blocks.len()).collect();
+        order.sort_by_key(|&block| (block != block_of[0], blocks[block].iter().min().copied()));
+        let mut renumbered = vec![0; blocks.len()];
+        for (i, &block) in order.iter().enumerate() {
+            renumbered[block] = i;
+        }
+        let mut dfa = DFA::new();
+        for &block in &order {
+            let representative = blocks[block][0];
+            dfa.add(State {
+                accepting: self.states[representative].accepting.clone(),
+            });
+        }
+        for (i, &block) in order.iter().enumerate() {
+            let representative = blocks[block][0];
+            for (class, range) in alphabet.iter().enumerate() {
+                dfa.connect_range(
+                    i,
+                    renumbered[block_of[delta[representative][class]]],
+                    *range,
+                );
+            }
+        }
+        dfa.starts = self
+            .starts
+            .iter()
+            .map(|&start| renumbered[block_of[start]])
+            .collect();
+        dfa
+    }
+
+    fn reachable(&self) -> BTreeSet<usize> {
+        let mut reachable: BTreeSet<usize> = self.starts.iter().copied().collect();
+        let mut pending = self.starts.clone();
+        while let Some(state) = pending.pop() {
+            for c in self.connections.iter().filter(|c| c.start == state) {
+                if reachable.insert(c.end) {
+                    pending.push(c.end);
+                }
+            }
+        }
+        reachable
+    }
+
+    // Drops states that can't be reached from a start state and redirects
+    // transitions into states that can't reach an accepting state to the trap.
+    fn prune(&self) -> DFA {
+        let reachable = self.reachable();
+        let mut live: BTreeSet<usize> = (0..self.states.len())
+            .filter(|&i| self.states[i].accepting.is_some() && !is_trap(&self.states[i]))
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for c in &self.connections {
+                if live.contains(&c.end) && live.insert(c.start) {
+                    changed = true;
+                }
+            }
+        }
+        let trap = self.states.iter().position(is_trap).unwrap();
+        let kept: Vec<usize> = (0..self.states.len())
+            .filter(|&i| {
+                let useful = self.starts.contains(&i) || live.contains(&i);
+                (reachable.contains(&i) && useful) || i == trap
+            })
+            .collect();
+        let mut renumbered = HashMap::new();
+        let mut dfa = DFA::new();
+        for &i in &kept {
+            renumbered.insert(
+                i,
+                dfa.add(State {
+                    accepting: self.states[i].accepting.clone(),
+                }),
+            );
+        }
+        for c in &self.connections {
+            let Some(&start) = renumbered.get(&c.start) else {
+                continue;
+            };
+            let end = match renumbered.get(&c.end) {
+                Some(&end) => end,
+                None => renumbered[&trap],
+            };
+            dfa.connect_range(start, end, c.range);
+        }
+        dfa.starts = self.starts.iter().map(|start| renumbered[start]).collect();
+        dfa
+    }
 }
 
 impl NFA {
@@ -77,7 +284,7 @@ impl NFA {
         states.push(State { accepting: None });
         NFA {
             states,
-            entry,
+            entries: vec![entry],
             connections: Vec::new(),
         }
     }
@@ -101,30 +308,58 @@ impl NFA {
         self.connections
             .push(EpsilonConnection::Epsilon(start, end))
     }
+
+    // Each state's edges as alphabet class and target. The NFA's ranges are
+    // all ranges of the alphabet it was built on.
+    fn class_edges(&self, alphabet: &[(u32, u32)]) -> Vec<Vec<(usize, usize)>> {
+        let mut edges = vec![Vec::new(); self.states.len()];
+        for connection in &self.connections {
+            if let &EpsilonConnection::Connection(range, a, b) = connection {
+                let class = alphabet.binary_search(&range).unwrap();
+                edges[a].push((class, b));
+            }
+        }
+        edges
+    }
+
+    fn epsilon_edges(&self) -> Vec<Vec<usize>> {
+        let mut edges = vec![Vec::new(); self.states.len()];
+        for connection in &self.connections {
+            if let &EpsilonConnection::Epsilon(a, b) = connection {
+                edges[a].push(b);
+            }
+        }
+        edges
+    }
 }
 
-fn connect_element(nfa: &mut NFA, alphabet: &Vec<(u32, u32)>, element: &Element) -> (usize, usize) {
-    match element {
+fn connect_element(
+    nfa: &mut NFA,
+    alphabet: &Vec<(u32, u32)>,
+    rule: &SmolStr,
+    element: &Element,
+) -> Result<(usize, usize)> {
+    Ok(match element {
         Element::Group { subelems } => {
-            assert!(subelems.len() > 0);
-            if subelems.len() == 1 {
-                connect_element(nfa, alphabet, &subelems[0])
-            } else {
-                let first = &subelems[0];
-                let last = subelems.last().unwrap();
-                let (entry, mut o) = connect_element(nfa, alphabet, first);
-                for i in 1..subelems.len() - 1 {
-                    let elem = &subelems[i];
-                    let (i, o2) = connect_element(nfa, alphabet, elem);
-                    nfa.connect_epsilon(o, i);
-                    o = o2;
-                }
-                let (i, exit) = connect_element(nfa, alphabet, last);
+            let Some((last, rest)) = subelems.split_last() else {
+                bail!(Grammar, "Token '{}' contains an empty group", rule);
+            };
+            let Some((first, middle)) = rest.split_first() else {
+                return connect_element(nfa, alphabet, rule, last);
+            };
+            let (entry, mut o) = connect_element(nfa, alphabet, rule, first)?;
+            for elem in middle {
+                let (i, o2) = connect_element(nfa, alphabet, rule, elem)?;
                 nfa.connect_epsilon(o, i);
-                (entry, exit)
+                o = o2;
             }
+            let (i, exit) = connect_element(nfa, alphabet, rule, last)?;
+            nfa.connect_epsilon(o, i);
+            (entry, exit)
         }
-        Element::Set { chars, ranges } => {
+        // check_sets rejected ranges written backwards when the grammar was
+        // parsed, so the slices of the alphabet below are in order.
+        Element::Set { chars, ranges, .. } => {
             let entry = nfa.add_empty();
             let exit = nfa.add_empty();
             let mut connections = HashSet::new();
@@ -132,8 +367,8 @@ fn connect_element(nfa: &mut NFA, alphabet: &Vec<(u32, u32)>, element: &Element)
                 connections.insert((*c as u32, *c as u32));
             }
             for range in ranges {
-                let start_index = alphabet.iter().position(|r| r.0 == range.0 as u32).unwrap();
-                let end_index = alphabet.iter().position(|r| r.1 == range.1 as u32).unwrap() + 1;
+                let start_index = alphabet.partition_point(|r| r.0 < range.0 as u32);
+                let end_index = alphabet.partition_point(|r| r.1 < range.1 as u32) + 1;
                 for range in &alphabet[start_index..end_index] {
                     connections.insert((range.0, range.1));
                 }
@@ -147,25 +382,25 @@ fn connect_element(nfa: &mut NFA, alphabet: &Vec<(u32, u32)>, element: &Element)
             let entry = nfa.add_empty();
             let exit = nfa.add_empty();
             for elem in subelems {
-                let (elem_start, elem_end) = connect_element(nfa, alphabet, elem);
+                let (elem_start, elem_end) = connect_element(nfa, alphabet, rule, elem)?;
                 nfa.connect_epsilon(entry, elem_start);
                 nfa.connect_epsilon(elem_end, exit);
             }
             (entry, exit)
         }
-        Element::OneOrMore { inner } => {
-            let (entry, exit) = connect_element(nfa, alphabet, &inner);
-            nfa.connect_epsilon(exit, entry);
-            (entry, exit)
-        }
+        Element::OneOrMore { inner } => connect_repeated(nfa, alphabet, rule, inner)?,
         Element::ZeroOrMore { inner } => {
-            let (entry, exit) = connect_element(nfa, alphabet, &inner);
-            nfa.connect_epsilon(exit, entry);
+            let (entry, exit) = connect_repeated(nfa, alphabet, rule, inner)?;
             nfa.connect_epsilon(entry, exit);
             (entry, exit)
         }
-        Element::Rule { .. } => panic!(),
-        Element::NegatedSet { chars, ranges } => {
+        Element::Rule { name, .. } => bail!(
+            Grammar,
+            "Token '{}' references '{}', which wasn't inlined as a fragment",
+            rule,
+            name
+        ),
+        Element::NegatedSet { chars, ranges, .. } => {
             let entry = nfa.add_empty();
             let exit = nfa.add_empty();
             let mut connections: HashSet<(u32, u32)> =
@@ -174,8 +409,8 @@ fn connect_element(nfa: &mut NFA, alphabet: &Vec<(u32, u32)>, element: &Element)
                 connections.remove(&(*c as u32, *c as u32));
             }
             for range in ranges {
-                let start_index = alphabet.iter().position(|r| r.0 == range.0 as u32).unwrap();
-                let end_index = alphabet.iter().position(|r| r.1 == range.1 as u32).unwrap() + 1;
+                let start_index = alphabet.partition_point(|r| r.0 < range.0 as u32);
+                let end_index = alphabet.partition_point(|r| r.1 < range.1 as u32) + 1;
                 for range in &alphabet[start_index..end_index] {
                     connections.remove(&(range.0, range.1));
                 }
@@ -188,8 +423,9 @@ fn connect_element(nfa: &mut NFA, alphabet: &Vec<(u32, u32)>, element: &Element)
         Element::Literal { lit } => {
             let start = nfa.add_empty();
             let mut chars = lit.chars();
-
-            let first = chars.next().unwrap();
+            let Some(first) = chars.next() else {
+                bail!(Grammar, "Token '{}' contains an empty literal", rule);
+            };
             let mut prev = start;
             let mut end = nfa.add_empty();
             nfa.connect_range(prev, end, (first as u32, first as u32));
@@ -203,16 +439,267 @@ fn connect_element(nfa: &mut NFA, alphabet: &Vec<(u32, u32)>, element: &Element)
             (start, end)
         }
         Element::Optional { inner } => {
-            let (entry, exit) = connect_element(nfa, alphabet, &inner);
+            let (entry, exit) = connect_element(nfa, alphabet, rule, inner)?;
             nfa.connect_epsilon(entry, exit);
             (entry, exit)
         }
+    })
+}
+
+// Every fragment's entry has no edges leading into it and its exit has none
+// leading out, so concatenation can't loop back into a neighbour. The repeat
+// edge would break that, so it's kept inside fresh entry and exit states.
+fn connect_repeated(
+    nfa: &mut NFA,
+    alphabet: &Vec<(u32, u32)>,
+    rule: &SmolStr,
+    inner: &Element,
+) -> Result<(usize, usize)> {
+    let entry = nfa.add_empty();
+    let exit = nfa.add_empty();
+    let (inner_entry, inner_exit) = connect_element(nfa, alphabet, rule, inner)?;
+    nfa.connect_epsilon(entry, inner_entry);
+    nfa.connect_epsilon(inner_exit, inner_entry);
+    nfa.connect_epsilon(inner_exit, exit);
+    Ok((entry, exit))
+}
+
+fn fold_set(chars: &[char], ranges: &[(char, char)]) -> (Vec<char>, Vec<(char, char)>) {
+    let mut folded = BTreeSet::new();
+    for c in chars {
+        folded.extend(case_variants(*c));
+    }
+    for (start, end) in ranges {
+        for c in *start..=*end {
+            folded.extend(
+                case_variants(c)
+                    .into_iter()
+                    .filter(|v| v < start || v > end),
+            );
+        }
+    }
+    (folded.into_iter().collect(), ranges.to_vec())
+}
+
+fn fold_case(element: &Element) -> Element {
+    match element {
+        Element::Literal { lit } => fold_literal(lit),
+        Element::Set {
+            chars,
+            ranges,
+            classes,
+        } => {
+            let (chars, ranges) = fold_set(chars, ranges);
+            Element::Set {
+                chars,
+                ranges,
+                classes: classes.clone(),
+            }
+        }
+        Element::NegatedSet {
+            chars,
+            ranges,
+            classes,
+        } => {
+            let (chars, ranges) = fold_set(chars, ranges);
+            Element::NegatedSet {
+                chars,
+                ranges,
+                classes: classes.clone(),
+            }
+        }
+        Element::OneOrMore { inner } => Element::OneOrMore {
+            inner: Box::new(fold_case(inner)),
+        },
+        Element::ZeroOrMore { inner } => Element::ZeroOrMore {
+            inner: Box::new(fold_case(inner)),
+        },
+        Element::Optional { inner } => Element::Optional {
+            inner: Box::new(fold_case(inner)),
+        },
+        Element::Alternatives { subelems } => Element::Alternatives {
+            subelems: subelems.iter().map(fold_case).collect(),
+        },
+        Element::Group { subelems } => Element::Group {
+            subelems: subelems.iter().map(fold_case).collect(),
+        },
+        Element::Rule { .. } => element.clone(),
+    }
+}
+
+fn find_raw_element(element: &Element) -> Option<&'static str> {
+    match element {
+        Element::Literal { .. } => Some("literal"),
+        Element::Set { .. } | Element::NegatedSet { .. } => Some("set"),
+        Element::Rule { .. } => None,
+        Element::OneOrMore { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::Optional { inner } => find_raw_element(inner),
+        Element::Alternatives { subelems } | Element::Group { subelems } => {
+            subelems.iter().find_map(find_raw_element)
+        }
+    }
+}
+
+fn find_class<'a>(grammar: &'a Grammar, name: &SmolStr) -> Result<&'a Element> {
+    if let Some((_, set)) = grammar.classes.iter().find(|(n, _)| n == name) {
+        return Ok(set);
+    }
+    let Some(rule) = grammar.rules.iter().find(|r| &r.name == name) else {
+        bail!(Grammar, "Set references unknown class '{}'", name);
+    };
+    match &rule.element {
+        Element::Group { subelems } if subelems.len() == 1 && rule.is_terminal => Ok(&subelems[0]),
+        element => Ok(element),
+    }
+}
+
+fn resolve_set(
+    grammar: &Grammar,
+    chars: &mut Vec<char>,
+    ranges: &mut Vec<(char, char)>,
+    classes: &[SmolStr],
+    visiting: &mut Vec<SmolStr>,
+) -> Result<()> {
+    for name in classes {
+        ensure!(
+            !visiting.contains(name),
+            Grammar,
+            "Class '{}' references itself",
+            name
+        );
+        let Element::Set {
+            chars: class_chars,
+            ranges: class_ranges,
+            classes: class_classes,
+        } = find_class(grammar, name)?
+        else {
+            bail!(
+                Grammar,
+                "'{}' is referenced in a set, but isn't a character class",
+                name
+            );
+        };
+        chars.extend(class_chars);
+        ranges.extend(class_ranges);
+        visiting.push(name.clone());
+        resolve_set(grammar, chars, ranges, class_classes, visiting)?;
+        visiting.pop();
     }
+    Ok(())
+}
+
+fn resolve_classes(grammar: &Grammar, element: &Element) -> Result<Element> {
+    Ok(match element {
+        Element::Set {
+            chars,
+            ranges,
+            classes,
+        } => {
+            let mut chars = chars.clone();
+            let mut ranges = ranges.clone();
+            resolve_set(grammar, &mut chars, &mut ranges, classes, &mut Vec::new())?;
+            Element::Set {
+                chars,
+                ranges,
+                classes: Vec::new(),
+            }
+        }
+        Element::NegatedSet {
+            chars,
+            ranges,
+            classes,
+        } => {
+            let mut chars = chars.clone();
+            let mut ranges = ranges.clone();
+            resolve_set(grammar, &mut chars, &mut ranges, classes, &mut Vec::new())?;
+            Element::NegatedSet {
+                chars,
+                ranges,
+                classes: Vec::new(),
+            }
+        }
+        Element::OneOrMore { inner } => Element::OneOrMore {
+            inner: Box::new(resolve_classes(grammar, inner)?),
+        },
+        Element::ZeroOrMore { inner } => Element::ZeroOrMore {
+            inner: Box::new(resolve_classes(grammar, inner)?),
+        },
+        Element::Optional { inner } => Element::Optional {
+            inner: Box::new(resolve_classes(grammar, inner)?),
+        },
+        Element::Alternatives { subelems } => Element::Alternatives {
+            subelems: subelems
+                .iter()
+                .map(|e| resolve_classes(grammar, e))
+                .collect::<Result<_>>()?,
+        },
+        Element::Group { subelems } => Element::Group {
+            subelems: subelems
+                .iter()
+                .map(|e| resolve_classes(grammar, e))
+                .collect::<Result<_>>()?,
+        },
+        Element::Literal { .. } | Element::Rule { .. } => element.clone(),
+    })
+}
+
+fn inline_fragments(
+    grammar: &Grammar,
+    element: &Element,
+    visiting: &mut Vec<SmolStr>,
+) -> Result<Element> {
+    Ok(match element {
+        Element::Rule { name, .. } => {
+            let Some(referenced) = grammar.rules.iter().find(|r| &r.name == name) else {
+                bail!(Grammar, "'{}' is referenced, but no rule defines it", name);
+            };
+            ensure!(
+                !visiting.contains(name),
+                Grammar,
+                "{} '{}' references itself",
+                if referenced.fragment {
+                    "Fragment"
+                } else {
+                    "Token"
+                },
+                name
+            );
+            visiting.push(name.clone());
+            let inlined = inline_fragments(grammar, &referenced.element, visiting)?;
+            visiting.pop();
+            inlined
+        }
+        Element::OneOrMore { inner } => Element::OneOrMore {
+            inner: Box::new(inline_fragments(grammar, inner, visiting)?),
+        },
+        Element::ZeroOrMore { inner } => Element::ZeroOrMore {
+            inner: Box::new(inline_fragments(grammar, inner, visiting)?),
+        },
+        Element::Optional { inner } => Element::Optional {
+            inner: Box::new(inline_fragments(grammar, inner, visiting)?),
+        },
+        Element::Alternatives { subelems } => Element::Alternatives {
+            subelems: subelems
+                .iter()
+                .map(|e| inline_fragments(grammar, e, visiting))
+                .collect::<Result<_>>()?,
+        },
+        Element::Group { subelems } => Element::Group {
+            subelems: subelems
+                .iter()
+                .map(|e| inline_fragments(grammar, e, visiting))
+                .collect::<Result<_>>()?,
+        },
+        Element::Set { .. } | Element::NegatedSet { .. } | Element::Literal { .. } => {
+            element.clone()
+        }
+    })
 }
 
 fn get_ranges_from_element(element: &Element, raw_ranges: &mut BTreeSet<(char, char)>) {
     match element {
-        Element::Set { chars, ranges } => {
+        Element::Set { chars, ranges, .. } => {
             for c in chars {
                 raw_ranges.insert((*c, *c));
             }
@@ -220,7 +707,7 @@ fn get_ranges_from_element(element: &Element, raw_ranges: &mut BTreeSet<(char, c
                 raw_ranges.insert((r.0, r.1));
             }
         }
-        Element::NegatedSet { chars, ranges } => {
+        Element::NegatedSet { chars, ranges, .. } => {
             for c in chars {
                 raw_ranges.insert((*c, *c));
             }
@@ -246,148 +733,731 @@ fn get_ranges_from_element(element: &Element, raw_ranges: &mut BTreeSet<(char, c
                 get_ranges_from_element(elem, raw_ranges)
             }
         }
-        _ => panic!(),
+        // Fragments are inlined by now, connect_element reports any left.
+        Element::Rule { .. } => {}
+    }
+}
+
+fn has_negated_set(element: &Element) -> bool {
+    match element {
+        Element::NegatedSet { .. } => true,
+        Element::OneOrMore { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::Optional { inner } => has_negated_set(inner),
+        Element::Alternatives { subelems } | Element::Group { subelems } => {
+            subelems.iter().any(has_negated_set)
+        }
+        _ => false,
     }
 }
 
-fn construct_alphabet<'a, I>(rules: I) -> Vec<(u32, u32)>
+// A compact alphabet ends at the highest code point the rules mention, and
+// anything above it leads to the trap. Negated sets only mention what they
+// exclude, so they keep the alphabet full.
+fn construct_alphabet<'a, I>(rules: I, compact: bool) -> Vec<(u32, u32)>
+where
+    I: Iterator<Item = &'a Rule>,
+{
+    let (points, compact) = alphabet_points(rules, compact);
+    alphabet_from_points(points, compact)
+}
+
+// The code points the rules' ranges start and end at, and whether the
+// alphabet may still be compact.
+fn alphabet_points<'a, I>(rules: I, compact: bool) -> (BTreeSet<u32>, bool)
 where
     I: Iterator<Item = &'a Rule>,
 {
     let mut raw_ranges = BTreeSet::new();
+    let mut compact = compact;
     for rule in rules {
         get_ranges_from_element(&rule.element, &mut raw_ranges);
+        compact &= !has_negated_set(&rule.element);
     }
-    let range_points: Vec<u32> = raw_ranges
+    let points = raw_ranges
         .iter()
         .flat_map(|(a, b)| [*a, *b].into_iter().map(|c| c as u32))
-        .collect::<BTreeSet<u32>>()
-        .into_iter()
         .collect();
-    let mut ranges = BTreeSet::new();
+    (points, compact)
+}
+
+fn alphabet_from_points(range_points: BTreeSet<u32>, compact: bool) -> Vec<(u32, u32)> {
+    let mut ranges = BTreeSet::from([(0, 0)]);
     let mut prev = 0u32;
     for point in range_points {
         ranges.insert((prev, prev));
-        if prev + 1 <= point - 1 {
+        if prev + 1 < point {
             ranges.insert((prev + 1, point - 1));
         }
         ranges.insert((point, point));
         prev = point;
     }
-    if prev + 1 <= char::MAX as u32 {
+    if prev < char::MAX as u32 && !compact {
         ranges.insert((prev + 1, char::MAX as u32));
     }
     ranges.into_iter().collect()
 }
 
-fn construct_nfa<'a, I>(rules: I, alphabet: &Vec<(u32, u32)>) -> NFA
+fn construct_nfa<'a, I>(rules: I, alphabet: &Vec<(u32, u32)>, modes: &[SmolStr]) -> Result<NFA>
 where
     I: Iterator<Item = &'a Rule>,
 {
+    let rules: Vec<&Rule> = rules.collect();
     let mut nfa = NFA::new();
+    for _ in modes {
+        let entry = nfa.add_empty();
+        nfa.entries.push(entry);
+    }
+    // Anchored tokens get a second entry per mode, used at the start of a line.
+    let line_starts = if rules.iter().any(|rule| rule.line_start) {
+        (0..=modes.len()).map(|_| nfa.add_empty()).collect()
+    } else {
+        Vec::new()
+    };
+    nfa.entries.extend(&line_starts);
     for rule in rules {
         let exit = nfa.add(State {
             accepting: Some(rule.name.clone()),
         });
-        let (elem_entry, elem_exit) = connect_element(&mut nfa, alphabet, &rule.element);
-        nfa.connect_epsilon(nfa.entry, elem_entry);
+        let (elem_entry, elem_exit) =
+            connect_element(&mut nfa, alphabet, &rule.name, &rule.element)?;
+        let mode = match &rule.mode {
+            None => 0,
+            Some(mode) => {
+                let Some(index) = modes.iter().position(|m| m == mode) else {
+                    bail!(
+                        Grammar,
+                        "Token '{}' belongs to mode '{}', which isn't declared",
+                        rule.name,
+                        mode
+                    );
+                };
+                index + 1
+            }
+        };
+        if !rule.line_start {
+            nfa.connect_epsilon(nfa.entries[mode], elem_entry);
+        }
+        if let Some(&line_start) = line_starts.get(mode) {
+            nfa.connect_epsilon(line_start, elem_entry);
+        }
         nfa.connect_epsilon(elem_exit, exit);
     }
-    nfa
+    Ok(nfa)
 }
 
-fn epsilon_closure(nfa: &NFA, connected: &mut BTreeSet<usize>) {
-    for connection in &nfa.connections {
-        if let EpsilonConnection::Epsilon(a, b) = connection {
-            if connected.contains(a) {
-                if !connected.contains(b) {
-                    connected.insert(*b);
-                    epsilon_closure(nfa, connected);
-                }
+fn epsilon_closure(epsilon: &[Vec<usize>], connected: &mut BTreeSet<usize>) {
+    let mut pending: Vec<usize> = connected.iter().copied().collect();
+    while let Some(state) = pending.pop() {
+        for &next in &epsilon[state] {
+            if connected.insert(next) {
+                pending.push(next);
             }
         }
     }
 }
 
 fn powerset_construction(
-    nfa: &NFA,
+    edges: &[Vec<(usize, usize)>],
+    epsilon: &[Vec<usize>],
     start_closure: usize,
     powersets: &mut Vec<BTreeSet<usize>>,
     connections: &mut Vec<Connection>,
-    alphabet: &Vec<(u32, u32)>,
+    alphabet: &[(u32, u32)],
 ) {
-    for arange in alphabet {
-        let mut transition_closure = BTreeSet::new();
-        for connection in &nfa.connections {
-            if powersets[start_closure].contains(&connection.get_a()) {
-                match connection {
-                    EpsilonConnection::Epsilon(..) => (),
-                    &EpsilonConnection::Connection(range, _, b) => {
-                        if arange == &range {
-                            transition_closure.insert(b);
-                        }
-                    }
+    // Each entry is a powerset and the alphabet index to continue from. A
+    // newly found powerset is explored before the transition into it is
+    // recorded, which numbers and connects states depth first.
+    let mut pending = vec![(start_closure, 0)];
+    let mut ids: HashMap<BTreeSet<usize>, usize> = powersets
+        .iter()
+        .enumerate()
+        .map(|(i, powerset)| (powerset.clone(), i))
+        .collect();
+    // The NFA states each powerset moves to by alphabet class, collected
+    // from its states' edges when it is first explored, and the powerset
+    // each such move set's closure turned out to be.
+    let mut moves: HashMap<usize, HashMap<usize, BTreeSet<usize>>> = HashMap::new();
+    let mut closed: HashMap<BTreeSet<usize>, usize> = HashMap::new();
+    while let Some((start, index)) = pending.pop() {
+        let Some(arange) = alphabet.get(index) else {
+            moves.remove(&start);
+            continue;
+        };
+        let targets = moves.entry(start).or_insert_with(|| {
+            let mut targets: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+            for &state in &powersets[start] {
+                for &(class, b) in &edges[state] {
+                    targets.entry(class).or_default().insert(b);
                 }
             }
-        }
-        epsilon_closure(nfa, &mut transition_closure);
-        let pos = if let Some(pos) = powersets.iter().position(|c| c == &transition_closure) {
-            pos
+            targets
+        });
+        let empty = BTreeSet::new();
+        let target = targets.get(&index).unwrap_or(&empty);
+        let known = closed.get(target).copied().or_else(|| {
+            let mut transition_closure = target.clone();
+            epsilon_closure(epsilon, &mut transition_closure);
+            let pos = *ids.get(&transition_closure)?;
+            closed.insert(target.clone(), pos);
+            Some(pos)
+        });
+        if let Some(pos) = known {
+            connections.push(Connection {
+                range: (arange.0, arange.1),
+                start,
+                end: pos,
+            });
+            pending.push((start, index + 1));
         } else {
+            let mut transition_closure = target.clone();
+            epsilon_closure(epsilon, &mut transition_closure);
             let pos = powersets.len();
+            ids.insert(transition_closure.clone(), pos);
             powersets.push(transition_closure);
-            powerset_construction(nfa, pos, powersets, connections, alphabet);
-            pos
+            pending.push((start, index));
+            pending.push((pos, 0));
+        }
+    }
+}
+
+fn prepare_terminals(grammar: &Grammar) -> Result<Vec<Rule>> {
+    for rule in grammar.rules.iter().filter(|rule| !rule.is_terminal) {
+        if let Some(kind) = find_raw_element(&rule.element) {
+            bail!(
+                Grammar,
+                "Nonterminal '{}' contains a raw {}; define it as a token",
+                rule.name,
+                kind
+            );
+        }
+    }
+    ensure!(
+        grammar
+            .rules
+            .iter()
+            .any(|rule| rule.is_terminal && !rule.fragment),
+        Grammar,
+        "The grammar doesn't define any tokens, so there's nothing to lex"
+    );
+    grammar
+        .rules
+        .iter()
+        .filter(|rule| rule.is_terminal && !rule.fragment)
+        .map(|rule| {
+            let mut rule = rule.clone();
+            rule.element = inline_fragments(grammar, &rule.element, &mut Vec::new())?;
+            rule.element = resolve_classes(grammar, &rule.element)?;
+            if grammar.case_insensitive && !rule.case_sensitive {
+                rule.element = fold_case(&rule.element);
+            }
+            if let Some(lookahead) = &rule.lookahead {
+                let lookahead = inline_fragments(grammar, lookahead, &mut Vec::new())?;
+                let lookahead = resolve_classes(grammar, &lookahead)?;
+                rule.lookahead = Some(if grammar.case_insensitive && !rule.case_sensitive {
+                    fold_case(&lookahead)
+                } else {
+                    lookahead
+                });
+            }
+            Ok(rule)
+        })
+        .collect()
+}
+
+// The end-of-input token, `_EOF` unless the grammar renames it.
+pub fn eof_name(grammar: &Grammar) -> SmolStr {
+    grammar.eof.clone().unwrap_or_else(|| SmolStr::new("_EOF"))
+}
+
+// A lexer per token with a negative lookahead, matching just that lookahead.
+fn construct_lookaheads(terminals: &[Rule]) -> Result<Vec<(SmolStr, Lexer)>> {
+    let mut lookaheads = Vec::new();
+    for rule in terminals {
+        let Some(lookahead) = &rule.lookahead else {
+            continue;
         };
-        connections.push(Connection {
-            range: (arange.0, arange.1),
-            start: start_closure,
-            end: pos,
-        });
+        let mut rule = rule.clone();
+        rule.element = lookahead.clone();
+        rule.lookahead = None;
+        rule.mode = None;
+        rule.line_start = false;
+        let alphabet = construct_alphabet([&rule].into_iter(), false);
+        let dfa = match construct_dfa([&rule].into_iter(), &alphabet, &[], &[]) {
+            Ok((dfa, _, _)) => dfa,
+            Err(PargeError::EmptyMatch(name)) => bail!(
+                Grammar,
+                "The lookahead of token '{}' matches the empty string, so it would reject every match",
+                name
+            ),
+            Err(e) => return Err(e),
+        };
+        let name = rule.name.clone();
+        let terminals = vec![rule];
+        let (table, trap, accepts) = index_dfa(&dfa, &alphabet, &terminals);
+        let lexer = Lexer {
+            dfa,
+            alphabet,
+            terminals,
+            equivalences: Vec::new(),
+            match_mode: MatchMode::Shortest,
+            modes: Vec::new(),
+            folded: Vec::new(),
+            lookaheads: Vec::new(),
+            eof: SmolStr::new("_EOF"),
+            table,
+            trap,
+            accepts,
+        };
+        lookaheads.push((name, lexer));
     }
+    Ok(lookaheads)
 }
 
-impl Lexer {
-    pub fn from_rules(rules: &Vec<Rule>) -> Result<Self> {
-        let alphabet = construct_alphabet(rules.iter().filter(|rule| rule.is_terminal));
-        let nfa = construct_nfa(rules.iter().filter(|rule| rule.is_terminal), &alphabet);
-        let mut powersets = Vec::new();
-        let mut connections = Vec::new();
+// Each token that loses to another on some input, with the tokens it loses to.
+type Shadowed = BTreeMap<SmolStr, BTreeSet<SmolStr>>;
+
+fn construct_dfa<'a, I>(
+    terminals: I,
+    alphabet: &Vec<(u32, u32)>,
+    modes: &[SmolStr],
+    exclusive: &[Vec<SmolStr>],
+) -> Result<(DFA, Shadowed, Vec<Vec<SmolStr>>)>
+where
+    I: Iterator<Item = &'a Rule>,
+{
+    let terminals: Vec<&Rule> = terminals.collect();
+    let nfa = construct_nfa(terminals.iter().copied(), alphabet, modes)?;
+    let mut powersets = Vec::new();
+    let mut connections = Vec::new();
+    let epsilon = nfa.epsilon_edges();
+    let class_edges = nfa.class_edges(alphabet);
+    let mut starts = Vec::new();
+    for &entry in &nfa.entries {
         let mut closure = BTreeSet::new();
-        closure.insert(nfa.entry);
-        epsilon_closure(&nfa, &mut closure);
+        closure.insert(entry);
+        epsilon_closure(&epsilon, &mut closure);
+        if let Some(name) = closure
+            .iter()
+            .find_map(|&i| nfa.states[i].accepting.as_ref())
+        {
+            return Err(PargeError::EmptyMatch(name.clone()));
+        }
+        let start = powersets.len();
         powersets.push(closure);
-        powerset_construction(&nfa, 0, &mut powersets, &mut connections, &alphabet);
-        let mut dfa = DFA::new();
-        for ps in powersets {
-            if ps.is_empty() {
-                dfa.add(State {
-                    accepting: Some(SmolStr::from("_TRAP")),
+        powerset_construction(
+            &class_edges,
+            &epsilon,
+            start,
+            &mut powersets,
+            &mut connections,
+            alphabet,
+        );
+        starts.push(start);
+    }
+    let mut dfa = DFA::new();
+    let mut shadowed: Shadowed = BTreeMap::new();
+    // Every input reaches exactly one powerset, so two tokens accepted in
+    // the same powersets match the same strings.
+    let mut accepted_in: BTreeMap<&SmolStr, Vec<usize>> = BTreeMap::new();
+    for (index, ps) in powersets.into_iter().enumerate() {
+        if ps.is_empty() {
+            dfa.add(State {
+                accepting: Some(SmolStr::from("_TRAP")),
+            });
+            continue;
+        }
+        let mut acceptions = Vec::new();
+        for i in ps {
+            if let Some(accept) = &nfa.states[i].accepting {
+                acceptions.push(accept);
+            }
+        }
+        for names in exclusive {
+            let accepted: Vec<&SmolStr> = names
+                .iter()
+                .filter(|name| acceptions.contains(name))
+                .collect();
+            if accepted.len() >= 2 {
+                return Err(PargeError::AcceptConflict {
+                    tokens: accepted.into_iter().cloned().collect(),
+                    example: shortest_input(&starts, &connections, index),
                 });
+            }
+        }
+        for &name in &acceptions {
+            accepted_in.entry(name).or_default().push(index);
+        }
+        let winner = acceptions
+            .iter()
+            .copied()
+            .min_by_key(|name| rank(&terminals, name));
+        if let Some(winner) = winner {
+            for &name in acceptions.iter().filter(|name| **name != winner) {
+                shadowed
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(winner.clone());
+            }
+            dfa.add(State {
+                accepting: Some(winner.clone()),
+            });
+        } else {
+            dfa.add_empty();
+        }
+    }
+    for c in connections {
+        dfa.connect_range(c.start, c.end, c.range);
+    }
+    // Backends index the trap directly, so a DFA whose transitions are
+    // never dead still gets one, even if it's unreachable.
+    if !dfa.states.iter().any(is_trap) {
+        let trap = dfa.add(State {
+            accepting: Some(SmolStr::from("_TRAP")),
+        });
+        for range in alphabet {
+            dfa.connect_range(trap, trap, *range);
+        }
+    }
+    dfa.starts = starts;
+    let mut identical: Vec<Vec<SmolStr>> = Vec::new();
+    let mut grouped = BTreeSet::new();
+    for (name, states) in &accepted_in {
+        if !grouped.insert(*name) {
+            continue;
+        }
+        let mut group: Vec<&SmolStr> = accepted_in
+            .iter()
+            .filter(|(other, other_states)| other != &name && *other_states == states)
+            .map(|(other, _)| *other)
+            .collect();
+        if group.is_empty() {
+            continue;
+        }
+        grouped.extend(group.iter().copied());
+        group.push(name);
+        group.sort_by_key(|name| rank(&terminals, name));
+        identical.push(group.into_iter().cloned().collect());
+    }
+    Ok((dfa.minimize(alphabet).prune(), shadowed, identical))
+}
+
+// The shortest input leading from a start to the given powerset, found by
+// a breadth-first search over the powerset connections.
+fn shortest_input(starts: &[usize], connections: &[Connection], target: usize) -> String {
+    let mut previous: BTreeMap<usize, Option<(usize, char)>> =
+        starts.iter().map(|&start| (start, None)).collect();
+    let mut queue: VecDeque<usize> = starts.iter().copied().collect();
+    while let Some(state) = queue.pop_front() {
+        if state == target {
+            break;
+        }
+        for c in connections.iter().filter(|c| c.start == state) {
+            if previous.contains_key(&c.end) {
                 continue;
             }
-            let mut acceptions = Vec::new();
-            for i in ps {
-                if let Some(accept) = &nfa.states[i].accepting {
-                    acceptions.push(accept);
-                }
+            let Some(ch) = (c.range.0..=c.range.1).find_map(char::from_u32) else {
+                continue;
+            };
+            previous.insert(c.end, Some((state, ch)));
+            queue.push_back(c.end);
+        }
+    }
+    let mut input = Vec::new();
+    let mut state = target;
+    while let Some(&Some((from, ch))) = previous.get(&state) {
+        input.push(ch);
+        state = from;
+    }
+    input.into_iter().rev().collect()
+}
+
+// Among tokens accepting the same input, the lowest rank wins.
+fn rank(terminals: &[&Rule], name: &SmolStr) -> (Reverse<u32>, usize) {
+    let index = terminals.iter().position(|r| &r.name == name).unwrap();
+    (Reverse(terminals[index].priority), index)
+}
+
+impl Lexer {
+    // For embedding: the grammar is parsed and validated like parse_file does.
+    pub fn from_source(src: &str) -> Result<Self> {
+        Lexer::from_grammar(&rules::parse_str(src)?)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Lexer::from_grammar(&rules::parse_file(path)?)
+    }
+
+    pub fn from_grammar(grammar: &Grammar) -> Result<Self> {
+        Lexer::build(grammar)
+    }
+
+    // For one generated file with several lexers: every grammar is built over
+    // one alphabet that has the ranges all of them need.
+    pub fn from_grammars(grammars: &[(SmolStr, Grammar)]) -> Result<Vec<(SmolStr, Lexer)>> {
+        let mut all_points = BTreeSet::new();
+        let mut all_compact = true;
+        let mut prepared = Vec::new();
+        for (_, grammar) in grammars {
+            let terminals = prepare_terminals(grammar)?;
+            let (points, compact) = alphabet_points(terminals.iter(), grammar.compact_alphabet);
+            all_points.extend(points);
+            all_compact &= compact;
+            prepared.push(terminals);
+        }
+        let alphabet = alphabet_from_points(all_points, all_compact);
+        grammars
+            .iter()
+            .zip(prepared)
+            .map(|((name, grammar), terminals)| {
+                let lexer = Lexer::build_on(grammar, terminals, alphabet.clone())?;
+                Ok((name.clone(), lexer))
+            })
+            .collect()
+    }
+
+    fn build(grammar: &Grammar) -> Result<Self> {
+        let terminals = prepare_terminals(grammar)?;
+        let alphabet = construct_alphabet(terminals.iter(), grammar.compact_alphabet);
+        Lexer::build_on(grammar, terminals, alphabet)
+    }
+
+    fn build_on(
+        grammar: &Grammar,
+        terminals: Vec<Rule>,
+        alphabet: Vec<(u32, u32)>,
+    ) -> Result<Self> {
+        let (dfa, shadowed, identical) = construct_dfa(
+            terminals.iter(),
+            &alphabet,
+            &grammar.modes,
+            &grammar.exclusive,
+        )?;
+        let lookaheads = construct_lookaheads(&terminals)?;
+        let (table, trap, accepts) = index_dfa(&dfa, &alphabet, &terminals);
+        let lexer = Lexer {
+            dfa,
+            alphabet,
+            terminals,
+            equivalences: grammar.equivalences.clone(),
+            match_mode: grammar.match_mode,
+            modes: grammar.modes.clone(),
+            folded: Vec::new(),
+            lookaheads,
+            eof: eof_name(grammar),
+            table,
+            trap,
+            accepts,
+        };
+        for group in &identical {
+            log::warn!(
+                "Tokens {:?} match exactly the same strings, so all but '{}' can never be matched",
+                group,
+                group[0]
+            );
+        }
+        for token in lexer.unmatchable_tokens() {
+            if identical.iter().any(|group| group.contains(token)) {
+                continue;
+            }
+            match shadowed.get(token) {
+                Some(winners) => log::warn!(
+                    "Token '{}' can never be matched, it's always shadowed by {:?}",
+                    token,
+                    winners
+                ),
+                None => log::warn!("Token '{}' can never be matched", token),
+            }
+        }
+        Ok(lexer)
+    }
+
+    // Rebuilds the DFA without the keywords that another token matches just
+    // as well, so a backend can match that token and look the keyword up
+    // afterwards. Each keyword is kept where removing it would change what
+    // its literal lexes as.
+    pub fn fold_keywords(&self) -> Result<Self> {
+        let mut removed: Vec<(&SmolStr, &SmolStr)> = Vec::new();
+        if self.modes.is_empty() && self.get_line_starts().is_empty() {
+            removed = self
+                .get_keywords()
+                .into_iter()
+                .filter(|(name, lit)| {
+                    let rule = self.get_rule(name).unwrap();
+                    !rule.skip
+                        && !rule.line_start
+                        && rule.action.is_none()
+                        && self.longest_match(lit, 0, self.start_state())
+                            == Some((*name, lit.len()))
+                })
+                .collect();
+        }
+        let keywords: Vec<&SmolStr> = self
+            .get_keywords()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        while !removed.is_empty() {
+            let names: Vec<&SmolStr> = removed.iter().map(|(name, _)| *name).collect();
+            let mut reduced = self.without(&names)?;
+            let folded: Vec<(SmolStr, SmolStr)> = removed
+                .iter()
+                .filter_map(|(name, lit)| {
+                    match reduced.longest_match(lit, 0, reduced.start_state()) {
+                        Some((host, end)) if end == lit.len() && !keywords.contains(&host) => {
+                            Some(((*name).clone(), host.clone()))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect();
+            if folded.len() == removed.len() {
+                reduced.folded = folded;
+                return Ok(reduced);
             }
+            removed.retain(|(name, _)| folded.iter().any(|(keyword, _)| keyword == *name));
+        }
+        Ok(self.clone())
+    }
+
+    fn without(&self, names: &[&SmolStr]) -> Result<Self> {
+        let kept: Vec<&Rule> = self
+            .terminals
+            .iter()
+            .filter(|rule| !names.contains(&&rule.name))
+            .collect();
+        let alphabet = construct_alphabet(kept.iter().copied(), !self.is_full_alphabet());
+        let (dfa, _, _) = construct_dfa(kept.into_iter(), &alphabet, &self.modes, &[])?;
+        let (table, trap, accepts) = index_dfa(&dfa, &alphabet, &self.terminals);
+        Ok(Lexer {
+            dfa,
+            alphabet,
+            terminals: self.terminals.clone(),
+            equivalences: self.equivalences.clone(),
+            match_mode: self.match_mode,
+            modes: self.modes.clone(),
+            folded: Vec::new(),
+            lookaheads: self.lookaheads.clone(),
+            eof: self.eof.clone(),
+            table,
+            trap,
+            accepts,
+        })
+    }
+
+    pub fn from_dfa_json(grammar: &Grammar, src: &str) -> Result<Self> {
+        ensure!(
+            grammar.modes.is_empty(),
+            Dfa,
+            "DFA files don't support lexer modes"
+        );
+        ensure!(
+            grammar.rules.iter().all(|rule| !rule.line_start),
+            Dfa,
+            "DFA files don't support line-start anchors"
+        );
+        let terminals = prepare_terminals(grammar)?;
+        let json = json::parse(src)?;
+        let alphabet = json
+            .get("alphabet")?
+            .as_array()?
+            .iter()
+            .map(|range| match range.as_array()? {
+                [first, last] => Ok((first.as_u32()?, last.as_u32()?)),
+                _ => bail!(
+                    Dfa,
+                    "Expected an alphabet range [first, last], found {:?}",
+                    range
+                ),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut next = 0;
+        for &(first, last) in &alphabet {
             ensure!(
-                acceptions.len() < 2,
-                "Accepting state must accept exactly one rule"
+                first == next && first <= last,
+                Dfa,
+                "Alphabet ranges must be sorted and contiguous"
             );
-            if acceptions.is_empty() {
-                dfa.add_empty();
-            } else {
-                dfa.add(State {
-                    accepting: Some(acceptions[0].clone()),
-                });
+            next = last + 1;
+        }
+        ensure!(
+            next == char::MAX as u32 + 1 || grammar.compact_alphabet,
+            Dfa,
+            "Alphabet ranges must cover every code point"
+        );
+        let mut dfa = DFA::new();
+        for state in json.get("states")?.as_array()? {
+            let accepting = state.as_optional_str()?;
+            if let Some(name) = accepting {
+                ensure!(
+                    name == "_TRAP" || terminals.iter().any(|rule| &rule.name == name),
+                    Dfa,
+                    "State accepts unknown token '{}'",
+                    name
+                );
             }
+            dfa.add(State {
+                accepting: accepting.cloned(),
+            });
         }
-        for c in connections {
-            dfa.connect_range(c.start, c.end, c.range);
+        ensure!(!dfa.states.is_empty(), Dfa, "DFA has no states");
+        ensure!(
+            dfa.states.iter().filter(|s| is_trap(s)).count() == 1,
+            Dfa,
+            "DFA must have exactly one _TRAP state"
+        );
+        let ranges: HashSet<(u32, u32)> = alphabet.iter().copied().collect();
+        let mut connected = HashSet::new();
+        for connection in json.get("connections")?.as_array()? {
+            let start = connection.get("start")?.as_usize()?;
+            let end = connection.get("end")?.as_usize()?;
+            let range = (
+                connection.get("first")?.as_u32()?,
+                connection.get("last")?.as_u32()?,
+            );
+            ensure!(
+                start < dfa.states.len() && end < dfa.states.len(),
+                Dfa,
+                "Connection {} -> {} refers to a missing state",
+                start,
+                end
+            );
+            ensure!(
+                ranges.contains(&range),
+                Dfa,
+                "Connection range {:?} is not in the alphabet",
+                range
+            );
+            ensure!(
+                connected.insert((start, range)),
+                Dfa,
+                "State {} has more than one connection for {:?}",
+                start,
+                range
+            );
+            dfa.connect_range(start, end, range);
         }
-        Ok(Lexer { dfa, alphabet })
+        ensure!(
+            connected.len() == dfa.states.len() * alphabet.len(),
+            Dfa,
+            "Every state needs a connection for every alphabet range"
+        );
+        let lookaheads = construct_lookaheads(&terminals)?;
+        let (table, trap, accepts) = index_dfa(&dfa, &alphabet, &terminals);
+        Ok(Lexer {
+            dfa,
+            alphabet,
+            terminals,
+            equivalences: grammar.equivalences.clone(),
+            match_mode: grammar.match_mode,
+            modes: Vec::new(),
+            folded: Vec::new(),
+            lookaheads,
+            eof: eof_name(grammar),
+            table,
+            trap,
+            accepts,
+        })
     }
 
     pub fn get_states(&self) -> Vec<Option<&SmolStr>> {
@@ -398,16 +1468,2174 @@ impl Lexer {
             .collect()
     }
 
-    pub fn get_alphabet(&self) -> &Vec<(u32, u32)> {
-        &self.alphabet
+    pub fn get_terminals(&self) -> &[Rule] {
+        &self.terminals
     }
 
-    pub fn get_connections(&self, start: usize) -> Vec<(u32, u32, usize)> {
-        self.dfa
-            .connections
+    pub fn get_rule(&self, name: &str) -> Option<&Rule> {
+        self.terminals.iter().find(|rule| rule.name == name)
+    }
+
+    pub fn is_internal(&self, token: &str) -> bool {
+        self.get_rule(token)
+            .is_some_and(|rule| rule.internal.is_some())
+    }
+
+    pub fn reported_token<'a>(&'a self, token: &'a SmolStr) -> &'a SmolStr {
+        self.get_rule(token)
+            .and_then(|rule| rule.internal.as_ref())
+            .unwrap_or(token)
+    }
+
+    pub fn get_skipped(&self) -> Vec<&SmolStr> {
+        self.terminals
             .iter()
-            .filter(|&c| c.start == start)
-            .map(|c| (c.range.0, c.range.1, c.end))
+            .filter(|rule| rule.skip)
+            .map(|rule| &rule.name)
             .collect()
     }
+
+    pub fn has_displays(&self) -> bool {
+        self.terminals.iter().any(|rule| rule.display.is_some())
+    }
+
+    pub fn display(&self, token: &SmolStr) -> SmolStr {
+        if let Some(display) = self.get_rule(token).and_then(|rule| rule.display.as_ref()) {
+            return display.clone();
+        }
+        self.get_keywords()
+            .into_iter()
+            .find(|(name, _)| *name == token)
+            .map_or_else(|| token.clone(), |(_, lit)| lit.clone())
+    }
+
+    pub fn get_keywords(&self) -> Vec<(&SmolStr, &SmolStr)> {
+        self.terminals
+            .iter()
+            .filter(|rule| rule.internal.is_none())
+            .filter_map(|rule| match &rule.element {
+                Element::Group { subelems } => match subelems.as_slice() {
+                    [Element::Literal { lit }] => Some((&rule.name, lit)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn has_lookaheads(&self) -> bool {
+        !self.lookaheads.is_empty()
+    }
+
+    pub fn get_lookaheads(&self) -> &[(SmolStr, Lexer)] {
+        &self.lookaheads
+    }
+
+    // The lexer for a token's negative lookahead, which accepts as soon as
+    // the lookahead matches.
+    pub fn get_lookahead(&self, token: &str) -> Option<&Lexer> {
+        self.lookaheads
+            .iter()
+            .find(|(name, _)| name == token)
+            .map(|(_, lookahead)| lookahead)
+    }
+
+    pub fn get_folded(&self) -> &[(SmolStr, SmolStr)] {
+        &self.folded
+    }
+
+    pub fn get_equivalences(&self) -> &[(SmolStr, Vec<SmolStr>)] {
+        &self.equivalences
+    }
+
+    pub fn get_match_mode(&self) -> MatchMode {
+        self.match_mode
+    }
+
+    pub fn get_eof(&self) -> &SmolStr {
+        &self.eof
+    }
+
+    pub fn get_modes(&self) -> Vec<(&SmolStr, usize)> {
+        self.modes
+            .iter()
+            .zip(&self.dfa.starts[1..])
+            .map(|(mode, &start)| (mode, start))
+            .collect()
+    }
+
+    pub fn get_line_starts(&self) -> Vec<(usize, usize)> {
+        let count = self.modes.len() + 1;
+        self.dfa.starts[..count]
+            .iter()
+            .copied()
+            .zip(self.dfa.starts[count..].iter().copied())
+            .collect()
+    }
+
+    pub fn get_action(&self, token: &str) -> Option<&ModeAction> {
+        self.get_rule(token).and_then(|rule| rule.action.as_ref())
+    }
+
+    // Sorted, contiguous ranges from 0, where a range's index is its class in
+    // every backend. Only a compact alphabet stops short of char::MAX.
+    pub fn get_alphabet(&self) -> &Vec<(u32, u32)> {
+        &self.alphabet
+    }
+
+    pub fn is_full_alphabet(&self) -> bool {
+        self.alphabet
+            .last()
+            .is_some_and(|&(_, last)| last == char::MAX as u32)
+    }
+
+    pub fn nfa_graph(&self) -> Result<NfaGraph> {
+        let nfa = construct_nfa(self.terminals.iter(), &self.alphabet, &self.modes)?;
+        let mut epsilon = Vec::new();
+        let mut ranges = Vec::new();
+        for connection in &nfa.connections {
+            match *connection {
+                EpsilonConnection::Epsilon(start, end) => epsilon.push((start, end)),
+                EpsilonConnection::Connection(range, start, end) => {
+                    ranges.push((start, range, end))
+                }
+            }
+        }
+        Ok(NfaGraph {
+            states: nfa
+                .states
+                .into_iter()
+                .map(|state| state.accepting)
+                .collect(),
+            entries: nfa.entries,
+            epsilon,
+            ranges,
+        })
+    }
+
+    // Sorted by range, so generated code doesn't depend on the order in which
+    // the DFA was built or loaded.
+    pub fn get_connections(&self, start: usize) -> Vec<(u32, u32, usize)> {
+        let mut connections: Vec<(u32, u32, usize)> = self
+            .dfa
+            .connections
+            .iter()
+            .filter(|&c| c.start == start)
+            .map(|c| (c.range.0, c.range.1, c.end))
+            .collect();
+        connections.sort();
+        connections
+    }
+
+    // The target of every state for every alphabet class, indexed by
+    // [state][class]. get_states gives what each state accepts.
+    pub fn dfa_table(&self) -> Vec<Vec<usize>> {
+        self.table.clone()
+    }
+
+    // The states from which only one token can still be accepted, e.g. the
+    // inside of a long literal that no other token starts with. What each
+    // state accepts is carried back over the transitions until nothing
+    // changes; a state that can reach two tokens needs no more than those.
+    pub fn get_committed_states(&self) -> BTreeSet<usize> {
+        let trap = self.get_trap();
+        let mut predecessors = vec![Vec::new(); self.dfa.states.len()];
+        for c in &self.dfa.connections {
+            if c.start != trap && c.end != trap {
+                predecessors[c.end].push(c.start);
+            }
+        }
+        let mut reachable: Vec<BTreeSet<&SmolStr>> = (0..self.dfa.states.len())
+            .map(|i| self.accept(i).filter(|_| i != trap).into_iter().collect())
+            .collect();
+        let mut pending: Vec<usize> = (0..reachable.len())
+            .filter(|&i| !reachable[i].is_empty())
+            .collect();
+        while let Some(state) = pending.pop() {
+            for &pred in &predecessors[state] {
+                let before = reachable[pred].len();
+                for token in reachable[state].clone() {
+                    if reachable[pred].len() >= 2 {
+                        break;
+                    }
+                    reachable[pred].insert(token);
+                }
+                if reachable[pred].len() > before {
+                    pending.push(pred);
+                }
+            }
+        }
+        (0..reachable.len())
+            .filter(|&i| reachable[i].len() == 1)
+            .collect()
+    }
+
+    // A DFA over the same alphabet that reads input backwards from the end of
+    // a token and accepts wherever that token could have started, built by
+    // running the transitions of this one in reverse. Indexed like
+    // dfa_table; the state read first is 0.
+    pub fn reverse_table(&self) -> (Vec<Vec<usize>>, Vec<bool>, usize) {
+        let table = self.dfa_table();
+        let start = self.start_state();
+        let accepting: BTreeSet<usize> = (0..table.len())
+            .filter(|&i| self.accept(i).is_some())
+            .collect();
+        // The states that step to each state, and on which class.
+        let mut predecessors = vec![Vec::new(); table.len()];
+        for (state, row) in table.iter().enumerate() {
+            for (class, &next) in row.iter().enumerate() {
+                predecessors[next].push((class, state));
+            }
+        }
+        let mut sets = vec![accepting, BTreeSet::new()];
+        if sets[0].is_empty() {
+            sets.pop();
+        }
+        let trap = sets.len() - 1;
+        let mut indices: HashMap<BTreeSet<usize>, usize> = sets
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, set)| (set, index))
+            .collect();
+        let mut transitions: Vec<Vec<usize>> = Vec::new();
+        let mut i = 0;
+        while i < sets.len() {
+            let mut befores = vec![BTreeSet::new(); self.alphabet.len()];
+            for &state in &sets[i] {
+                for &(class, before) in &predecessors[state] {
+                    befores[class].insert(before);
+                }
+            }
+            let row = befores
+                .into_iter()
+                .map(|before| {
+                    *indices.entry(before).or_insert_with_key(|before| {
+                        sets.push(before.clone());
+                        sets.len() - 1
+                    })
+                })
+                .collect();
+            transitions.push(row);
+            i += 1;
+        }
+        let accepts = sets.iter().map(|set| set.contains(&start)).collect();
+        (transitions, accepts, trap)
+    }
+
+    // Tokens not accepted by any state reachable from the start state, either
+    // because their language is empty or because other tokens always win.
+    fn unmatchable_tokens(&self) -> Vec<&SmolStr> {
+        let accepted: BTreeSet<&SmolStr> = self
+            .dfa
+            .reachable()
+            .into_iter()
+            .filter_map(|state| self.dfa.states[state].accepting.as_ref())
+            .collect();
+        self.terminals
+            .iter()
+            .map(|rule| &rule.name)
+            .filter(|name| !accepted.contains(name))
+            .collect()
+    }
+
+    pub fn get_trap(&self) -> usize {
+        self.trap
+    }
+
+    pub fn start_state(&self) -> usize {
+        self.dfa.starts[0]
+    }
+
+    pub fn step(&self, state: usize, ch: u32) -> usize {
+        let class = self.alphabet.partition_point(|&(_, last)| last < ch);
+        match self.table.get(state) {
+            Some(row) if class < row.len() && self.alphabet[class].0 <= ch => row[class],
+            _ => self.trap,
+        }
+    }
+
+    pub fn accept(&self, state: usize) -> Option<&SmolStr> {
+        self.accepts.get(state)?.as_ref()
+    }
+
+    fn longest_match(&self, input: &str, pos: usize, start: usize) -> Option<(&SmolStr, usize)> {
+        self.walk(input, pos, start).0
+    }
+
+    // Also reports whether the walk ran out of input, in which case more
+    // input could still change the match.
+    fn walk(&self, input: &str, pos: usize, start: usize) -> (Option<(&SmolStr, usize)>, bool) {
+        let trap = self.get_trap();
+        let mut state = start;
+        let mut found = None;
+        let mut undecided = false;
+        for (i, c) in input[pos..].char_indices() {
+            state = self.step(state, c as u32);
+            if state == trap {
+                return (found, undecided);
+            }
+            if let Some(token) = self.accept(state) {
+                let end = pos + i + c.len_utf8();
+                match self.rejected(state, &input[end..]) {
+                    Some(true) => continue,
+                    Some(false) => {}
+                    None => undecided = true,
+                }
+                found = Some((token, end));
+                if self.match_mode == MatchMode::Shortest {
+                    return (found, false);
+                }
+            }
+        }
+        (found, true)
+    }
+
+    // Whether the token accepted in the state is ruled out by its negative
+    // lookahead matching what follows, or None when the input ends first.
+    fn rejected(&self, state: usize, rest: &str) -> Option<bool> {
+        let Some(token) = &self.dfa.states[state].accepting else {
+            return Some(false);
+        };
+        let Some(lookahead) = self.get_lookahead(token) else {
+            return Some(false);
+        };
+        let trap = lookahead.get_trap();
+        let mut state = lookahead.start_state();
+        for c in rest.chars() {
+            state = lookahead.step(state, c as u32);
+            if state == trap {
+                return Some(false);
+            }
+            if lookahead.accept(state).is_some() {
+                return Some(true);
+            }
+        }
+        None
+    }
+
+    pub fn stream<'a>(
+        &'a self,
+        input: &'a str,
+        bulk_errors: bool,
+        max_lookahead: usize,
+    ) -> TokenStream<'a> {
+        TokenStream {
+            lexer: self,
+            skipped: self.get_skipped(),
+            input,
+            pos: 0,
+            modes: Vec::new(),
+            bulk_errors,
+            lookahead: VecDeque::with_capacity(max_lookahead),
+            max_lookahead,
+        }
+    }
+
+    pub fn chunked(&self, bulk_errors: bool) -> ChunkedStream<'_> {
+        ChunkedStream {
+            lexer: self,
+            buf: String::new(),
+            partial: Vec::new(),
+            offset: 0,
+            at_line_start: true,
+            modes: Vec::new(),
+            bulk_errors,
+        }
+    }
+
+    pub fn tokenize(&self, input: &str) -> Result<Vec<(SmolStr, String)>> {
+        self.collect(input, false)
+    }
+
+    // Like tokenize, but runs of unmatched input become error tokens instead
+    // of failing the whole call.
+    pub fn tokenize_bulk(&self, input: &str) -> Result<Vec<(SmolStr, String)>> {
+        self.collect(input, true)
+    }
+
+    fn collect(&self, input: &str, bulk_errors: bool) -> Result<Vec<(SmolStr, String)>> {
+        let mut stream = self.stream(input, bulk_errors, 0);
+        let mut tokens = Vec::new();
+        loop {
+            let token = stream.next_token()?;
+            let eof = token.0 == self.eof;
+            tokens.push(token);
+            if eof {
+                return Ok(tokens);
+            }
+        }
+    }
+}
+
+pub struct TokenStream<'a> {
+    lexer: &'a Lexer,
+    skipped: Vec<&'a SmolStr>,
+    input: &'a str,
+    pos: usize,
+    modes: Vec<usize>,
+    bulk_errors: bool,
+    lookahead: VecDeque<(SmolStr, String)>,
+    max_lookahead: usize,
+}
+
+fn start_state(lexer: &Lexer, modes: &[usize], at_line_start: bool) -> usize {
+    let start = modes.last().copied().unwrap_or(lexer.start_state());
+    if !at_line_start {
+        return start;
+    }
+    lexer
+        .get_line_starts()
+        .into_iter()
+        .find(|&(state, _)| state == start)
+        .map_or(start, |(_, line_start)| line_start)
+}
+
+fn switch_mode(lexer: &Lexer, modes: &mut Vec<usize>, token: &str) {
+    match lexer.get_action(token) {
+        Some(ModeAction::Push(mode)) => {
+            let (_, start) = lexer
+                .get_modes()
+                .into_iter()
+                .find(|(name, _)| *name == mode)
+                .unwrap();
+            modes.push(start);
+        }
+        Some(ModeAction::Pop) => {
+            modes.pop();
+        }
+        None => (),
+    }
+}
+
+impl<'a> TokenStream<'a> {
+    fn start_state(&self) -> usize {
+        let at_line_start = self.pos == 0 || self.input[..self.pos].ends_with('\n');
+        start_state(self.lexer, &self.modes, at_line_start)
+    }
+
+    fn switch_mode(&mut self, token: &str) {
+        switch_mode(self.lexer, &mut self.modes, token);
+    }
+
+    fn scan(&mut self) -> Result<(SmolStr, String)> {
+        let input = self.input;
+        let lexer = self.lexer;
+        let mut matched = lexer.longest_match(input, self.pos, self.start_state());
+        while let Some((token, end)) = matched.filter(|(token, _)| self.skipped.contains(token)) {
+            self.pos = end;
+            self.switch_mode(token);
+            matched = lexer.longest_match(input, self.pos, self.start_state());
+        }
+        if self.pos >= input.len() {
+            return Ok((lexer.eof.clone(), String::new()));
+        }
+        if let Some((token, end)) = matched {
+            let text = String::from(&input[self.pos..end]);
+            self.pos = end;
+            self.switch_mode(token);
+            return Ok((token.clone(), text));
+        }
+        if !self.bulk_errors {
+            return Err(PargeError::NoMatch {
+                offset: self.pos,
+                input: input[self.pos..].chars().take(16).collect(),
+            });
+        }
+        let start = self.pos;
+        while self.pos < input.len()
+            && lexer
+                .longest_match(input, self.pos, self.start_state())
+                .is_none()
+        {
+            self.pos += input[self.pos..].chars().next().unwrap().len_utf8();
+        }
+        Ok((SmolStr::from("_ERR"), String::from(&input[start..self.pos])))
+    }
+
+    pub fn next_token(&mut self) -> Result<(SmolStr, String)> {
+        match self.lookahead.pop_front() {
+            Some(token) => Ok(token),
+            None => self.scan(),
+        }
+    }
+
+    pub fn peek_n(&mut self, n: usize) -> Result<Vec<&(SmolStr, String)>> {
+        ensure!(
+            n <= self.max_lookahead,
+            Lex,
+            "Can't peek {} tokens ahead, the maximum lookahead is {}",
+            n,
+            self.max_lookahead
+        );
+        while self.lookahead.len() < n {
+            let token = self.scan()?;
+            self.lookahead.push_back(token);
+        }
+        Ok(self.lookahead.iter().take(n).collect())
+    }
+}
+
+// Lexes input that arrives in byte chunks. A token is only returned once
+// the input after it shows that it can't grow any longer, so a token or
+// UTF-8 sequence split across chunks is held back until the next feed.
+pub struct ChunkedStream<'a> {
+    lexer: &'a Lexer,
+    buf: String,
+    partial: Vec<u8>,
+    offset: usize,
+    at_line_start: bool,
+    modes: Vec<usize>,
+    bulk_errors: bool,
+}
+
+impl<'a> ChunkedStream<'a> {
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<(SmolStr, String)>> {
+        self.partial.extend_from_slice(chunk);
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => bail!(
+                Lex,
+                "Invalid UTF-8 at offset {}",
+                self.offset + self.buf.len() + e.valid_up_to()
+            ),
+        };
+        self.buf
+            .push_str(std::str::from_utf8(&self.partial[..valid]).unwrap());
+        self.partial.drain(..valid);
+        self.drain(false)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<(SmolStr, String)>> {
+        ensure!(
+            self.partial.is_empty(),
+            Lex,
+            "Input ends inside a UTF-8 sequence at offset {}",
+            self.offset + self.buf.len()
+        );
+        let mut tokens = self.drain(true)?;
+        tokens.push((self.lexer.eof.clone(), String::new()));
+        Ok(tokens)
+    }
+
+    fn drain(&mut self, last: bool) -> Result<Vec<(SmolStr, String)>> {
+        let mut tokens = Vec::new();
+        while let Some(token) = self.scan(last)? {
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+
+    fn start_state_at(&self, pos: usize) -> usize {
+        let at_line_start = if pos == 0 {
+            self.at_line_start
+        } else {
+            self.buf[..pos].ends_with('\n')
+        };
+        start_state(self.lexer, &self.modes, at_line_start)
+    }
+
+    fn consume(&mut self, end: usize) -> String {
+        let text: String = self.buf.drain(..end).collect();
+        self.offset += end;
+        self.at_line_start = text.ends_with('\n');
+        text
+    }
+
+    fn scan(&mut self, last: bool) -> Result<Option<(SmolStr, String)>> {
+        let lexer = self.lexer;
+        let skipped = lexer.get_skipped();
+        loop {
+            if self.buf.is_empty() {
+                return Ok(None);
+            }
+            let (matched, exhausted) = lexer.walk(&self.buf, 0, self.start_state_at(0));
+            if exhausted && !last {
+                return Ok(None);
+            }
+            if let Some((token, end)) = matched {
+                let text = self.consume(end);
+                switch_mode(lexer, &mut self.modes, token);
+                if skipped.contains(&token) {
+                    continue;
+                }
+                return Ok(Some((token.clone(), text)));
+            }
+            if !self.bulk_errors {
+                return Err(PargeError::NoMatch {
+                    offset: self.offset,
+                    input: self.buf.chars().take(16).collect(),
+                });
+            }
+            // The error run ends where some token matches, which more input
+            // can't undo, but a walk that ran out might still find one.
+            let mut end = self.buf.chars().next().unwrap().len_utf8();
+            while end < self.buf.len() {
+                let (matched, exhausted) = lexer.walk(&self.buf, end, self.start_state_at(end));
+                if matched.is_some() {
+                    break;
+                }
+                if exhausted && !last {
+                    return Ok(None);
+                }
+                end += self.buf[end..].chars().next().unwrap().len_utf8();
+            }
+            if end == self.buf.len() && !last {
+                return Ok(None);
+            }
+            return Ok(Some((SmolStr::from("_ERR"), self.consume(end))));
+        }
+    }
+}
+
+fn indent_width(mut width: usize, text: &str, tab_width: usize) -> usize {
+    for c in text.chars() {
+        match c {
+            '\t' => width = (width / tab_width + 1) * tab_width,
+            '\r' => (),
+            _ => width += 1,
+        }
+    }
+    width
+}
+
+pub fn insert_indentation(
+    tokens: Vec<(SmolStr, String)>,
+    tab_width: usize,
+    eof: &str,
+) -> Result<Vec<(SmolStr, String)>> {
+    let mut result = Vec::new();
+    let mut indents = vec![0];
+    let mut at_line_start = true;
+    let mut width = 0;
+    for (token, text) in tokens {
+        if token == eof {
+            while indents.len() > 1 {
+                indents.pop();
+                result.push((SmolStr::from("DEDENT"), String::new()));
+            }
+        } else if !text.is_empty() && text.chars().all(|c| " \t\r\n".contains(c)) {
+            let line = match text.rfind('\n') {
+                Some(nl) => {
+                    at_line_start = true;
+                    width = 0;
+                    &text[nl + 1..]
+                }
+                None => &text[..],
+            };
+            if at_line_start {
+                width = indent_width(width, line, tab_width);
+            }
+        } else {
+            if at_line_start {
+                if width > *indents.last().unwrap() {
+                    indents.push(width);
+                    result.push((SmolStr::from("INDENT"), String::new()));
+                } else {
+                    while width < *indents.last().unwrap() {
+                        indents.pop();
+                        result.push((SmolStr::from("DEDENT"), String::new()));
+                    }
+                    ensure!(
+                        width == *indents.last().unwrap(),
+                        Lex,
+                        "Dedent to width {} does not match any outer indentation level",
+                        width
+                    );
+                }
+                at_line_start = false;
+            }
+            if text.ends_with('\n') {
+                at_line_start = true;
+                width = 0;
+            }
+        }
+        result.push((token, text));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RuleBuilder;
+
+    fn tokenize(src: &str, input: &str) -> Vec<(SmolStr, String)> {
+        Lexer::from_source(src).unwrap().tokenize(input).unwrap()
+    }
+
+    fn tokens(expected: &[(&str, &str)]) -> Vec<(SmolStr, String)> {
+        expected
+            .iter()
+            .map(|(token, text)| (SmolStr::from(*token), String::from(*text)))
+            .collect()
+    }
+
+    const INDENTED: &str = r#"
+token NAME = ([a-z])+;
+token NL = "\n";
+token WS = ([ \t])+;
+"#;
+
+    #[test]
+    fn indentation_tokens() {
+        let lexed = tokenize(INDENTED, "a\n  b\n    c\n  d\ne\n");
+        let indented = insert_indentation(lexed, 4, "_EOF").unwrap();
+        let names: Vec<&str> = indented
+            .iter()
+            .map(|(token, _)| token.as_str())
+            .filter(|token| !matches!(*token, "NL" | "WS"))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "NAME", "INDENT", "NAME", "INDENT", "NAME", "DEDENT", "NAME", "DEDENT", "NAME",
+                "_EOF"
+            ]
+        );
+    }
+
+    #[test]
+    fn indentation_dedents_at_eof() {
+        let lexed = tokenize(INDENTED, "a\n b\n  c");
+        let indented = insert_indentation(lexed, 4, "_EOF").unwrap();
+        assert_eq!(
+            indented[indented.len() - 3..],
+            tokens(&[("DEDENT", ""), ("DEDENT", ""), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn indentation_tab_width() {
+        // A tab reaches the next multiple of the tab width, so " \t" and
+        // "\t" indent equally.
+        let lexed = tokenize(INDENTED, "a\n\tb\n \tc\n");
+        let indented = insert_indentation(lexed, 8, "_EOF").unwrap();
+        let count = |name: &str| indented.iter().filter(|(token, _)| token == name).count();
+        assert_eq!(count("INDENT"), 1);
+        assert_eq!(count("DEDENT"), 1);
+    }
+
+    #[test]
+    fn indentation_rejects_unmatched_dedent() {
+        let lexed = tokenize(INDENTED, "a\n    b\n  c\n");
+        assert!(insert_indentation(lexed, 4, "_EOF").is_err());
+    }
+
+    #[test]
+    fn shortest_match_mode() {
+        let longest = "token A = \"a\";\ntoken AB = \"ab\";\ntoken B = \"b\";\n";
+        let shortest = format!("match = shortest;\n{}", longest);
+        assert_eq!(
+            tokenize(longest, "ab"),
+            tokens(&[("AB", "ab"), ("_EOF", "")])
+        );
+        assert_eq!(
+            tokenize(&shortest, "ab"),
+            tokens(&[("A", "a"), ("B", "b"), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn tokenize_uses_maximal_munch() {
+        let src = r#"token ARROW = "->";
+token MINUS = "-";
+token GT = ">";
+token NUM = ([0-9])+;
+"#;
+        assert_eq!(
+            tokenize(src, "1->-2>"),
+            tokens(&[
+                ("NUM", "1"),
+                ("ARROW", "->"),
+                ("MINUS", "-"),
+                ("NUM", "2"),
+                ("GT", ">"),
+                ("_EOF", ""),
+            ])
+        );
+        assert_eq!(tokenize(src, ""), tokens(&[("_EOF", "")]));
+    }
+
+    #[test]
+    fn tokenize_backtracks_to_last_accept() {
+        // "abc" is only a prefix of "abcd", so the DFA traps at 'x' and has
+        // to fall back to the last accepting position after "ab".
+        let src =
+            "token AB = \"ab\";\ntoken ABCD = \"abcd\";\ntoken C = \"c\";\ntoken X = \"x\";\n";
+        assert_eq!(
+            tokenize(src, "abcxabcd"),
+            tokens(&[
+                ("AB", "ab"),
+                ("C", "c"),
+                ("X", "x"),
+                ("ABCD", "abcd"),
+                ("_EOF", "")
+            ])
+        );
+    }
+
+    #[test]
+    fn tokenize_rejects_unrecognized_input() {
+        let lexer = Lexer::from_source("token A = \"a\";\n").unwrap();
+        assert!(lexer.tokenize("aab").is_err());
+        assert_eq!(
+            lexer.tokenize("aa").unwrap(),
+            tokens(&[("A", "a"), ("A", "a"), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn case_insensitive_grammar() {
+        let src = r#"options { case_insensitive = true; }
+token IF = "if";
+case_sensitive token ELSE = "else";
+token NAME = ([a-z])+;
+skip token WS = ([ ])+;
+"#;
+        let names: Vec<SmolStr> = tokenize(src, "If iF else ELSE eLsE Name")
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+        assert_eq!(names, ["IF", "IF", "ELSE", "NAME", "NAME", "NAME", "_EOF"]);
+    }
+
+    #[test]
+    fn internal_tokens_take_part_in_disambiguation() {
+        let src = r#"internal token RESERVED = "goto";
+internal(NAME) token KEYWORD = "let";
+token NAME = ([a-z])+;
+skip token WS = ([ ])+;
+"#;
+        assert_eq!(
+            tokenize(src, "gotos goto let"),
+            tokens(&[
+                ("NAME", "gotos"),
+                ("_ERR", "goto"),
+                ("NAME", "let"),
+                ("_EOF", "")
+            ])
+        );
+    }
+
+    fn same_dfa(a: &Lexer, b: &Lexer) -> bool {
+        a.get_states() == b.get_states()
+            && a.get_alphabet() == b.get_alphabet()
+            && a.dfa_table() == b.dfa_table()
+    }
+
+    #[test]
+    fn builder_matches_parsed_grammar() {
+        let parsed = Lexer::from_source(
+            r#"token IF = "if";
+token IDENT = [a-z_] ([a-z0-9_])*;
+token OP = ("+" | "-");
+skip token WS = ([ ])+;
+"#,
+        )
+        .unwrap();
+        let grammar = Grammar::from_rules(vec![
+            RuleBuilder::token("IF").literal("if").build(),
+            RuleBuilder::token("IDENT")
+                .set(&['_'], &[('a', 'z')])
+                .set(&['_'], &[('a', 'z'), ('0', '9')])
+                .zero_or_more()
+                .build(),
+            RuleBuilder::token("OP")
+                .alternatives(vec![
+                    Element::Literal { lit: "+".into() },
+                    Element::Literal { lit: "-".into() },
+                ])
+                .build(),
+            RuleBuilder::token("WS")
+                .set(&[' '], &[])
+                .one_or_more()
+                .skip()
+                .build(),
+        ])
+        .unwrap();
+        let built = Lexer::from_grammar(&grammar).unwrap();
+        assert!(same_dfa(&parsed, &built));
+    }
+
+    #[test]
+    fn unmatchable_tokens() {
+        let shadowed = Lexer::from_source(
+            "token NAME = ([a-z])+;\ntoken IF = \"if\";\ntoken NUM = ([0-9])+;\n",
+        )
+        .unwrap();
+        assert_eq!(shadowed.unmatchable_tokens(), ["IF"]);
+        let empty =
+            Lexer::from_source("token NAME = ([a-z])+;\ntoken NONE = [^\\u{0}-\\u{10FFFF}];\n")
+                .unwrap();
+        assert_eq!(empty.unmatchable_tokens(), ["NONE"]);
+        let fine = Lexer::from_source("token IF = \"if\";\ntoken NAME = ([a-z])+;\n").unwrap();
+        assert!(fine.unmatchable_tokens().is_empty());
+    }
+
+    #[test]
+    fn malformed_terminals_are_errors() {
+        // Grammars changed after parsing skip the parser's checks, building
+        // the lexer reports what it can't handle instead of panicking.
+        let literal = |lit: &str| Element::Literal { lit: lit.into() };
+        for (element, mode, expected) in [
+            (
+                Element::Group {
+                    subelems: Vec::new(),
+                },
+                None,
+                "Token 'A' contains an empty group",
+            ),
+            (
+                Element::Group {
+                    subelems: vec![literal("a"), literal("")],
+                },
+                None,
+                "Token 'A' contains an empty literal",
+            ),
+            (
+                Element::Rule {
+                    var: None,
+                    name: "MISSING".into(),
+                },
+                None,
+                "'MISSING' is referenced, but no rule defines it",
+            ),
+            (
+                literal("a"),
+                Some("STRING"),
+                "Token 'A' belongs to mode 'STRING', which isn't declared",
+            ),
+        ] {
+            let mut grammar = rules::parse_str("token A = \"a\";\ntoken B = \"b\";\n").unwrap();
+            grammar.rules[0].element = element;
+            grammar.rules[0].mode = mode.map(SmolStr::new);
+            let err = Lexer::from_grammar(&grammar).err().unwrap();
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn tokens_reference_tokens() {
+        let src = "token FLOAT = INT \".\" INT;\ntoken INT = ([0-9])+;\ntoken WS = \" \";\n";
+        assert_eq!(
+            tokenize(src, "2.5 10 10.25"),
+            tokens(&[
+                ("FLOAT", "2.5"),
+                ("WS", " "),
+                ("INT", "10"),
+                ("WS", " "),
+                ("FLOAT", "10.25"),
+                ("_EOF", ""),
+            ])
+        );
+        let err = Lexer::from_source("token A = \"a\" (A)?;\n").err().unwrap();
+        assert_eq!(err.to_string(), "Token 'A' references itself");
+    }
+
+    const HEADINGS: &str = r##"token HEADING = ^"#" ([a-z])+;
+token HASH = "#";
+token NAME = ([a-z])+;
+token WS = ([ ])+;
+token NL = "\n";
+"##;
+
+    #[test]
+    fn line_start_anchor() {
+        assert_eq!(
+            tokenize(HEADINGS, "#a b#c\n#d\n #e"),
+            tokens(&[
+                ("HEADING", "#a"),
+                ("WS", " "),
+                ("NAME", "b"),
+                ("HASH", "#"),
+                ("NAME", "c"),
+                ("NL", "\n"),
+                ("HEADING", "#d"),
+                ("NL", "\n"),
+                ("WS", " "),
+                ("HASH", "#"),
+                ("NAME", "e"),
+                ("_EOF", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn repetitions_nest_in_groups_and_alternatives() {
+        let lexer =
+            Lexer::from_source("token A = (\"ab\")+ \"c\";\ntoken B = ((\"a\" | \"b\"))* \"c\";\n")
+                .unwrap();
+        let start = lexer.start_state();
+        let accepts = |input: &str| {
+            let state = input
+                .chars()
+                .fold(start, |state, c| lexer.step(state, c as u32));
+            lexer.accept(state).map(|name| name.to_string())
+        };
+        for (input, expected) in [
+            ("abc", Some("A")),
+            ("ababc", Some("A")),
+            ("c", Some("B")),
+            ("abac", Some("B")),
+            ("bbc", Some("B")),
+            ("ab", None),
+            ("abcc", None),
+            ("acb", None),
+        ] {
+            assert_eq!(accepts(input).as_deref(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn single_trap_state() {
+        for src in [
+            "token IF = \"if\";\ntoken ELSE = \"else\";\ntoken NUM = ([0-9])+;\ntoken OP = [+*/-];\n",
+            // No transition is dead here, so the trap is unreachable.
+            "token ANY = (.)+;\n",
+        ] {
+            let lexer = Lexer::from_source(src).unwrap();
+            let traps: Vec<usize> = lexer
+                .get_states()
+                .iter()
+                .enumerate()
+                .filter(|(_, state)| matches!(state, Some(name) if *name == "_TRAP"))
+                .map(|(i, _)| i)
+                .collect();
+            assert_eq!(traps, [lexer.get_trap()], "{}", src);
+        }
+    }
+
+    #[test]
+    fn dfa_table_agrees_with_connections() {
+        let lexer = Lexer::from_source(
+            "token IF = \"if\";\ntoken ID = ([a-z])+;\ntoken NUM = ([0-9])+;\nskip token WS = (\" \")+;\n",
+        )
+        .unwrap();
+        let alphabet = lexer.get_alphabet();
+        let table = lexer.dfa_table();
+        assert_eq!(table.len(), lexer.get_states().len());
+        for (state, row) in table.iter().enumerate() {
+            assert_eq!(row.len(), alphabet.len());
+            let connections = lexer.get_connections(state);
+            for (class, &target) in row.iter().enumerate() {
+                let (start, end) = alphabet[class];
+                let expected = connections
+                    .iter()
+                    .find(|&&(s, e, _)| s == start && e == end)
+                    .map_or(lexer.get_trap(), |&(_, _, target)| target);
+                assert_eq!(target, expected, "state {} class {}", state, class);
+            }
+        }
+    }
+
+    fn chunked(
+        lexer: &Lexer,
+        input: &[u8],
+        size: usize,
+        bulk_errors: bool,
+    ) -> Result<Vec<(SmolStr, String)>> {
+        let mut stream = lexer.chunked(bulk_errors);
+        let mut tokens = Vec::new();
+        for chunk in input.chunks(size) {
+            tokens.extend(stream.feed(chunk)?);
+        }
+        tokens.extend(stream.finish()?);
+        Ok(tokens)
+    }
+
+    #[test]
+    fn chunks_lex_like_the_whole_input() {
+        let lexer = Lexer::from_source(
+            "token ARROW = \"->\";\ntoken MINUS = \"-\";\ntoken NAME = ([a-z\u{e9}])+;\ntoken WS = ([ \\n])+;\n",
+        )
+        .unwrap();
+        let input = "caf\u{e9} -> x - -> \u{e9}t\u{e9}\n";
+        let whole = lexer.tokenize(input).unwrap();
+        for size in 1..=input.len() {
+            assert_eq!(
+                chunked(&lexer, input.as_bytes(), size, false).unwrap(),
+                whole,
+                "{}",
+                size
+            );
+        }
+
+        let input = "ab ?! cd";
+        let whole = lexer.tokenize_bulk(input).unwrap();
+        assert_eq!(chunked(&lexer, input.as_bytes(), 1, true).unwrap(), whole);
+        assert!(chunked(&lexer, "ab\u{e9}".as_bytes(), 1, false).is_ok());
+        let err = chunked(&lexer, &"ab\u{e9}".as_bytes()[..3], 1, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Input ends inside a UTF-8 sequence at offset 2"
+        );
+    }
+
+    #[test]
+    fn folded_keywords() {
+        let lexer = Lexer::from_source(
+            "token IF = \"if\";\ntoken ELSE = \"else\";\ntoken WHILE = \"while\";\ntoken RETURN = \"return\";\ntoken NAME = ([a-z])+;\ntoken ARROW = \"->\";\n",
+        )
+        .unwrap();
+        let folded = lexer.fold_keywords().unwrap();
+        assert!(
+            folded.get_states().len() * 3 < lexer.get_states().len(),
+            "{} vs {} states",
+            folded.get_states().len(),
+            lexer.get_states().len()
+        );
+        // ARROW doesn't overlap NAME, so it stays in the DFA.
+        let names: Vec<(&str, &str)> = folded
+            .get_folded()
+            .iter()
+            .map(|(keyword, host)| (keyword.as_str(), host.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("IF", "NAME"),
+                ("ELSE", "NAME"),
+                ("WHILE", "NAME"),
+                ("RETURN", "NAME")
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_with_the_same_language() {
+        let grammar = rules::parse_str(
+            "token NUM = ([0-9])+;\ntoken DIGITS = [0-9] ([0-9])*;\ntoken NAME = ([a-z])+;\ntoken IF = \"if\";\ntoken INT = ([0-9])+;\n",
+        )
+        .unwrap();
+        let terminals = prepare_terminals(&grammar).unwrap();
+        let alphabet = construct_alphabet(terminals.iter(), false);
+        let (_, _, identical) = construct_dfa(terminals.iter(), &alphabet, &[], &[]).unwrap();
+        // IF is shadowed by NAME, but NAME matches more.
+        assert_eq!(identical, [vec!["NUM", "DIGITS", "INT"]]);
+    }
+
+    #[test]
+    fn nullable_tokens_are_rejected() {
+        let err = Lexer::from_source("token ID = ([a-z])*;\n").err().unwrap();
+        assert_eq!(err.to_string(), "Token 'ID' matches the empty string");
+        let err = Lexer::from_source("token A = \"a\";\ntoken OPT = (\"b\")?;\n")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Token 'OPT' matches the empty string");
+        assert!(Lexer::from_source("token ID = ([a-z])+;\n").is_ok());
+    }
+
+    #[test]
+    fn two_modes() {
+        let src = r#"push(STR) token OPEN = "\"";
+token NAME = ([a-z])+;
+skip token WS = ([ ])+;
+mode STR {
+    token TEXT = ([^"])+;
+    pop token CLOSE = "\"";
+}
+"#;
+        assert_eq!(
+            tokenize(src, "ab \"cd ef\" gh"),
+            tokens(&[
+                ("NAME", "ab"),
+                ("OPEN", "\""),
+                ("TEXT", "cd ef"),
+                ("CLOSE", "\""),
+                ("NAME", "gh"),
+                ("_EOF", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn unexported_tokens_lex_as_errors() {
+        let src = "export token NAME = ([a-z])+;\ntoken NUM = ([0-9])+;\nskip token WS = ([ ])+;\n";
+        assert_eq!(
+            tokenize(src, "ab 12 cd"),
+            tokens(&[("NAME", "ab"), ("_ERR", "12"), ("NAME", "cd"), ("_EOF", "")])
+        );
+        let all = "token NAME = ([a-z])+;\ntoken NUM = ([0-9])+;\nskip token WS = ([ ])+;\n";
+        assert_eq!(
+            tokenize(all, "ab 12"),
+            tokens(&[("NAME", "ab"), ("NUM", "12"), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn class_references_in_sets() {
+        let referenced = Lexer::from_source(
+            r#"class ALPHA = [a-zA-Z];
+class ALNUM = [\{ALPHA}0-9];
+token DIGIT = [0-9];
+token IDENT = [\{ALPHA}_] ([\{ALNUM}_])*;
+token NOT_DIGIT = [^\{DIGIT}\{ALPHA}_];
+"#,
+        )
+        .unwrap();
+        let written = Lexer::from_source(
+            r#"token DIGIT = [0-9];
+token IDENT = [a-zA-Z_] ([a-zA-Z0-9_])*;
+token NOT_DIGIT = [^0-9a-zA-Z_];
+"#,
+        )
+        .unwrap();
+        assert!(same_dfa(&referenced, &written));
+    }
+
+    #[test]
+    fn class_references_need_sets() {
+        let err = Lexer::from_source("token AB = \"ab\";\ntoken X = [\\{AB}];\n")
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("'AB' is referenced in a set, but isn't a character class"));
+
+        let err =
+            Lexer::from_source("class A = [a\\{B}];\nclass B = [b\\{A}];\ntoken X = [\\{A}];\n")
+                .err()
+                .unwrap();
+        assert!(err.to_string().contains("references itself"));
+    }
+
+    #[test]
+    fn bulk_errors_coalesce_unmatched_runs() {
+        let lexer = Lexer::from_source(INDENTED).unwrap();
+        assert!(lexer.tokenize("ab ?!?# cd").is_err());
+        assert_eq!(
+            lexer.tokenize_bulk("ab ?!?# cd").unwrap(),
+            tokens(&[
+                ("NAME", "ab"),
+                ("WS", " "),
+                ("_ERR", "?!?#"),
+                ("WS", " "),
+                ("NAME", "cd"),
+                ("_EOF", "")
+            ])
+        );
+        assert_eq!(
+            lexer.tokenize_bulk("?!").unwrap(),
+            tokens(&[("_ERR", "?!"), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn nonterminals_reject_raw_sets() {
+        let err = Lexer::from_source("token A = \"a\";\nnonterm S = A [x] -> S();\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Nonterminal 'S' contains a raw set; define it as a token"
+        );
+
+        // Literals are lifted into tokens of their own instead.
+        let grammar = Grammar::from_rules(vec![
+            RuleBuilder::token("A").literal("a").build(),
+            RuleBuilder::nonterm("S", "S", &[])
+                .rule("A")
+                .literal("b")
+                .build(),
+        ])
+        .unwrap();
+        let lexer = Lexer::from_grammar(&grammar).unwrap();
+        let texts: Vec<String> = lexer
+            .tokenize("ab")
+            .unwrap()
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+        assert_eq!(texts, ["a", "b", ""]);
+    }
+
+    #[test]
+    fn committed_states() {
+        let lexer = Lexer::from_source(
+            "token NAME = ([a-z])+;\ntoken HASH = \"#\";\ntoken INCLUDE = \"#include\";\n",
+        )
+        .unwrap();
+        let committed = lexer.get_committed_states();
+        let walk = |text: &str| {
+            text.chars().fold(lexer.start_state(), |state, ch| {
+                lexer.step(state, ch as u32)
+            })
+        };
+        // "#" may still end as HASH, but after "#i" only INCLUDE can match.
+        assert!(!committed.contains(&lexer.start_state()));
+        assert!(!committed.contains(&walk("#")));
+        for prefix in ["#i", "#inc", "#includ"] {
+            assert!(committed.contains(&walk(prefix)), "{}", prefix);
+            assert_eq!(lexer.accept(walk(prefix)), None);
+        }
+        assert!(committed.contains(&walk("#include")));
+        assert!(committed.contains(&walk("name")));
+        assert!(!committed.contains(&lexer.get_trap()));
+    }
+
+    #[test]
+    fn step_follows_the_dfa_table() {
+        let lexer = Lexer::from_source(
+            "token NAME = ([a-z_])+;\ntoken NUM = ([0-9])+;\ntoken OP = [+\\-*/];\nskip token WS = ([ \\n])+;\n",
+        )
+        .unwrap();
+        let table = lexer.dfa_table();
+        for (state, row) in table.iter().enumerate() {
+            for (class, &(first, last)) in lexer.get_alphabet().iter().enumerate() {
+                assert_eq!(lexer.step(state, first), row[class]);
+                assert_eq!(lexer.step(state, last), row[class]);
+            }
+        }
+        assert_eq!(lexer.step(table.len(), 'a' as u32), lexer.get_trap());
+    }
+
+    fn matches(lexer: &Lexer, input: &str) -> bool {
+        let mut state = lexer.start_state();
+        for c in input.chars() {
+            state = lexer.step(state, c as u32);
+        }
+        lexer.accept(state).is_some()
+    }
+
+    #[test]
+    fn bounded_repetition_counts() {
+        let cases = [
+            ("{3}", 3, Some(3)),
+            ("{2,4}", 2, Some(4)),
+            ("{2,}", 2, None),
+            ("{0,}", 0, None),
+            ("{0,2}", 0, Some(2)),
+        ];
+        for (bounds, min, max) in cases {
+            let lexer =
+                Lexer::from_source(&format!("token A = \"x\" (\"a\"){};\n", bounds)).unwrap();
+            for count in 0..8 {
+                let expected = count >= min && max.is_none_or(|max| count <= max);
+                let input = format!("x{}", "a".repeat(count));
+                assert_eq!(matches(&lexer, &input), expected, "{} {}", bounds, count);
+            }
+        }
+    }
+
+    #[test]
+    fn escaped_literal_is_one_transition() {
+        let lexer = Lexer::from_source("token TAB = \"\\t\";\n").unwrap();
+        let start = lexer.get_connections(lexer.start_state());
+        let leaving: Vec<(u32, u32)> = start
+            .iter()
+            .filter(|&&(_, _, end)| end != lexer.get_trap())
+            .map(|&(r0, r1, _)| (r0, r1))
+            .collect();
+        assert_eq!(leaving, [(9, 9)]);
+        let end = lexer.step(lexer.start_state(), 9);
+        assert_eq!(lexer.accept(end).map(SmolStr::as_str), Some("TAB"));
+        assert!(lexer
+            .get_connections(end)
+            .iter()
+            .all(|&(_, _, next)| next == lexer.get_trap()));
+    }
+
+    #[test]
+    fn peek_n_does_not_consume() {
+        let lexer = Lexer::from_source(INDENTED).unwrap();
+        let mut stream = lexer.stream("ab cd", false, 2);
+        let peeked: Vec<(SmolStr, String)> =
+            stream.peek_n(2).unwrap().into_iter().cloned().collect();
+        assert_eq!(peeked, tokens(&[("NAME", "ab"), ("WS", " ")]));
+        assert_eq!(stream.next_token().unwrap(), peeked[0]);
+        assert_eq!(stream.next_token().unwrap(), peeked[1]);
+        assert!(stream.peek_n(3).is_err());
+        // Past the end, every further token is _EOF.
+        let peeked: Vec<(SmolStr, String)> =
+            stream.peek_n(2).unwrap().into_iter().cloned().collect();
+        assert_eq!(peeked, tokens(&[("NAME", "cd"), ("_EOF", "")]));
+        assert_eq!(stream.next_token().unwrap(), peeked[0]);
+        assert_eq!(stream.next_token().unwrap(), peeked[1]);
+        assert_eq!(stream.next_token().unwrap(), peeked[1]);
+    }
+
+    #[test]
+    fn exclusive_tokens_must_not_overlap() {
+        let error = Lexer::from_source(
+            r#"token KW = "if";
+token ID = ([a-z])+;
+assert exclusive KW, ID;
+"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Tokens [\"KW\", \"ID\"] are asserted to be exclusive, but all of them match \"if\""
+        );
+        Lexer::from_source(
+            r#"token KW = "if";
+token NUM = ([0-9])+;
+assert exclusive KW, NUM;
+"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn exclusivity_conflicts_show_the_shortest_example() {
+        let error = Lexer::from_source(
+            r#"token NUM = ([0-9])+;
+token A = "x" ([a-z])+;
+token B = ([a-z])+ "yz";
+token C = ([a-z])* "zz";
+assert exclusive A, B, C;
+"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Tokens [\"A\", \"B\"] are asserted to be exclusive, but all of them match \"xyz\""
+        );
+    }
+
+    #[test]
+    fn compact_alphabet() {
+        let rules = "token NAME = ([a-z])+;\ntoken NUM = ([0-9])+;\n";
+        let full = Lexer::from_source(rules).unwrap();
+        assert_eq!(full.get_alphabet().last().unwrap().1, char::MAX as u32);
+        assert!(full.is_full_alphabet());
+
+        let compact = Lexer::from_source(&format!(
+            "options {{ compact_alphabet = true; }}\n{}",
+            rules
+        ))
+        .unwrap();
+        assert_eq!(compact.get_alphabet().last().unwrap().1, 'z' as u32);
+        assert!(!compact.is_full_alphabet());
+        assert!(compact.get_alphabet().len() < full.get_alphabet().len());
+        // Input beyond the alphabet goes straight to the trap.
+        assert_eq!(
+            compact.step(compact.start_state(), '\u{e9}' as u32),
+            compact.get_trap()
+        );
+        assert_eq!(
+            compact.tokenize("ab 12").err().unwrap().to_string(),
+            full.tokenize("ab 12").err().unwrap().to_string()
+        );
+        assert_eq!(
+            compact.tokenize("ab12").unwrap(),
+            full.tokenize("ab12").unwrap()
+        );
+
+        // A negated set matches up to char::MAX, so the alphabet stays full.
+        let negated =
+            Lexer::from_source("options { compact_alphabet = true; }\ntoken NOT_A = ([^a])+;\n")
+                .unwrap();
+        assert!(negated.is_full_alphabet());
+    }
+
+    #[test]
+    fn alphabet_partitions_code_points() {
+        for src in [
+            "token NAME = ([a-z])+;\ntoken NUM = ([0-9])+;\ntoken WS = [ \\t\\n];\n",
+            "token ANY = (.)+;\n",
+            "token SMILE = \"\\u{1F600}\";\ntoken MAX = [\\u{10FFFF}];\n",
+            "token NOT_X = ([^x])+;\ntoken X = \"x\";\n",
+        ] {
+            let lexer = Lexer::from_source(src).unwrap();
+            let alphabet = lexer.get_alphabet();
+            assert_eq!(alphabet[0].0, 0, "{}", src);
+            assert_eq!(alphabet.last().unwrap().1, char::MAX as u32, "{}", src);
+            for &(start, end) in alphabet {
+                assert!(start <= end, "{}", src);
+            }
+            for pair in alphabet.windows(2) {
+                assert_eq!(pair[0].1 + 1, pair[1].0, "{}", src);
+            }
+        }
+        // NUL has a class even when no set names a code point.
+        let any = Lexer::from_source("token ANY = (.)+;\n").unwrap();
+        assert_eq!(
+            any.tokenize("a\0b").unwrap(),
+            tokens(&[("ANY", "a\0b"), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn negative_lookahead() {
+        let src = "token NUM = ([0-9])+ !([a-z]);\ntoken WORD = ([0-9a-z])+;\ntoken WS = \" \";\n";
+        assert_eq!(
+            tokenize(src, "12 34"),
+            tokens(&[("NUM", "12"), ("WS", " "), ("NUM", "34"), ("_EOF", "")])
+        );
+        // The lookahead matches after "12", so NUM is rejected there.
+        assert_eq!(
+            tokenize(src, "12ab 3"),
+            tokens(&[("WORD", "12ab"), ("WS", " "), ("NUM", "3"), ("_EOF", "")])
+        );
+        // Falls back to the last accepting position the lookahead allows.
+        let src = "token NUM = ([0-9])+ !(\"x\");\ntoken HEX = [0-9] \"x\";\n";
+        assert_eq!(
+            tokenize(src, "123x"),
+            tokens(&[("NUM", "12"), ("HEX", "3x"), ("_EOF", "")])
+        );
+
+        let err = Lexer::from_source("token NUM = ([0-9])+ !(([a-z])*);\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "The lookahead of token 'NUM' matches the empty string, so it would reject every match"
+        );
+    }
+
+    #[test]
+    fn word_boundary() {
+        let src = "token RETURN = \"return\" \\b;\ntoken LETTER = [a-z];\ntoken WS = \" \";\n";
+        assert_eq!(
+            tokenize(src, "return returns"),
+            tokens(&[
+                ("RETURN", "return"),
+                ("WS", " "),
+                ("LETTER", "r"),
+                ("LETTER", "e"),
+                ("LETTER", "t"),
+                ("LETTER", "u"),
+                ("LETTER", "r"),
+                ("LETTER", "n"),
+                ("LETTER", "s"),
+                ("_EOF", "")
+            ])
+        );
+        // Without the boundary the keyword matches inside the longer word.
+        let src = src.replace(" \\b", "");
+        assert_eq!(
+            tokenize(&src, "returns")[..2],
+            tokens(&[("RETURN", "return"), ("LETTER", "s")])
+        );
+        // Digits and underscores are word characters too, punctuation isn't.
+        let src = "token RETURN = \"return\" \\b;\ntoken LETTER = [a-z];\ntoken OTHER = [0-9_;];\n";
+        for (input, last) in [("return1", "1"), ("return_", "_")] {
+            let lexed = tokenize(src, input);
+            assert_eq!(lexed[0], (SmolStr::from("LETTER"), String::from("r")));
+            assert_eq!(lexed[6], (SmolStr::from("OTHER"), String::from(last)));
+        }
+        assert_eq!(
+            tokenize(src, "return;"),
+            tokens(&[("RETURN", "return"), ("OTHER", ";"), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn grammars_without_tokens_are_rejected() {
+        for src in ["nonterm S = x:S -> S(x);\n", "fragment token D = [0-9];\n"] {
+            let err = Lexer::from_source(src).err().unwrap();
+            assert!(matches!(err, PargeError::Grammar(_)), "{:?}", err);
+            assert_eq!(
+                err.to_string(),
+                "The grammar doesn't define any tokens, so there's nothing to lex"
+            );
+        }
+        // A skip token still makes a lexer, even if it never returns anything.
+        let lexer = Lexer::from_source("skip token WS = \" \";\n").unwrap();
+        assert_ne!(lexer.start_state(), lexer.get_trap());
+    }
+
+    #[test]
+    fn unicode_properties() {
+        let lexer = Lexer::from_source("token WORD = (\\p{L})+;\ntoken OTHER = \\P{L};\n").unwrap();
+        for word in [
+            "Straße",
+            "λόγος",
+            "слово",
+            "كلمة",
+            "漢字",
+            "ひらがな",
+            "한국어",
+        ] {
+            assert_eq!(
+                lexer.tokenize(word).unwrap(),
+                tokens(&[("WORD", word), ("_EOF", "")]),
+                "{}",
+                word
+            );
+        }
+        for other in ["7", "٣", "-", "!", " ", "。", "😀"] {
+            assert_eq!(
+                lexer.tokenize(other).unwrap(),
+                tokens(&[("OTHER", other), ("_EOF", "")]),
+                "{}",
+                other
+            );
+        }
+        // Properties work inside brackets too.
+        assert_eq!(
+            tokenize("token ID = ([\\p{L}\\p{Nd}_])+;\n", "x٣_ü1"),
+            tokens(&[("ID", "x٣_ü1"), ("_EOF", "")])
+        );
+        assert!(Lexer::from_source("token A = \\p{Klingon};\n").is_err());
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        let lexer = Lexer::from_source(
+            r#"token SMILE = "\u{1F600}";
+token UPPER = [\u{0041}-\u{005A}];
+"#,
+        )
+        .unwrap();
+        let smile = lexer.step(lexer.start_state(), 0x1F600);
+        assert_eq!(lexer.accept(smile).map(SmolStr::as_str), Some("SMILE"));
+        for c in ['A', 'M', 'Z'] {
+            let upper = lexer.step(lexer.start_state(), c as u32);
+            assert_eq!(lexer.accept(upper).map(SmolStr::as_str), Some("UPPER"));
+        }
+        for c in ['@', '[', 'a'] {
+            assert_eq!(lexer.step(lexer.start_state(), c as u32), lexer.get_trap());
+        }
+    }
+
+    #[test]
+    fn invalid_unicode_escapes() {
+        for escape in ["\\u{D800}", "\\u{110000}"] {
+            let error = Lexer::from_source(&format!("token T = \"{}\";\n", escape))
+                .err()
+                .unwrap();
+            assert!(error
+                .to_string()
+                .starts_with("Invalid unicode escape, expected a scalar value"));
+        }
+        let error = Lexer::from_source("token T = [\\u{DFFF}];\n")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("{DFFF}"));
+    }
+
+    #[test]
+    fn class_shorthands() {
+        let pairs = [
+            (r"\d", "[0-9]"),
+            (r"[\d_]", "[0-9_]"),
+            (r"\w", "[0-9A-Z_a-z]"),
+            (r"\s", r"[\t\n\r ]"),
+            (r"\D", "[^0-9]"),
+            (r"\S", r"[^\t\n\r ]"),
+        ];
+        for (shorthand, written) in pairs {
+            let lexer = |class| Lexer::from_source(&format!("token T = {};\n", class)).unwrap();
+            assert!(
+                same_dfa(&lexer(shorthand), &lexer(written)),
+                "{}",
+                shorthand
+            );
+        }
+    }
+
+    #[test]
+    fn reverse_table_reads_tokens_backwards() {
+        let lexer = Lexer::from_source("token AB = \"ab\";\ntoken C = ([c])+;\n").unwrap();
+        let (transitions, accepts, trap) = lexer.reverse_table();
+        let read_back = |text: &str| {
+            let mut state = 0;
+            for c in text.chars().rev() {
+                let class = lexer
+                    .get_alphabet()
+                    .partition_point(|&(_, last)| last < c as u32);
+                state = transitions[state][class];
+            }
+            state != trap && accepts[state]
+        };
+        assert!(read_back("ab"));
+        assert!(read_back("ccc"));
+        assert!(!read_back("b"));
+        assert!(!read_back("ba"));
+        assert!(!read_back("abc"));
+    }
+
+    #[test]
+    fn any_character() {
+        let lexer = Lexer::from_source(
+            r#"token STR = "\"" .* "\"";
+token NAME = ([a-z])+;
+"#,
+        )
+        .unwrap();
+        // "." keeps the alphabet split wherever other tokens need it.
+        for &(r0, r1) in lexer.get_alphabet() {
+            let letters = ('a' as u32..='z' as u32).contains(&r0);
+            assert_eq!(letters, ('a' as u32..='z' as u32).contains(&r1));
+            assert!(r0 == r1 || !(r0..=r1).contains(&('"' as u32)));
+        }
+        assert_eq!(
+            lexer.tokenize("\"a b\u{e9} \\ \\n\"x").unwrap(),
+            tokens(&[("STR", "\"a b\u{e9} \\ \\n\""), ("NAME", "x"), ("_EOF", "")])
+        );
+        // Maximal munch runs on to the last quote it can reach.
+        assert_eq!(
+            lexer.tokenize("\"a\"b\"").unwrap(),
+            tokens(&[("STR", "\"a\"b\""), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn insensitive_literals() {
+        let lexer = Lexer::from_source(
+            r#"token IF = i"if";
+token CAFE = i"caf\u{e9}";
+token NAME = ([a-zA-Z\u{c0}-\u{ff}])+;
+"#,
+        )
+        .unwrap();
+        let first = |input: &str| lexer.tokenize(input).unwrap()[0].0.clone();
+        for input in ["if", "IF", "If", "iF"] {
+            assert_eq!(first(input), "IF", "{}", input);
+        }
+        for input in ["caf\u{e9}", "CAF\u{c9}", "Caf\u{c9}"] {
+            assert_eq!(first(input), "CAFE", "{}", input);
+        }
+        assert_eq!(first("iff"), "NAME");
+        assert_eq!(first("cafe"), "NAME");
+    }
+
+    #[test]
+    fn grammars_share_an_alphabet() {
+        let words = rules::parse_str("token NAME = ([a-z])+;\ntoken WS = \" \";\n").unwrap();
+        let numbers = rules::parse_str("token INT = ([0-9])+;\ntoken WS = \" \";\n").unwrap();
+        let lexers = Lexer::from_grammars(&[
+            (SmolStr::new("words"), words),
+            (SmolStr::new("numbers"), numbers),
+        ])
+        .unwrap();
+        let (words, numbers) = (&lexers[0].1, &lexers[1].1);
+        assert_eq!(words.get_alphabet(), numbers.get_alphabet());
+        assert_eq!(
+            words.tokenize("ab c").unwrap(),
+            tokens(&[("NAME", "ab"), ("WS", " "), ("NAME", "c"), ("_EOF", "")])
+        );
+        assert_eq!(
+            numbers.tokenize("12 3").unwrap(),
+            tokens(&[("INT", "12"), ("WS", " "), ("INT", "3"), ("_EOF", "")])
+        );
+        assert!(words.tokenize("1").is_err());
+    }
+
+    #[test]
+    fn token_displays() {
+        let lexer = Lexer::from_source(
+            r#"token RPAREN = ")" display "')'";
+token LPAREN = "(";
+token NAME = ([a-z])+;
+"#,
+        )
+        .unwrap();
+        assert!(lexer.has_displays());
+        assert_eq!(lexer.display(&SmolStr::new("RPAREN")), "')'");
+        assert_eq!(lexer.display(&SmolStr::new("LPAREN")), "(");
+        assert_eq!(lexer.display(&SmolStr::new("NAME")), "NAME");
+    }
+
+    #[test]
+    fn fragments_are_inlined() {
+        let fragments = Lexer::from_source(
+            r#"fragment token DIGIT = [0-9];
+token INT = (DIGIT)+;
+token FLOAT = (DIGIT)+ "." (DIGIT)+;
+"#,
+        )
+        .unwrap();
+        let written = Lexer::from_source(
+            r#"token INT = ([0-9])+;
+token FLOAT = ([0-9])+ "." ([0-9])+;
+"#,
+        )
+        .unwrap();
+        assert!(same_dfa(&fragments, &written));
+        assert!(fragments
+            .get_states()
+            .into_iter()
+            .flatten()
+            .all(|token| token != "DIGIT"));
+    }
+
+    #[test]
+    fn skipped_tokens() {
+        let lexer = Lexer::from_source(
+            r#"token ID = ([a-z])+;
+skip token WS = ([ \t\r\n])+;
+"#,
+        )
+        .unwrap();
+        assert_eq!(lexer.get_skipped(), [&SmolStr::new("WS")]);
+        assert_eq!(
+            lexer.tokenize("a  b").unwrap(),
+            tokens(&[("ID", "a"), ("ID", "b"), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn accept_conflicts_follow_priority() {
+        let first = |src: &str, input: &str| tokenize(src, input)[0].0.clone();
+        // Declaration order decides between tokens of equal priority.
+        let declared = "token IF = \"if\";\ntoken ID = ([a-z])+;\n";
+        assert_eq!(first(declared, "if"), "IF");
+        assert_eq!(first(declared, "iff"), "ID");
+        let reversed = "token ID = ([a-z])+;\ntoken IF = \"if\";\n";
+        assert_eq!(first(reversed, "if"), "ID");
+        let prioritized = "token ID = ([a-z])+;\npriority(1) token IF = \"if\";\n";
+        assert_eq!(first(prioritized, "if"), "IF");
+        assert_eq!(first(prioritized, "i"), "ID");
+    }
+
+    #[test]
+    fn hex_and_octal_escapes() {
+        let lexer =
+            |element: &str| Lexer::from_source(&format!("token T = {};\n", element)).unwrap();
+        let plain = lexer(r#""A""#);
+        assert!(same_dfa(&lexer(r#""\x41""#), &plain));
+        assert!(same_dfa(&lexer(r#""\101""#), &plain));
+        let range = lexer("[A-Z]");
+        assert!(same_dfa(&lexer(r"[\x41-\x5a]"), &range));
+        assert!(same_dfa(&lexer(r"[\101-\132]"), &range));
+    }
+
+    // What a hand-built DFA accepts after reading the whole input.
+    fn accepts(dfa: &DFA, input: &str) -> Option<SmolStr> {
+        let mut state = dfa.starts[0];
+        for c in input.chars() {
+            state = dfa
+                .connections
+                .iter()
+                .find(|t| t.start == state && t.range.0 <= c as u32 && c as u32 <= t.range.1)
+                .unwrap()
+                .end;
+        }
+        dfa.states[state].accepting.clone()
+    }
+
+    // Alphabet classes for 'a', 'b' and 'c' and everything around them.
+    const ALPHABET: [(u32, u32); 5] = [
+        (0, 96),
+        (97, 97),
+        (98, 98),
+        (99, 99),
+        (100, char::MAX as u32),
+    ];
+
+    // A DFA over ALPHABET where every character not on an edge goes to the
+    // trap.
+    fn hand_built(accepting: &[Option<&str>], edges: &[(usize, char, usize)]) -> DFA {
+        let mut dfa = DFA::new();
+        for accepting in accepting {
+            dfa.add(State {
+                accepting: accepting.map(SmolStr::new),
+            });
+        }
+        let trap = accepting.iter().position(|a| *a == Some("_TRAP")).unwrap();
+        for state in 0..accepting.len() {
+            for range in ALPHABET {
+                let end = edges
+                    .iter()
+                    .find(|&&(start, c, _)| start == state && range.0 == c as u32)
+                    .map_or(trap, |&(_, _, end)| end);
+                dfa.connect_range(state, end, range);
+            }
+        }
+        dfa
+    }
+
+    #[test]
+    fn prune_drops_unreachable_and_dead_states() {
+        // "ab" is the only token; 3 loops on 'c' without ever accepting and 4
+        // accepts but has no incoming edge.
+        let dfa = hand_built(
+            &[None, None, Some("T"), None, Some("T"), Some("_TRAP")],
+            &[
+                (0, 'a', 1),
+                (1, 'b', 2),
+                (0, 'c', 3),
+                (3, 'c', 3),
+                (4, 'a', 2),
+            ],
+        );
+        let pruned = dfa.prune();
+        assert_eq!(pruned.states.len(), 4);
+        // Dead states now lead to the trap, which accepts nothing either.
+        let token = |dfa: &DFA, input: &str| accepts(dfa, input).filter(|name| name != "_TRAP");
+        for input in ["", "a", "ab", "abc", "c", "cc", "ccab", "b", "d"] {
+            assert_eq!(token(&dfa, input), token(&pruned, input), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn minimize_merges_equivalent_states() {
+        // "ac" | "bc" with a separate path per first character.
+        let dfa = hand_built(
+            &[None, None, None, Some("T"), Some("T"), Some("_TRAP")],
+            &[(0, 'a', 1), (0, 'b', 2), (1, 'c', 3), (2, 'c', 4)],
+        );
+        let minimal = dfa.minimize(&ALPHABET);
+        assert_eq!(minimal.states.len(), 4);
+        for input in ["", "a", "b", "ac", "bc", "cc", "acc", "ab", "d", "bcx"] {
+            assert_eq!(
+                accepts(&dfa, input),
+                accepts(&minimal, input),
+                "{:?}",
+                input
+            );
+        }
+        let lexer = Lexer::from_source("token T = \"ac\" | \"bc\";\n").unwrap();
+        assert_eq!(lexer.get_states().len(), 4);
+    }
+
+    #[test]
+    fn long_epsilon_chains() {
+        // Deep enough that a recursive closure would overflow the stack.
+        let count = 200_000;
+        let epsilon: Vec<Vec<usize>> = (0..count)
+            .map(|i| {
+                if i + 1 < count {
+                    vec![i + 1, 0]
+                } else {
+                    Vec::new()
+                }
+            })
+            .collect();
+        let mut closure = BTreeSet::from([0]);
+        epsilon_closure(&epsilon, &mut closure);
+        assert_eq!(closure.len(), count);
+
+        // Every optional adds epsilon edges around its literal.
+        let src = format!("token T = \"b\" {};\n", "(\"a\")? ".repeat(100));
+        let lexer = Lexer::from_source(&src).unwrap();
+        let input = format!("b{}", "a".repeat(100));
+        assert_eq!(
+            lexer.tokenize(&input).unwrap(),
+            tokens(&[("T", &input), ("_EOF", "")])
+        );
+    }
+
+    #[test]
+    fn many_dfa_states() {
+        // Remembering the last twelve characters takes 2^12 states, each
+        // found from the one before it.
+        let src = format!("token T = ([ab])* \"a\"{};\n", " [ab]".repeat(11));
+        let lexer = Lexer::from_source(&src).unwrap();
+        assert!(lexer.get_states().len() > 4096);
+        let input = format!("ba{}", "b".repeat(11));
+        assert_eq!(
+            lexer.tokenize(&input).unwrap()[0],
+            tokens(&[("T", &input)])[0]
+        );
+    }
+
+    #[test]
+    fn large_grammar_builds() {
+        // The same grammar as benches/grammars/keywords.pgrules, where
+        // construction time is measured.
+        let mut src = String::new();
+        for i in 0..100 {
+            src.push_str(&format!("token KW{} = \"kw{}x\";\n", i, i));
+            let c = char::from_u32(0x400 + i * 3).unwrap();
+            src.push_str(&format!(
+                "token SET{} = [{}-\\u{{{:x}}}];\n",
+                i,
+                c,
+                0x401 + i * 3
+            ));
+        }
+        src.push_str("token ID = ([a-z0-9])+;\ntoken WS = \" \";\n");
+        let lexer = Lexer::from_source(&src).unwrap();
+        assert!(lexer.get_alphabet().len() > 200);
+        assert_eq!(
+            lexer.tokenize("kw99x kw7\u{401}").unwrap(),
+            tokens(&[
+                ("KW99", "kw99x"),
+                ("WS", " "),
+                ("ID", "kw7"),
+                ("SET0", "\u{401}"),
+                ("_EOF", "")
+            ])
+        );
+    }
+
+    #[test]
+    fn dfa_json_round_trip() {
+        let src = r#"token IF = "if";
+token NAME = ([a-z\u{e9}])+;
+token NUM = ([0-9])+;
+skip token WS = ([ ])+;
+"#;
+        let grammar = rules::parse_str(src).unwrap();
+        let lexer = Lexer::from_grammar(&grammar).unwrap();
+        let mut json = Vec::new();
+        crate::codegen::json::gen_json(&lexer, &mut json).unwrap();
+        let loaded = Lexer::from_dfa_json(&grammar, &String::from_utf8(json).unwrap()).unwrap();
+        assert_eq!(loaded.get_states(), lexer.get_states());
+        assert_eq!(loaded.get_alphabet(), lexer.get_alphabet());
+        for state in 0..lexer.get_states().len() {
+            assert_eq!(loaded.get_connections(state), lexer.get_connections(state));
+        }
+        assert_eq!(
+            loaded.tokenize("if caf\u{e9} 12").unwrap(),
+            lexer.tokenize("if caf\u{e9} 12").unwrap()
+        );
+    }
+}
+
+// Checks the DFA against a direct walk of the NFA it was built from, on random
+// grammars and inputs. The seed is fixed so failures reproduce.
+#[cfg(test)]
+mod nfa_reference {
+    use super::*;
+    use crate::RuleBuilder;
+    use proptest::{prelude::*, test_runner::RngSeed};
+
+    const CHARS: [char; 4] = ['a', 'b', 'c', 'd'];
+
+    fn leaf() -> impl Strategy<Value = Element> {
+        let some_chars = || prop::collection::vec(prop::sample::select(&CHARS[..]), 1..3);
+        let range = prop::sample::select(vec![('a', 'b'), ('b', 'c'), ('a', 'c')]);
+        prop_oneof![
+            some_chars().prop_map(|chars| Element::Literal {
+                lit: chars.into_iter().collect::<String>().into(),
+            }),
+            (some_chars(), prop::collection::vec(range.clone(), 0..2)).prop_map(
+                |(chars, ranges)| Element::Set {
+                    chars,
+                    ranges,
+                    classes: Vec::new(),
+                }
+            ),
+            (some_chars(), prop::collection::vec(range, 0..2)).prop_map(|(chars, ranges)| {
+                Element::NegatedSet {
+                    chars,
+                    ranges,
+                    classes: Vec::new(),
+                }
+            }),
+        ]
+    }
+
+    fn element() -> impl Strategy<Value = Element> {
+        leaf().prop_recursive(3, 12, 3, |inner| {
+            prop_oneof![
+                inner.clone().prop_map(|inner| Element::OneOrMore {
+                    inner: Box::new(inner)
+                }),
+                inner.clone().prop_map(|inner| Element::ZeroOrMore {
+                    inner: Box::new(inner)
+                }),
+                inner.clone().prop_map(|inner| Element::Optional {
+                    inner: Box::new(inner)
+                }),
+                prop::collection::vec(inner.clone(), 1..4)
+                    .prop_map(|subelems| Element::Alternatives { subelems }),
+                prop::collection::vec(inner, 1..4).prop_map(|subelems| Element::Group { subelems }),
+            ]
+        })
+    }
+
+    // Every token ends in a leaf, so none of them matches the empty string.
+    fn grammar() -> impl Strategy<Value = Grammar> {
+        prop::collection::vec((element(), leaf(), 0..2u32), 1..5).prop_map(|tokens| {
+            let rules = tokens
+                .into_iter()
+                .enumerate()
+                .map(|(i, (element, last, priority))| {
+                    RuleBuilder::token(&format!("T{}", i))
+                        .element(element)
+                        .element(last)
+                        .priority(priority)
+                        .build()
+                })
+                .collect();
+            Grammar::from_rules(rules).unwrap()
+        })
+    }
+
+    fn input() -> impl Strategy<Value = String> {
+        prop::collection::vec(prop::sample::select(&CHARS[..]), 0..12)
+            .prop_map(|chars| chars.into_iter().collect())
+    }
+
+    // The longest match from pos, with ties going to the higher priority and
+    // then to the earlier token.
+    fn nfa_match<'a>(
+        nfa: &'a NfaGraph,
+        terminals: &[&Rule],
+        input: &str,
+        pos: usize,
+    ) -> Option<(&'a SmolStr, usize)> {
+        let closure = |mut states: BTreeSet<usize>| {
+            let mut pending: Vec<usize> = states.iter().copied().collect();
+            while let Some(state) = pending.pop() {
+                for &(_, end) in nfa.epsilon.iter().filter(|(start, _)| *start == state) {
+                    if states.insert(end) {
+                        pending.push(end);
+                    }
+                }
+            }
+            states
+        };
+        let mut states = closure(BTreeSet::from([nfa.entries[0]]));
+        let mut found = None;
+        for (i, c) in input[pos..].char_indices() {
+            let next = nfa
+                .ranges
+                .iter()
+                .filter(|(start, (low, high), _)| {
+                    states.contains(start) && (*low..=*high).contains(&(c as u32))
+                })
+                .map(|(_, _, end)| *end)
+                .collect();
+            states = closure(next);
+            if states.is_empty() {
+                break;
+            }
+            let winner = states
+                .iter()
+                .filter_map(|&state| nfa.states[state].as_ref())
+                .min_by_key(|name| rank(terminals, name));
+            if let Some(winner) = winner {
+                found = Some((winner, pos + i + c.len_utf8()));
+            }
+        }
+        found
+    }
+
+    fn nfa_tokenize(lexer: &Lexer, input: &str) -> Option<Vec<(SmolStr, String)>> {
+        let nfa = lexer.nfa_graph().unwrap();
+        let terminals: Vec<&Rule> = lexer.get_terminals().iter().collect();
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        while pos < input.len() {
+            let (token, end) = nfa_match(&nfa, &terminals, input, pos)?;
+            tokens.push((token.clone(), String::from(&input[pos..end])));
+            pos = end;
+        }
+        tokens.push((lexer.get_eof().clone(), String::new()));
+        Some(tokens)
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig {
+            cases: 512,
+            rng_seed: RngSeed::Fixed(1068),
+            failure_persistence: None,
+            ..ProptestConfig::default()
+        })]
+
+        #[test]
+        fn dfa_agrees_with_nfa(grammar in grammar(), inputs in prop::collection::vec(input(), 8)) {
+            let lexer = Lexer::from_grammar(&grammar).unwrap();
+            for input in inputs {
+                prop_assert_eq!(
+                    lexer.tokenize(&input).ok(),
+                    nfa_tokenize(&lexer, &input),
+                    "input {:?} for grammar\n{}",
+                    input,
+                    grammar
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,15 @@
+pub mod codegen;
+pub mod error;
+mod json;
+pub mod lexer;
+pub mod parser;
+pub mod rules;
+pub mod runtime;
+mod unicode;
+
+pub use error::PargeError;
+pub use lexer::Lexer;
+pub use parser::Parser;
+pub use rules::{
+    parse_file, parse_reader, parse_str, Element, Grammar, ModeAction, Rule, RuleBuilder,
+};
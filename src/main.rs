@@ -1,12 +1,18 @@
-use std::{fs::File, path::Path};
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
 
-use color_eyre::eyre::{bail, Result};
+use color_eyre::eyre::{bail, ensure, Result};
 use fern::colors::{Color, ColoredLevelConfig};
-use lexer::Lexer;
-
-mod codegen;
-mod lexer;
-mod rules;
+use parge::codegen::{
+    self, c::CConfig, cpp::CppConfig, go::GoConfig, java::JavaConfig, js::JsConfig,
+    python::PythonConfig, rust::RustConfig, Layout, Names,
+};
+use parge::{lexer, rules, rules::MatchMode, Lexer, Parser};
+use smol_str::SmolStr;
 
 fn main() -> Result<()> {
     color_eyre::install()?;
@@ -45,15 +51,24 @@ fn main() -> Result<()> {
                     std::fs::OpenOptions::new()
                         .write(true)
                         .create(true)
+                        .truncate(true)
                         .open("parge.log")?,
                 ),
         )
         .apply()?;
-    let matches = clap::Command::new("parge")
+    let matches = command().get_matches();
+    if matches.is_present("watch") {
+        return watch(&matches, WATCH_POLL, None);
+    }
+    run(&matches)
+}
+
+fn command() -> clap::Command<'static> {
+    clap::Command::new("parge")
         .arg(
             clap::Arg::new("rules")
                 .required(true)
-                .help("The path of the rules file"),
+                .help("The path of the rules file, or - to read them from stdin"),
         )
         .arg(
             clap::Arg::new("output")
@@ -64,46 +79,1489 @@ fn main() -> Result<()> {
         .arg(
             clap::Arg::new("lang")
                 .short('l')
-                .help("The language to generate")
-                .required(true)
+                .help("The languages to generate, separated by commas")
+                .required_unless_present_any(["tokenize", "repl", "emit", "check", "stats"])
                 .takes_value(true)
-                .possible_values(["cpp", "rust", "java"]),
+                .multiple_occurrences(true)
+                .use_value_delimiter(true)
+                .possible_values(["cpp", "c", "rust", "java", "python", "js", "ts", "go"]),
+        )
+        .arg(
+            clap::Arg::new("tokenize")
+                .long("tokenize")
+                .help("Tokenize the given input file instead of generating code")
+                .takes_value(true),
+        )
+        .arg(clap::Arg::new("repl").long("repl").help(
+            "Tokenize lines read from stdin; :reload re-reads the rules, :states dumps the DFA",
+        ))
+        .arg(
+            clap::Arg::new("check")
+                .long("check")
+                .help("Only validate the rules and build the lexer and parser, printing ok"),
+        )
+        .arg(
+            clap::Arg::new("indent")
+                .long("indent")
+                .help("Emit INDENT/DEDENT tokens, counting tabs as the given width")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("error-context")
+                .long("error-context")
+                .help("Report the line, column and line text of lexing errors"),
+        )
+        .arg(
+            clap::Arg::new("keyword-hash")
+                .long("keyword-hash")
+                .help("Emit a perfect-hash lookup from keyword text to its token"),
+        )
+        .arg(clap::Arg::new("fold-keywords").long("fold-keywords").help(
+            "Lex keywords as the identifier-like token they overlap and look them up afterwards",
+        ))
+        .arg(clap::Arg::new("serde").long("serde").help(
+            "Derive serde's Serialize and Deserialize for the token enums (rust only)",
+        ))
+        .arg(
+            clap::Arg::new("lex-trait")
+                .long("lex-trait")
+                .help("Implement parge::runtime::Lex for the lexer (rust only)"),
+        )
+        .arg(
+            clap::Arg::new("combine")
+                .long("combine")
+                .help("Also generate lexers for these rules files in the same lexer.rs, one module per file, sharing one alphabet (rust only)")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            clap::Arg::new("reverse")
+                .long("reverse")
+                .help("Add prev() to read the token before the current position (rust only)"),
+        )
+        .arg(
+            clap::Arg::new("impl-display")
+                .long("impl-display")
+                .help("Implement Display for the token enum (rust only)"),
+        )
+        .arg(
+            clap::Arg::new("bulk-errors")
+                .long("bulk-errors")
+                .help("Coalesce runs of unmatched input into a single _ERR token"),
+        )
+        .arg(
+            clap::Arg::new("recover")
+                .long("recover")
+                .help("Emit each unmatched character as its own _ERR token and keep lexing"),
+        )
+        .arg(
+            clap::Arg::new("lookahead")
+                .long("lookahead")
+                .help("Buffer up to the given number of tokens for peek and peek_n lookahead")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("max-error-run")
+                .long("max-error-run")
+                .help("Return a --bulk-errors run once it reaches the given number of code units (cpp and java)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("buffer-capacity")
+                .long("buffer-capacity")
+                .help("Initial capacity of the input buffer in code units (cpp and java, default 4096)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("validate-utf8")
+                .long("validate-utf8")
+                .help("Validate the whole input as UTF-8 before lexing (cpp only)"),
+        )
+        .arg(
+            clap::Arg::new("driver")
+                .long("driver")
+                .help("Also emit a main that prints the tokens read from stdin (cpp and java)"),
+        )
+        .arg(
+            clap::Arg::new("single-header")
+                .long("single-header")
+                .help("Emit the lexer as one header that can be included from several sources (cpp only)"),
+        )
+        .arg(
+            clap::Arg::new("file-name")
+                .long("file-name")
+                .help("Base name of the generated header and source, e.g. json_lexer (cpp only)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("token-type")
+                .long("token-type")
+                .help("Underlying integer type of the token enum (cpp only)")
+                .takes_value(true)
+                .possible_values([
+                    "int8_t", "uint8_t", "int16_t", "uint16_t", "int32_t", "uint32_t", "int64_t",
+                    "uint64_t",
+                ]),
+        )
+        .arg(clap::Arg::new("stable-token-ids").long("stable-token-ids").help(
+            "Number tokens by a hash of their name, so grammar edits don't renumber them (cpp only)",
+        ))
+        .arg(clap::Arg::new("token-counts").long("token-counts").help(
+            "Count how often the lexer returns each token, readable with token_count (cpp only)",
+        ))
+        .arg(clap::Arg::new("source-comments").long("source-comments").help(
+            "Comment accepting states with the rule and grammar line they match (cpp only)",
+        ))
+        .arg(
+            clap::Arg::new("normalize-newlines")
+                .long("normalize-newlines")
+                .help("Convert CRLF line endings in token text to LF"),
         )
-        .get_matches();
-    let language = matches.value_of("lang").unwrap();
+        .arg(clap::Arg::new("positions").long("positions").help(
+            "Record the line and column where each token starts, and its byte offset and length",
+        ))
+        .arg(
+            clap::Arg::new("namespace")
+                .long("namespace")
+                .help("Wrap the lexer in a C++ namespace or Java package")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("lexer-name")
+                .long("lexer-name")
+                .help("Name of the generated lexer class (default: Lexer)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("token-name")
+                .long("token-name")
+                .help("Name of the generated token enum (default: Token)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("table-driven")
+                .long("table-driven")
+                .help("Walk the DFA with a transition table instead of nested switches (cpp only)"),
+        )
+        .arg(
+            clap::Arg::new("utf16")
+                .long("utf16")
+                .help("Read UTF-16LE input and return UTF-16 token text (cpp only)"),
+        )
+        .arg(
+            clap::Arg::new("bytes")
+                .long("bytes")
+                .help("Treat each input byte as one Latin-1 character instead of decoding UTF-8"),
+        )
+        .arg(
+            clap::Arg::new("strip-bom")
+                .long("strip-bom")
+                .help("Skip a byte-order mark at the start of the input"),
+        )
+        .arg(
+            clap::Arg::new("tables-only")
+                .long("tables-only")
+                .help("Emit only the DFA tables and a description of how to walk them"),
+        )
+        .arg(
+            clap::Arg::new("emit")
+                .long("emit")
+                .help("Write a debugging view of the DFA instead of a lexer")
+                .takes_value(true)
+                .possible_values(["dot", "nfa-dot", "json", "first-follow", "rules-json"]),
+        )
+        .arg(
+            clap::Arg::new("match")
+                .long("match")
+                .help("Override the grammar's matching strategy")
+                .takes_value(true)
+                .possible_values(["longest", "shortest"]),
+        )
+        .arg(
+            clap::Arg::new("dfa")
+                .long("dfa")
+                .help("Load the DFA from a file written by --emit json instead of building it")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("stats")
+                .long("stats")
+                .help("Print the size of the DFA after building it"),
+        )
+        .arg(
+            clap::Arg::new("newline")
+                .long("newline")
+                .help("The line ending of the generated cpp and java files (default lf)")
+                .takes_value(true)
+                .possible_values(["lf", "crlf"]),
+        )
+        .arg(
+            clap::Arg::new("code-indent")
+                .long("code-indent")
+                .help("Indent the generated cpp and java code with the given number of spaces or with tab (default 4)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::new("watch")
+                .long("watch")
+                .help("Run again whenever the rules file or one of its imports changes"),
+        )
+        .arg(
+            clap::Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .help("Don't print the written files and DFA statistics after generating"),
+        )
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<()> {
     let output = matches
         .value_of("output")
-        .map(|p| Path::new(p))
+        .map(Path::new)
         .unwrap_or(Path::new("."));
     let rules = Path::new(matches.value_of("rules").unwrap());
+    let indent = match matches.value_of("code-indent") {
+        None => Layout::default().indent,
+        Some("tab") => "\t".to_string(),
+        Some(width) => {
+            let width = width.parse::<usize>()?;
+            ensure!(
+                (1..=8).contains(&width),
+                "--code-indent must be tab or a width from 1 to 8"
+            );
+            " ".repeat(width)
+        }
+    };
+    let layout = Layout {
+        indent,
+        newline: match matches.value_of("newline") {
+            Some("crlf") => "\r\n",
+            _ => "\n",
+        },
+    };
+    let bulk_errors = matches.is_present("bulk-errors");
+    let lookahead = matches
+        .value_of("lookahead")
+        .map(|k| k.parse::<usize>())
+        .transpose()?;
+    if let Some(lookahead) = lookahead {
+        ensure!(lookahead > 0, "Lookahead must be at least 1");
+    }
+    let max_error_run = matches
+        .value_of("max-error-run")
+        .map(|n| n.parse::<usize>())
+        .transpose()?;
+    if let Some(max_error_run) = max_error_run {
+        ensure!(max_error_run > 0, "--max-error-run must be at least 1");
+        ensure!(bulk_errors, "--max-error-run needs --bulk-errors");
+    }
+    let buffer_capacity = matches
+        .value_of("buffer-capacity")
+        .map(|n| n.parse::<usize>())
+        .transpose()?;
+    let validate_utf8 = matches.is_present("validate-utf8");
+    let bytes = matches.is_present("bytes");
+    ensure!(
+        !(bytes && (validate_utf8 || matches.is_present("utf16"))),
+        "--bytes can't be combined with --validate-utf8 or --utf16"
+    );
+    let strip_bom = matches.is_present("strip-bom");
+    ensure!(
+        !(strip_bom && bytes),
+        "--strip-bom can't be combined with --bytes"
+    );
+    let normalize_newlines = matches.is_present("normalize-newlines");
+    let indentation = matches
+        .value_of("indent")
+        .map(|w| w.parse::<usize>())
+        .transpose()?;
+    ensure!(
+        indentation != Some(0),
+        "--indent must be a tab width of at least 1"
+    );
 
-    let parsed_rules = rules::parse_file(rules)?;
-    let lexer = lexer::Lexer::from_rules(&parsed_rules)?;
+    let match_mode = matches.value_of("match").map(|mode| match mode {
+        "shortest" => MatchMode::Shortest,
+        _ => MatchMode::Longest,
+    });
 
-    match language {
-        "cpp" => generate_cpp(&lexer, output)?,
-        "java" => generate_java(&lexer, output)?,
-        l => bail!("Language currently not supported: {}", l),
+    let start = Instant::now();
+    let mut grammar = if rules == Path::new("-") {
+        ensure!(
+            !matches.is_present("repl"),
+            "--repl needs a rules file to reload, it can't read the rules from stdin"
+        );
+        rules::parse_reader(std::io::stdin())?
+    } else {
+        rules::parse_file(rules)?
+    };
+    if let Some(mode) = match_mode {
+        grammar.match_mode = mode;
+    }
+    if matches.value_of("emit") == Some("rules-json") {
+        generate_tables(
+            &grammar,
+            output,
+            "rules.json",
+            codegen::rules_json::gen_rules_json,
+        )?;
+        return Ok(());
     }
+    let lexer = match matches.value_of("dfa") {
+        Some(dfa) => Lexer::from_dfa_json(&grammar, &std::fs::read_to_string(dfa)?)?,
+        None => Lexer::from_grammar(&grammar)?,
+    };
+    let construction_time = start.elapsed();
+    ensure!(
+        indentation.is_none()
+            || ["INDENT", "DEDENT"]
+                .iter()
+                .all(|name| lexer.get_rule(name).is_none()),
+        "--indent emits INDENT and DEDENT tokens, so the rules can't define tokens with those names"
+    );
+
+    if matches.is_present("stats") {
+        print_stats(&lexer);
+        let action = ["lang", "tokenize", "repl", "emit", "check"];
+        if !action.iter().any(|arg| matches.is_present(arg)) {
+            return Ok(());
+        }
+    }
+
+    if matches.is_present("check") {
+        if grammar.rules.iter().any(|rule| !rule.is_terminal) {
+            Parser::from_grammar(&grammar)?;
+        }
+        println!("ok");
+        return Ok(());
+    }
+
+    if matches.is_present("repl") {
+        ensure!(!bytes, "--bytes isn't supported with --repl");
+        return repl(rules, lexer, bulk_errors, match_mode);
+    }
+
+    if let Some(input) = matches.value_of("tokenize") {
+        let input = std::fs::read(input)?;
+        let input = if bytes {
+            input.iter().map(|&b| b as char).collect()
+        } else {
+            match String::from_utf8(input) {
+                Ok(input) => input,
+                Err(e) if validate_utf8 => bail!(
+                    "Input is not valid UTF-8 at byte offset {}",
+                    e.utf8_error().valid_up_to()
+                ),
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let input = match input.strip_prefix('\u{feff}') {
+            Some(rest) if strip_bom => rest.to_string(),
+            _ => input,
+        };
+        let normalize = |token: &SmolStr, text: String| {
+            if normalize_newlines && token != "_ERR" {
+                text.replace("\r\n", "\n")
+            } else {
+                text
+            }
+        };
+        if let Some(lookahead) = lookahead {
+            ensure!(
+                indentation.is_none(),
+                "--lookahead can't be combined with --indent when tokenizing"
+            );
+            let mut stream = lexer.stream(&input, bulk_errors, lookahead);
+            loop {
+                let (token, text) = stream.next_token()?;
+                let text = normalize(&token, text);
+                let peeked: Vec<&SmolStr> = stream
+                    .peek_n(lookahead)?
+                    .into_iter()
+                    .map(|(token, _)| token)
+                    .collect();
+                println!("{} {:?} {:?}", token, text, peeked);
+                if &token == lexer.get_eof() {
+                    return Ok(());
+                }
+            }
+        }
+        let mut tokens = if bulk_errors {
+            lexer.tokenize_bulk(&input)?
+        } else {
+            lexer.tokenize(&input)?
+        };
+        if let Some(tab_width) = indentation {
+            tokens = lexer::insert_indentation(tokens, tab_width, lexer.get_eof())?;
+        }
+        for (token, text) in tokens {
+            let text = normalize(&token, text);
+            println!("{} {:?}", token, text);
+        }
+        return Ok(());
+    }
+
+    if let Some(emit) = matches.value_of("emit") {
+        let files = match emit {
+            "dot" => generate_tables(&lexer, output, "lexer.dot", codegen::dot::gen_dot)?,
+            "nfa-dot" => generate_tables(&lexer, output, "nfa.dot", codegen::dot::gen_nfa_dot)?,
+            "json" => {
+                ensure!(
+                    grammar.modes.is_empty(),
+                    "--emit json doesn't support lexer modes"
+                );
+                ensure!(
+                    lexer.get_line_starts().is_empty(),
+                    "--emit json doesn't support line-start anchors"
+                );
+                generate_tables(&lexer, output, "lexer.json", codegen::json::gen_json)?
+            }
+            "first-follow" => generate_tables(
+                &grammar,
+                output,
+                "first_follow.txt",
+                codegen::first_follow::gen_first_follow,
+            )?,
+            e => bail!("Unknown emit format: {}", e),
+        };
+        if !matches.is_present("quiet") {
+            print_summary(&lexer, &files, construction_time)?;
+        }
+        return Ok(());
+    }
+
+    let error_context = matches.is_present("error-context");
+    let positions = matches.is_present("positions");
+    let keyword_hash = matches.is_present("keyword-hash");
+    let fold_keywords = matches.is_present("fold-keywords");
+    let recover = matches.is_present("recover");
+    ensure!(
+        !(bulk_errors && recover),
+        "--bulk-errors can't be combined with --recover"
+    );
+    let table_driven = matches.is_present("table-driven");
+    let utf16 = matches.is_present("utf16");
+    let tables_only = matches.is_present("tables-only");
+    ensure!(
+        !(fold_keywords && tables_only),
+        "--fold-keywords can't be combined with --tables-only"
+    );
+    let source_comments = matches.is_present("source-comments");
+    ensure!(
+        !(source_comments && tables_only),
+        "--source-comments can't be combined with --tables-only"
+    );
+    let driver = matches.is_present("driver");
+    ensure!(
+        !(driver && tables_only),
+        "--driver can't be combined with --tables-only"
+    );
+    let single_header = matches.is_present("single-header");
+    ensure!(
+        !(single_header && tables_only),
+        "--single-header can't be combined with --tables-only"
+    );
+    let file_name = matches.value_of("file-name");
+    let token_type = matches.value_of("token-type");
+    let stable_token_ids = matches.is_present("stable-token-ids");
+    ensure!(
+        !((token_type.is_some() || stable_token_ids) && tables_only),
+        "--token-type and --stable-token-ids can't be combined with --tables-only"
+    );
+    let serde = matches.is_present("serde");
+    let display_impl = matches.is_present("impl-display");
+    let lex_trait = matches.is_present("lex-trait");
+    let reverse = matches.is_present("reverse");
+    let combine: Vec<&str> = matches
+        .values_of("combine")
+        .map(|paths| paths.collect())
+        .unwrap_or_default();
+    let token_counts = matches.is_present("token-counts");
+    ensure!(
+        !(token_counts && tables_only),
+        "--token-counts can't be combined with --tables-only"
+    );
+    // The tables are the same whatever the driver does with them, so a flag
+    // that only changes the driver would silently do nothing.
+    let runtime_flags = [
+        ("--error-context", error_context),
+        ("--positions", positions),
+        ("--recover", recover),
+        ("--keyword-hash", keyword_hash),
+        ("--indent", indentation.is_some()),
+        ("--lookahead", lookahead.is_some()),
+        ("--bulk-errors", bulk_errors),
+        ("--strip-bom", strip_bom),
+        ("--bytes", bytes),
+        ("--validate-utf8", validate_utf8),
+        ("--utf16", utf16),
+        ("--table-driven", table_driven),
+        ("--normalize-newlines", normalize_newlines),
+        ("--buffer-capacity", buffer_capacity.is_some()),
+    ];
+    if let Some((flag, _)) = runtime_flags.iter().find(|(_, set)| *set && tables_only) {
+        bail!("{} can't be combined with --tables-only", flag);
+    }
+    ensure!(
+        !(token_counts && stable_token_ids),
+        "--token-counts indexes the counts by token, so it can't be combined with --stable-token-ids"
+    );
+    if let Some(name) = file_name {
+        ensure!(
+            !name.is_empty() && !name.contains(['/', '\\', '.']),
+            "--file-name '{}' must be a plain file name without a directory or extension",
+            name
+        );
+    }
+    let mut langs: Vec<&str> = Vec::new();
+    for lang in matches.values_of("lang").unwrap() {
+        if !langs.contains(&lang) {
+            langs.push(lang);
+        }
+    }
+    ensure!(
+        !(langs.contains(&"c") && langs.contains(&"cpp")),
+        "c and cpp both write lexer.h, generate them into separate directories"
+    );
+    // Every language is checked before anything is written, so a flag one of
+    // them doesn't support can't leave the others half generated.
+    let check = |lang: &str| -> Result<()> {
+        ensure!(
+            grammar.modes.is_empty() || (lang == "cpp" && !tables_only),
+            "Lexer modes are only supported for cpp"
+        );
+        ensure!(
+            lexer.get_line_starts().is_empty() || (lang == "cpp" && !tables_only),
+            "Line-start anchors are only supported for cpp"
+        );
+        ensure!(
+            !driver || lang == "cpp" || lang == "java",
+            "--driver is only supported for cpp and java"
+        );
+        ensure!(
+            !single_header || lang == "cpp",
+            "--single-header is only supported for cpp"
+        );
+        ensure!(
+            file_name.is_none() || lang == "cpp",
+            "--file-name is only supported for cpp"
+        );
+        ensure!(
+            (token_type.is_none() && !stable_token_ids) || lang == "cpp",
+            "--token-type and --stable-token-ids are only supported for cpp"
+        );
+        ensure!(
+            !(serde || display_impl || lex_trait || reverse) && combine.is_empty()
+                || lang == "rust",
+            "--serde, --impl-display, --lex-trait, --reverse and --combine are only supported for rust"
+        );
+        ensure!(
+            !token_counts || lang == "cpp",
+            "--token-counts is only supported for cpp"
+        );
+        ensure!(
+            !lexer.has_lookaheads() || lang == "cpp",
+            "Negative lookahead is only supported for cpp so far"
+        );
+        ensure!(
+            (!matches.is_present("newline") && !matches.is_present("code-indent"))
+                || lang == "cpp"
+                || lang == "java",
+            "--newline and --code-indent are only supported for cpp and java"
+        );
+        ensure!(
+            grammar.eof.is_none() || lang == "cpp" || lang == "java",
+            "A custom EOF token is only supported for cpp and java"
+        );
+        if lang != "cpp" {
+            ensure!(!validate_utf8, "--validate-utf8 is only supported for cpp");
+            ensure!(
+                !source_comments,
+                "--source-comments is only supported for cpp"
+            );
+            ensure!(!table_driven, "--table-driven is only supported for cpp");
+            ensure!(!utf16, "--utf16 is only supported for cpp");
+        }
+        ensure!(
+            indentation.is_none() || lang == "java",
+            "--indent is only supported for java"
+        );
+        if lang != "cpp" && lang != "java" {
+            ensure!(
+                !error_context
+                    && !keyword_hash
+                    && lookahead.is_none()
+                    && !positions
+                    && !recover
+                    && !tables_only,
+                "--error-context, --keyword-hash, --lookahead, --positions, --recover and --tables-only aren't supported for {}",
+                lang
+            );
+            ensure!(!strip_bom, "--strip-bom is only supported for cpp and java");
+            ensure!(
+                !fold_keywords,
+                "--fold-keywords is only supported for cpp and java"
+            );
+            ensure!(
+                max_error_run.is_none() && buffer_capacity.is_none(),
+                "--max-error-run and --buffer-capacity are only supported for cpp and java"
+            );
+            ensure!(
+                !bytes || lang == "c",
+                "--bytes is only supported for cpp, c and java"
+            );
+        }
+        match lang {
+            "cpp" if tables_only => ensure!(
+                !lexer.has_lookaheads(),
+                "Negative lookahead can't be combined with --tables-only"
+            ),
+            "cpp" => {
+                ensure!(
+                    !(positions && lookahead.is_some()),
+                    "--positions can't be combined with --lookahead for cpp"
+                );
+                ensure!(
+                    !(utf16 && (keyword_hash || fold_keywords || validate_utf8)),
+                    "--utf16 can't be combined with --keyword-hash, --fold-keywords or --validate-utf8"
+                );
+                ensure!(
+                    !(utf16 && driver),
+                    "--driver can't be combined with --utf16"
+                );
+                ensure!(
+                    !lexer.has_lookaheads()
+                        || (!table_driven && lexer.get_match_mode() == MatchMode::Longest),
+                    "Negative lookahead isn't supported with --table-driven or match = shortest"
+                );
+                ensure!(
+                    !utf16
+                        || lexer
+                            .get_terminals()
+                            .iter()
+                            .all(|rule| rule.value_type.is_none()),
+                    "--utf16 doesn't support typed tokens"
+                );
+                ensure!(
+                    !(bytes && (keyword_hash || fold_keywords))
+                        || lexer.get_keywords().iter().all(|(_, lit)| lit.is_ascii()),
+                    "--keyword-hash and --fold-keywords need ASCII keywords with --bytes"
+                );
+            }
+            // An error run depends on what follows it, so it can't be found
+            // reading backwards.
+            "rust" => ensure!(
+                !(reverse && bulk_errors),
+                "--reverse can't be combined with --bulk-errors"
+            ),
+            "c" | "java" | "python" | "js" | "ts" | "go" => {}
+            l => bail!("Language currently not supported: {}", l),
+        }
+        Ok(())
+    };
+    let mut checked = Vec::new();
+    for lang in langs {
+        check(lang)?;
+        checked.push((lang, code_names(matches, lang)?));
+    }
+    let has_nonterminals = grammar.rules.iter().any(|rule| !rule.is_terminal);
+    let parser = if has_nonterminals
+        && checked
+            .iter()
+            .any(|(lang, _)| *lang == "java" || *lang == "rust")
+    {
+        Some(Parser::from_grammar(&grammar)?)
+    } else {
+        None
+    };
+    let combined = if checked.iter().any(|(lang, _)| *lang == "rust") && !combine.is_empty() {
+        ensure!(
+            parser.is_none(),
+            "--combine only supports grammars without nonterminals"
+        );
+        let grammars = combined_grammars(rules, &combine, match_mode)?;
+        Lexer::from_grammars(&grammars)?
+    } else {
+        Vec::new()
+    };
+
+    let mut files = Vec::new();
+    for (lang, names) in checked {
+        files.extend(match lang {
+            "cpp" if tables_only => generate_tables(
+                &lexer,
+                output,
+                &format!("{}_tables.h", file_name.unwrap_or("lexer")),
+                |lexer, tables| {
+                    let config = CppConfig {
+                        layout: layout.clone(),
+                        ..CppConfig::default()
+                    };
+                    codegen::cpp::gen_tables(lexer, &config, tables)
+                },
+            )?,
+            "java" if tables_only => {
+                generate_tables(&lexer, output, "LexerTables.java", |lexer, tables| {
+                    let config = JavaConfig {
+                        layout: layout.clone(),
+                        ..JavaConfig::default()
+                    };
+                    codegen::java::gen_tables(lexer, &config, tables)
+                })?
+            }
+            "cpp" => {
+                let cpp_config = CppConfig {
+                    error_context,
+                    keyword_hash,
+                    fold_keywords,
+                    bulk_errors,
+                    max_error_run,
+                    recover,
+                    lookahead,
+                    validate_utf8,
+                    normalize_newlines,
+                    positions,
+                    table_driven,
+                    utf16,
+                    bytes,
+                    strip_bom,
+                    buffer_capacity,
+                    source_comments,
+                    file_name: file_name.map(str::to_string),
+                    token_type: token_type.map(str::to_string),
+                    stable_token_ids,
+                    token_counts,
+                    names,
+                    layout: layout.clone(),
+                };
+                generate_cpp(&lexer, &cpp_config, driver, single_header, output)?
+            }
+            "c" => {
+                let c_config = CConfig {
+                    bulk_errors,
+                    normalize_newlines,
+                    bytes,
+                };
+                generate_c(&lexer, &c_config, output)?
+            }
+            "java" => {
+                let java_config = JavaConfig {
+                    indentation,
+                    error_context,
+                    keyword_hash,
+                    fold_keywords,
+                    bulk_errors,
+                    max_error_run,
+                    recover,
+                    lookahead,
+                    normalize_newlines,
+                    positions,
+                    bytes,
+                    strip_bom,
+                    buffer_capacity,
+                    names,
+                    layout: layout.clone(),
+                };
+                generate_java(&lexer, parser.as_ref(), &java_config, driver, output)?
+            }
+            "rust" => {
+                let rust_config = RustConfig {
+                    bulk_errors,
+                    normalize_newlines,
+                    serde,
+                    display_impl,
+                    lex_trait,
+                    reverse,
+                };
+                if combine.is_empty() {
+                    generate_rust(&lexer, parser.as_ref(), &rust_config, output)?
+                } else {
+                    generate_combined_rust(&combined, &rust_config, output)?
+                }
+            }
+            "python" => {
+                let python_config = PythonConfig {
+                    bulk_errors,
+                    normalize_newlines,
+                };
+                generate_python(&lexer, &python_config, output)?
+            }
+            l @ ("js" | "ts") => {
+                let js_config = JsConfig {
+                    typescript: l == "ts",
+                    bulk_errors,
+                    normalize_newlines,
+                };
+                generate_js(&lexer, &js_config, output)?
+            }
+            "go" => {
+                let go_config = GoConfig {
+                    bulk_errors,
+                    normalize_newlines,
+                };
+                generate_go(&lexer, &go_config, output)?
+            }
+            l => unreachable!("{} was checked above", l),
+        });
+    }
+
+    if !matches.is_present("quiet") {
+        print_summary(&lexer, &files, construction_time)?;
+    }
+    Ok(())
+}
+
+const WATCH_POLL: Duration = Duration::from_millis(200);
+
+// Runs everything again whenever the rules file or one of its imports
+// changes. Errors are printed, and the next change is waited for. With a
+// number of changes, it returns after running again for that many.
+fn watch(matches: &clap::ArgMatches, poll: Duration, changes: Option<usize>) -> Result<()> {
+    let rules = Path::new(matches.value_of("rules").unwrap());
+    ensure!(
+        rules != Path::new("-"),
+        "--watch needs a rules file to watch, it can't read the rules from stdin"
+    );
+    ensure!(
+        !matches.is_present("repl"),
+        "--watch can't be combined with --repl"
+    );
+    let mut files = vec![rules.to_path_buf()];
+    let mut changes = changes;
+    loop {
+        let start = Instant::now();
+        match run(matches) {
+            Ok(()) => eprintln!("Regenerated in {:.2?}", start.elapsed()),
+            Err(e) => eprintln!("Error: {}\nFailed after {:.2?}", e, start.elapsed()),
+        }
+        if changes == Some(0) {
+            return Ok(());
+        }
+        // A file that doesn't load keeps the last files that did.
+        if let Ok(sources) = rules::source_files(rules) {
+            files = sources;
+        }
+        wait_for_change(&files, poll);
+        eprintln!("Changed, regenerating");
+        changes = changes.map(|n| n - 1);
+    }
+}
+
+// Returns once one of the files changed and they have stopped changing, since
+// an editor may save several times in a row.
+fn wait_for_change(files: &[PathBuf], poll: Duration) {
+    let seen = modified_times(files);
+    let mut current = seen.clone();
+    while current == seen {
+        std::thread::sleep(poll);
+        current = modified_times(files);
+    }
+    loop {
+        std::thread::sleep(poll);
+        let next = modified_times(files);
+        if next == current {
+            break;
+        }
+        current = next;
+    }
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn repl(
+    rules: &Path,
+    mut lexer: Lexer,
+    bulk_errors: bool,
+    match_mode: Option<MatchMode>,
+) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let input = line.strip_suffix('\n').unwrap_or(&line);
+        let input = input.strip_suffix('\r').unwrap_or(input);
+        match input {
+            ":reload" => match rules::parse_file(rules).and_then(|mut g| {
+                if let Some(mode) = match_mode {
+                    g.match_mode = mode;
+                }
+                Lexer::from_grammar(&g)
+            }) {
+                Ok(reloaded) => {
+                    lexer = reloaded;
+                    println!("Reloaded {}", rules.display());
+                }
+                Err(e) => println!("Error: {}", e),
+            },
+            ":states" => print_states(&lexer),
+            _ => {
+                let mut stream = lexer.stream(input, bulk_errors, 0);
+                loop {
+                    match stream.next_token() {
+                        Ok((token, text)) => {
+                            println!("{} {:?}", token, text);
+                            if &token == lexer.get_eof() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            println!("Error: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn print_states(lexer: &Lexer) {
+    let show =
+        |c: u32| char::from_u32(c).map_or_else(|| format!("U+{:04X}", c), |c| format!("{:?}", c));
+    let states = lexer.get_states();
+    for (i, state) in states.iter().enumerate() {
+        if *state == Some(&SmolStr::new("_TRAP")) {
+            continue;
+        }
+        match state {
+            Some(token) => println!("{}: accepts {}", i, token),
+            None => println!("{}:", i),
+        }
+        let mut connections = lexer.get_connections(i);
+        connections.sort();
+        let mut merged: Vec<(u32, u32, usize)> = Vec::new();
+        for (r0, r1, result) in connections {
+            match merged.last_mut() {
+                Some(last) if last.1 + 1 == r0 && last.2 == result => last.1 = r1,
+                _ => merged.push((r0, r1, result)),
+            }
+        }
+        for (r0, r1, result) in merged {
+            if states[result] != Some(&SmolStr::new("_TRAP")) {
+                println!("  {}..={} -> {}", show(r0), show(r1), result);
+            }
+        }
+    }
+}
+
+fn print_stats(lexer: &Lexer) {
+    let states = lexer.get_states();
+    let is_trap = |state: usize| states[state] == Some(&SmolStr::new("_TRAP"));
+    let mut reachable: BTreeSet<usize> = BTreeSet::from([0]);
+    reachable.extend(lexer.get_modes().into_iter().map(|(_, start)| start));
+    reachable.extend(lexer.get_line_starts().into_iter().map(|(_, start)| start));
+    let mut pending: Vec<usize> = reachable.iter().copied().collect();
+    let mut transitions = 0;
+    while let Some(state) = pending.pop() {
+        for (_, _, result) in lexer.get_connections(state) {
+            if !is_trap(result) {
+                transitions += 1;
+            }
+            if reachable.insert(result) {
+                pending.push(result);
+            }
+        }
+    }
+    println!("DFA states: {}", states.len());
+    println!("Transitions: {}", transitions);
+    println!(
+        "Accepting states: {}",
+        (0..states.len())
+            .filter(|&i| states[i].is_some() && !is_trap(i))
+            .count()
+    );
+    println!("Alphabet size: {}", lexer.get_alphabet().len());
+    println!(
+        "Trap reachable: {}",
+        if reachable.iter().any(|&i| is_trap(i)) {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+}
+
+fn print_summary(lexer: &Lexer, files: &[PathBuf], construction_time: Duration) -> Result<()> {
+    println!("Wrote {} file(s):", files.len());
+    for file in files {
+        println!(
+            "  {} ({} bytes)",
+            file.display(),
+            std::fs::metadata(file)?.len()
+        );
+    }
+    let states = lexer.get_states();
+    let tokens: BTreeSet<&SmolStr> = states
+        .iter()
+        .flatten()
+        .filter(|s| **s != "_TRAP")
+        .copied()
+        .collect();
+    println!(
+        "DFA states: {}, alphabet size: {}, tokens: {}, construction time: {:.2?}",
+        states.len(),
+        lexer.get_alphabet().len(),
+        tokens.len(),
+        construction_time
+    );
     Ok(())
 }
 
-fn generate_cpp(lexer: &Lexer, output: &Path) -> Result<()> {
+fn write_outputs(outputs: Vec<(PathBuf, Vec<u8>)>) -> Result<Vec<PathBuf>> {
+    // Every output goes to a temporary file in its directory first, and is
+    // only renamed into place once all of them are written. A temporary file
+    // that isn't renamed is removed when it's dropped.
+    let mut temps = Vec::new();
+    for (path, contents) in &outputs {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut temp = tempfile::Builder::new()
+            .prefix(".parge-")
+            .suffix(".tmp")
+            .tempfile_in(dir)?;
+        temp.write_all(contents)?;
+        // The temporary file is only readable by its owner, the output keeps
+        // the permissions of the file it replaces.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).map_or(0o644, |m| m.permissions().mode());
+            temp.as_file()
+                .set_permissions(std::fs::Permissions::from_mode(mode))?;
+        }
+        temps.push(temp);
+    }
+    let mut replaced = Vec::new();
+    for (temp, (path, _)) in temps.into_iter().zip(&outputs) {
+        if let Err(e) = temp.persist(path) {
+            if replaced.is_empty() {
+                bail!("Couldn't write {}: {}", path.display(), e.error);
+            }
+            bail!(
+                "Couldn't write {}: {}, but {} already replaced",
+                path.display(),
+                e.error,
+                replaced.join(", ")
+            );
+        }
+        replaced.push(path.display().to_string());
+    }
+    Ok(outputs.into_iter().map(|(path, _)| path).collect())
+}
+
+fn code_names(matches: &clap::ArgMatches, lang: &str) -> Result<Names> {
+    let is_identifier = |name: &str| {
+        name.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    let mut names = Names::default();
+    for (arg, name) in [
+        ("lexer-name", &mut names.lexer),
+        ("token-name", &mut names.token),
+    ] {
+        if let Some(value) = matches.value_of(arg) {
+            ensure!(
+                matches!(lang, "cpp" | "java"),
+                "--{} is only supported for cpp and java",
+                arg
+            );
+            ensure!(
+                is_identifier(value),
+                "--{} '{}' isn't an identifier",
+                arg,
+                value
+            );
+            *name = value.to_string();
+        }
+    }
+    ensure!(
+        names.lexer != names.token,
+        "The lexer class and the token enum can't both be named '{}'",
+        names.lexer
+    );
+    if let Some(namespace) = matches.value_of("namespace") {
+        let separator = match lang {
+            "cpp" => "::",
+            "java" => ".",
+            _ => bail!("--namespace is only supported for cpp and java"),
+        };
+        ensure!(
+            namespace.split(separator).all(is_identifier),
+            "--namespace '{}' isn't a valid {} name",
+            namespace,
+            if lang == "cpp" {
+                "namespace"
+            } else {
+                "package"
+            }
+        );
+        names.namespace = Some(namespace.to_string());
+    }
+    Ok(names)
+}
+
+fn generate_cpp(
+    lexer: &Lexer,
+    config: &CppConfig,
+    driver: bool,
+    single_header: bool,
+    output: &Path,
+) -> Result<Vec<PathBuf>> {
     if !output.is_dir() {
         std::fs::create_dir_all(output)?;
     }
-    codegen::cpp::gen_header_lexer(&lexer, &mut File::create(output.join("lexer.h")).unwrap())?;
-    codegen::cpp::gen_body_lexer(&lexer, &mut File::create(output.join("lexer.cpp")).unwrap())?;
-    Ok(())
+    let stem = codegen::cpp::file_stem(config);
+    let mut outputs = Vec::new();
+    if single_header {
+        let mut header = Vec::new();
+        codegen::cpp::gen_single_header(lexer, config, &mut header)?;
+        outputs.push((output.join(format!("{}.h", stem)), header));
+    } else {
+        let mut header = Vec::new();
+        let mut body = Vec::new();
+        codegen::cpp::gen_header_lexer(lexer, config, &mut header)?;
+        codegen::cpp::gen_body_lexer(lexer, config, &mut body)?;
+        outputs.push((output.join(format!("{}.h", stem)), header));
+        outputs.push((output.join(format!("{}.cpp", stem)), body));
+    }
+    if driver {
+        let mut main = Vec::new();
+        codegen::cpp::gen_driver(lexer, config, &mut main)?;
+        outputs.push((output.join("main.cpp"), main));
+    }
+    write_outputs(outputs)
 }
 
-fn generate_java(lexer: &Lexer, output: &Path) -> Result<()> {
+fn generate_c(lexer: &Lexer, config: &CConfig, output: &Path) -> Result<Vec<PathBuf>> {
     if !output.is_dir() {
         std::fs::create_dir_all(output)?;
     }
-    codegen::java::gen_lexer(
-        &lexer,
-        &mut File::create(output.join("Lexer.java")).unwrap(),
-    )?;
-    Ok(())
+    let mut header = Vec::new();
+    let mut body = Vec::new();
+    codegen::c::gen_header_lexer(lexer, &mut header)?;
+    codegen::c::gen_body_lexer(lexer, config, &mut body)?;
+    write_outputs(vec![
+        (output.join("lexer.h"), header),
+        (output.join("lexer.c"), body),
+    ])
+}
+
+fn generate_tables<T>(
+    source: &T,
+    output: &Path,
+    name: &str,
+    gen: impl Fn(&T, &mut Vec<u8>) -> parge::error::Result<()>,
+) -> Result<Vec<PathBuf>> {
+    if !output.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let mut tables = Vec::new();
+    gen(source, &mut tables)?;
+    write_outputs(vec![(output.join(name), tables)])
+}
+
+fn generate_rust(
+    lexer: &Lexer,
+    parser: Option<&Parser>,
+    config: &RustConfig,
+    output: &Path,
+) -> Result<Vec<PathBuf>> {
+    if !output.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let mut lexer_file = Vec::new();
+    codegen::rust::gen_lexer(lexer, config, &mut lexer_file)?;
+    let mut outputs = vec![(output.join("lexer.rs"), lexer_file)];
+    if let Some(parser) = parser {
+        let mut ast_file = Vec::new();
+        codegen::rust_ast::gen_ast(parser, &mut ast_file)?;
+        outputs.push((output.join("ast.rs"), ast_file));
+    }
+    write_outputs(outputs)
+}
+
+// Each grammar becomes a module named after its rules file.
+fn combined_grammars(
+    rules: &Path,
+    paths: &[&str],
+    match_mode: Option<MatchMode>,
+) -> Result<Vec<(SmolStr, rules::Grammar)>> {
+    ensure!(
+        rules != Path::new("-"),
+        "--combine needs a rules file to name the module after, it can't read the rules from stdin"
+    );
+    let module_name = |path: &Path| -> Result<SmolStr> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("");
+        ensure!(
+            name.chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "Can't name a module after '{}', the file name isn't an identifier",
+            path.display()
+        );
+        Ok(SmolStr::new(name))
+    };
+    let mut grammars: Vec<(SmolStr, rules::Grammar)> = Vec::new();
+    for path in std::iter::once(rules).chain(paths.iter().map(Path::new)) {
+        let mut grammar = rules::parse_file(path)?;
+        if let Some(mode) = match_mode {
+            grammar.match_mode = mode;
+        }
+        let name = module_name(path)?;
+        ensure!(
+            grammars.iter().all(|(other, _)| *other != name),
+            "Two of the combined rules files are named '{}'",
+            name
+        );
+        grammars.push((name, grammar));
+    }
+    Ok(grammars)
+}
+
+fn generate_combined_rust(
+    lexers: &[(SmolStr, Lexer)],
+    config: &RustConfig,
+    output: &Path,
+) -> Result<Vec<PathBuf>> {
+    if !output.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let mut lexer_file = Vec::new();
+    codegen::rust::gen_combined(lexers, config, &mut lexer_file)?;
+    write_outputs(vec![(output.join("lexer.rs"), lexer_file)])
+}
+
+fn generate_python(lexer: &Lexer, config: &PythonConfig, output: &Path) -> Result<Vec<PathBuf>> {
+    if !output.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let mut lexer_file = Vec::new();
+    codegen::python::gen_lexer(lexer, config, &mut lexer_file)?;
+    write_outputs(vec![(output.join("lexer.py"), lexer_file)])
+}
+
+fn generate_js(lexer: &Lexer, config: &JsConfig, output: &Path) -> Result<Vec<PathBuf>> {
+    if !output.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let mut lexer_file = Vec::new();
+    codegen::js::gen_lexer(lexer, config, &mut lexer_file)?;
+    let name = if config.typescript {
+        "lexer.ts"
+    } else {
+        "lexer.js"
+    };
+    write_outputs(vec![(output.join(name), lexer_file)])
+}
+
+fn generate_go(lexer: &Lexer, config: &GoConfig, output: &Path) -> Result<Vec<PathBuf>> {
+    if !output.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let mut lexer_file = Vec::new();
+    codegen::go::gen_lexer(lexer, config, &mut lexer_file)?;
+    write_outputs(vec![(output.join("lexer.go"), lexer_file)])
+}
+
+fn generate_java(
+    lexer: &Lexer,
+    parser: Option<&Parser>,
+    config: &JavaConfig,
+    driver: bool,
+    output: &Path,
+) -> Result<Vec<PathBuf>> {
+    if !output.is_dir() {
+        std::fs::create_dir_all(output)?;
+    }
+    let mut lexer_file = Vec::new();
+    codegen::java::gen_lexer(lexer, config, &mut lexer_file)?;
+    let mut outputs = vec![(
+        output.join(format!("{}.java", config.names.lexer)),
+        lexer_file,
+    )];
+    if let Some(parser) = parser {
+        let mut parser_file = Vec::new();
+        codegen::java_parser::gen_parser(parser, config, &mut parser_file)?;
+        outputs.push((output.join("Parser.java"), parser_file));
+    }
+    if driver {
+        let mut main = Vec::new();
+        codegen::java::gen_driver(config, &mut main)?;
+        outputs.push((output.join("Main.java"), main));
+    }
+    write_outputs(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("parge-tests").join(test);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn wait_for_change_returns_after_an_edit() {
+        let dir = test_dir("wait_for_change_returns_after_an_edit");
+        let rules = dir.join("rules.pgrules");
+        std::fs::write(&rules, "token A = \"a\";\n").unwrap();
+        let files = vec![rules.clone()];
+        let (done, waited) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            wait_for_change(&files, Duration::from_millis(10));
+            done.send(()).unwrap();
+        });
+        // Nothing changed yet, so it keeps waiting.
+        assert!(waited.recv_timeout(Duration::from_millis(100)).is_err());
+        std::fs::write(&rules, "token B = \"b\";\n").unwrap();
+        // Some file systems only keep whole seconds.
+        std::fs::File::options()
+            .write(true)
+            .open(&rules)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        assert!(waited.recv_timeout(Duration::from_secs(10)).is_ok());
+    }
+
+    #[test]
+    fn watch_regenerates_after_an_import_changes() {
+        let dir = test_dir("watch_regenerates_after_an_import_changes");
+        let rules = dir.join("rules.pgrules");
+        let words = dir.join("words.pgrules");
+        std::fs::write(&rules, "import \"words.pgrules\";\ntoken WS = \" \";\n").unwrap();
+        std::fs::write(&words, "token NAME = ([a-z])+;\n").unwrap();
+        let out = dir.join("out");
+        let matches = command().get_matches_from([
+            "parge",
+            rules.to_str().unwrap(),
+            "-l",
+            "python",
+            "-q",
+            "-o",
+            out.to_str().unwrap(),
+        ]);
+        let lexer = out.join("lexer.py");
+        let (done, finished) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            done.send(watch(&matches, Duration::from_millis(10), Some(1)).is_ok())
+                .unwrap();
+        });
+        let start = Instant::now();
+        while !lexer.exists() && start.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let before = std::fs::read_to_string(&lexer).unwrap();
+        assert!(!before.contains("NUM"));
+        std::fs::write(&words, "token NAME = ([a-z])+;\ntoken NUM = ([0-9])+;\n").unwrap();
+        // The edit may land before the watch looks at the files, so it is
+        // dated ever later until the watch has seen it. Some file systems
+        // only keep whole seconds.
+        let mut ahead = 0;
+        let ok = loop {
+            ahead += 5;
+            std::fs::File::options()
+                .write(true)
+                .open(&words)
+                .unwrap()
+                .set_modified(SystemTime::now() + Duration::from_secs(ahead))
+                .unwrap();
+            match finished.recv_timeout(Duration::from_millis(100)) {
+                Ok(ok) => break ok,
+                Err(_) => assert!(start.elapsed() < Duration::from_secs(20)),
+            }
+        };
+        assert!(ok);
+        let after = std::fs::read_to_string(&lexer).unwrap();
+        assert_ne!(before, after);
+        assert!(after.contains("NUM"));
+    }
+
+    #[test]
+    fn failed_write_leaves_no_partial_output() {
+        let dir = test_dir("failed_write_leaves_no_partial_output");
+        std::fs::write(dir.join("lexer.h"), "old header").unwrap();
+        // The second file can't be created, its directory doesn't exist.
+        let outputs = vec![
+            (dir.join("lexer.h"), b"new header".to_vec()),
+            (dir.join("missing").join("lexer.cpp"), b"new body".to_vec()),
+        ];
+        assert!(write_outputs(outputs).is_err());
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["lexer.h"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("lexer.h")).unwrap(),
+            "old header"
+        );
+    }
+
+    #[test]
+    fn outputs_are_written_together() {
+        let dir = test_dir("outputs_are_written_together");
+        let outputs = vec![
+            (dir.join("lexer.h"), b"header".to_vec()),
+            (dir.join("lexer.cpp"), b"body".to_vec()),
+        ];
+        let written = write_outputs(outputs).unwrap();
+        assert_eq!(written, [dir.join("lexer.h"), dir.join("lexer.cpp")]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("lexer.cpp")).unwrap(),
+            "body"
+        );
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["lexer.cpp", "lexer.h"]);
+    }
+
+    #[test]
+    fn failed_rename_reports_replaced_outputs() {
+        let dir = test_dir("failed_rename_reports_replaced_outputs");
+        std::fs::write(dir.join("lexer.h.tmp"), "mine").unwrap();
+        // A file can't replace a directory that isn't empty.
+        std::fs::create_dir_all(dir.join("lexer.cpp").join("inner")).unwrap();
+        let outputs = vec![
+            (dir.join("lexer.h"), b"new header".to_vec()),
+            (dir.join("lexer.cpp"), b"new body".to_vec()),
+            (dir.join("lexer.txt"), b"new text".to_vec()),
+        ];
+        let err = write_outputs(outputs).unwrap_err().to_string();
+        assert!(err.starts_with("Couldn't write"), "{}", err);
+        assert!(err.contains("lexer.cpp"), "{}", err);
+        assert!(err.ends_with("lexer.h already replaced"), "{}", err);
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["lexer.cpp", "lexer.h", "lexer.h.tmp"]);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("lexer.h.tmp")).unwrap(),
+            "mine"
+        );
+    }
 }
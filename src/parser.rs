@@ -0,0 +1,655 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use smol_str::SmolStr;
+
+use crate::{
+    error::{bail, ensure, Result},
+    lexer::eof_name,
+    rules::{Element, Grammar, Rule},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldKind {
+    Token,
+    Node(SmolStr),
+    AnyNode,
+    Any,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldType {
+    pub kind: FieldKind,
+    pub list: bool,
+}
+
+pub struct Node {
+    pub name: SmolStr,
+    pub fields: Vec<(SmolStr, FieldType)>,
+}
+
+pub struct FirstSets {
+    nonterminals: BTreeSet<SmolStr>,
+    first: BTreeMap<SmolStr, BTreeSet<SmolStr>>,
+    nullable: HashSet<SmolStr>,
+}
+
+pub type FollowSets = BTreeMap<SmolStr, BTreeSet<SmolStr>>;
+
+pub struct Parser {
+    rules: Vec<Rule>,
+    tokens: BTreeSet<SmolStr>,
+    first: FirstSets,
+    follow: FollowSets,
+    nodes: Vec<Node>,
+    eof: SmolStr,
+}
+
+impl FieldType {
+    fn merge(&self, other: &FieldType) -> FieldType {
+        let kind = match (&self.kind, &other.kind) {
+            (a, b) if a == b => a.clone(),
+            (FieldKind::Node(_) | FieldKind::AnyNode, FieldKind::Node(_) | FieldKind::AnyNode) => {
+                FieldKind::AnyNode
+            }
+            _ => FieldKind::Any,
+        };
+        FieldType {
+            kind,
+            list: self.list || other.list,
+        }
+    }
+}
+
+impl FirstSets {
+    pub fn get(&self, name: &str) -> (&BTreeSet<SmolStr>, bool) {
+        (&self.first[name], self.nullable.contains(name))
+    }
+
+    pub fn of(&self, element: &Element) -> (BTreeSet<SmolStr>, bool) {
+        match element {
+            Element::Rule { name, .. } if !self.nonterminals.contains(name) => {
+                (BTreeSet::from([name.clone()]), false)
+            }
+            Element::Rule { name, .. } => (
+                self.first.get(name).cloned().unwrap_or_default(),
+                self.nullable.contains(name),
+            ),
+            Element::Group { subelems } => {
+                let mut first = BTreeSet::new();
+                for subelem in subelems {
+                    let (sub, nullable) = self.of(subelem);
+                    first.extend(sub);
+                    if !nullable {
+                        return (first, false);
+                    }
+                }
+                (first, true)
+            }
+            Element::Alternatives { subelems } => {
+                let mut first = BTreeSet::new();
+                let mut nullable = false;
+                for subelem in subelems {
+                    let (sub, sub_nullable) = self.of(subelem);
+                    first.extend(sub);
+                    nullable |= sub_nullable;
+                }
+                (first, nullable)
+            }
+            Element::Optional { inner } | Element::ZeroOrMore { inner } => (self.of(inner).0, true),
+            Element::OneOrMore { inner } => self.of(inner),
+            Element::Literal { .. } | Element::Set { .. } | Element::NegatedSet { .. } => {
+                (BTreeSet::new(), false)
+            }
+        }
+    }
+
+    fn follow_contexts(
+        &self,
+        element: &Element,
+        follow: &BTreeSet<SmolStr>,
+        visit: &mut dyn FnMut(&Element, &BTreeSet<SmolStr>),
+    ) {
+        visit(element, follow);
+        match element {
+            Element::Group { subelems } => {
+                let mut follow = follow.clone();
+                for subelem in subelems.iter().rev() {
+                    self.follow_contexts(subelem, &follow, visit);
+                    let (first, nullable) = self.of(subelem);
+                    if !nullable {
+                        follow.clear();
+                    }
+                    follow.extend(first);
+                }
+            }
+            Element::Alternatives { subelems } => {
+                for subelem in subelems {
+                    self.follow_contexts(subelem, follow, visit);
+                }
+            }
+            Element::Optional { inner } => self.follow_contexts(inner, follow, visit),
+            Element::ZeroOrMore { inner } | Element::OneOrMore { inner } => {
+                let mut follow = follow.clone();
+                follow.extend(self.of(inner).0);
+                self.follow_contexts(inner, &follow, visit);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn first_follow(rules: &[Rule], eof: &SmolStr) -> (FirstSets, FollowSets) {
+    let mut sets = FirstSets {
+        nonterminals: rules.iter().map(|rule| rule.name.clone()).collect(),
+        first: rules
+            .iter()
+            .map(|rule| (rule.name.clone(), BTreeSet::new()))
+            .collect(),
+        nullable: HashSet::new(),
+    };
+    loop {
+        let mut changed = false;
+        for rule in rules {
+            let (first, nullable) = sets.of(&rule.element);
+            if nullable && sets.nullable.insert(rule.name.clone()) {
+                changed = true;
+            }
+            let entry = sets.first.get_mut(&rule.name).unwrap();
+            if entry.len() != first.len() {
+                *entry = first;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut follow: FollowSets = rules
+        .iter()
+        .map(|rule| (rule.name.clone(), BTreeSet::new()))
+        .collect();
+    if let Some(start) = rules.first() {
+        follow.get_mut(&start.name).unwrap().insert(eof.clone());
+    }
+    loop {
+        let mut changed = false;
+        for rule in rules {
+            let context = follow[&rule.name].clone();
+            sets.follow_contexts(&rule.element, &context, &mut |e, f| {
+                if let Element::Rule { name, .. } = e {
+                    if let Some(entry) = follow.get_mut(name) {
+                        let before = entry.len();
+                        entry.extend(f.iter().cloned());
+                        changed |= entry.len() != before;
+                    }
+                }
+            });
+        }
+        if !changed {
+            return (sets, follow);
+        }
+    }
+}
+
+impl Parser {
+    pub fn from_grammar(grammar: &Grammar) -> Result<Self> {
+        let rules: Vec<Rule> = grammar
+            .rules
+            .iter()
+            .filter(|rule| !rule.is_terminal)
+            .cloned()
+            .collect();
+        ensure!(!rules.is_empty(), Parser, "The grammar has no nonterminals");
+        let tokens: BTreeSet<SmolStr> = grammar
+            .rules
+            .iter()
+            .filter(|rule| rule.is_terminal && !rule.fragment)
+            .map(|rule| rule.name.clone())
+            .collect();
+        for rule in &rules {
+            let mut names = Vec::new();
+            references(&rule.element, &mut names)?;
+            for name in names {
+                if let Some(token) = grammar.rules.iter().find(|r| &r.name == name) {
+                    ensure!(
+                        !token.is_terminal || (!token.skip && token.internal.is_none()),
+                        Parser,
+                        "Nonterminal '{}' references '{}', which is never returned by the lexer",
+                        rule.name,
+                        name
+                    );
+                }
+            }
+        }
+        let eof = eof_name(grammar);
+        let (first, follow) = first_follow(&rules, &eof);
+        let mut parser = Parser {
+            rules,
+            tokens,
+            first,
+            follow,
+            nodes: Vec::new(),
+            eof,
+        };
+        parser.check_left_recursion()?;
+        parser.check_ll1()?;
+        parser.compute_nodes()?;
+        Ok(parser)
+    }
+
+    pub fn get_rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn get_eof(&self) -> &SmolStr {
+        &self.eof
+    }
+
+    pub fn get_start(&self) -> &Rule {
+        &self.rules[0]
+    }
+
+    pub fn get_nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn is_token(&self, name: &str) -> bool {
+        self.tokens.contains(name)
+    }
+
+    pub fn node_of(&self, name: &str) -> &SmolStr {
+        let rule = self.rules.iter().find(|r| r.name == name).unwrap();
+        rule.constructor_name.as_ref().unwrap()
+    }
+
+    pub fn first(&self, element: &Element) -> BTreeSet<SmolStr> {
+        self.first.of(element).0
+    }
+
+    pub fn nullable(&self, element: &Element) -> bool {
+        self.first.of(element).1
+    }
+
+    pub fn bindings(&self, rule: &Rule) -> Vec<(SmolStr, FieldType)> {
+        let mut bindings: Vec<(SmolStr, FieldType)> = Vec::new();
+        self.collect_bindings(&rule.element, false, &mut bindings);
+        let mut repeated = BTreeSet::new();
+        bound_vars(&rule.element, &mut repeated);
+        let mut merged: Vec<(SmolStr, FieldType)> = Vec::new();
+        for (var, mut field) in bindings {
+            field.list |= repeated.contains(&var);
+            match merged.iter_mut().find(|(name, _)| name == &var) {
+                Some((_, existing)) => *existing = existing.merge(&field),
+                None => merged.push((var, field)),
+            }
+        }
+        merged
+    }
+
+    fn left_calls<'a>(&self, element: &'a Element, calls: &mut Vec<&'a SmolStr>) {
+        match element {
+            Element::Rule { name, .. } if !self.is_token(name) => calls.push(name),
+            Element::Group { subelems } => {
+                for subelem in subelems {
+                    self.left_calls(subelem, calls);
+                    if !self.nullable(subelem) {
+                        return;
+                    }
+                }
+            }
+            Element::Alternatives { subelems } => {
+                for subelem in subelems {
+                    self.left_calls(subelem, calls);
+                }
+            }
+            Element::Optional { inner }
+            | Element::ZeroOrMore { inner }
+            | Element::OneOrMore { inner } => self.left_calls(inner, calls),
+            _ => {}
+        }
+    }
+
+    fn check_left_recursion(&self) -> Result<()> {
+        let calls: BTreeMap<&SmolStr, Vec<&SmolStr>> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let mut calls = Vec::new();
+                self.left_calls(&rule.element, &mut calls);
+                (&rule.name, calls)
+            })
+            .collect();
+        let mut done = HashSet::new();
+        for rule in &self.rules {
+            find_left_cycle(&rule.name, &calls, &mut Vec::new(), &mut done)?;
+        }
+        Ok(())
+    }
+
+    fn check_ll1(&self) -> Result<()> {
+        let mut conflicts = Vec::new();
+        for rule in &self.rules {
+            let name = &rule.name;
+            let context = &self.follow[name];
+            self.first
+                .follow_contexts(&rule.element, context, &mut |e, f| match e {
+                    Element::Alternatives { subelems } => {
+                        let mut seen = BTreeSet::new();
+                        let mut nullable = 0;
+                        for subelem in subelems {
+                            let (first, sub_nullable) = self.first.of(subelem);
+                            if let Some(token) = first.iter().find(|t| seen.contains(*t)) {
+                                conflicts.push(format!(
+                                    "Nonterminal '{}' isn't LL(1): more than one alternative starts with '{}'",
+                                    name, token
+                                ));
+                            }
+                            seen.extend(first);
+                            nullable += sub_nullable as usize;
+                        }
+                        if nullable > 1 {
+                            conflicts.push(format!(
+                                "Nonterminal '{}' isn't LL(1): more than one alternative can be empty",
+                                name
+                            ));
+                        } else if nullable == 1 {
+                            if let Some(token) = seen.iter().find(|t| f.contains(*t)) {
+                                conflicts.push(format!(
+                                    "Nonterminal '{}' isn't LL(1): '{}' can both start an alternative and follow an empty one",
+                                    name, token
+                                ));
+                            }
+                        }
+                    }
+                    Element::Optional { inner }
+                    | Element::ZeroOrMore { inner }
+                    | Element::OneOrMore { inner } => {
+                        let (first, nullable) = self.first.of(inner);
+                        if nullable {
+                            conflicts.push(format!(
+                                "Nonterminal '{}' isn't LL(1): an optional or repeated part can be empty",
+                                name
+                            ));
+                        } else if let Some(token) = first.iter().find(|t| f.contains(*t)) {
+                            conflicts.push(format!(
+                                "Nonterminal '{}' isn't LL(1): '{}' can both continue an optional or repeated part and follow it",
+                                name, token
+                            ));
+                        }
+                    }
+                    _ => {}
+                });
+        }
+        ensure!(conflicts.is_empty(), Parser, "{}", conflicts.join("\n"));
+        Ok(())
+    }
+
+    fn collect_bindings(
+        &self,
+        element: &Element,
+        repeated: bool,
+        bindings: &mut Vec<(SmolStr, FieldType)>,
+    ) {
+        match element {
+            Element::Rule {
+                var: Some(var),
+                name,
+            } => {
+                let kind = if self.is_token(name) {
+                    FieldKind::Token
+                } else {
+                    FieldKind::Node(self.node_of(name).clone())
+                };
+                bindings.push((
+                    var.clone(),
+                    FieldType {
+                        kind,
+                        list: repeated,
+                    },
+                ));
+            }
+            Element::Group { subelems } | Element::Alternatives { subelems } => {
+                for subelem in subelems {
+                    self.collect_bindings(subelem, repeated, bindings);
+                }
+            }
+            Element::Optional { inner } => self.collect_bindings(inner, repeated, bindings),
+            Element::ZeroOrMore { inner } | Element::OneOrMore { inner } => {
+                self.collect_bindings(inner, true, bindings)
+            }
+            _ => {}
+        }
+    }
+
+    fn compute_nodes(&mut self) -> Result<()> {
+        let mut nodes: Vec<Node> = Vec::new();
+        for rule in &self.rules {
+            let bindings = self.bindings(rule);
+            let name = rule.constructor_name.clone().unwrap();
+            let vars = rule.constructor_vars.as_deref().unwrap_or_default();
+            let mut fields = Vec::new();
+            for var in vars {
+                let Some((_, field)) = bindings.iter().find(|(name, _)| name == var) else {
+                    bail!(
+                        Parser,
+                        "Constructor '{}' of '{}' uses '{}', which isn't bound",
+                        name,
+                        rule.name,
+                        var
+                    );
+                };
+                fields.push((var.clone(), field.clone()));
+            }
+            match nodes.iter_mut().find(|node| node.name == name) {
+                Some(node) => {
+                    ensure!(
+                        node.fields.len() == fields.len()
+                            && node.fields.iter().zip(&fields).all(|(a, b)| a.0 == b.0),
+                        Parser,
+                        "Constructor '{}' is used with different fields",
+                        name
+                    );
+                    for (existing, (_, field)) in node.fields.iter_mut().zip(fields) {
+                        existing.1 = existing.1.merge(&field);
+                    }
+                }
+                None => nodes.push(Node { name, fields }),
+            }
+        }
+        self.nodes = nodes;
+        Ok(())
+    }
+}
+
+fn find_left_cycle<'a>(
+    name: &'a SmolStr,
+    calls: &BTreeMap<&'a SmolStr, Vec<&'a SmolStr>>,
+    path: &mut Vec<&'a SmolStr>,
+    done: &mut HashSet<&'a SmolStr>,
+) -> Result<()> {
+    if let Some(start) = path.iter().position(|n| *n == name) {
+        let cycle: Vec<&str> = path[start..]
+            .iter()
+            .chain([&name])
+            .map(|n| n.as_str())
+            .collect();
+        bail!(Parser, "Left recursion: {}", cycle.join(" -> "));
+    }
+    if done.contains(name) {
+        return Ok(());
+    }
+    path.push(name);
+    for callee in &calls[name] {
+        find_left_cycle(callee, calls, path, done)?;
+    }
+    path.pop();
+    done.insert(name);
+    Ok(())
+}
+
+fn references<'a>(element: &'a Element, names: &mut Vec<&'a SmolStr>) -> Result<()> {
+    match element {
+        Element::Rule { name, .. } => names.push(name),
+        Element::Group { subelems } | Element::Alternatives { subelems } => {
+            for subelem in subelems {
+                references(subelem, names)?;
+            }
+        }
+        Element::Optional { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::OneOrMore { inner } => references(inner, names)?,
+        Element::Literal { .. } | Element::Set { .. } | Element::NegatedSet { .. } => {
+            bail!(
+                Parser,
+                "Nonterminals can only reference tokens and other nonterminals"
+            )
+        }
+    }
+    Ok(())
+}
+
+fn bound_vars<'a>(
+    element: &'a Element,
+    repeated: &mut BTreeSet<&'a SmolStr>,
+) -> BTreeSet<&'a SmolStr> {
+    match element {
+        Element::Rule { var: Some(var), .. } => BTreeSet::from([var]),
+        Element::Group { subelems } => {
+            let mut vars = BTreeSet::new();
+            for subelem in subelems {
+                for var in bound_vars(subelem, repeated) {
+                    if !vars.insert(var) {
+                        repeated.insert(var);
+                    }
+                }
+            }
+            vars
+        }
+        Element::Alternatives { subelems } => {
+            let mut vars = BTreeSet::new();
+            for subelem in subelems {
+                vars.extend(bound_vars(subelem, repeated));
+            }
+            vars
+        }
+        Element::Optional { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::OneOrMore { inner } => bound_vars(inner, repeated),
+        _ => BTreeSet::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules;
+
+    // Some mismatches are already caught while parsing the grammar.
+    fn parser_error(src: &str) -> String {
+        rules::parse_str(src)
+            .and_then(|grammar| Parser::from_grammar(&grammar).map(|_| ()))
+            .unwrap_err()
+            .to_string()
+    }
+
+    fn names(set: &BTreeSet<SmolStr>) -> Vec<&str> {
+        set.iter().map(|name| name.as_str()).collect()
+    }
+
+    #[test]
+    fn textbook_first_follow() {
+        let src = r#"token PLUS = "+";
+token STAR = "*";
+token LPAREN = "(";
+token RPAREN = ")";
+token ID = ([a-z])+;
+nonterm E = a:T b:Ep -> E(a, b);
+nonterm Ep = (PLUS a:T b:Ep)? -> Ep(a, b);
+nonterm T = a:F b:Tp -> T(a, b);
+nonterm Tp = (STAR a:F b:Tp)? -> Tp(a, b);
+nonterm F = (ID | (LPAREN a:E RPAREN)) -> F(a);
+"#;
+        let grammar = rules::parse_str(src).unwrap();
+        let rules: Vec<Rule> = grammar
+            .rules
+            .iter()
+            .filter(|rule| !rule.is_terminal)
+            .cloned()
+            .collect();
+        let (first, follow) = first_follow(&rules, &SmolStr::from("_EOF"));
+        for (name, expected, nullable) in [
+            ("E", vec!["ID", "LPAREN"], false),
+            ("Ep", vec!["PLUS"], true),
+            ("T", vec!["ID", "LPAREN"], false),
+            ("Tp", vec!["STAR"], true),
+            ("F", vec!["ID", "LPAREN"], false),
+        ] {
+            let (set, is_nullable) = first.get(name);
+            assert_eq!(names(set), expected, "FIRST({})", name);
+            assert_eq!(is_nullable, nullable, "nullable({})", name);
+        }
+        for (name, expected) in [
+            ("E", vec!["RPAREN", "_EOF"]),
+            ("Ep", vec!["RPAREN", "_EOF"]),
+            ("T", vec!["PLUS", "RPAREN", "_EOF"]),
+            ("Tp", vec!["PLUS", "RPAREN", "_EOF"]),
+            ("F", vec!["PLUS", "RPAREN", "STAR", "_EOF"]),
+        ] {
+            assert_eq!(names(&follow[name]), expected, "FOLLOW({})", name);
+        }
+    }
+
+    #[test]
+    fn direct_left_recursion() {
+        let src = "token B = \"b\";\nnonterm A = x:A B -> A(x);\n";
+        assert_eq!(parser_error(src), "Left recursion: A -> A");
+    }
+
+    #[test]
+    fn indirect_left_recursion() {
+        let src = "token C = \"c\";\nnonterm S = x:A C -> S(x);\nnonterm A = x:B -> A(x);\nnonterm B = x:A C -> B(x);\n";
+        assert_eq!(parser_error(src), "Left recursion: A -> B -> A");
+    }
+
+    #[test]
+    fn right_recursion_is_accepted() {
+        let src = "token B = \"b\";\nnonterm A = B (rest:A)? -> A(rest);\n";
+        let grammar = rules::parse_str(src).unwrap();
+        assert!(Parser::from_grammar(&grammar).is_ok());
+    }
+
+    #[test]
+    fn constructor_arity() {
+        let tokens = "token NUM = ([0-9])+;\ntoken PLUS = \"+\";\n";
+        let grammar = rules::parse_str(&format!(
+            "{tokens}nonterm Add = l:NUM PLUS r:NUM -> Add(l, r);\n"
+        ))
+        .unwrap();
+        let parser = Parser::from_grammar(&grammar).unwrap();
+        let fields: Vec<&str> = parser.get_nodes()[0]
+            .fields
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(fields, ["l", "r"]);
+        assert_eq!(
+            parser_error(&format!(
+                "{tokens}nonterm Add = l:NUM PLUS r:NUM -> Add(l);\n"
+            )),
+            "'Add' binds 'r', but constructor 'Add' doesn't take it"
+        );
+        assert_eq!(
+            parser_error(&format!(
+                "{tokens}nonterm Add = l:NUM PLUS NUM -> Add(l, r);\n"
+            )),
+            "Constructor 'Add' of 'Add' uses 'r', which isn't bound"
+        );
+        assert_eq!(
+            parser_error(&format!(
+                "{tokens}nonterm Add = l:NUM PLUS NUM -> Add(l, l);\n"
+            )),
+            "Constructor 'Add' of 'Add' takes 'l' more than once"
+        );
+    }
+}
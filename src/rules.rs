@@ -1,19 +1,31 @@
-use std::{collections::HashSet, fs::File, io::Read, path::Path};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
-use color_eyre::eyre::{bail, ensure, Result};
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, tag, take_while, take_while1, take_while_m_n},
-    character::complete::{newline, one_of, satisfy, space0, space1},
-    combinator::{map, opt},
+    bytes::complete::{tag, take_while, take_while_m_n},
+    character::complete::{
+        digit1, line_ending, multispace0, multispace1, one_of, satisfy, space0, space1,
+    },
+    combinator::{cut, map, map_res, opt, success, verify},
     error::ParseError,
-    multi::{many0, separated_list0, separated_list1},
-    sequence::tuple,
+    multi::{many0, many1, separated_list0, separated_list1},
+    sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
 use smol_str::SmolStr;
 
-#[derive(Debug)]
+use crate::{
+    error::{bail, ensure, PargeError, Result},
+    unicode::{char_after, char_before, property_ranges},
+};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Element {
     Rule {
         var: Option<SmolStr>,
@@ -22,10 +34,12 @@ pub enum Element {
     Set {
         chars: Vec<char>,
         ranges: Vec<(char, char)>,
+        classes: Vec<SmolStr>,
     },
     NegatedSet {
         chars: Vec<char>,
         ranges: Vec<(char, char)>,
+        classes: Vec<SmolStr>,
     },
     Literal {
         lit: SmolStr,
@@ -47,83 +61,470 @@ pub enum Element {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Rule {
     pub is_terminal: bool,
+    pub fragment: bool,
+    pub skip: bool,
+    pub priority: u32,
     pub export: bool,
+    pub case_sensitive: bool,
+    pub internal: Option<SmolStr>,
     pub name: SmolStr,
     pub element: Element,
     pub constructor_name: Option<SmolStr>,
     pub constructor_vars: Option<Vec<SmolStr>>,
+    pub value_type: Option<SmolStr>,
+    pub display: Option<SmolStr>,
+    pub mode: Option<SmolStr>,
+    pub action: Option<ModeAction>,
+    pub line_start: bool,
+    pub lookahead: Option<Element>,
+    pub attributes: Vec<(SmolStr, Option<SmolStr>)>,
+    pub line: Option<usize>,
+}
+
+impl Rule {
+    // Attributes are hints for the backends, the rules don't interpret them.
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|(attribute, _)| attribute == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModeAction {
+    Push(SmolStr),
+    Pop,
+}
+
+pub const VALUE_TYPES: [&str; 4] = ["int", "long", "float", "double"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMode {
+    #[default]
+    Longest,
+    Shortest,
+}
+
+#[derive(Debug)]
+pub struct Grammar {
+    pub rules: Vec<Rule>,
+    pub classes: Vec<(SmolStr, Element)>,
+    pub equivalences: Vec<(SmolStr, Vec<SmolStr>)>,
+    pub exclusive: Vec<Vec<SmolStr>>,
+    pub match_mode: MatchMode,
+    pub case_insensitive: bool,
+    pub strict_sets: bool,
+    pub compact_alphabet: bool,
+    pub eof: Option<SmolStr>,
+    pub modes: Vec<SmolStr>,
+}
+
+enum Statement {
+    Rule(Rule),
+    Class(SmolStr, Element),
+    Equiv(SmolStr, Vec<SmolStr>),
+    AssertExclusive(Vec<SmolStr>),
+    Override(Rule),
+    Match(MatchMode),
+    Eof(SmolStr),
+    Fallback(SmolStr),
+    Options(Vec<(SmolStr, SmolStr)>),
+    Import(SmolStr),
+    Mode(SmolStr, Vec<Rule>),
+}
+
+fn parse_unicode_escape(src: &str) -> IResult<&str, char> {
+    let (rest, digits) = delimited(
+        tag("\\u{"),
+        take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit()),
+        tag("}"),
+    )(src)?;
+    match char::from_u32(u32::from_str_radix(digits, 16).unwrap()) {
+        Some(c) => Ok((rest, c)),
+        None => Err(nom::Err::Failure(nom::error::Error::from_error_kind(
+            src,
+            nom::error::ErrorKind::HexDigit,
+        ))),
+    }
+}
+
+fn parse_short_unicode_escape(src: &str) -> IResult<&str, char> {
+    let (rest, digits) = preceded(
+        tag("\\u"),
+        take_while_m_n(4, 4, |c: char| c.is_ascii_hexdigit()),
+    )(src)?;
+    match char::from_u32(u32::from_str_radix(digits, 16).unwrap()) {
+        Some(c) => Ok((rest, c)),
+        None => Err(nom::Err::Failure(nom::error::Error::from_error_kind(
+            src,
+            nom::error::ErrorKind::HexDigit,
+        ))),
+    }
+}
+
+fn parse_byte_escape(src: &str) -> IResult<&str, char> {
+    let hex = preceded(
+        tag("\\x"),
+        map_res(take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit()), |d| {
+            u8::from_str_radix(d, 16)
+        }),
+    );
+    let octal = preceded(
+        tag("\\"),
+        map_res(take_while_m_n(3, 3, |c: char| c.is_digit(8)), |d| {
+            u16::from_str_radix(d, 8)
+        }),
+    );
+    alt((
+        map(hex, char::from),
+        map(octal, |v| char::from_u32(v as u32).unwrap()),
+    ))(src)
+}
+
+fn parse_named_escape(src: &str) -> IResult<&str, char> {
+    let (src, _) = tag("\\")(src)?;
+    let (src, c) = one_of("ntrvf0\\\"[]^-")(src)?;
+    let c = match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'v' => '\u{b}',
+        'f' => '\u{c}',
+        '0' => '\0',
+        c => c,
+    };
+    Ok((src, c))
+}
+
+// Literals and sets share one set of escapes, so an escape means the same
+// character wherever it's written. Any other backslash is an error in both.
+fn parse_escape(src: &str) -> IResult<&str, char> {
+    let (rest, c) = opt(alt((
+        parse_unicode_escape,
+        parse_short_unicode_escape,
+        parse_byte_escape,
+        parse_named_escape,
+    )))(src)?;
+    match c {
+        Some(c) => Ok((rest, c)),
+        None if src.starts_with('\\') => Err(nom::Err::Failure(
+            nom::error::Error::from_error_kind(src, nom::error::ErrorKind::Escaped),
+        )),
+        None => Err(nom::Err::Error(nom::error::Error::from_error_kind(
+            src,
+            nom::error::ErrorKind::Escaped,
+        ))),
+    }
+}
+
+fn parse_set_char(src: &str) -> IResult<&str, char> {
+    alt((parse_escape, satisfy(|c: char| c != ']')))(src)
+}
+
+fn shorthand_ranges(class: char) -> Vec<(char, char)> {
+    let ranges = match class.to_ascii_lowercase() {
+        'd' => vec![('0', '9')],
+        'w' => vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        _ => vec![('\t', '\n'), ('\r', '\r'), (' ', ' ')],
+    };
+    if class.is_ascii_lowercase() {
+        return ranges;
+    }
+    complement(ranges)
+}
+
+fn complement(ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    let mut complement = Vec::new();
+    let mut next = Some('\0');
+    for (start, end) in ranges {
+        if let Some(next) = next.filter(|next| *next < start) {
+            complement.push((next, char_before(start).unwrap()));
+        }
+        next = char_after(end);
+    }
+    if let Some(next) = next {
+        complement.push((next, char::MAX));
+    }
+    complement
+}
+
+// \p{L} matches the general category or property, \P{L} everything else.
+fn parse_property(src: &str) -> IResult<&str, Element> {
+    let (rest, (class, name)) = tuple((
+        alt((tag("\\p"), tag("\\P"))),
+        delimited(
+            tag("{"),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+            tag("}"),
+        ),
+    ))(src)?;
+    match property_ranges(name) {
+        Some(ranges) => {
+            let (chars, classes) = (Vec::new(), Vec::new());
+            if class == "\\P" {
+                Ok((
+                    rest,
+                    Element::NegatedSet {
+                        chars,
+                        ranges,
+                        classes,
+                    },
+                ))
+            } else {
+                Ok((
+                    rest,
+                    Element::Set {
+                        chars,
+                        ranges,
+                        classes,
+                    },
+                ))
+            }
+        }
+        None => Err(nom::Err::Failure(nom::error::Error::from_error_kind(
+            src,
+            nom::error::ErrorKind::Alpha,
+        ))),
+    }
+}
+
+// A trailing \b asserts that no word character follows, the lookahead !\w.
+fn word_boundary() -> Element {
+    Element::Set {
+        chars: Vec::new(),
+        ranges: shorthand_ranges('w'),
+        classes: Vec::new(),
+    }
+}
+
+fn parse_shorthand(src: &str) -> IResult<&str, char> {
+    preceded(tag("\\"), one_of("dDwWsS"))(src)
 }
 
-fn parse_set<'src>(src: &'src str) -> IResult<&'src str, Element> {
+// Outside of literals, a metacharacter can be matched by escaping it, e.g. \(.
+fn parse_escaped_meta(src: &str) -> IResult<&str, Element> {
+    map(preceded(tag("\\"), one_of("()|+*?.[]{}^!\\\"")), |c| {
+        Element::Literal {
+            lit: SmolStr::new(c.to_string()),
+        }
+    })(src)
+}
+
+fn parse_shorthand_element(src: &str) -> IResult<&str, Element> {
+    let (src, class) = parse_shorthand(src)?;
+    let ranges = shorthand_ranges(class.to_ascii_lowercase());
+    let (chars, classes) = (Vec::new(), Vec::new());
+    if class.is_ascii_lowercase() {
+        Ok((
+            src,
+            Element::Set {
+                chars,
+                ranges,
+                classes,
+            },
+        ))
+    } else {
+        Ok((
+            src,
+            Element::NegatedSet {
+                chars,
+                ranges,
+                classes,
+            },
+        ))
+    }
+}
+
+fn parse_set(src: &str) -> IResult<&str, Element> {
     let (src, _) = tag("[")(src)?;
     let (src, negated) = opt(tag("^"))(src)?;
     let negated = negated.is_some();
     enum CharOrRange {
         Char(char),
         Range((char, char)),
+        Ranges(Vec<(char, char)>),
+        Class(SmolStr),
     }
     let (src, char_or_range) = many0(alt((
-        map(tag("\\]"), |_| CharOrRange::Char(']')),
-        map(tag("\\\\"), |_| CharOrRange::Char('\\')),
-        map(tag("\\-"), |_| CharOrRange::Char('-')),
         map(
-            tuple((
-                satisfy(|c: char| c != ']'),
-                tag("-"),
-                satisfy(|c: char| c != ']'),
-            )),
+            delimited(tag("\\{"), parse_name, tag("}")),
+            CharOrRange::Class,
+        ),
+        map(parse_shorthand, |class| {
+            CharOrRange::Ranges(shorthand_ranges(class))
+        }),
+        map(parse_property, |property| {
+            CharOrRange::Ranges(match property {
+                Element::NegatedSet { ranges, .. } => complement(ranges),
+                Element::Set { ranges, .. } => ranges,
+                _ => unreachable!(),
+            })
+        }),
+        map(
+            tuple((parse_set_char, tag("-"), parse_set_char)),
             |(a, _, b)| CharOrRange::Range((a, b)),
         ),
-        map(satisfy(|c: char| c != ']'), |c| CharOrRange::Char(c)),
+        map(parse_set_char, CharOrRange::Char),
     )))(src)?;
     let (src, _) = tag("]")(src)?;
     let mut chars = Vec::new();
     let mut ranges = Vec::new();
+    let mut classes = Vec::new();
     for cor in char_or_range {
         match cor {
             CharOrRange::Char(c) => chars.push(c),
             CharOrRange::Range(c) => ranges.push(c),
+            CharOrRange::Ranges(r) => ranges.extend(r),
+            CharOrRange::Class(name) => classes.push(name),
         }
     }
     if negated {
-        Ok((src, Element::NegatedSet { chars, ranges }))
+        Ok((
+            src,
+            Element::NegatedSet {
+                chars,
+                ranges,
+                classes,
+            },
+        ))
     } else {
-        Ok((src, Element::Set { chars, ranges }))
+        Ok((
+            src,
+            Element::Set {
+                chars,
+                ranges,
+                classes,
+            },
+        ))
     }
 }
 
-fn parse_literal<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_literal_text(src: &str) -> IResult<&str, SmolStr> {
     let (src, _) = tag("\"")(src)?;
-    let (src, contents) = escaped(take_while1(|c: char| c != '"'), '\\', tag("\""))(src)?;
+    let (src, contents) = many1(alt((
+        parse_escape,
+        satisfy(|c: char| c != '"' && c != '\\'),
+    )))(src)?;
     let (src, _) = tag("\"")(src)?;
-    Ok((
-        src,
-        Element::Literal {
-            lit: SmolStr::new(contents),
-        },
-    ))
+    Ok((src, contents.into_iter().collect()))
 }
 
-fn parse_repetition<'src>(src: &'src str) -> IResult<&'src str, Element> {
-    let (src, base) = parse_group(src)?;
-    let inner = Box::new(base);
-    let (src, kind) = one_of("+*?")(src)?;
-    match kind {
-        '+' => Ok((src, Element::OneOrMore { inner })),
-        '*' => Ok((src, Element::ZeroOrMore { inner })),
-        '?' => Ok((src, Element::Optional { inner })),
-        _ => Err(nom::Err::Error(nom::error::Error::from_error_kind(
+fn parse_literal(src: &str) -> IResult<&str, Element> {
+    map(parse_literal_text, |lit| Element::Literal { lit })(src)
+}
+
+pub fn case_variants(c: char) -> Vec<char> {
+    let mut variants = vec![c];
+    for variant in c.to_lowercase().chain(c.to_uppercase()) {
+        if c.to_lowercase().count() == 1
+            && c.to_uppercase().count() == 1
+            && !variants.contains(&variant)
+        {
+            variants.push(variant);
+        }
+    }
+    variants
+}
+
+pub fn fold_literal(lit: &str) -> Element {
+    Element::Group {
+        subelems: lit
+            .chars()
+            .map(|c| {
+                let variants = case_variants(c);
+                if variants.len() == 1 {
+                    Element::Literal {
+                        lit: SmolStr::from(c.to_string()),
+                    }
+                } else {
+                    Element::Set {
+                        chars: variants,
+                        ranges: Vec::new(),
+                        classes: Vec::new(),
+                    }
+                }
+            })
+            .collect(),
+    }
+}
+
+fn parse_insensitive_literal(src: &str) -> IResult<&str, Element> {
+    map(preceded(tag("i"), parse_literal_text), |lit| {
+        fold_literal(&lit)
+    })(src)
+}
+
+/// The largest count accepted in `{n,m}`. Bounds are expanded into copies of
+/// the element, so anything much larger only produces an unusable automaton.
+const MAX_REPETITIONS: usize = 1000;
+
+fn parse_bounds(src: &str) -> IResult<&str, (usize, Option<usize>)> {
+    let invalid = || {
+        nom::Err::Failure(nom::error::Error::from_error_kind(
             src,
-            nom::error::ErrorKind::MapRes,
-        ))),
+            nom::error::ErrorKind::Verify,
+        ))
+    };
+    let number = |rest| -> IResult<&str, usize> {
+        let (rest, digits) = digit1(rest)?;
+        match digits.parse::<usize>() {
+            Ok(n) if n <= MAX_REPETITIONS => Ok((rest, n)),
+            _ => Err(invalid()),
+        }
+    };
+    let (rest, _) = tag("{")(src)?;
+    let (rest, min) = number(rest)?;
+    let (rest, max) = alt((
+        map(preceded(tag(","), number), Some),
+        map(tag(","), |_| None),
+        success(Some(min)),
+    ))(rest)?;
+    let (rest, _) = tag("}")(rest)?;
+    // {0,} is the same as *, but nothing else may match only the empty string.
+    if max.is_some_and(|max| max < min || max == 0) {
+        return Err(invalid());
+    }
+    Ok((rest, (min, max)))
+}
+
+fn repeat(element: Element, min: usize, max: Option<usize>) -> Element {
+    let mut subelems = vec![element.clone(); min];
+    match max {
+        None if min == 0 => {
+            return Element::ZeroOrMore {
+                inner: Box::new(element),
+            }
+        }
+        None => {
+            let last = subelems.pop().unwrap();
+            subelems.push(Element::OneOrMore {
+                inner: Box::new(last),
+            });
+        }
+        Some(max) => {
+            for _ in min..max {
+                subelems.push(Element::Optional {
+                    inner: Box::new(element.clone()),
+                });
+            }
+        }
+    }
+    if subelems.len() == 1 {
+        subelems.remove(0)
+    } else {
+        Element::Group { subelems }
     }
 }
 
-fn parse_repetition_no_rule<'src>(src: &'src str) -> IResult<&'src str, Element> {
-    let (src, base) = parse_group_no_rule(src)?;
+fn parse_repetition_suffix(src: &str, base: Element) -> IResult<&str, Element> {
+    match parse_bounds(src) {
+        Ok((src, (min, max))) => return Ok((src, repeat(base, min, max))),
+        Err(nom::Err::Failure(e)) => return Err(nom::Err::Failure(e)),
+        Err(_) => {}
+    }
     let inner = Box::new(base);
     let (src, kind) = one_of("+*?")(src)?;
     match kind {
@@ -137,11 +538,29 @@ fn parse_repetition_no_rule<'src>(src: &'src str) -> IResult<&'src str, Element>
     }
 }
 
-fn parse_group<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_any(src: &str) -> IResult<&str, Element> {
+    map(tag("."), |_| Element::NegatedSet {
+        chars: Vec::new(),
+        ranges: Vec::new(),
+        classes: Vec::new(),
+    })(src)
+}
+
+fn parse_repetition(src: &str) -> IResult<&str, Element> {
+    let (src, base) = alt((parse_any, parse_group))(src)?;
+    parse_repetition_suffix(src, base)
+}
+
+fn parse_repetition_no_rule(src: &str) -> IResult<&str, Element> {
+    let (src, base) = alt((parse_any, parse_group_no_rule))(src)?;
+    parse_repetition_suffix(src, base)
+}
+
+fn parse_group(src: &str) -> IResult<&str, Element> {
     let (src, _) = tag("(")(src)?;
-    let (src, _) = space0(src)?;
-    let (src, mut elements) = separated_list1(space1, parse_element)(src)?;
-    let (src, _) = space0(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, mut elements) = separated_list1(parse_element_separator, parse_element)(src)?;
+    let (src, _) = parse_blank(src)?;
     let (src, _) = tag(")")(src)?;
     if elements.len() == 1 {
         Ok((src, elements.remove(0)))
@@ -150,12 +569,12 @@ fn parse_group<'src>(src: &'src str) -> IResult<&'src str, Element> {
     }
 }
 
-fn parse_alternatives<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_alternatives(src: &str) -> IResult<&str, Element> {
     let (src, _) = tag("(")(src)?;
-    let (src, _) = space0(src)?;
+    let (src, _) = parse_blank(src)?;
     let (src, mut elements) =
-        separated_list1(tuple((space0, tag("|"), space0)), parse_element)(src)?;
-    let (src, _) = space0(src)?;
+        separated_list1(tuple((parse_blank, tag("|"), parse_blank)), parse_element)(src)?;
+    let (src, _) = parse_blank(src)?;
     let (src, _) = tag(")")(src)?;
     if elements.len() == 1 {
         Ok((src, elements.remove(0)))
@@ -164,11 +583,11 @@ fn parse_alternatives<'src>(src: &'src str) -> IResult<&'src str, Element> {
     }
 }
 
-fn parse_group_no_rule<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_group_no_rule(src: &str) -> IResult<&str, Element> {
     let (src, _) = tag("(")(src)?;
-    let (src, _) = space0(src)?;
-    let (src, mut elements) = separated_list1(space1, parse_element_no_rule)(src)?;
-    let (src, _) = space0(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, mut elements) = separated_list1(parse_element_separator, parse_element_no_rule)(src)?;
+    let (src, _) = parse_blank(src)?;
     let (src, _) = tag(")")(src)?;
     if elements.len() == 1 {
         Ok((src, elements.remove(0)))
@@ -177,12 +596,14 @@ fn parse_group_no_rule<'src>(src: &'src str) -> IResult<&'src str, Element> {
     }
 }
 
-fn parse_alternatives_no_rule<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_alternatives_no_rule(src: &str) -> IResult<&str, Element> {
     let (src, _) = tag("(")(src)?;
-    let (src, _) = space0(src)?;
-    let (src, mut elements) =
-        separated_list1(tuple((space0, tag("|"), space0)), parse_element_no_rule)(src)?;
-    let (src, _) = space0(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, mut elements) = separated_list1(
+        tuple((parse_blank, tag("|"), parse_blank)),
+        parse_element_no_rule,
+    )(src)?;
+    let (src, _) = parse_blank(src)?;
     let (src, _) = tag(")")(src)?;
     if elements.len() == 1 {
         Ok((src, elements.remove(0)))
@@ -191,135 +612,2627 @@ fn parse_alternatives_no_rule<'src>(src: &'src str) -> IResult<&'src str, Elemen
     }
 }
 
-fn parse_element_rule<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_element_rule(src: &str) -> IResult<&str, Element> {
     let (src, var_opt) = opt(tuple((parse_name, tag(":"))))(src)?;
     let var = var_opt.map(|(var, _)| var);
     let (src, name) = parse_name(src)?;
     Ok((src, Element::Rule { var, name }))
 }
 
-fn parse_element<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_fragment_ref(src: &str) -> IResult<&str, Element> {
+    map(verify(parse_name, |name: &str| name != "display"), |name| {
+        Element::Rule { var: None, name }
+    })(src)
+}
+
+fn parse_element(src: &str) -> IResult<&str, Element> {
     alt((
+        parse_shorthand_element,
+        parse_property,
+        parse_escaped_meta,
         parse_repetition,
         parse_literal,
+        parse_insensitive_literal,
         parse_set,
+        parse_any,
         parse_element_rule,
         parse_group,
         parse_alternatives,
     ))(src)
 }
 
-fn parse_element_no_rule<'src>(src: &'src str) -> IResult<&'src str, Element> {
+fn parse_element_no_rule(src: &str) -> IResult<&str, Element> {
     alt((
+        parse_shorthand_element,
+        parse_property,
+        parse_escaped_meta,
         parse_repetition_no_rule,
         parse_literal,
+        parse_insensitive_literal,
         parse_set,
+        parse_any,
+        parse_fragment_ref,
         parse_group_no_rule,
         parse_alternatives_no_rule,
     ))(src)
 }
 
-fn parse_token<'src>(src: &'src str) -> IResult<&'src str, Rule> {
+fn parse_token(src: &str) -> IResult<&str, Rule> {
     let (src, _) = tag("token")(src)?;
     let (src, _) = space1(src)?;
     let (src, name) = parse_name(src)?;
     let (src, _) = space0(src)?;
     let (src, _) = tag("=")(src)?;
-    let (src, _) = space0(src)?;
-    let (src, elements) = separated_list1(space1, parse_element_no_rule)(src)?;
-    let (src, _) = tag(";")(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, line_start) = opt(terminated(tag("^"), parse_blank))(src)?;
+    let line_start = line_start.is_some();
+    let (src, elements) = cut(|src| parse_body(src, parse_element_no_rule))(src)?;
+    let (src, lookahead) = opt(alt((
+        preceded(
+            tuple((parse_blank, tag("!"), parse_blank)),
+            parse_element_no_rule,
+        ),
+        map(preceded(parse_blank, tag("\\b")), |_| word_boundary()),
+    )))(src)?;
+    let (src, value_type) = opt(preceded(
+        tuple((parse_blank, tag("->"), parse_blank)),
+        parse_name,
+    ))(src)?;
+    let (src, display) = opt(preceded(
+        tuple((
+            parse_element_separator,
+            tag("display"),
+            parse_element_separator,
+        )),
+        parse_literal_text,
+    ))(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
     Ok((
         src,
         Rule {
             export: false,
+            case_sensitive: false,
+            internal: None,
             is_terminal: true,
+            fragment: false,
+            skip: false,
+            priority: 0,
             name,
             element: Element::Group { subelems: elements },
             constructor_name: None,
             constructor_vars: None,
+            value_type,
+            display,
+            mode: None,
+            action: None,
+            line_start,
+            lookahead,
+            attributes: Vec::new(),
+            line: None,
         },
     ))
 }
 
-fn parse_constructor<'src>(src: &'src str) -> IResult<&'src str, (SmolStr, Vec<SmolStr>)> {
+fn parse_class(src: &str) -> IResult<&str, (SmolStr, Element)> {
+    let (src, _) = tag("class")(src)?;
+    let (src, _) = space1(src)?;
+    let (src, name) = parse_name(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("=")(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, set) = cut(parse_set)(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
+    Ok((src, (name, set)))
+}
+
+fn parse_equiv(src: &str) -> IResult<&str, (SmolStr, Vec<SmolStr>)> {
+    let (src, _) = tag("equiv")(src)?;
+    let (src, _) = space1(src)?;
+    let (src, name) = parse_name(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("=")(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, members) = cut(separated_list1(
+        tuple((parse_blank, tag("|"), parse_blank)),
+        parse_name,
+    ))(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
+    Ok((src, (name, members)))
+}
+
+fn parse_assert_exclusive(src: &str) -> IResult<&str, Vec<SmolStr>> {
+    let (src, _) = tag("assert")(src)?;
+    let (src, _) = space1(src)?;
+    let (src, _) = tag("exclusive")(src)?;
+    let (src, _) = cut(parse_element_separator)(src)?;
+    let (src, names) = cut(separated_list1(
+        tuple((parse_blank, tag(","), parse_blank)),
+        parse_name,
+    ))(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
+    Ok((src, names))
+}
+
+fn parse_constructor(src: &str) -> IResult<&str, (SmolStr, Vec<SmolStr>)> {
     let (src, type_name) = parse_name(src)?;
+    let (src, _) = parse_blank(src)?;
     let (src, _) = tag("(")(src)?;
-    let (src, vars) = separated_list0(tuple((space0, tag(","), space0)), parse_name)(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, vars) =
+        separated_list0(tuple((parse_blank, tag(","), parse_blank)), parse_name)(src)?;
+    let (src, _) = parse_blank(src)?;
+    // A trailing comma is fine, but only after at least one variable.
+    let (src, _) = if vars.is_empty() {
+        (src, None)
+    } else {
+        opt(terminated(tag(","), parse_blank))(src)?
+    };
     let (src, _) = tag(")")(src)?;
     Ok((src, (type_name, vars)))
 }
 
-fn parse_name<'src>(src: &'src str) -> IResult<&'src str, SmolStr> {
+fn parse_name(src: &str) -> IResult<&str, SmolStr> {
     let (src, name_fc) = take_while_m_n(1, 1, |c: char| c.is_alphabetic())(src)?;
     let (src, name) = take_while(|c: char| c.is_alphanumeric() || c == '_')(src)?;
     let name = SmolStr::new(format!("{}{}", name_fc, name));
     Ok((src, name))
 }
 
-fn parse_nonterminal<'src>(src: &'src str) -> IResult<&'src str, Rule> {
+// A rule body is a sequence of elements, or alternatives of sequences
+// separated by '|' without the parentheses they need elsewhere.
+fn parse_body<'src>(
+    src: &'src str,
+    element: fn(&'src str) -> IResult<&'src str, Element>,
+) -> IResult<&'src str, Vec<Element>> {
+    let (src, mut branches) = separated_list1(
+        tuple((parse_blank, tag("|"), parse_blank)),
+        separated_list1(parse_element_separator, element),
+    )(src)?;
+    if branches.len() == 1 {
+        return Ok((src, branches.remove(0)));
+    }
+    let subelems = branches
+        .into_iter()
+        .map(|mut elements| {
+            if elements.len() == 1 {
+                elements.remove(0)
+            } else {
+                Element::Group { subelems: elements }
+            }
+        })
+        .collect();
+    Ok((src, vec![Element::Alternatives { subelems }]))
+}
+
+fn parse_nonterminal(src: &str) -> IResult<&str, Rule> {
     let (src, _) = tag("nonterm")(src)?;
     let (src, _) = space1(src)?;
     let (src, name) = parse_name(src)?;
     let (src, _) = space0(src)?;
     let (src, _) = tag("=")(src)?;
-    let (src, _) = space0(src)?;
-    let (src, elements) = separated_list1(space1, parse_element)(src)?;
-    let (src, _) = space0(src)?;
-    let (src, _) = tag("->")(src)?;
-    let (src, _) = space0(src)?;
-    let (src, (type_name, vars)) = parse_constructor(src)?;
-    let (src, _) = tag(";")(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, elements) = cut(|src| parse_body(src, parse_element))(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, _) = cut(tag("->"))(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, (type_name, vars)) = cut(parse_constructor)(src)?;
+    let (src, _) = parse_blank(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
     Ok((
         src,
         Rule {
             export: false,
+            case_sensitive: false,
+            internal: None,
             is_terminal: false,
+            fragment: false,
+            skip: false,
+            priority: 0,
             name,
             element: Element::Group { subelems: elements },
             constructor_name: Some(type_name),
             constructor_vars: Some(vars),
+            value_type: None,
+            display: None,
+            mode: None,
+            action: None,
+            line_start: false,
+            lookahead: None,
+            attributes: Vec::new(),
+            line: None,
         },
     ))
 }
 
-fn parse_rule<'src>(src: &'src str) -> IResult<&'src str, Rule> {
-    let (src, export) = opt(tag("export "))(src)?;
+enum Modifier {
+    Export,
+    Fragment,
+    Skip,
+    CaseSensitive,
+    Internal(SmolStr),
+    Priority(u32),
+    Push(SmolStr),
+    Pop,
+}
+
+fn parse_modifier(src: &str) -> IResult<&str, Modifier> {
+    let (src, modifier) = alt((
+        map(tag("export"), |_| Modifier::Export),
+        map(tag("fragment"), |_| Modifier::Fragment),
+        map(tag("skip"), |_| Modifier::Skip),
+        map(tag("case_sensitive"), |_| Modifier::CaseSensitive),
+        map(
+            preceded(
+                tag("internal"),
+                opt(delimited(tag("("), parse_name, tag(")"))),
+            ),
+            |target| Modifier::Internal(target.unwrap_or_else(|| SmolStr::new("_ERR"))),
+        ),
+        map(
+            delimited(
+                tag("priority("),
+                map_res(digit1, str::parse::<u32>),
+                tag(")"),
+            ),
+            Modifier::Priority,
+        ),
+        map(
+            delimited(tag("push("), parse_name, tag(")")),
+            Modifier::Push,
+        ),
+        map(tag("pop"), |_| Modifier::Pop),
+    ))(src)?;
+    let (src, _) = space1(src)?;
+    Ok((src, modifier))
+}
+
+fn parse_attribute(src: &str) -> IResult<&str, (SmolStr, Option<SmolStr>)> {
+    let (src, _) = tag("@")(src)?;
+    let (src, name) = parse_name(src)?;
+    let (src, value) = opt(delimited(
+        tag("("),
+        alt((parse_literal_text, parse_name)),
+        tag(")"),
+    ))(src)?;
+    Ok((src, (name, value)))
+}
+
+fn parse_rule(src: &str) -> IResult<&str, Rule> {
+    let (src, attributes) = many0(terminated(parse_attribute, parse_element_separator))(src)?;
+    let remaining = src.len();
+    let (src, modifiers) = many0(parse_modifier)(src)?;
     let (src, mut rule) = alt((parse_token, parse_nonterminal))(src)?;
-    rule.export = export.is_some();
+    // Only how much source is left is known here, load_source turns it into
+    // a line number.
+    rule.line = Some(remaining);
+    rule.attributes = attributes;
+    for modifier in modifiers {
+        match modifier {
+            Modifier::Export => rule.export = true,
+            Modifier::Fragment => rule.fragment = true,
+            Modifier::Skip => rule.skip = true,
+            Modifier::CaseSensitive => rule.case_sensitive = true,
+            Modifier::Internal(target) => rule.internal = Some(target),
+            Modifier::Priority(priority) => rule.priority = priority,
+            Modifier::Push(mode) => rule.action = Some(ModeAction::Push(mode)),
+            Modifier::Pop => rule.action = Some(ModeAction::Pop),
+        }
+    }
     Ok((src, rule))
 }
 
-fn parse_rules<'src>(src: &'src str) -> IResult<&'src str, Vec<Rule>> {
-    let (src, rules) = separated_list1(newline, parse_rule)(src)?;
-    let (src, _) = opt(newline)(src)?;
-    Ok((src, rules))
+fn parse_option(src: &str) -> IResult<&str, (SmolStr, SmolStr)> {
+    let (src, name) = parse_name(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("=")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, value) = parse_name(src)?;
+    let (src, _) = tag(";")(src)?;
+    Ok((src, (name, value)))
 }
 
-pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Vec<Rule>> {
-    let mut rule_file = File::open(path)?;
-    let mut src = String::new();
-    rule_file.read_to_string(&mut src)?;
-    match parse_rules(&src) {
-        Ok((rest, rules)) => {
-            ensure!(
-                rest.is_empty(),
-                "Failed to parse whole file, remainder was: {:?}",
-                rest
-            );
-            let mut rule_names = HashSet::new();
-            for rule in &rules {
-                rule_names.insert(&rule.name);
+fn parse_options(src: &str) -> IResult<&str, Vec<(SmolStr, SmolStr)>> {
+    let (src, _) = tag("options")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("{")(src)?;
+    let (src, _) = multispace0(src)?;
+    let (src, options) = cut(separated_list0(multispace1, parse_option))(src)?;
+    let (src, _) = multispace0(src)?;
+    let (src, _) = cut(tag("}"))(src)?;
+    Ok((src, options))
+}
+
+fn parse_match(src: &str) -> IResult<&str, MatchMode> {
+    let (src, _) = tag("match")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("=")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, mode) = cut(alt((
+        map(tag("longest"), |_| MatchMode::Longest),
+        map(tag("shortest"), |_| MatchMode::Shortest),
+    )))(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
+    Ok((src, mode))
+}
+
+fn parse_eof(src: &str) -> IResult<&str, SmolStr> {
+    let (src, _) = tag("eof")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("=")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, name) = cut(parse_name)(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
+    Ok((src, name))
+}
+
+fn parse_fallback(src: &str) -> IResult<&str, SmolStr> {
+    let (src, _) = tag("fallback")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("=")(src)?;
+    let (src, _) = space0(src)?;
+    let (src, name) = cut(parse_name)(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
+    Ok((src, name))
+}
+
+fn parse_import(src: &str) -> IResult<&str, SmolStr> {
+    let (src, _) = tag("import")(src)?;
+    let (src, _) = space1(src)?;
+    let (src, path) = cut(parse_literal_text)(src)?;
+    let (src, _) = cut(tag(";"))(src)?;
+    Ok((src, path))
+}
+
+fn parse_mode(src: &str) -> IResult<&str, (SmolStr, Vec<Rule>)> {
+    let (src, _) = tag("mode")(src)?;
+    let (src, _) = space1(src)?;
+    let (src, name) = parse_name(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = tag("{")(src)?;
+    let (src, _) = cut(many1(parse_line_end))(src)?;
+    let (src, _) = parse_leading_comments(src)?;
+    let (src, rules) = cut(separated_list1(
+        parse_separator,
+        preceded(space0, parse_rule),
+    ))(src)?;
+    let (src, _) = many0(parse_line_end)(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = cut(tag("}"))(src)?;
+    Ok((src, (name, rules)))
+}
+
+fn parse_statement(src: &str) -> IResult<&str, Statement> {
+    alt((
+        map(parse_import, Statement::Import),
+        map(parse_match, Statement::Match),
+        map(parse_eof, Statement::Eof),
+        map(parse_fallback, Statement::Fallback),
+        map(parse_options, Statement::Options),
+        map(parse_class, |(name, set)| Statement::Class(name, set)),
+        map(parse_equiv, |(name, members)| {
+            Statement::Equiv(name, members)
+        }),
+        map(parse_assert_exclusive, Statement::AssertExclusive),
+        map(parse_mode, |(name, rules)| Statement::Mode(name, rules)),
+        map(preceded(tag("override "), parse_rule), Statement::Override),
+        map(parse_rule, Statement::Rule),
+    ))(src)
+}
+
+fn parse_line_comment(src: &str) -> IResult<&str, &str> {
+    preceded(alt((tag("#"), tag("//"))), take_while(|c: char| c != '\n'))(src)
+}
+
+fn parse_block_comment(src: &str) -> IResult<&str, &str> {
+    let (mut rest, _) = tag("/*")(src)?;
+    let mut depth = 1;
+    while depth > 0 {
+        if let Some(r) = rest.strip_prefix("/*") {
+            depth += 1;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("*/") {
+            depth -= 1;
+            rest = r;
+        } else {
+            let mut chars = rest.chars();
+            if chars.next().is_none() {
+                return Err(nom::Err::Error(nom::error::Error::from_error_kind(
+                    src,
+                    nom::error::ErrorKind::TakeUntil,
+                )));
             }
-            ensure!(rule_names.len() == rules.len(), "Rule names aren't unique");
-            Ok(rules)
+            rest = chars.as_str();
         }
-        Err(nom::Err::Error(nom::error::Error { input, code })) => {
-            bail!(
-                "Error '{:?}' while parsing with remaining input: {:?}",
-                code,
-                input
-            )
+    }
+    Ok((rest, &src[..src.len() - rest.len()]))
+}
+
+fn parse_comment(src: &str) -> IResult<&str, &str> {
+    alt((parse_line_comment, parse_block_comment))(src)
+}
+
+fn parse_leading_comments(src: &str) -> IResult<&str, ()> {
+    let (src, _) = many0(alt((
+        parse_line_end,
+        map(terminated(parse_block_comment, space0), |_| ()),
+    )))(src)?;
+    Ok((src, ()))
+}
+
+fn parse_line_end(src: &str) -> IResult<&str, ()> {
+    let (src, _) = space0(src)?;
+    let (src, _) = many0(terminated(parse_comment, space0))(src)?;
+    let (src, _) = line_ending(src)?;
+    Ok((src, ()))
+}
+
+fn parse_separator(src: &str) -> IResult<&str, ()> {
+    let (src, _) = parse_line_end(src)?;
+    let (src, _) = parse_leading_comments(src)?;
+    Ok((src, ()))
+}
+
+// Whitespace, including newlines, and comments inside a statement, which only ends at
+// its ';'.
+fn parse_blank(src: &str) -> IResult<&str, ()> {
+    let (src, _) = many0(alt((map(multispace1, |_| ()), map(parse_comment, |_| ()))))(src)?;
+    Ok((src, ()))
+}
+
+fn parse_element_separator(src: &str) -> IResult<&str, ()> {
+    let (src, _) = many1(alt((map(multispace1, |_| ()), map(parse_comment, |_| ()))))(src)?;
+    Ok((src, ()))
+}
+
+fn parse_statements(src: &str) -> IResult<&str, Vec<Statement>> {
+    let (src, _) = parse_leading_comments(src)?;
+    let (src, statements) = separated_list1(parse_separator, parse_statement)(src)?;
+    let (src, _) = many0(parse_line_end)(src)?;
+    let (src, _) = space0(src)?;
+    let (src, _) = many0(terminated(parse_comment, space0))(src)?;
+    Ok((src, statements))
+}
+
+fn rule_references(rule: &Rule) -> Vec<&SmolStr> {
+    let mut names = Vec::new();
+    referenced_rules(&rule.element, &mut names);
+    if let Some(lookahead) = &rule.lookahead {
+        referenced_rules(lookahead, &mut names);
+    }
+    names
+}
+
+fn referenced_rules<'a>(element: &'a Element, names: &mut Vec<&'a SmolStr>) {
+    match element {
+        Element::Rule { name, .. } => names.push(name),
+        Element::OneOrMore { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::Optional { inner } => referenced_rules(inner, names),
+        Element::Alternatives { subelems } | Element::Group { subelems } => {
+            for elem in subelems {
+                referenced_rules(elem, names);
+            }
+        }
+        Element::Set { .. } | Element::NegatedSet { .. } | Element::Literal { .. } => {}
+    }
+}
+
+fn bound_variables<'a>(element: &'a Element, vars: &mut Vec<&'a SmolStr>) {
+    match element {
+        Element::Rule { var: Some(var), .. } if !vars.contains(&var) => vars.push(var),
+        Element::OneOrMore { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::Optional { inner } => bound_variables(inner, vars),
+        Element::Alternatives { subelems } | Element::Group { subelems } => {
+            for elem in subelems {
+                bound_variables(elem, vars);
+            }
         }
-        _ => bail!("Unexpected error while parsing"),
+        _ => {}
+    }
+}
+
+fn referenced_sets<'a>(element: &'a Element, sets: &mut Vec<&'a Element>) {
+    match element {
+        Element::Set { .. } | Element::NegatedSet { .. } => sets.push(element),
+        Element::OneOrMore { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::Optional { inner } => referenced_sets(inner, sets),
+        Element::Alternatives { subelems } | Element::Group { subelems } => {
+            for elem in subelems {
+                referenced_sets(elem, sets);
+            }
+        }
+        Element::Rule { .. } | Element::Literal { .. } => {}
+    }
+}
+
+fn describe_range(start: char, end: char) -> String {
+    if start == end {
+        format!("'{}'", start.escape_debug())
+    } else {
+        format!("'{}-{}'", start.escape_debug(), end.escape_debug())
+    }
+}
+
+// Lists the characters a set names more than once, e.g. [a-cb-d] names b-c twice.
+fn set_overlaps(chars: &[char], ranges: &[(char, char)]) -> Vec<String> {
+    let items: Vec<(char, char)> = chars
+        .iter()
+        .map(|&c| (c, c))
+        .chain(ranges.iter().copied())
+        .collect();
+    let mut overlaps = Vec::new();
+    for (i, &(start, end)) in items.iter().enumerate() {
+        for &(other_start, other_end) in &items[i + 1..] {
+            let (lo, hi) = (start.max(other_start), end.min(other_end));
+            if lo > hi {
+                continue;
+            }
+            if (start, end) == (other_start, other_end) {
+                overlaps.push(format!("{} appears twice", describe_range(start, end)));
+            } else {
+                overlaps.push(format!(
+                    "{} and {} overlap on {}",
+                    describe_range(start, end),
+                    describe_range(other_start, other_end),
+                    describe_range(lo, hi)
+                ));
+            }
+        }
+    }
+    overlaps
+}
+
+fn check_sets(owner: &SmolStr, element: &Element, strict: bool) -> Result<()> {
+    let mut sets = Vec::new();
+    referenced_sets(element, &mut sets);
+    for set in sets {
+        let (Element::Set { chars, ranges, .. } | Element::NegatedSet { chars, ranges, .. }) = set
+        else {
+            continue;
+        };
+        if let Some(&(start, end)) = ranges.iter().find(|(start, end)| start > end) {
+            bail!(
+                Grammar,
+                "Set {} in '{}' has the range {} written backwards, it should be {}",
+                set,
+                owner,
+                describe_range(start, end),
+                describe_range(end, start)
+            );
+        }
+        let overlaps = set_overlaps(chars, ranges);
+        if overlaps.is_empty() {
+            continue;
+        }
+        let message = format!(
+            "Set {} in '{}' names characters more than once: {}",
+            set,
+            owner,
+            overlaps.join(", ")
+        );
+        ensure!(!strict, Grammar, "{}", message);
+        log::warn!("{}", message);
+    }
+    Ok(())
+}
+
+// Tokens are used by the lexer and the first nonterminal is the parser's
+// start, so only fragments and the other nonterminals can go unused.
+fn unused_rules(rules: &[Rule]) -> Vec<&Rule> {
+    let start = rules.iter().find(|rule| !rule.is_terminal);
+    rules
+        .iter()
+        .filter(|rule| {
+            let used = rule.export
+                || (rule.is_terminal && !rule.fragment)
+                || start.is_some_and(|start| start.name == rule.name);
+            !used
+        })
+        .filter(|rule| {
+            !rules
+                .iter()
+                .filter(|r| r.name != rule.name)
+                .any(|r| rule_references(r).contains(&&rule.name))
+        })
+        .collect()
+}
+
+fn warn_unused(rules: &[Rule]) {
+    for rule in unused_rules(rules) {
+        let kind = if rule.fragment {
+            "Fragment"
+        } else {
+            "Nonterminal"
+        };
+        log::warn!("{} '{}' is never referenced", kind, rule.name);
+    }
+}
+
+// A token copied from a fragment usually meant to reference it instead.
+fn warn_fragment_copies(rules: &[Rule]) {
+    for fragment in rules.iter().filter(|rule| rule.fragment) {
+        let body = fragment.element.to_string();
+        for rule in rules
+            .iter()
+            .filter(|rule| rule.is_terminal && !rule.fragment)
+        {
+            if rule.element.to_string() == body {
+                log::warn!(
+                    "Token '{}' has the same definition as fragment '{}'",
+                    rule.name,
+                    fragment.name
+                );
+            }
+        }
+    }
+}
+
+fn parse_bool_option(name: &str, value: &str) -> Result<bool> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => bail!(
+            Grammar,
+            "Option '{}' expects true or false, got '{}'",
+            name,
+            value
+        ),
+    }
+}
+
+fn error_location(path: &Path, src: &str, rest: &str) -> String {
+    let offset = src.len() - rest.trim_start().len();
+    let line_start = src[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = src[offset..].find('\n').map_or(src.len(), |i| offset + i);
+    let line = src[..offset].matches('\n').count() + 1;
+    let before = &src[line_start..offset];
+    let caret: String = before
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "  --> {}:{}:{}\n   | {}\n   | {}^",
+        path.display(),
+        line,
+        before.chars().count() + 1,
+        src[line_start..line_end].trim_end_matches('\r'),
+        caret
+    )
+}
+
+fn decode_source(path: &Path, bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|e| {
+        let valid = e.utf8_error().valid_up_to();
+        let bytes = e.as_bytes();
+        let invalid = match e.utf8_error().error_len() {
+            Some(len) => &bytes[valid..valid + len],
+            None => &bytes[valid..],
+        };
+        let invalid: Vec<String> = invalid.iter().map(|b| format!("{:02x}", b)).collect();
+        let src = std::str::from_utf8(&bytes[..valid]).unwrap();
+        PargeError::Parse {
+            message: format!(
+                "Invalid UTF-8 at byte offset {}: [{}], rules files have to be UTF-8",
+                valid,
+                invalid.join(" ")
+            ),
+            location: error_location(path, src, ""),
+        }
+    })
+}
+
+fn load_statements(
+    path: &Path,
+    loading: &mut Vec<PathBuf>,
+    loaded: &mut HashSet<PathBuf>,
+) -> Result<Vec<Statement>> {
+    let canonical = path.canonicalize().map_err(|source| PargeError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    if let Some(start) = loading.iter().position(|p| p == &canonical) {
+        let cycle: Vec<String> = loading[start..]
+            .iter()
+            .chain([&canonical])
+            .map(|p| p.display().to_string())
+            .collect();
+        bail!(Grammar, "Import cycle: {}", cycle.join(" -> "));
+    }
+    if !loaded.insert(canonical.clone()) {
+        return Ok(Vec::new());
+    }
+    let mut rule_file = File::open(path)?;
+    let mut bytes = Vec::new();
+    rule_file.read_to_end(&mut bytes)?;
+    let src = decode_source(path, bytes)?;
+    loading.push(canonical);
+    let statements = load_source(path, &src, loading, loaded)?;
+    loading.pop();
+    Ok(statements)
+}
+
+fn load_source(
+    path: &Path,
+    src: &str,
+    loading: &mut Vec<PathBuf>,
+    loaded: &mut HashSet<PathBuf>,
+) -> Result<Vec<Statement>> {
+    let parsed = match parse_statements(src) {
+        Ok(("", statements)) => Ok(statements),
+        Ok((rest, _)) => Err((String::from("Failed to parse statement"), rest)),
+        Err(nom::Err::Error(nom::error::Error { input, code })) => {
+            Err((format!("Error '{:?}' while parsing", code), input))
+        }
+        Err(nom::Err::Failure(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Verify,
+        })) => Err((
+            format!(
+                "Invalid repetition bounds: {:?}",
+                input.split_inclusive('}').next().unwrap_or(input)
+            ),
+            input,
+        )),
+        Err(nom::Err::Failure(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Alpha,
+        })) => Err((
+            format!(
+                "Unknown unicode property: {:?}",
+                input.split_inclusive('}').next().unwrap_or(input)
+            ),
+            input,
+        )),
+        Err(nom::Err::Failure(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Escaped,
+        })) => Err((
+            format!(
+                "Unknown escape: {:?}",
+                input.chars().take(2).collect::<String>()
+            ),
+            input,
+        )),
+        Err(nom::Err::Failure(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::HexDigit,
+        })) => Err((
+            format!(
+                "Invalid unicode escape, expected a scalar value up to 10FFFF that isn't a surrogate: {:?}",
+                input.split_inclusive('}').next().unwrap_or(input)
+            ),
+            input,
+        )),
+        Err(nom::Err::Failure(nom::error::Error { input, code })) => {
+            Err((format!("Error '{:?}' while parsing", code), input))
+        }
+        _ => Err((String::from("Unexpected error while parsing"), src)),
+    };
+    let statements = match parsed {
+        Ok(statements) => statements,
+        Err((message, rest)) => {
+            return Err(PargeError::Parse {
+                message,
+                location: error_location(path, src, rest),
+            })
+        }
+    };
+    let mut expanded = Vec::new();
+    for mut statement in statements {
+        match &mut statement {
+            Statement::Rule(rule) | Statement::Override(rule) => locate_rule(src, rule),
+            Statement::Mode(_, rules) => rules.iter_mut().for_each(|rule| locate_rule(src, rule)),
+            _ => {}
+        }
+        match statement {
+            Statement::Import(import) => {
+                let import = path
+                    .parent()
+                    .unwrap_or(Path::new("."))
+                    .join(import.as_str());
+                expanded.extend(load_statements(&import, loading, loaded)?);
+            }
+            statement => expanded.push(statement),
+        }
+    }
+    Ok(expanded)
+}
+
+fn locate_rule(src: &str, rule: &mut Rule) {
+    rule.line = rule
+        .line
+        .map(|remaining| src[..src.len() - remaining].matches('\n').count() + 1);
+}
+
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Grammar> {
+    let statements = load_statements(path.as_ref(), &mut Vec::new(), &mut HashSet::new())?;
+    build_grammar(statements)
+}
+
+// The rules file and every file it imports, for watching them.
+pub fn source_files<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let mut loaded = HashSet::new();
+    load_statements(path.as_ref(), &mut Vec::new(), &mut loaded)?;
+    let mut files: Vec<PathBuf> = loaded.into_iter().collect();
+    files.sort();
+    Ok(files)
+}
+
+pub fn parse_str(src: &str) -> Result<Grammar> {
+    let statements = load_source(
+        Path::new("<string>"),
+        src,
+        &mut Vec::new(),
+        &mut HashSet::new(),
+    )?;
+    build_grammar(statements)
+}
+
+pub fn parse_reader<R: Read>(mut reader: R) -> Result<Grammar> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let src = decode_source(Path::new("<stdin>"), bytes)?;
+    let statements = load_source(
+        Path::new("<stdin>"),
+        &src,
+        &mut Vec::new(),
+        &mut HashSet::new(),
+    )?;
+    build_grammar(statements)
+}
+
+fn literal_token_name(lit: &str) -> String {
+    if lit.starts_with(|c: char| c.is_ascii_alphabetic())
+        && lit.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return lit.to_ascii_uppercase();
+    }
+    let parts: Vec<String> = lit
+        .chars()
+        .map(|c| {
+            match c {
+                '+' => "PLUS",
+                '-' => "MINUS",
+                '*' => "STAR",
+                '/' => "SLASH",
+                '%' => "PERCENT",
+                '=' => "EQ",
+                '<' => "LT",
+                '>' => "GT",
+                '!' => "BANG",
+                '&' => "AMP",
+                '|' => "PIPE",
+                '^' => "CARET",
+                '~' => "TILDE",
+                '?' => "QUESTION",
+                ':' => "COLON",
+                ';' => "SEMI",
+                ',' => "COMMA",
+                '.' => "DOT",
+                '(' => "LPAREN",
+                ')' => "RPAREN",
+                '[' => "LBRACKET",
+                ']' => "RBRACKET",
+                '{' => "LBRACE",
+                '}' => "RBRACE",
+                '@' => "AT",
+                '#' => "HASH",
+                '$' => "DOLLAR",
+                '"' => "QUOTE",
+                '\'' => "APOS",
+                '`' => "BACKTICK",
+                '\\' => "BACKSLASH",
+                '_' => "UNDERSCORE",
+                c if c.is_ascii_alphanumeric() => return c.to_ascii_uppercase().to_string(),
+                c => return format!("U{:X}", c as u32),
+            }
+            .to_string()
+        })
+        .collect();
+    let name = parts.join("_");
+    if name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name
+    } else {
+        format!("T_{}", name)
+    }
+}
+
+// The literal a token matches, if its whole body is a single literal.
+fn token_literal(element: &Element) -> Option<&SmolStr> {
+    match element {
+        Element::Literal { lit } => Some(lit),
+        Element::Group { subelems } if subelems.len() == 1 => token_literal(&subelems[0]),
+        _ => None,
+    }
+}
+
+fn lift_literal(
+    element: &mut Element,
+    rules: &[Rule],
+    export: bool,
+    taken: &mut HashSet<SmolStr>,
+    lifted: &mut Vec<Rule>,
+) {
+    match element {
+        Element::Literal { lit } => {
+            let existing = rules.iter().chain(lifted.iter()).find(|rule| {
+                rule.is_terminal
+                    && !rule.fragment
+                    && !rule.skip
+                    && rule.internal.is_none()
+                    && rule.mode.is_none()
+                    && rule.lookahead.is_none()
+                    && !rule.line_start
+                    && (rule.export || !export)
+                    && token_literal(&rule.element) == Some(lit)
+            });
+            let name = match existing {
+                Some(rule) => rule.name.clone(),
+                None => {
+                    let base = literal_token_name(lit);
+                    let mut name = SmolStr::new(&base);
+                    let mut n = 2;
+                    while !taken.insert(name.clone()) {
+                        name = SmolStr::new(format!("{}_{}", base, n));
+                        n += 1;
+                    }
+                    let mut token = RuleBuilder::token(&name).literal(lit).display(lit);
+                    if export {
+                        token = token.export();
+                    }
+                    lifted.push(token.build());
+                    name
+                }
+            };
+            *element = Element::Rule { var: None, name };
+        }
+        Element::OneOrMore { inner }
+        | Element::ZeroOrMore { inner }
+        | Element::Optional { inner } => lift_literal(inner, rules, export, taken, lifted),
+        Element::Alternatives { subelems } | Element::Group { subelems } => {
+            for elem in subelems {
+                lift_literal(elem, rules, export, taken, lifted);
+            }
+        }
+        _ => {}
+    }
+}
+
+// A literal like "+" in a nonterminal becomes a reference to a token for it,
+// either one that is exactly that literal or a new one. The new tokens go
+// first so that e.g. "if" wins over an identifier token.
+fn lift_literals(rules: &mut Vec<Rule>, mut taken: HashSet<SmolStr>) {
+    let export = rules.iter().any(|rule| rule.is_terminal && rule.export);
+    let mut lifted = Vec::new();
+    for i in 0..rules.len() {
+        if rules[i].is_terminal {
+            continue;
+        }
+        let mut element = std::mem::replace(
+            &mut rules[i].element,
+            Element::Group {
+                subelems: Vec::new(),
+            },
+        );
+        lift_literal(&mut element, rules, export, &mut taken, &mut lifted);
+        rules[i].element = element;
+    }
+    rules.splice(0..0, lifted);
+}
+
+fn build_grammar(statements: Vec<Statement>) -> Result<Grammar> {
+    let mut rules = Vec::new();
+    let mut classes = Vec::new();
+    let mut equivalences: Vec<(SmolStr, Vec<SmolStr>)> = Vec::new();
+    let mut exclusive = Vec::new();
+    let mut match_mode = None;
+    let mut eof = None;
+    let mut fallback = None;
+    let mut case_insensitive = false;
+    let mut strict_sets = false;
+    let mut compact_alphabet = false;
+    for statement in statements {
+        match statement {
+            Statement::Rule(rule) => rules.push(rule),
+            Statement::Class(name, set) => classes.push((name, set)),
+            Statement::Equiv(name, members) => equivalences.push((name, members)),
+            Statement::AssertExclusive(names) => exclusive.push(names),
+            Statement::Import(_) => unreachable!("imports are expanded while loading"),
+            Statement::Override(mut rule) => {
+                let overridden = rules.iter().position(|r| r.name == rule.name);
+                let Some(overridden) = overridden else {
+                    bail!(
+                        Grammar,
+                        "Rule '{}' overrides a rule that doesn't exist",
+                        rule.name
+                    );
+                };
+                rule.mode = rules[overridden].mode.clone();
+                rules[overridden] = rule;
+            }
+            Statement::Mode(mode, mode_rules) => {
+                for mut rule in mode_rules {
+                    rule.mode = Some(mode.clone());
+                    rules.push(rule);
+                }
+            }
+            Statement::Match(mode) => {
+                ensure!(
+                    match_mode.is_none(),
+                    Grammar,
+                    "Match mode is declared twice"
+                );
+                match_mode = Some(mode);
+            }
+            Statement::Eof(name) => {
+                ensure!(eof.is_none(), Grammar, "The EOF token is declared twice");
+                eof = Some(name);
+            }
+            Statement::Fallback(name) => {
+                ensure!(
+                    fallback.is_none(),
+                    Grammar,
+                    "The fallback token is declared twice"
+                );
+                fallback = Some(name);
+            }
+            Statement::Options(options) => {
+                for (name, value) in options {
+                    match name.as_str() {
+                        "case_insensitive" => case_insensitive = parse_bool_option(&name, &value)?,
+                        "strict_sets" => strict_sets = parse_bool_option(&name, &value)?,
+                        "compact_alphabet" => compact_alphabet = parse_bool_option(&name, &value)?,
+                        _ => bail!(Grammar, "Unknown option '{}'", name),
+                    }
+                }
+            }
+        }
+    }
+    // The fallback takes a single code point where no other token matches
+    // anything. Being the last token, it loses every tie of that length.
+    if let Some(name) = fallback {
+        ensure!(
+            match_mode != Some(MatchMode::Shortest),
+            Grammar,
+            "Fallback token '{}' needs match = longest, the shortest match would always stop at it",
+            name
+        );
+        ensure!(
+            rules.iter().all(|rule| rule.mode.is_none()),
+            Grammar,
+            "Fallback token '{}' can't be combined with lexer modes",
+            name
+        );
+        let mut token = RuleBuilder::token(&name).any();
+        if rules.iter().any(|rule| rule.is_terminal && rule.export) {
+            token = token.export();
+        }
+        rules.push(token.build());
+    }
+    let taken = rules
+        .iter()
+        .map(|rule| &rule.name)
+        .chain(classes.iter().map(|(name, _)| name))
+        .chain(equivalences.iter().map(|(name, _)| name))
+        .chain(eof.iter())
+        .cloned()
+        .collect();
+    lift_literals(&mut rules, taken);
+    // Once any token is exported, the tokens that aren't become internal.
+    if rules.iter().any(|rule| rule.is_terminal && rule.export) {
+        for rule in rules.iter_mut().filter(|rule| rule.is_terminal) {
+            if rule.export {
+                ensure!(
+                    !rule.fragment && !rule.skip && rule.internal.is_none(),
+                    Grammar,
+                    "Token '{}' is exported, so it can't be a fragment, skipped or internal",
+                    rule.name
+                );
+            } else if !rule.fragment && !rule.skip && rule.internal.is_none() {
+                rule.internal = Some(SmolStr::new("_ERR"));
+            }
+        }
+    }
+    let set_owners = rules
+        .iter()
+        .flat_map(|rule| {
+            [Some(&rule.element), rule.lookahead.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(|element| (&rule.name, element))
+        })
+        .chain(classes.iter().map(|(name, set)| (name, set)));
+    for (owner, element) in set_owners {
+        check_sets(owner, element, strict_sets)?;
+    }
+    let mut rule_names = HashSet::new();
+    let names = rules
+        .iter()
+        .map(|rule| &rule.name)
+        .chain(classes.iter().map(|(name, _)| name))
+        .chain(equivalences.iter().map(|(name, _)| name))
+        .chain(eof.iter());
+    for name in names {
+        if !rule_names.insert(name) {
+            return Err(PargeError::DuplicateName(name.clone()));
+        }
+    }
+    for name in exclusive.iter().flatten() {
+        ensure!(
+            rules
+                .iter()
+                .any(|r| &r.name == name && r.is_terminal && !r.fragment),
+            Grammar,
+            "Exclusivity assertion names '{}', which isn't a token",
+            name
+        );
+    }
+    let mut equiv_members = HashSet::new();
+    for (name, members) in &equivalences {
+        for member in members {
+            ensure!(
+                rules.iter().any(|r| &r.name == member
+                    && r.is_terminal
+                    && !r.fragment
+                    && r.internal.is_none()),
+                Grammar,
+                "Equivalence class '{}' contains '{}', which isn't a public token",
+                name,
+                member
+            );
+            ensure!(
+                equiv_members.insert(member),
+                Grammar,
+                "Token '{}' belongs to more than one equivalence class",
+                member
+            );
+        }
+    }
+    for rule in rules.iter().filter(|rule| rule.is_terminal) {
+        if let Some(target) = &rule.internal {
+            ensure!(
+                target == "_ERR"
+                    || rules.iter().any(|r| &r.name == target
+                        && r.is_terminal
+                        && !r.fragment
+                        && r.internal.is_none()),
+                Grammar,
+                "Internal token '{}' maps to '{}', which isn't a public token",
+                rule.name,
+                target
+            );
+        }
+        if let Some(value_type) = &rule.value_type {
+            ensure!(
+                VALUE_TYPES.contains(&value_type.as_str()),
+                Grammar,
+                "Token '{}' has unsupported value type '{}', expected one of {:?}",
+                rule.name,
+                value_type,
+                VALUE_TYPES
+            );
+        }
+    }
+    let mut modes: Vec<SmolStr> = Vec::new();
+    for rule in &rules {
+        let Some(mode) = &rule.mode else {
+            continue;
+        };
+        ensure!(
+            rule.is_terminal && !rule.fragment,
+            Grammar,
+            "Only tokens can belong to mode '{}', but '{}' isn't one",
+            mode,
+            rule.name
+        );
+        if !modes.contains(mode) {
+            modes.push(mode.clone());
+        }
+    }
+    for rule in &rules {
+        let Some(action) = &rule.action else {
+            continue;
+        };
+        ensure!(
+            rule.is_terminal && !rule.fragment && rule.internal.is_none(),
+            Grammar,
+            "Only public tokens can switch modes, but '{}' isn't one",
+            rule.name
+        );
+        ensure!(
+            !rules
+                .iter()
+                .any(|r| r.internal.as_ref() == Some(&rule.name)),
+            Grammar,
+            "Token '{}' switches modes, so internal tokens can't map to it",
+            rule.name
+        );
+        if let ModeAction::Push(mode) = action {
+            ensure!(
+                modes.contains(mode),
+                Grammar,
+                "Token '{}' pushes mode '{}', which isn't declared",
+                rule.name,
+                mode
+            );
+        }
+    }
+    let mut unresolved = Vec::new();
+    for rule in &rules {
+        for name in rule_references(rule) {
+            if !rules.iter().any(|r| &r.name == name) {
+                unresolved.push((name.clone(), rule.name.clone()));
+            }
+        }
+    }
+    if !unresolved.is_empty() {
+        return Err(PargeError::UnresolvedReference(unresolved));
+    }
+    for rule in &rules {
+        if rule.fragment {
+            ensure!(
+                rule.is_terminal,
+                Grammar,
+                "Nonterminal '{}' can't be a fragment",
+                rule.name
+            );
+            ensure!(
+                rule.internal.is_none()
+                    && rule.value_type.is_none()
+                    && rule.display.is_none()
+                    && !rule.line_start
+                    && rule.lookahead.is_none(),
+                Grammar,
+                "Fragment '{}' doesn't produce tokens, so it can't be internal, typed, displayed, anchored or have a lookahead",
+                rule.name
+            );
+        }
+        if rule.skip {
+            ensure!(
+                rule.is_terminal && !rule.fragment,
+                Grammar,
+                "Only tokens can be skipped, but '{}' isn't one",
+                rule.name
+            );
+            ensure!(
+                rule.internal.is_none() && rule.value_type.is_none(),
+                Grammar,
+                "Skipped token '{}' is never returned, so it can't be internal or typed",
+                rule.name
+            );
+        }
+        for name in rule_references(rule) {
+            let fragment = rules.iter().any(|r| &r.name == name && r.fragment);
+            if rule.is_terminal {
+                ensure!(
+                    rules.iter().any(|r| &r.name == name && r.is_terminal),
+                    Grammar,
+                    "Token '{}' references '{}', which isn't a token or fragment",
+                    rule.name,
+                    name
+                );
+            } else {
+                ensure!(
+                    !fragment,
+                    Grammar,
+                    "Nonterminal '{}' references fragment '{}', which only tokens can use",
+                    rule.name,
+                    name
+                );
+            }
+        }
+    }
+    for rule in rules.iter().filter(|rule| !rule.is_terminal) {
+        let (Some(name), Some(params)) = (&rule.constructor_name, &rule.constructor_vars) else {
+            continue;
+        };
+        let mut bound = Vec::new();
+        bound_variables(&rule.element, &mut bound);
+        for (i, param) in params.iter().enumerate() {
+            ensure!(
+                !params[..i].contains(param),
+                Grammar,
+                "Constructor '{}' of '{}' takes '{}' more than once",
+                name,
+                rule.name,
+                param
+            );
+            ensure!(
+                bound.contains(&param),
+                Grammar,
+                "Constructor '{}' of '{}' uses '{}', which isn't bound",
+                name,
+                rule.name,
+                param
+            );
+        }
+        if let Some(var) = bound.iter().find(|var| !params.contains(var)) {
+            bail!(
+                Grammar,
+                "'{}' binds '{}', but constructor '{}' doesn't take it",
+                rule.name,
+                var,
+                name
+            );
+        }
+    }
+    warn_unused(&rules);
+    warn_fragment_copies(&rules);
+    Ok(Grammar {
+        rules,
+        classes,
+        equivalences,
+        exclusive,
+        match_mode: match_mode.unwrap_or_default(),
+        case_insensitive,
+        strict_sets,
+        compact_alphabet,
+        eof,
+        modes,
+    })
+}
+
+impl Grammar {
+    pub fn from_rules(rules: Vec<Rule>) -> Result<Grammar> {
+        build_grammar(rules.into_iter().map(Statement::Rule).collect())
+    }
+}
+
+fn write_char_escape(f: &mut fmt::Formatter<'_>, c: char) -> fmt::Result {
+    if c.is_control() || (c.is_whitespace() && c != ' ') || (!c.is_ascii() && !c.is_alphanumeric())
+    {
+        write!(f, "\\u{{{:x}}}", c as u32)
+    } else {
+        write!(f, "{}", c)
+    }
+}
+
+// Whether parse_name reads the whole text, so it can be written unquoted.
+fn is_name(text: &str) -> bool {
+    text.chars().next().is_some_and(char::is_alphabetic)
+        && text.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn write_literal(f: &mut fmt::Formatter<'_>, lit: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in lit.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '\0' => write!(f, "\\0")?,
+            c => write_char_escape(f, c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn write_set(
+    f: &mut fmt::Formatter<'_>,
+    negated: bool,
+    chars: &[char],
+    ranges: &[(char, char)],
+    classes: &[SmolStr],
+) -> fmt::Result {
+    let set_char = |f: &mut fmt::Formatter<'_>, c: char, first: bool| match c {
+        ']' | '\\' | '-' => write!(f, "\\{}", c),
+        // A leading caret would negate the set.
+        '^' if first => write!(f, "\\u{{5e}}"),
+        c => write_char_escape(f, c),
+    };
+    write!(f, "[")?;
+    if negated {
+        write!(f, "^")?;
+    }
+    let mut first = !negated;
+    for &c in chars {
+        set_char(f, c, first)?;
+        first = false;
+    }
+    for &(start, end) in ranges {
+        set_char(f, start, first)?;
+        write!(f, "-")?;
+        set_char(f, end, false)?;
+        first = false;
+    }
+    for class in classes {
+        write!(f, "\\{{{}}}", class)?;
+    }
+    write!(f, "]")
+}
+
+fn write_elements(
+    f: &mut fmt::Formatter<'_>,
+    elements: &[Element],
+    separator: &str,
+) -> fmt::Result {
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            write!(f, "{}", separator)?;
+        }
+        write!(f, "{}", element)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Element::Rule {
+                var: Some(var),
+                name,
+            } => write!(f, "{}:{}", var, name),
+            Element::Rule { var: None, name } => write!(f, "{}", name),
+            Element::Set {
+                chars,
+                ranges,
+                classes,
+            } => write_set(f, false, chars, ranges, classes),
+            Element::NegatedSet {
+                chars,
+                ranges,
+                classes,
+            } if chars.is_empty() && ranges.is_empty() && classes.is_empty() => write!(f, "."),
+            Element::NegatedSet {
+                chars,
+                ranges,
+                classes,
+            } => write_set(f, true, chars, ranges, classes),
+            Element::Literal { lit } => write_literal(f, lit),
+            // Only groups can be repeated, so the inner element gets its own parentheses.
+            Element::OneOrMore { inner } => write!(f, "({})+", inner),
+            Element::ZeroOrMore { inner } => write!(f, "({})*", inner),
+            Element::Optional { inner } => write!(f, "({})?", inner),
+            Element::Alternatives { subelems } => {
+                write!(f, "(")?;
+                write_elements(f, subelems, " | ")?;
+                write!(f, ")")
+            }
+            Element::Group { subelems } => {
+                write!(f, "(")?;
+                write_elements(f, subelems, " ")?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.attributes {
+            write!(f, "@{}", name)?;
+            match value {
+                Some(value) if is_name(value) => write!(f, "({})", value)?,
+                Some(value) => {
+                    write!(f, "(")?;
+                    write_literal(f, value)?;
+                    write!(f, ")")?;
+                }
+                None => {}
+            }
+            write!(f, " ")?;
+        }
+        if self.export {
+            write!(f, "export ")?;
+        }
+        if self.fragment {
+            write!(f, "fragment ")?;
+        }
+        if self.skip {
+            write!(f, "skip ")?;
+        }
+        if self.case_sensitive {
+            write!(f, "case_sensitive ")?;
+        }
+        match &self.internal {
+            Some(target) if target == "_ERR" => write!(f, "internal ")?,
+            Some(target) => write!(f, "internal({}) ", target)?,
+            None => {}
+        }
+        if self.priority != 0 {
+            write!(f, "priority({}) ", self.priority)?;
+        }
+        match &self.action {
+            Some(ModeAction::Push(mode)) => write!(f, "push({}) ", mode)?,
+            Some(ModeAction::Pop) => write!(f, "pop ")?,
+            None => {}
+        }
+        let kind = if self.is_terminal { "token" } else { "nonterm" };
+        write!(f, "{} {} = ", kind, self.name)?;
+        if self.line_start {
+            write!(f, "^")?;
+        }
+        match &self.element {
+            Element::Group { subelems } => write_elements(f, subelems, " ")?,
+            element => write!(f, "{}", element)?,
+        }
+        if let Some(lookahead) = &self.lookahead {
+            write!(f, " !{}", lookahead)?;
+        }
+        if let Some(constructor) = &self.constructor_name {
+            write!(f, " -> {}", constructor)?;
+        }
+        if let Some(value_type) = &self.value_type {
+            write!(f, " -> {}", value_type)?;
+        }
+        if let Some(vars) = &self.constructor_vars {
+            write!(f, "({})", vars.join(", "))?;
+        }
+        if let Some(display) = &self.display {
+            write!(f, " display ")?;
+            write_literal(f, display)?;
+        }
+        write!(f, ";")
+    }
+}
+
+impl fmt::Display for Grammar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.match_mode == MatchMode::Shortest {
+            writeln!(f, "match = shortest;")?;
+        }
+        if let Some(eof) = &self.eof {
+            writeln!(f, "eof = {};", eof)?;
+        }
+        let mut options = Vec::new();
+        if self.case_insensitive {
+            options.push("case_insensitive = true;");
+        }
+        if self.strict_sets {
+            options.push("strict_sets = true;");
+        }
+        if self.compact_alphabet {
+            options.push("compact_alphabet = true;");
+        }
+        if !options.is_empty() {
+            writeln!(f, "options {{ {} }}", options.join(" "))?;
+        }
+        for (name, set) in &self.classes {
+            writeln!(f, "class {} = {};", name, set)?;
+        }
+        // Rule order decides priority, so consecutive rules of a mode share a block.
+        let mut mode = None;
+        for rule in &self.rules {
+            if rule.mode != mode {
+                if mode.is_some() {
+                    writeln!(f, "}}")?;
+                }
+                if let Some(name) = &rule.mode {
+                    writeln!(f, "mode {} {{", name)?;
+                }
+                mode = rule.mode.clone();
+            }
+            if mode.is_some() {
+                write!(f, "    ")?;
+            }
+            writeln!(f, "{}", rule)?;
+        }
+        if mode.is_some() {
+            writeln!(f, "}}")?;
+        }
+        for (name, members) in &self.equivalences {
+            writeln!(f, "equiv {} = {};", name, members.join(" | "))?;
+        }
+        for names in &self.exclusive {
+            writeln!(f, "assert exclusive {};", names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct RuleBuilder {
+    rule: Rule,
+    elements: Vec<Element>,
+}
+
+impl RuleBuilder {
+    fn new(name: &str, is_terminal: bool) -> RuleBuilder {
+        RuleBuilder {
+            rule: Rule {
+                export: false,
+                case_sensitive: false,
+                internal: None,
+                is_terminal,
+                fragment: false,
+                skip: false,
+                priority: 0,
+                name: SmolStr::new(name),
+                element: Element::Group {
+                    subelems: Vec::new(),
+                },
+                constructor_name: None,
+                constructor_vars: None,
+                value_type: None,
+                display: None,
+                mode: None,
+                action: None,
+                line_start: false,
+                lookahead: None,
+                attributes: Vec::new(),
+                line: None,
+            },
+            elements: Vec::new(),
+        }
+    }
+
+    pub fn token(name: &str) -> RuleBuilder {
+        RuleBuilder::new(name, true)
+    }
+
+    pub fn nonterm(name: &str, constructor: &str, vars: &[&str]) -> RuleBuilder {
+        let mut builder = RuleBuilder::new(name, false);
+        builder.rule.constructor_name = Some(SmolStr::new(constructor));
+        builder.rule.constructor_vars = Some(vars.iter().map(SmolStr::new).collect());
+        builder
+    }
+
+    pub fn export(mut self) -> RuleBuilder {
+        self.rule.export = true;
+        self
+    }
+
+    pub fn fragment(mut self) -> RuleBuilder {
+        self.rule.fragment = true;
+        self
+    }
+
+    pub fn skip(mut self) -> RuleBuilder {
+        self.rule.skip = true;
+        self
+    }
+
+    pub fn case_sensitive(mut self) -> RuleBuilder {
+        self.rule.case_sensitive = true;
+        self
+    }
+
+    pub fn internal(mut self, target: &str) -> RuleBuilder {
+        self.rule.internal = Some(SmolStr::new(target));
+        self
+    }
+
+    pub fn priority(mut self, priority: u32) -> RuleBuilder {
+        self.rule.priority = priority;
+        self
+    }
+
+    pub fn value_type(mut self, value_type: &str) -> RuleBuilder {
+        self.rule.value_type = Some(SmolStr::new(value_type));
+        self
+    }
+
+    pub fn display(mut self, display: &str) -> RuleBuilder {
+        self.rule.display = Some(SmolStr::new(display));
+        self
+    }
+
+    pub fn mode(mut self, mode: &str) -> RuleBuilder {
+        self.rule.mode = Some(SmolStr::new(mode));
+        self
+    }
+
+    pub fn push(mut self, mode: &str) -> RuleBuilder {
+        self.rule.action = Some(ModeAction::Push(SmolStr::new(mode)));
+        self
+    }
+
+    pub fn pop(mut self) -> RuleBuilder {
+        self.rule.action = Some(ModeAction::Pop);
+        self
+    }
+
+    pub fn attribute(mut self, name: &str, value: Option<&str>) -> RuleBuilder {
+        self.rule
+            .attributes
+            .push((SmolStr::new(name), value.map(SmolStr::new)));
+        self
+    }
+
+    pub fn line_start(mut self) -> RuleBuilder {
+        self.rule.line_start = true;
+        self
+    }
+
+    pub fn element(mut self, element: Element) -> RuleBuilder {
+        self.elements.push(element);
+        self
+    }
+
+    pub fn literal(self, lit: &str) -> RuleBuilder {
+        self.element(Element::Literal {
+            lit: SmolStr::new(lit),
+        })
+    }
+
+    pub fn insensitive_literal(self, lit: &str) -> RuleBuilder {
+        self.element(fold_literal(lit))
+    }
+
+    pub fn set(self, chars: &[char], ranges: &[(char, char)]) -> RuleBuilder {
+        self.element(Element::Set {
+            chars: chars.to_vec(),
+            ranges: ranges.to_vec(),
+            classes: Vec::new(),
+        })
+    }
+
+    pub fn negated_set(self, chars: &[char], ranges: &[(char, char)]) -> RuleBuilder {
+        self.element(Element::NegatedSet {
+            chars: chars.to_vec(),
+            ranges: ranges.to_vec(),
+            classes: Vec::new(),
+        })
+    }
+
+    pub fn any(self) -> RuleBuilder {
+        self.negated_set(&[], &[])
+    }
+
+    pub fn rule(self, name: &str) -> RuleBuilder {
+        self.element(Element::Rule {
+            var: None,
+            name: SmolStr::new(name),
+        })
+    }
+
+    pub fn bind(self, var: &str, name: &str) -> RuleBuilder {
+        self.element(Element::Rule {
+            var: Some(SmolStr::new(var)),
+            name: SmolStr::new(name),
+        })
+    }
+
+    pub fn alternatives(self, subelems: Vec<Element>) -> RuleBuilder {
+        self.element(Element::Alternatives { subelems })
+    }
+
+    fn wrap_last(mut self, wrap: fn(Box<Element>) -> Element) -> RuleBuilder {
+        let last = self
+            .elements
+            .pop()
+            .expect("repetition needs a preceding element");
+        self.elements.push(wrap(Box::new(last)));
+        self
+    }
+
+    pub fn one_or_more(self) -> RuleBuilder {
+        self.wrap_last(|inner| Element::OneOrMore { inner })
+    }
+
+    pub fn zero_or_more(self) -> RuleBuilder {
+        self.wrap_last(|inner| Element::ZeroOrMore { inner })
+    }
+
+    pub fn optional(self) -> RuleBuilder {
+        self.wrap_last(|inner| Element::Optional { inner })
+    }
+
+    pub fn build(mut self) -> Rule {
+        self.rule.element = Element::Group {
+            subelems: self.elements,
+        };
+        self.rule
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lexer;
+
+    // Writes the files into a directory of their own and returns the path
+    // of the first one.
+    fn grammar_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join("parge-tests").join(test);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, src) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, src).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    #[test]
+    fn override_replaces_imported_rule() {
+        let main = grammar_files(
+            "override_replaces_imported_rule",
+            &[
+                (
+                    "main.pgrules",
+                    "import \"base.pgrules\";\noverride token ID = ([a-z0-9])+;\n",
+                ),
+                (
+                    "base.pgrules",
+                    "token ID = ([a-z])+;\ntoken NUM = ([0-9])+;\n",
+                ),
+            ],
+        );
+        let grammar = parse_file(&main).unwrap();
+        assert_eq!(grammar.rules.len(), 2);
+        let lexer = Lexer::from_grammar(&grammar).unwrap();
+        let tokens = lexer.tokenize("a1").unwrap();
+        assert_eq!(tokens[0], (SmolStr::from("ID"), String::from("a1")));
+    }
+
+    #[test]
+    fn duplicate_without_override_is_rejected() {
+        let main = grammar_files(
+            "duplicate_without_override_is_rejected",
+            &[
+                (
+                    "main.pgrules",
+                    "import \"base.pgrules\";\ntoken ID = ([a-z0-9])+;\n",
+                ),
+                ("base.pgrules", "token ID = ([a-z])+;\n"),
+            ],
+        );
+        assert!(matches!(
+            parse_file(&main),
+            Err(PargeError::DuplicateName(name)) if name == "ID"
+        ));
+    }
+
+    #[test]
+    fn override_needs_an_existing_rule() {
+        let err = parse_str("override token ID = ([a-z])+;\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("overrides a rule that doesn't exist"));
+    }
+
+    #[test]
+    fn value_type_is_kept_apart_from_constructors() {
+        let grammar = parse_str("token INT = ([0-9])+ -> long;\n").unwrap();
+        let rule = &grammar.rules[0];
+        assert_eq!(rule.value_type.as_deref(), Some("long"));
+        assert!(rule.constructor_name.is_none());
+        assert_eq!(rule.to_string(), "token INT = ([0-9])+ -> long;");
+
+        let err = parse_str("token INT = ([0-9])+ -> short;\n").unwrap_err();
+        assert!(err.to_string().contains("unsupported value type 'short'"));
+    }
+
+    #[test]
+    fn equivalence_classes() {
+        let grammar = parse_str(
+            "token LT = \"<\";\ntoken GT = \">\";\ntoken EQ = \"=\";\nequiv CMP = LT | GT;\n",
+        )
+        .unwrap();
+        assert_eq!(
+            grammar.equivalences,
+            [(
+                SmolStr::from("CMP"),
+                vec![SmolStr::from("LT"), SmolStr::from("GT")]
+            )]
+        );
+
+        let err = parse_str("token LT = \"<\";\nequiv CMP = LT | NE;\n").unwrap_err();
+        assert!(err.to_string().contains("NE"), "{}", err);
+    }
+
+    fn rule_names(grammar: &Grammar) -> Vec<&str> {
+        grammar
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn line_comments() {
+        let grammar = parse_str(
+            r##"# leading comment
+// another one
+
+token HASH = "#"; # after the rule
+# between rules
+token SLASHES = "//"; // after the rule
+// trailing comment"##,
+        )
+        .unwrap();
+        assert_eq!(rule_names(&grammar), ["HASH", "SLASHES"]);
+        assert_eq!(grammar.rules[0].to_string(), r##"token HASH = "#";"##);
+        assert_eq!(grammar.rules[1].to_string(), r#"token SLASHES = "//";"#);
+    }
+
+    #[test]
+    fn block_comments() {
+        let grammar = parse_str(
+            r#"/* before the first rule;
+   spanning lines */
+token A = "a";
+/* between rules, with a ; and
+token B = "b";
+   /* nested */ still inside */
+token C = "c"; /* after a rule */
+"#,
+        )
+        .unwrap();
+        assert_eq!(rule_names(&grammar), ["A", "C"]);
+
+        assert!(parse_str("token A = \"a\";\n/* never closed\ntoken B = \"b\";\n").is_err());
+    }
+
+    #[test]
+    fn flexible_element_separators() {
+        let compact = parse_str(
+            "token ID = [a-z] ([a-z0-9])* (\"'\")?;\nnonterm S = name:ID (ID)* -> S(name);\n",
+        )
+        .unwrap();
+        for src in [
+            "token ID = [a-z]\t([a-z0-9])*\t\t(\"'\")?;\nnonterm S = name:ID\t(ID)* -> S(name);\n",
+            "token ID =\n    [a-z]   ( [a-z0-9] )*\n    ( \"'\" )?\n;\nnonterm S =\n\tname:ID \t (ID)*\n\t-> S(name);\n",
+            "token ID = [a-z] // first letter\n    ([a-z0-9])* /* rest */ (\"'\")? # prime\n;\nnonterm S = name:ID /* more */ (ID)* -> S(name);\n",
+        ] {
+            let grammar = parse_str(src).unwrap();
+            assert_eq!(grammar.to_string(), compact.to_string(), "{:?}", src);
+            for (rule, expected) in grammar.rules.iter().zip(&compact.rules) {
+                assert_eq!(rule.element, expected.element, "{:?}", src);
+            }
+        }
+    }
+
+    #[test]
+    fn multi_line_alternation() {
+        let src = r#"token NUM = ([0-9])+;
+token NAME = ([a-z])+;
+token LPAREN = "(";
+token RPAREN = ")";
+nonterm Atom = (
+        value:NUM
+    |   name:NAME
+    |   (LPAREN inner:Atom RPAREN)
+    )
+    -> Atom(value, name, inner);
+options {
+    case_insensitive = true;
+}
+"#;
+        let grammar = parse_str(src).unwrap();
+        assert!(grammar.case_insensitive);
+        assert_eq!(
+            grammar.rules[4].to_string(),
+            "nonterm Atom = (value:NUM | name:NAME | (LPAREN inner:Atom RPAREN)) -> Atom(value, name, inner);"
+        );
+
+        let one_line = parse_str(
+            "class DIGIT = [0-9];
+token A = ([\\{DIGIT}])+;
+token B = \"b\";
+equiv AB = A | B;
+assert exclusive A, B;
+nonterm S = x:A y:B -> S(x, y);
+",
+        )
+        .unwrap();
+        let spread = parse_str(
+            "class DIGIT =\n    [0-9]\n;\ntoken A = ([\\{DIGIT}])+;\ntoken B = \"b\";\nequiv AB =\n    A\n  | B;\nassert exclusive\n    A,\n    B;\nnonterm S = x:A y:B -> S(\n    x,\n    y\n);\n",
+        )
+        .unwrap();
+        assert_eq!(spread.to_string(), one_line.to_string());
+    }
+
+    #[test]
+    fn constructor_argument_lists() {
+        for (body, constructor, vars) in [
+            ("A A -> Foo()", "Foo()", &[][..]),
+            ("A A -> Foo( )", "Foo()", &[]),
+            ("a:A A -> Foo(a)", "Foo(a)", &["a"]),
+            ("a:A b:A -> Foo(a, b)", "Foo(a, b)", &["a", "b"]),
+            ("a:A b:A -> Foo(a, b,)", "Foo(a, b)", &["a", "b"]),
+            ("a:A b:A -> Foo ( a ,b , )", "Foo(a, b)", &["a", "b"]),
+            (
+                "a:A b:A -> Foo(\n    a,\n    b,\n)",
+                "Foo(a, b)",
+                &["a", "b"],
+            ),
+        ] {
+            let src = format!("token A = \"a\";\nnonterm S = {};\n", body);
+            let grammar = parse_str(&src).unwrap();
+            let rule = &grammar.rules[1];
+            assert_eq!(rule.constructor_name.as_deref(), Some("Foo"), "{}", body);
+            let names: Vec<&str> = rule
+                .constructor_vars
+                .iter()
+                .flatten()
+                .map(|v| v.as_str())
+                .collect();
+            assert_eq!(names, vars, "{}", body);
+            assert!(rule.to_string().ends_with(&format!("-> {};", constructor)));
+        }
+        for body in ["a:A -> Foo(,)", "a:A -> Foo(a,,)", "a:A b:A -> Foo(a b)"] {
+            let src = format!("token A = \"a\";\nnonterm S = {};\n", body);
+            assert!(parse_str(&src).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn constructor_vars_match_bindings() {
+        let check = |body: &str| {
+            let src = format!(
+                "token A = \"a\";\ntoken B = \"b\";\nnonterm S = {};\n",
+                body
+            );
+            match parse_str(&src) {
+                Err(PargeError::Grammar(message)) => message,
+                result => panic!("{}: {:?}", body, result.map(|g| g.to_string())),
+            }
+        };
+        // Arity mismatches, in both directions.
+        assert_eq!(
+            check("x:A y:B -> Foo(x)"),
+            "'S' binds 'y', but constructor 'Foo' doesn't take it"
+        );
+        assert_eq!(
+            check("x:A B -> Foo(x, y)"),
+            "Constructor 'Foo' of 'S' uses 'y', which isn't bound"
+        );
+        // The same arity, but a different name.
+        assert_eq!(
+            check("x:A y:B -> Foo(x, z)"),
+            "Constructor 'Foo' of 'S' uses 'z', which isn't bound"
+        );
+        assert!(parse_str("token A = \"a\";\nnonterm S = x:A y:A -> Foo(y, x);\n").is_ok());
+    }
+
+    #[test]
+    fn inline_literals_become_tokens() {
+        let grammar = parse_str(
+            "token NUM = ([0-9])+;\ntoken LP = \"(\";\nskip token WS = \" \";\nnonterm Add = l:NUM \"+\" r:Atom -> Add(l, r);\nnonterm Atom = (LP n:NUM \")\") -> Atom(n);\n",
+        )
+        .unwrap();
+        assert_eq!(
+            grammar
+                .rules
+                .iter()
+                .find(|r| r.name == "Add")
+                .unwrap()
+                .to_string(),
+            "nonterm Add = l:NUM PLUS r:Atom -> Add(l, r);"
+        );
+        // An existing token with the same literal is reused.
+        assert_eq!(
+            grammar
+                .rules
+                .iter()
+                .find(|r| r.name == "Atom")
+                .unwrap()
+                .to_string(),
+            "nonterm Atom = (LP n:NUM RPAREN) -> Atom(n);"
+        );
+        let lexer = Lexer::from_grammar(&grammar).unwrap();
+        let tokens: Vec<SmolStr> = lexer
+            .tokenize("1 + (2)")
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+        assert_eq!(tokens, ["NUM", "PLUS", "LP", "NUM", "RPAREN", "_EOF"]);
+        crate::Parser::from_grammar(&grammar).unwrap();
+    }
+
+    #[test]
+    fn top_level_alternation() {
+        for (bare, parenthesized) in [
+            (
+                "token X = \"a\" | \"b\";\n",
+                "token X = (\"a\" | \"b\");\n",
+            ),
+            (
+                "token X = \"a\" | \"b\" ([0-9])+ | [x-z];\nskip token WS = \" \" | \"\\t\";\n",
+                "token X = (\"a\" | (\"b\" ([0-9])+) | [x-z]);\nskip token WS = (\" \" | \"\\t\");\n",
+            ),
+            (
+                "token N = ([0-9])+;\nnonterm S = a:N | (a:N N) -> S(a);\n",
+                "token N = ([0-9])+;\nnonterm S = (a:N | (a:N N)) -> S(a);\n",
+            ),
+        ] {
+            let bare = parse_str(bare).unwrap();
+            let parenthesized = parse_str(parenthesized).unwrap();
+            assert_eq!(bare.to_string(), parenthesized.to_string());
+            for (rule, expected) in bare.rules.iter().zip(&parenthesized.rules) {
+                assert_eq!(rule.element, expected.element);
+            }
+            let bare = Lexer::from_grammar(&bare).unwrap();
+            let parenthesized = Lexer::from_grammar(&parenthesized).unwrap();
+            assert_eq!(bare.get_states(), parenthesized.get_states());
+            assert_eq!(bare.dfa_table(), parenthesized.dfa_table());
+        }
+    }
+
+    fn body_element(body: &str) -> Element {
+        let grammar = parse_str(&format!("token A = {};\n", body)).unwrap();
+        match &grammar.rules[0].element {
+            Element::Group { subelems } if subelems.len() == 1 => subelems[0].clone(),
+            element => element.clone(),
+        }
+    }
+
+    #[test]
+    fn bounded_repetition_desugars() {
+        assert_eq!(body_element("(\"a\"){3}").to_string(), r#"("a" "a" "a")"#);
+        assert_eq!(
+            body_element("(\"a\"){2,4}").to_string(),
+            r#"("a" "a" ("a")? ("a")?)"#
+        );
+        assert_eq!(body_element("(\"a\"){2,}").to_string(), r#"("a" ("a")+)"#);
+        assert_eq!(body_element("(\"a\"){1}").to_string(), r#""a""#);
+        assert_eq!(body_element("(\"a\"){0,}").to_string(), r#"("a")*"#);
+        assert_eq!(
+            body_element("(\"a\"){0,2}").to_string(),
+            r#"(("a")? ("a")?)"#
+        );
+    }
+
+    #[test]
+    fn broken_bounds_are_rejected() {
+        for bounds in [
+            "{5,2}",
+            "{0}",
+            "{0,0}",
+            "{1001}",
+            "{2,1001}",
+            "{18446744073709551615}",
+            "{99999999999999999999999}",
+        ] {
+            let err = parse_str(&format!("token A = (\"a\"){};\n", bounds)).unwrap_err();
+            assert!(
+                err.to_string().contains("Invalid repetition bounds"),
+                "{}: {}",
+                bounds,
+                err
+            );
+        }
+    }
+
+    fn literal(body: &str) -> SmolStr {
+        match body_element(body) {
+            Element::Literal { lit } => lit,
+            element => panic!("{} isn't a literal", element),
+        }
+    }
+
+    #[test]
+    fn literal_escapes() {
+        assert_eq!(literal(r#""\n\t\r\\\0\"""#), "\n\t\r\\\0\"");
+        assert_eq!(literal(r#""a\tb""#).chars().count(), 3);
+    }
+
+    #[test]
+    fn escaped_metacharacters() {
+        for meta in "()|+*?.[]{}^!\\\"".chars() {
+            assert_eq!(literal(&format!("\\{}", meta)), meta.to_string());
+        }
+        // \( is a literal and doesn't open a group.
+        assert_eq!(body_element(r#"\( "a" \)"#), body_element(r#""(" "a" ")""#));
+        let grammar = "token CALL = ([a-z])+ \\( \\);\ntoken OR = \\| \\|;\n";
+        let lexer = Lexer::from_source(grammar).unwrap();
+        let tokens: Vec<String> = lexer
+            .tokenize("f()||")
+            .unwrap()
+            .into_iter()
+            .map(|(token, text)| format!("{} {}", token, text))
+            .collect();
+        assert_eq!(tokens, ["CALL f()", "OR ||", "_EOF "]);
+        assert!(lexer.tokenize("f").is_err());
+    }
+
+    #[test]
+    fn escapes_decode_alike_in_literals_and_sets() {
+        for (escape, expected) in [
+            (r"\n", '\n'),
+            (r"\t", '\t'),
+            (r"\r", '\r'),
+            (r"\v", '\u{b}'),
+            (r"\f", '\u{c}'),
+            (r"\0", '\0'),
+            (r"\\", '\\'),
+            (r#"\""#, '"'),
+            (r"\[", '['),
+            (r"\]", ']'),
+            (r"\^", '^'),
+            (r"\-", '-'),
+            (r"\x41", 'A'),
+            (r"\101", 'A'),
+            (r"\u00e9", '\u{e9}'),
+            (r"\u{1F600}", '\u{1f600}'),
+        ] {
+            assert_eq!(literal(&format!("\"{}\"", escape)), expected.to_string());
+            match body_element(&format!("[{}]", escape)) {
+                Element::Set { chars, .. } => assert_eq!(chars, [expected], "{}", escape),
+                element => panic!("{} isn't a set", element),
+            }
+            match body_element(&format!("[{}-{}]", escape, escape)) {
+                Element::Set { ranges, .. } => {
+                    assert_eq!(ranges, [(expected, expected)], "{}", escape)
+                }
+                element => panic!("{} isn't a set", element),
+            }
+        }
+        assert!(parse_str("token A = \"\\u{D800}\";\n").is_err());
+        assert!(parse_str("token A = [\\uD800];\n").is_err());
+        for body in [r#""\q""#, r"[\q]", r"[a\q]", r"[\q-z]"] {
+            let err = parse_str(&format!("token A = {};\n", body)).unwrap_err();
+            assert!(
+                err.to_string().contains("Unknown escape: \"\\\\q\""),
+                "{}: {}",
+                body,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn imports_resolve_relative_to_the_importer() {
+        let main = grammar_files(
+            "imports_resolve_relative_to_the_importer",
+            &[
+                (
+                    "main.pgrules",
+                    "import \"lib/words.pgrules\";\ntoken NUM = ([0-9])+;\n",
+                ),
+                (
+                    "lib/words.pgrules",
+                    "import \"space.pgrules\";\ntoken ID = ([a-z])+;\n",
+                ),
+                ("lib/space.pgrules", "token WS = \" \";\n"),
+            ],
+        );
+        let grammar = parse_file(&main).unwrap();
+        let mut names: Vec<&str> = grammar
+            .rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["ID", "NUM", "WS"]);
+    }
+
+    #[test]
+    fn import_cycles_are_rejected() {
+        let main = grammar_files(
+            "import_cycles_are_rejected",
+            &[
+                ("a.pgrules", "import \"b.pgrules\";\ntoken A = \"a\";\n"),
+                ("b.pgrules", "import \"a.pgrules\";\ntoken B = \"b\";\n"),
+            ],
+        );
+        let error = parse_file(&main).unwrap_err().to_string();
+        assert!(error.starts_with("Import cycle: "), "{}", error);
+        assert!(error.ends_with("a.pgrules"), "{}", error);
+        assert!(error.contains("b.pgrules -> "), "{}", error);
+    }
+
+    #[test]
+    fn blank_lines_between_rules() {
+        let plain = parse_str("token A = \"a\";\ntoken B = \"b\";\n").unwrap();
+        for src in [
+            "token A = \"a\";\n\ntoken B = \"b\";\n",
+            "token A = \"a\";  \t\n   \n\t\ntoken B = \"b\";",
+            "\n\ntoken A = \"a\";\r\n\r\ntoken B = \"b\";\r\n\r\n",
+        ] {
+            let grammar = parse_str(src).unwrap();
+            assert_eq!(grammar.to_string(), plain.to_string(), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn trailing_whitespace_and_comments() {
+        for (i, end) in [
+            "   ",
+            " \t\n\n\n",
+            "\n  \n\t",
+            "\n// trailing comment",
+            "\n# trailing comment\n\n",
+            " /* trailing\n   block */ \n",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let src = format!("token A = \"a\";{}", end);
+            assert_eq!(rule_names(&parse_str(&src).unwrap()), ["A"], "{:?}", src);
+            let path = grammar_files(
+                &format!("trailing_whitespace_and_comments_{}", i),
+                &[("rules.pgrules", &src)],
+            );
+            assert_eq!(rule_names(&parse_file(&path).unwrap()), ["A"], "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn crlf_rules_file() {
+        let lf =
+            "// Strings\ntoken STR = \"\\\"\" ([^\"\\r\\n])* \"\\\"\";\ntoken CR =\n    \"\\r\";\n";
+        let crlf = lf.replace('\n', "\r\n");
+        let lf = parse_str(lf).unwrap();
+        let crlf = parse_str(&crlf).unwrap();
+        assert_eq!(format!("{:?}", crlf.rules), format!("{:?}", lf.rules));
+        // An escaped \r inside a literal is still part of the token.
+        assert!(matches!(
+            &crlf.rules[1].element,
+            Element::Group { subelems } if matches!(
+                &subelems[..],
+                [Element::Literal { lit }] if lit == "\r"
+            )
+        ));
+    }
+
+    #[test]
+    fn parse_errors_report_line_and_column() {
+        let err = parse_str("token A = \"a\";\ntoken B = \"b\";\ntoken C = [a-z;\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Error 'Tag' while parsing\n  --> <string>:3:11\n   | token C = [a-z;\n   |           ^"
+        );
+
+        // The caret points at the token a rule fails on, not at the start of
+        // the statement, wherever the statement is in the file.
+        for (src, line) in [
+            ("token NAME = [a-z]+;\n", 1),
+            ("token A = \"a\";\ntoken NAME = [a-z]+;\n", 2),
+            ("mode M {\n  token A = \"a\";\ntoken NAME = [a-z]+;\n}\n", 3),
+        ] {
+            let err = parse_str(src).unwrap_err().to_string();
+            let location = format!("<string>:{}:19", line);
+            assert!(err.contains(&location), "{}", err);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_is_located() {
+        let path = grammar_files("invalid_utf8_is_located", &[("rules.pgrules", "")]);
+        std::fs::write(&path, b"token A = \"a\";\ntoken B = \"caf\xe9\";\n").unwrap();
+        let err = parse_file(&path).unwrap_err().to_string();
+        let location = format!("{}:2:15", path.display());
+        assert!(
+            err.starts_with("Invalid UTF-8 at byte offset 29: [e9], rules files have to be UTF-8"),
+            "{}",
+            err
+        );
+        assert!(err.contains(&location), "{}", err);
+
+        let err = parse_reader(&b"token A = \"\xe2\x82"[..])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Invalid UTF-8 at byte offset 11: [e2 82]"),
+            "{}",
+            err
+        );
+        assert!(err.contains("<stdin>:1:12"), "{}", err);
+    }
+
+    #[test]
+    fn unresolved_references() {
+        let src = "token A = \"a\";\nnonterm S = A T -> S();\nnonterm T = A -> T();\n";
+        parse_str(src).unwrap();
+        let err = parse_str(&src.replace("A T", "A Q").replace("= A ->", "= R ->"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Unresolved references: 'Q' in rule 'S', 'R' in rule 'T'"
+        );
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let src = r#"match = shortest;
+options { strict_sets = true; }
+class DIGIT = [0-9];
+token NUM = ([\{DIGIT}])+ ![a-z];
+skip token WS = ([ \t\n])+;
+priority(2) token KW = "if" display "'if'";
+token ESC = "\"\\\u{1F600}";
+token ANY = ^. [^a-c\]] (("x" | "y"))? ("z")*;
+push(STR) token QUOTE = "\"";
+mode STR {
+    pop token END = "\"";
+    token CHARS = ([^"])+;
+}
+nonterm S = name:KW (LPAREN num:NUM)* -> Call(name, num);
+@inline @doc("an opening (") token LPAREN = "(";
+"#;
+        let grammar = parse_str(src).unwrap();
+        let formatted = grammar.to_string();
+        assert!(formatted.contains("options { strict_sets = true; }\n"));
+        assert!(formatted.contains("\n@inline @doc(\"an opening (\") token LPAREN"));
+        let reparsed = parse_str(&formatted).unwrap();
+        assert_eq!(reparsed.to_string(), formatted);
+        let without_lines = |grammar: Grammar| -> Vec<Rule> {
+            grammar
+                .rules
+                .into_iter()
+                .map(|rule| Rule { line: None, ..rule })
+                .collect()
+        };
+        assert_eq!(without_lines(reparsed), without_lines(grammar));
+    }
+
+    #[test]
+    fn attributes_before_rules() {
+        let grammar =
+            parse_str("@inline\n@doc(\"a name\") @kind(Word)\nexport token A = \"a\";\n").unwrap();
+        let rule = &grammar.rules[0];
+        assert_eq!(
+            rule.attributes,
+            [
+                ("inline".into(), None),
+                ("doc".into(), Some("a name".into())),
+                ("kind".into(), Some("Word".into())),
+            ]
+        );
+        assert!(rule.export && rule.has_attribute("doc") && !rule.has_attribute("skip"));
+        assert_eq!(
+            rule.to_string(),
+            "@inline @doc(\"a name\") @kind(Word) export token A = \"a\";"
+        );
+        let built = RuleBuilder::token("A")
+            .literal("a")
+            .attribute("inline", None)
+            .build();
+        assert_eq!(built.to_string(), "@inline token A = \"a\";");
+    }
+
+    #[test]
+    fn strict_sets_option() {
+        assert!(!parse_str("token A = [a-c];\n").unwrap().strict_sets);
+        let grammar = parse_str("options { strict_sets = true; }\ntoken A = [a-c];\n").unwrap();
+        assert!(grammar.strict_sets);
+        assert!(grammar
+            .to_string()
+            .starts_with("options { strict_sets = true; }\n"));
+    }
+
+    #[test]
+    fn fallback_token() {
+        let grammar = parse_str("token A = \"a\";\nfallback = OTHER;\n").unwrap();
+        let lexer = Lexer::from_grammar(&grammar).unwrap();
+        let tokens: Vec<String> = lexer
+            .tokenize("a$\0b")
+            .unwrap()
+            .into_iter()
+            .map(|(token, text)| format!("{} {}", token, text))
+            .collect();
+        assert_eq!(tokens, ["A a", "OTHER $", "OTHER \0", "OTHER b", "_EOF "]);
+
+        for (src, expected) in [
+            (
+                "fallback = OTHER;\nfallback = MORE;\ntoken A = \"a\";\n",
+                "The fallback token is declared twice",
+            ),
+            (
+                "match = shortest;\nfallback = OTHER;\ntoken A = \"a\";\n",
+                "Fallback token 'OTHER' needs match = longest, the shortest match would always stop at it",
+            ),
+            (
+                "fallback = OTHER;\nmode M {\n    token A = \"a\";\n}\n",
+                "Fallback token 'OTHER' can't be combined with lexer modes",
+            ),
+        ] {
+            assert_eq!(parse_str(src).unwrap_err().to_string(), expected);
+        }
+        assert!(matches!(
+            parse_str("token A = \"a\";\nfallback = A;\n"),
+            Err(PargeError::DuplicateName(name)) if name == "A"
+        ));
+    }
+
+    #[test]
+    fn sets_naming_characters_twice() {
+        assert_eq!(set_overlaps(&['a', 'a'], &[]), ["'a' appears twice"]);
+        assert_eq!(
+            set_overlaps(&[], &[('a', 'c'), ('a', 'c')]),
+            ["'a-c' appears twice"]
+        );
+        assert_eq!(
+            set_overlaps(&[], &[('a', 'c'), ('b', 'd')]),
+            ["'a-c' and 'b-d' overlap on 'b-c'"]
+        );
+        assert_eq!(
+            set_overlaps(&['b'], &[('a', 'c')]),
+            ["'b' and 'a-c' overlap on 'b'"]
+        );
+        assert!(set_overlaps(&['d'], &[('a', 'c'), ('e', 'f')]).is_empty());
+
+        assert!(parse_str("token A = [a-cb-d];\n").is_ok());
+        let err = parse_str("options { strict_sets = true; }\ntoken A = [a-cb-d];\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "Set [a-cb-d] in 'A' names characters more than once: 'a-c' and 'b-d' overlap on 'b-c'"
+        );
+    }
+
+    #[test]
+    fn ranges_written_backwards() {
+        for (src, expected) in [
+            (
+                "token A = [z-a];\n",
+                "Set [z-a] in 'A' has the range 'z-a' written backwards, it should be 'a-z'",
+            ),
+            (
+                "token A = [^b-a];\n",
+                "Set [^b-a] in 'A' has the range 'b-a' written backwards, it should be 'a-b'",
+            ),
+        ] {
+            assert_eq!(parse_str(src).unwrap_err().to_string(), expected);
+        }
+        // The check doesn't depend on the rules coming from a file.
+        let rule = Rule {
+            element: Element::Set {
+                chars: Vec::new(),
+                ranges: vec![('9', '0')],
+                classes: Vec::new(),
+            },
+            ..parse_str("token A = \"a\";\n").unwrap().rules.remove(0)
+        };
+        assert!(Grammar::from_rules(vec![rule]).is_err());
+
+        let forward = Lexer::from_source("token A = [a-z];\n").unwrap();
+        assert!(forward.tokenize("abc").is_ok());
+    }
+
+    #[test]
+    fn escaped_range_endpoints() {
+        let grammar = parse_str(
+            "token CTRL = [\\x00-\\x1F];\ntoken WS = [\\t-\\r];\ntoken UPPER = [\\u{41}-\\u{5A}];\n",
+        )
+        .unwrap();
+        let ranges: Vec<&[(char, char)]> = grammar
+            .rules
+            .iter()
+            .map(|rule| match &rule.element {
+                Element::Group { subelems } => match &subelems[..] {
+                    [Element::Set { ranges, .. }] => &ranges[..],
+                    other => panic!("expected a set, got {:?}", other),
+                },
+                other => panic!("expected a group, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            ranges,
+            [&[('\0', '\u{1f}')][..], &[('\t', '\r')], &[('A', 'Z')]]
+        );
+        let lexer = Lexer::from_source("token CTRL = [\\x00-\\x1F];\n").unwrap();
+        for (c, expected) in [('\0', true), ('\n', true), ('\u{1f}', true), (' ', false)] {
+            let state = lexer.step(lexer.start_state(), c as u32);
+            assert_eq!(lexer.accept(state).is_some(), expected, "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn unused_rules_are_found() {
+        let src = r#"fragment token DIGIT = [0-9];
+fragment token UNUSED = [a-z];
+fragment token SELF = "x" (SELF)?;
+token NUM = (DIGIT)+;
+token PLUS = "+";
+nonterm Sum = head:NUM (PLUS tail:Term)* -> Sum(head, tail);
+nonterm Term = value:NUM -> Term(value);
+nonterm Orphan = value:NUM -> Orphan(value);
+export nonterm Api = value:NUM -> Api(value);
+"#;
+        let grammar = parse_str(src).unwrap();
+        let unused: Vec<&str> = unused_rules(&grammar.rules)
+            .into_iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(unused, ["UNUSED", "SELF", "Orphan"]);
     }
 }
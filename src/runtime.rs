@@ -0,0 +1,6 @@
+// Implemented by Rust lexers generated with --lex-trait, so code can be
+// written against several generated lexers at once. The u32 is the token's
+// discriminant in its lexer's Token enum, and None marks the end of input.
+pub trait Lex {
+    fn next(&mut self) -> Option<(u32, String)>;
+}
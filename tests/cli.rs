@@ -0,0 +1,502 @@
+mod common;
+
+use std::fs;
+
+use common::{generate, has_tool, parge, run, run_cpp, stderr, stdout, workdir};
+
+const INDENTED: &str = r#"token NAME = ([a-z])+;
+token NL = "\n";
+token WS = ([ \t])+;
+"#;
+
+#[test]
+fn tokenize_with_indentation() {
+    let dir = workdir("tokenize_with_indentation");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    fs::write(dir.join("input.txt"), "a\n  b\n").unwrap();
+    let output = parge(
+        &dir,
+        &["rules.pgrules", "--tokenize", "input.txt", "--indent", "4"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let tokens: Vec<String> = stdout(&output).lines().map(String::from).collect();
+    assert!(tokens.contains(&String::from("INDENT \"\"")));
+    assert!(tokens.contains(&String::from("DEDENT \"\"")));
+}
+
+#[test]
+fn indent_is_rejected_for_cpp() {
+    let dir = workdir("indent_is_rejected_for_cpp");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    let output = parge(
+        &dir,
+        &["rules.pgrules", "-l", "cpp", "--indent", "4", "-o", "out"],
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--indent is only supported for java"));
+    assert!(!dir.join("out").exists());
+}
+
+#[test]
+fn indent_tokens_must_not_be_defined() {
+    let dir = workdir("indent_tokens_must_not_be_defined");
+    fs::write(
+        dir.join("rules.pgrules"),
+        format!("{}token INDENT = \"  \";\n", INDENTED),
+    )
+    .unwrap();
+    fs::write(dir.join("input.txt"), "a\n").unwrap();
+    let output = parge(
+        &dir,
+        &["rules.pgrules", "--tokenize", "input.txt", "--indent", "4"],
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("can't define tokens with those names"));
+}
+
+#[test]
+fn tables_only_rejects_driver_flags() {
+    let dir = workdir("tables_only_rejects_driver_flags");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    for (lang, flags) in [
+        ("java", &["--error-context", "--positions", "--recover"][..]),
+        ("java", &["--indent", "4"][..]),
+        ("java", &["--lookahead", "2"][..]),
+        ("cpp", &["--bulk-errors"][..]),
+        ("cpp", &["--strip-bom"][..]),
+        ("cpp", &["--bytes"][..]),
+    ] {
+        let mut args = vec!["rules.pgrules", "-l", lang, "--tables-only", "-o", "out"];
+        args.extend(flags);
+        let output = parge(&dir, &args);
+        assert!(!output.status.success(), "{:?}", flags);
+        assert!(
+            stderr(&output).contains(&format!(
+                "{} can't be combined with --tables-only",
+                flags[0]
+            )),
+            "{}",
+            stderr(&output)
+        );
+        assert!(!dir.join("out").exists());
+    }
+}
+
+#[test]
+fn match_strategy() {
+    let dir = workdir("match_strategy");
+    fs::write(
+        dir.join("rules.pgrules"),
+        "token A = \"a\";\ntoken AA = \"aa\";\n",
+    )
+    .unwrap();
+    fs::write(dir.join("input.txt"), "aaa").unwrap();
+    for (args, expected) in [
+        (&[][..], "AA \"aa\"\nA \"a\"\n_EOF \"\"\n"),
+        (
+            &["--match", "longest"][..],
+            "AA \"aa\"\nA \"a\"\n_EOF \"\"\n",
+        ),
+        (
+            &["--match", "shortest"][..],
+            "A \"a\"\nA \"a\"\nA \"a\"\n_EOF \"\"\n",
+        ),
+    ] {
+        let output = parge(
+            &dir,
+            &[&["rules.pgrules", "--tokenize", "input.txt"], args].concat(),
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert!(stdout(&output).ends_with(expected), "{}", stdout(&output));
+    }
+}
+
+#[test]
+fn indent_zero_is_rejected() {
+    let dir = workdir("indent_zero_is_rejected");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    fs::write(dir.join("input.txt"), "a\n\tb\n").unwrap();
+    let output = parge(
+        &dir,
+        &["rules.pgrules", "--tokenize", "input.txt", "--indent", "0"],
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("--indent must be a tab width of at least 1"));
+    assert!(!stderr(&output).contains("panicked"));
+}
+
+#[test]
+fn summary_lists_written_files() {
+    let dir = workdir("summary_lists_written_files");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    let output = parge(&dir, &["rules.pgrules", "-l", "cpp", "-o", "out"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let summary = stdout(&output);
+    assert!(summary.contains("Wrote 2 file(s):"), "{}", summary);
+    for file in ["lexer.h", "lexer.cpp"] {
+        let size = fs::metadata(dir.join("out").join(file)).unwrap().len();
+        let line = format!(
+            "{} ({} bytes)",
+            std::path::Path::new("out").join(file).display(),
+            size
+        );
+        assert!(summary.contains(&line), "{}", summary);
+    }
+    let stats = summary
+        .lines()
+        .find(|line| line.starts_with("DFA states: "))
+        .unwrap();
+    let states: usize = stats["DFA states: ".len()..]
+        .split(',')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((3..10).contains(&states), "{}", stats);
+    assert!(stats.contains("tokens: 3"), "{}", stats);
+}
+
+#[test]
+fn quiet_suppresses_summary() {
+    let dir = workdir("quiet_suppresses_summary");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    let output = parge(
+        &dir,
+        &["rules.pgrules", "-l", "cpp", "-o", "out", "--quiet"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stdout(&output).contains("Wrote"));
+    assert!(dir.join("out/lexer.cpp").is_file());
+}
+
+#[test]
+fn tokenize_reports_invalid_utf8_offset() {
+    let dir = workdir("tokenize_reports_invalid_utf8_offset");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    fs::write(dir.join("input.txt"), b"ab \xc3\xa9 c\xff d").unwrap();
+    let output = parge(
+        &dir,
+        &[
+            "rules.pgrules",
+            "--tokenize",
+            "input.txt",
+            "--validate-utf8",
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Input is not valid UTF-8 at byte offset 7"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn repl_tokenizes_and_reloads() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::{Command, Stdio};
+
+    let dir = workdir("repl_tokenizes_and_reloads");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_parge"))
+        .args(["rules.pgrules", "--repl"])
+        .current_dir(&dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut read_lines = |count: usize| -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                let mut line = String::new();
+                stdout.read_line(&mut line).unwrap();
+                line.trim_end().to_string()
+            })
+            .collect()
+    };
+
+    writeln!(stdin, "ab c").unwrap();
+    assert_eq!(
+        read_lines(4),
+        ["NAME \"ab\"", "WS \" \"", "NAME \"c\"", "_EOF \"\""]
+    );
+    // The file is only read again on :reload.
+    fs::write(
+        dir.join("rules.pgrules"),
+        "token NAME = ([a-z0-9])+;\ntoken WS = ([ ])+;\n",
+    )
+    .unwrap();
+    writeln!(stdin, ":reload").unwrap();
+    assert_eq!(read_lines(1), ["Reloaded rules.pgrules"]);
+    writeln!(stdin, "a1 b").unwrap();
+    assert_eq!(
+        read_lines(4),
+        ["NAME \"a1\"", "WS \" \"", "NAME \"b\"", "_EOF \"\""]
+    );
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn emit_dot() {
+    let dir = workdir("emit_dot");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    let output = parge(&dir, &["rules.pgrules", "--emit", "dot", "-o", "out"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let dot = fs::read_to_string(dir.join("out").join("lexer.dot")).unwrap();
+    assert!(dot.starts_with("digraph lexer {"));
+}
+
+#[test]
+fn warns_about_unmatchable_tokens() {
+    let dir = workdir("warns_about_unmatchable_tokens");
+    fs::write(
+        dir.join("rules.pgrules"),
+        "token NAME = ([a-z])+;\ntoken IF = \"if\";\n",
+    )
+    .unwrap();
+    let output = parge(&dir, &["rules.pgrules", "--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("Token 'IF' can never be matched, it's always shadowed by"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn warns_about_tokens_shadowed_by_priority() {
+    let dir = workdir("warns_about_tokens_shadowed_by_priority");
+    fs::write(
+        dir.join("rules.pgrules"),
+        "token KW = \"return\";\npriority(1) token ID = ([a-z])+;\n",
+    )
+    .unwrap();
+    let output = parge(&dir, &["rules.pgrules", "--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output)
+            .contains("Token 'KW' can never be matched, it's always shadowed by {\"ID\"}"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn warns_about_copied_tokens() {
+    let dir = workdir("warns_about_copied_tokens");
+    fs::write(
+        dir.join("rules.pgrules"),
+        "fragment token DIGIT = [0-9];\ntoken NUM = (DIGIT)+;\ntoken ONE = [0-9];\ntoken INT = ([0-9])+;\n",
+    )
+    .unwrap();
+    let output = parge(&dir, &["rules.pgrules", "--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    assert!(
+        stdout.contains(
+            "Tokens [\"NUM\", \"INT\"] match exactly the same strings, so all but 'NUM' can never be matched"
+        ),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("Token 'ONE' has the same definition as fragment 'DIGIT'"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn check_mode() {
+    let dir = workdir("check_mode");
+    fs::write(dir.join("good.pgrules"), INDENTED).unwrap();
+    let output = parge(&dir, &["good.pgrules", "--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).ends_with("ok\n"), "{}", stdout(&output));
+    assert!(fs::read_dir(&dir).unwrap().all(|entry| {
+        let name = entry.unwrap().file_name();
+        name == "good.pgrules" || name == "parge.log"
+    }));
+
+    fs::write(
+        dir.join("bad.pgrules"),
+        "token A = \"a\";\nnonterm S = A Q -> S();\n",
+    )
+    .unwrap();
+    let output = parge(&dir, &["bad.pgrules", "--check"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Unresolved references: 'Q' in rule 'S'"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!stdout(&output).contains("ok"));
+}
+
+#[test]
+fn grammar_from_stdin() {
+    let dir = workdir("grammar_from_stdin");
+    run(
+        &dir,
+        env!("CARGO_BIN_EXE_parge"),
+        &["-", "-l", "cpp", "-q", "-o", "out"],
+        INDENTED,
+    );
+    let header = fs::read_to_string(dir.join("out").join("lexer.h")).unwrap();
+    assert!(header.contains("NAME"), "{}", header);
+    assert!(dir.join("out").join("lexer.cpp").exists());
+}
+
+#[test]
+fn unsupported_language_writes_nothing() {
+    let dir = workdir("unsupported_language_writes_nothing");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    let output = parge(
+        &dir,
+        &[
+            "rules.pgrules",
+            "-l",
+            "cpp,python",
+            "--positions",
+            "-o",
+            "out",
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("aren't supported for python"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("out").exists());
+}
+
+#[test]
+fn stats() {
+    let dir = workdir("stats");
+    fs::write(
+        dir.join("rules.pgrules"),
+        "token A = \"ab\";\ntoken C = ([c])+;\n",
+    )
+    .unwrap();
+    let output = parge(&dir, &["rules.pgrules", "--stats"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // States: start, after 'a', A, C and the trap. The alphabet is NUL,
+    // everything below 'a', 'a', 'b', 'c' and everything above.
+    assert_eq!(
+        stdout(&output),
+        "DFA states: 5\nTransitions: 4\nAccepting states: 2\nAlphabet size: 6\nTrap reachable: yes\n"
+    );
+}
+
+#[test]
+fn code_layout() {
+    let dir = workdir("code_layout");
+    generate(&dir, INDENTED, &["-l", "cpp,java", "-q", "-o", "lf"]);
+    generate(
+        &dir,
+        INDENTED,
+        &[
+            "-l",
+            "cpp,java",
+            "--driver",
+            "--newline",
+            "crlf",
+            "--code-indent",
+            "tab",
+            "-q",
+            "-o",
+            "crlf",
+        ],
+    );
+    for file in ["lexer.h", "lexer.cpp", "Lexer.java"] {
+        let lf = fs::read_to_string(dir.join("lf").join(file)).unwrap();
+        assert!(!lf.contains('\r'), "{}", file);
+        assert!(lf.lines().any(|line| line.starts_with("    ")), "{}", file);
+        let crlf = fs::read_to_string(dir.join("crlf").join(file)).unwrap();
+        assert_eq!(
+            crlf.matches('\n').count(),
+            crlf.matches("\r\n").count(),
+            "{} has a bare LF",
+            file
+        );
+        assert!(
+            crlf.lines().all(|line| !line.starts_with(' ')),
+            "{} is indented with spaces",
+            file
+        );
+        // Only the layout changes, not the code.
+        assert_eq!(
+            crlf.replace("\r\n", "\n").replace('\t', "    "),
+            lf,
+            "{}",
+            file
+        );
+    }
+    if has_tool("g++") {
+        let output = run_cpp(&dir.join("crlf"), &["main.cpp", "lexer.cpp"], "ab c\n");
+        assert_eq!(output, "NAME: ab\nWS:  \nNAME: c\nNL: \n\n");
+    }
+}
+
+#[test]
+fn code_layout_is_only_for_cpp_and_java() {
+    let dir = workdir("code_layout_is_only_for_cpp_and_java");
+    fs::write(dir.join("rules.pgrules"), INDENTED).unwrap();
+    let output = parge(
+        &dir,
+        &["rules.pgrules", "-l", "python", "--newline", "crlf"],
+    );
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("--newline and --code-indent are only supported for cpp and java"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn watch_regenerates_on_change() {
+    use std::time::{Duration, Instant, SystemTime};
+
+    let dir = workdir("watch_regenerates_on_change");
+    let rules = dir.join("rules.pgrules");
+    fs::write(&rules, INDENTED).unwrap();
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_parge"))
+        .args(["rules.pgrules", "-l", "cpp", "-q", "--watch"])
+        .current_dir(&dir)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let header = dir.join("lexer.h");
+    let wait_for = |check: &dyn Fn(&str) -> bool| {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(20) {
+            if fs::read_to_string(&header).is_ok_and(|text| check(&text)) {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    };
+    let generated = wait_for(&|text| text.contains("NAME"));
+    let mut regenerated = false;
+    if generated {
+        fs::write(&rules, format!("{}token NUM = ([0-9])+;\n", INDENTED)).unwrap();
+        // Some file systems only keep whole seconds.
+        fs::File::options()
+            .write(true)
+            .open(&rules)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        regenerated = wait_for(&|text| text.contains("NUM"));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(generated && regenerated);
+}
@@ -0,0 +1,177 @@
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+// Each test works in its own directory, since parge writes parge.log and
+// the generated files next to where it runs.
+pub fn workdir(test: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn parge(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_parge"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+// Writes the rules and runs parge on them, failing the test if it fails.
+pub fn generate(dir: &Path, rules: &str, args: &[&str]) -> Output {
+    fs::write(dir.join("rules.pgrules"), rules).unwrap();
+    let output = parge(dir, &[&["rules.pgrules"], args].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    output
+}
+
+// The generated code is only compiled and run where the toolchain for its
+// language is installed, other machines skip those checks.
+pub fn has_tool(tool: &str) -> bool {
+    let found = Command::new(tool).arg("--version").output().is_ok();
+    if !found {
+        eprintln!("{} isn't installed, skipping", tool);
+    }
+    found
+}
+
+pub fn run(dir: &Path, program: &str, args: &[&str], input: &str) -> String {
+    use std::io::Write;
+    let mut child = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{} failed: {}",
+        program,
+        stderr(&output)
+    );
+    stdout(&output)
+}
+
+// Builds the C++ sources in the directory into ./main and runs it.
+pub fn run_cpp(dir: &Path, sources: &[&str], input: &str) -> String {
+    run(
+        dir,
+        "g++",
+        &[&["-std=c++17", "-Wall", "-o", "main"], sources].concat(),
+        "",
+    );
+    run(dir, &dir.join("main").display().to_string(), &[], input)
+}
+
+// Compiles the Java sources in the directory and runs the class Main.
+pub fn run_java(dir: &Path, sources: &[&str], input: &str) -> String {
+    run(dir, "javac", &[&["-d", "classes"], sources].concat(), "");
+    run(dir, "java", &["-cp", "classes", "Main"], input)
+}
+
+// Builds main.rs in the directory with rustc and runs it.
+pub fn run_rust(dir: &Path, args: &[&str], input: &str) -> String {
+    run(
+        dir,
+        "rustc",
+        &[&["--edition", "2021", "-o", "main", "main.rs"], args].concat(),
+        "",
+    );
+    run(dir, &dir.join("main").display().to_string(), &[], input)
+}
+
+// rustc arguments that make the parge library available to the program.
+pub fn extern_parge() -> Vec<String> {
+    let out = Path::new(env!("CARGO_BIN_EXE_parge")).parent().unwrap();
+    vec![
+        "--extern".to_string(),
+        format!("parge={}", out.join("libparge.rlib").display()),
+        "-L".to_string(),
+        format!("dependency={}", out.join("deps").display()),
+    ]
+}
+
+// Builds main.rs in the directory as a cargo package that depends on serde
+// and serde_json, and runs it. They are dev-dependencies of parge, so its
+// lock file pins them to versions that are already downloaded.
+pub fn run_serde(dir: &Path, input: &str) -> String {
+    fs::write(
+        dir.join("Cargo.toml"),
+        r#"[package]
+name = "main"
+version = "0.0.0"
+edition = "2021"
+
+[[bin]]
+name = "main"
+path = "main.rs"
+
+[dependencies]
+serde = { version = "1", features = ["derive"] }
+serde_json = "1"
+
+[workspace]
+"#,
+    )
+    .unwrap();
+    fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.lock"),
+        dir.join("Cargo.lock"),
+    )
+    .unwrap();
+    // The packages share a target directory so serde is only built once.
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cargo-target");
+    run(
+        dir,
+        env!("CARGO"),
+        &[
+            "run",
+            "--quiet",
+            "--offline",
+            "--target-dir",
+            &target.display().to_string(),
+        ],
+        input,
+    )
+}
+
+// Runs main.py next to the generated lexer.py.
+pub fn run_python(dir: &Path, input: &str) -> String {
+    run(dir, "python3", &["main.py"], input)
+}
+
+// Runs main.go as module "example", which can import a lexer generated into
+// the lexer directory as "example/lexer".
+pub fn run_go(dir: &Path, input: &str) -> String {
+    fs::write(dir.join("go.mod"), "module example\n\ngo 1.18\n").unwrap();
+    run(dir, "go", &["run", "main.go"], input)
+}
+
+// Runs main.mjs, which can import the generated lexer.js as an ES module.
+pub fn run_node(dir: &Path, input: &str) -> String {
+    fs::write(dir.join("package.json"), "{\"type\": \"module\"}\n").unwrap();
+    run(dir, "node", &["main.mjs"], input)
+}
@@ -0,0 +1,2604 @@
+// Compiles the generated lexers and checks how they behave at runtime.
+
+mod common;
+
+use std::fs;
+
+use common::{
+    generate, has_tool, parge, run, run_cpp, run_go, run_java, run_node, run_python, run_rust,
+    stderr, workdir,
+};
+
+// Generates the lexer with its stdin driver and runs it on the input.
+fn cpp_driver(test: &str, rules: &str, args: &[&str], input: &str) -> String {
+    let dir = workdir(test);
+    generate(
+        &dir,
+        rules,
+        &[&["-l", "cpp", "-q", "--driver"], args].concat(),
+    );
+    run_cpp(&dir, &["main.cpp", "lexer.cpp"], input)
+}
+
+fn java_driver(test: &str, rules: &str, args: &[&str], input: &str) -> String {
+    let dir = workdir(test);
+    generate(
+        &dir,
+        rules,
+        &[&["-l", "java", "-q", "--driver"], args].concat(),
+    );
+    run_java(&dir, &["Main.java", "Lexer.java"], input)
+}
+
+const WORDS: &str = r#"token NAME = ([a-z])+;
+token NL = "\n";
+token WS = ([ \t])+;
+"#;
+
+#[test]
+fn cpp_error_context() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_error_context");
+    generate(&dir, WORDS, &["-l", "cpp", "-q", "--error-context"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::string input = "ab cd\nef ?g\n";
+    Lexer lexer(input);
+    Token token;
+    do
+    {
+        lexer.next(token);
+    } while (token != Token::_ERR && token != Token::_EOF);
+    const LexError &error = lexer.error();
+    std::cout << error.line << ":" << error.column << ":" << error.line_text << "\n";
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(output, "2:4:ef ?g\n");
+}
+
+#[test]
+fn java_error_context() {
+    if !has_tool("javac") {
+        return;
+    }
+    let dir = workdir("java_error_context");
+    generate(&dir, WORDS, &["-l", "java", "-q", "--error-context"]);
+    fs::write(
+        dir.join("Main.java"),
+        r#"import java.io.ByteArrayInputStream;
+
+public class Main {
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(new ByteArrayInputStream("ab cd\nef ?g\n".getBytes("UTF-8")));
+        Lexer.TextToken token;
+        do {
+            token = lexer.next();
+        } while (!(token instanceof Lexer.ErrorToken) && token.getToken() != Lexer.Token._EOF);
+        Lexer.ErrorToken error = (Lexer.ErrorToken) token;
+        System.out.println(error.getLine() + ":" + error.getColumn() + ":" + error.getLineText());
+    }
+}
+"#,
+    )
+    .unwrap();
+    let output = run_java(&dir, &["Main.java", "Lexer.java"], "");
+    assert_eq!(output, "2:4:ef ?g\n");
+}
+
+const NUMBERS: &str = r#"token INT = ([0-9])+ -> long;
+skip token WS = ([ ])+;
+"#;
+
+#[test]
+fn cpp_typed_values() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_typed_values");
+    generate(&dir, NUMBERS, &["-l", "cpp", "-q"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::string input = "42 9000000000 99999999999999999999";
+    Lexer lexer(input);
+    Token token;
+    lexer.next(token);
+    while (token != Token::_EOF)
+    {
+        if (token == Token::INT)
+        {
+            std::cout << std::get<int64_t>(lexer.value()) << "\n";
+        }
+        else
+        {
+            std::cout << token_name(token) << "\n";
+            break;
+        }
+        lexer.next(token);
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(output, "42\n9000000000\n_ERR\n");
+}
+
+#[test]
+fn java_typed_values() {
+    if !has_tool("javac") {
+        return;
+    }
+    let dir = workdir("java_typed_values");
+    generate(&dir, NUMBERS, &["-l", "java", "-q"]);
+    fs::write(
+        dir.join("Main.java"),
+        r#"import java.io.ByteArrayInputStream;
+
+public class Main {
+    public static void main(String[] args) throws Exception {
+        String input = "42 9000000000 99999999999999999999";
+        Lexer lexer = new Lexer(new ByteArrayInputStream(input.getBytes("UTF-8")));
+        Lexer.TextToken token = lexer.next();
+        while (token.getToken() != Lexer.Token._EOF) {
+            if (token instanceof Lexer.ValueToken) {
+                Number value = ((Lexer.ValueToken) token).getValue();
+                System.out.println(value.getClass().getSimpleName() + " " + value);
+            } else {
+                System.out.println(token.getToken());
+                break;
+            }
+            token = lexer.next();
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let output = run_java(&dir, &["Main.java", "Lexer.java"], "");
+    assert_eq!(output, "Long 42\nLong 9000000000\n_ERR\n");
+}
+
+#[test]
+fn cpp_buffer_capacity() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_buffer_capacity");
+    generate(&dir, WORDS, &["-l", "cpp", "-q"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    Lexer lexer(std::cin, 1);
+    Token token;
+    std::string text = lexer.next(token);
+    while (token != Token::_EOF && token != Token::_ERR)
+    {
+        std::cout << token_name(token) << " " << text.size() << "\n";
+        text = lexer.next(token);
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "abcdefghijklmnop qrs\n");
+    assert_eq!(output, "NAME 16\nWS 1\nNAME 3\nNL 1\n");
+}
+
+#[test]
+fn cpp_file_names() {
+    let dir = workdir("cpp_file_names");
+    generate(
+        &dir,
+        WORDS,
+        &["-l", "cpp", "-q", "--file-name", "words_lexer"],
+    );
+    generate(
+        &dir,
+        NUMBERS,
+        &["-l", "cpp", "-q", "--file-name", "numbers_lexer"],
+    );
+    for (name, token) in [("words_lexer", "NAME"), ("numbers_lexer", "INT")] {
+        let header = fs::read_to_string(dir.join(format!("{}.h", name))).unwrap();
+        assert!(header.contains(token), "{}", header);
+        let body = fs::read_to_string(dir.join(format!("{}.cpp", name))).unwrap();
+        assert!(body.contains(&format!("#include \"{}.h\"", name)));
+    }
+    assert!(!dir.join("lexer.h").exists());
+    if has_tool("g++") {
+        for source in ["words_lexer.cpp", "numbers_lexer.cpp"] {
+            run(&dir, "g++", &["-std=c++17", "-Wall", "-c", source], "");
+        }
+    }
+}
+
+#[test]
+fn java_buffer_capacity() {
+    if !has_tool("javac") {
+        return;
+    }
+    let dir = workdir("java_buffer_capacity");
+    generate(&dir, WORDS, &["-l", "java", "-q"]);
+    fs::write(
+        dir.join("Main.java"),
+        r#"public class Main {
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(System.in, 1);
+        Lexer.TextToken token = lexer.next();
+        while (token.getToken() != Lexer.Token._EOF && token.getToken() != Lexer.Token._ERR) {
+            System.out.println(token.getToken() + " " + token.getText().length());
+            token = lexer.next();
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let output = run_java(&dir, &["Main.java", "Lexer.java"], "abcdefghijklmnop qrs\n");
+    assert_eq!(output, "NAME 16\nWS 1\nNAME 3\nNL 1\n");
+}
+
+#[test]
+fn max_error_run() {
+    // The error run is cut into pieces instead of being buffered whole, and
+    // the pieces still add up to the input.
+    let input = format!("ab {} cd\n", "?".repeat(10_000));
+    let check = |output: String| {
+        let mut text = String::new();
+        let mut errors = 0;
+        // NL's text ends its line, leaving an empty one behind.
+        for line in output.lines().filter(|line| !line.is_empty()) {
+            let (token, rest) = line.split_once(": ").unwrap();
+            if token == "_ERR" {
+                assert!(rest.len() <= 9, "{}", rest.len());
+                errors += 1;
+            }
+            text.push_str(rest);
+            if token == "NL" {
+                text.push('\n');
+            }
+        }
+        assert!(errors >= 10_000 / 9, "{}", errors);
+        assert_eq!(text, input);
+    };
+    let args = [
+        "--bulk-errors",
+        "--max-error-run",
+        "8",
+        "--buffer-capacity",
+        "64",
+    ];
+    if has_tool("g++") {
+        check(cpp_driver("cpp_max_error_run", WORDS, &args, &input));
+    }
+    if has_tool("javac") {
+        check(java_driver("java_max_error_run", WORDS, &args, &input));
+    }
+}
+
+const KEYWORDS: &str = r#"token IF = "if";
+token ELSE = "else";
+token WHILE = "while";
+token RETURN = "return";
+token NAME = ([a-z])+;
+skip token WS = ([ ])+;
+"#;
+
+#[test]
+fn driver() {
+    let input = "while x return 42";
+    let expected = "WHILE: while\nNAME: x\nRETURN: return\n_ERR: \n";
+    let dir = workdir("no_driver");
+    generate(&dir, KEYWORDS, &["-l", "cpp", "-l", "java", "-q"]);
+    assert!(!dir.join("main.cpp").exists() && !dir.join("Main.java").exists());
+    if has_tool("g++") {
+        assert_eq!(cpp_driver("cpp_driver", KEYWORDS, &[], input), expected);
+    }
+    if has_tool("javac") {
+        assert_eq!(java_driver("java_driver", KEYWORDS, &[], input), expected);
+    }
+}
+
+#[test]
+fn fallback_token() {
+    let rules = format!("{}fallback = UNKNOWN;\n", KEYWORDS);
+    let input = "while $x 4é";
+    let expected = "WHILE: while\nUNKNOWN: $\nNAME: x\nUNKNOWN: 4\nUNKNOWN: é\n";
+    if has_tool("g++") {
+        assert_eq!(cpp_driver("cpp_fallback", &rules, &[], input), expected);
+        assert_eq!(
+            cpp_driver(
+                "cpp_fallback_table_driven",
+                &rules,
+                &["--table-driven"],
+                input
+            ),
+            expected
+        );
+    }
+    // Java prints in the platform charset, which may not have é. A NUL is
+    // input like any other there, while the cpp lexer ends at it.
+    if has_tool("javac") {
+        assert_eq!(
+            java_driver("java_fallback", &rules, &[], "while $x 4\0"),
+            "WHILE: while\nUNKNOWN: $\nNAME: x\nUNKNOWN: 4\nUNKNOWN: \0\n"
+        );
+    }
+}
+
+#[test]
+fn fold_keywords() {
+    let input = "if returned else whilex return x\n";
+    if has_tool("g++") {
+        let plain = cpp_driver("cpp_keywords", KEYWORDS, &[], input);
+        let folded = cpp_driver("cpp_fold_keywords", KEYWORDS, &["--fold-keywords"], input);
+        assert!(
+            plain.starts_with("IF: if\nNAME: returned\nELSE: else\n"),
+            "{}",
+            plain
+        );
+        assert_eq!(folded, plain);
+    }
+    if has_tool("javac") {
+        let plain = java_driver("java_keywords", KEYWORDS, &[], input);
+        let folded = java_driver("java_fold_keywords", KEYWORDS, &["--fold-keywords"], input);
+        assert!(
+            plain.starts_with("IF: if\nNAME: returned\nELSE: else\n"),
+            "{}",
+            plain
+        );
+        assert_eq!(folded, plain);
+    }
+}
+
+#[test]
+fn cpp_word_boundary() {
+    if !has_tool("g++") {
+        return;
+    }
+    let rules = "token RETURN = \"return\" \\b;\ntoken LETTER = [a-z];\ntoken SEMI = \";\";\nskip token WS = \" \";\n";
+    let output = cpp_driver("cpp_word_boundary", rules, &[], "returns return;");
+    assert_eq!(
+        output,
+        "LETTER: r\nLETTER: e\nLETTER: t\nLETTER: u\nLETTER: r\nLETTER: n\nLETTER: s\nRETURN: return\nSEMI: ;\n"
+    );
+}
+
+#[test]
+fn match_strategy() {
+    let rules = "token A = \"a\";\ntoken AA = \"aa\";\n";
+    let longest = "AA: aa\nA: a\n";
+    let shortest = "A: a\nA: a\nA: a\n";
+    if has_tool("g++") {
+        assert_eq!(cpp_driver("cpp_match_longest", rules, &[], "aaa"), longest);
+        let output = cpp_driver("cpp_match_shortest", rules, &["--match", "shortest"], "aaa");
+        assert_eq!(output, shortest);
+    }
+    if has_tool("javac") {
+        assert_eq!(
+            java_driver("java_match_longest", rules, &[], "aaa"),
+            longest
+        );
+        let output = java_driver(
+            "java_match_shortest",
+            rules,
+            &["--match", "shortest"],
+            "aaa",
+        );
+        assert_eq!(output, shortest);
+    }
+}
+
+#[test]
+fn cpp_table_driven() {
+    if !has_tool("g++") {
+        return;
+    }
+    let rules = format!(
+        "{}token NUM = ([0-9])+;\ntoken STR = \"\\\"\" ([^\"])* \"\\\"\";\n",
+        KEYWORDS
+    );
+    let input = "if x else returns 12 \"a \u{e9}\" while ifx whil\n?";
+    let switch = cpp_driver("cpp_switch", &rules, &[], input);
+    let table = cpp_driver("cpp_table_driven", &rules, &["--table-driven"], input);
+    assert!(
+        switch.starts_with("IF: if\nNAME: x\nELSE: else\n"),
+        "{}",
+        switch
+    );
+    assert_eq!(switch, table);
+}
+
+#[test]
+fn cpp_keyword_hash() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_keyword_hash");
+    generate(&dir, KEYWORDS, &["-l", "cpp", "-q", "--keyword-hash"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    for (const char *text : {"if", "else", "while", "return", "iff", "els", ""})
+    {
+        std::cout << token_name(Lexer::keyword(text)) << "\n";
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(output, "IF\nELSE\nWHILE\nRETURN\n_ERR\n_ERR\n_ERR\n");
+}
+
+#[test]
+fn java_keyword_hash() {
+    if !has_tool("javac") {
+        return;
+    }
+    let dir = workdir("java_keyword_hash");
+    generate(&dir, KEYWORDS, &["-l", "java", "-q", "--keyword-hash"]);
+    fs::write(
+        dir.join("Main.java"),
+        r#"public class Main {
+    public static void main(String[] args) {
+        for (String text : new String[] {"if", "else", "while", "return", "iff", "els", ""}) {
+            System.out.println(Lexer.keyword(text));
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let output = run_java(&dir, &["Main.java", "Lexer.java"], "");
+    assert_eq!(output, "IF\nELSE\nWHILE\nRETURN\n_ERR\n_ERR\n_ERR\n");
+}
+
+#[test]
+fn bulk_errors() {
+    let input = "ab ?!?# cd\n";
+    let expected = "NAME: ab\nWS:  \n_ERR: ?!?#\nWS:  \nNAME: cd\nNL: \n\n";
+    if has_tool("g++") {
+        let output = cpp_driver("cpp_bulk_errors", WORDS, &["--bulk-errors"], input);
+        assert_eq!(output, expected);
+    }
+    if has_tool("javac") {
+        let output = java_driver("java_bulk_errors", WORDS, &["--bulk-errors"], input);
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn recover() {
+    let rules = "token ID = ([a-z])+;\n";
+    let expected = "ID: a\n_ERR: @\nID: b\n";
+    if has_tool("g++") {
+        let output = cpp_driver("cpp_recover", rules, &["--recover"], "a@b");
+        assert_eq!(output, expected);
+    }
+    if has_tool("javac") {
+        let output = java_driver("java_recover", rules, &["--recover"], "a@b");
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn unmatched_input_is_an_error() {
+    let expected = "NAME: ab\nWS:  \n_ERR: \n";
+    if has_tool("g++") {
+        let output = cpp_driver("cpp_unmatched_input", WORDS, &[], "ab ?cd\n");
+        assert_eq!(output, expected);
+    }
+    if has_tool("javac") {
+        let output = java_driver("java_unmatched_input", WORDS, &[], "ab ?cd\n");
+        assert_eq!(output, expected);
+    }
+}
+
+const INTS: &str = "token INT = ([0-9])+;\nskip token WS = ([ ])+;\n";
+
+const NAMES: &str = "token NAME = ([a-z])+;\nskip token WS = ([ ])+;\n";
+
+#[test]
+fn two_named_lexers() {
+    let expected = "NAME ab\nNAME cd\n_EOF \nINT 12\nINT 3\n_EOF \n";
+    let words = [
+        "--lexer-name",
+        "WordLexer",
+        "--token-name",
+        "WordToken",
+        "--namespace",
+        "words",
+    ];
+    let nums = ["--lexer-name", "NumLexer", "--token-name", "NumToken"];
+    if has_tool("g++") {
+        let dir = workdir("cpp_two_named_lexers");
+        generate(&dir, NAMES, &[&["-l", "cpp", "-q"], &words[..]].concat());
+        generate(&dir, INTS, &[&["-l", "cpp", "-q"], &nums[..]].concat());
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "wordlexer.h"
+#include "numlexer.h"
+#include <iostream>
+
+int main()
+{
+    std::string words_input = "ab cd";
+    std::string nums_input = "12 3";
+    words::WordLexer words(words_input);
+    NumLexer nums(nums_input);
+    words::WordToken word;
+    do
+    {
+        std::string text = words.next(word);
+        std::cout << words::token_name(word) << " " << text << "\n";
+    } while (word != words::WordToken::_EOF);
+    NumToken num;
+    do
+    {
+        std::string text = nums.next(num);
+        std::cout << token_name(num) << " " << text << "\n";
+    } while (num != NumToken::_EOF);
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        let output = run_cpp(&dir, &["main.cpp", "wordlexer.cpp", "numlexer.cpp"], "");
+        assert_eq!(output, expected);
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_two_named_lexers");
+        generate(&dir, NAMES, &[&["-l", "java", "-q"], &words[..]].concat());
+        generate(&dir, INTS, &[&["-l", "java", "-q"], &nums[..]].concat());
+        fs::write(
+            dir.join("Main.java"),
+            r#"import java.io.ByteArrayInputStream;
+
+public class Main {
+    public static void main(String[] args) throws Exception {
+        words.WordLexer wordLexer = new words.WordLexer(new ByteArrayInputStream("ab cd".getBytes("UTF-8")));
+        words.WordLexer.TextToken word;
+        do {
+            word = wordLexer.next();
+            System.out.println(word.getToken() + " " + word.getText());
+        } while (word.getToken() != words.WordLexer.WordToken._EOF);
+        NumLexer nums = new NumLexer(new ByteArrayInputStream("12 3".getBytes("UTF-8")));
+        NumLexer.TextToken num;
+        do {
+            num = nums.next();
+            System.out.println(num.getToken() + " " + num.getText());
+        } while (num.getToken() != NumLexer.NumToken._EOF);
+    }
+}
+"#,
+        )
+        .unwrap();
+        let output = run_java(&dir, &["Main.java", "WordLexer.java", "NumLexer.java"], "");
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn cpp_token_counts() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_token_counts");
+    generate(&dir, WORDS, &["-l", "cpp", "-q", "--token-counts"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+void lex(Lexer &lexer)
+{
+    Token token;
+    do
+    {
+        lexer.next(token);
+    } while (token != Token::_ERR && token != Token::_EOF);
+}
+
+void print(const Lexer &lexer)
+{
+    std::cout << lexer.token_count(Token::NAME) << " " << lexer.token_count(Token::WS) << " "
+              << lexer.token_count(Token::NL) << " " << lexer.token_count(Token::_EOF) << " "
+              << lexer.token_count(Token::_ERR) << "\n";
+}
+
+int main()
+{
+    std::string input = "ab cd\nef\n";
+    Lexer lexer(input);
+    lex(lexer);
+    print(lexer);
+    std::string more = "x y\n";
+    lexer.reset(more);
+    lex(lexer);
+    print(lexer);
+    lexer.reset_token_counts();
+    print(lexer);
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(output, "3 1 2 1 0\n5 2 3 2 0\n0 0 0 0 0\n");
+}
+
+#[test]
+fn cpp_header_included_twice() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_header_included_twice");
+    generate(&dir, WORDS, &["-l", "cpp", "-q"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::cout << token_name(Token::NAME) << "\n";
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    assert_eq!(run_cpp(&dir, &["main.cpp", "lexer.cpp"], ""), "NAME\n");
+}
+
+#[test]
+fn token_names() {
+    let expected = "NAME NL WS _EOF _ERR\n";
+    if has_tool("g++") {
+        let dir = workdir("cpp_token_names");
+        generate(&dir, WORDS, &["-l", "cpp", "-q"]);
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::cout << token_name(Token::NAME) << " " << token_name(Token::NL) << " "
+              << token_name(Token::WS) << " " << token_name(Token::_EOF) << " "
+              << token_name(Token::_ERR) << "\n";
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_cpp(&dir, &["main.cpp", "lexer.cpp"], ""), expected);
+    }
+    if has_tool("gcc") {
+        let dir = workdir("c_token_names");
+        generate(&dir, WORDS, &["-l", "c", "-q"]);
+        fs::write(
+            dir.join("main.c"),
+            r#"#include "lexer.h"
+#include <stdio.h>
+
+int main(void)
+{
+    printf("%s %s %s %s %s\n", token_name(TOKEN_NAME), token_name(TOKEN_NL),
+           token_name(TOKEN_WS), token_name(TOKEN__EOF), token_name(TOKEN__ERR));
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        run(
+            &dir,
+            "gcc",
+            &[
+                "-std=c99", "-Wall", "-Werror", "-o", "main", "main.c", "lexer.c",
+            ],
+            "",
+        );
+        let output = run(&dir, &dir.join("main").display().to_string(), &[], "");
+        assert_eq!(output, expected);
+    }
+    if has_tool("rustc") {
+        let dir = workdir("rust_token_names");
+        generate(&dir, WORDS, &["-l", "rust", "-q"]);
+        fs::write(
+            dir.join("main.rs"),
+            r#"#![allow(dead_code)]
+include!("lexer.rs");
+
+fn main() {
+    let names: Vec<&str> = [Token::NAME, Token::NL, Token::WS, Token::_EOF, Token::_ERR]
+        .iter()
+        .map(|token| token.name())
+        .collect();
+    println!("{}", names.join(" "));
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_rust(&dir, &[], ""), expected);
+    }
+}
+
+#[test]
+fn cpp_modes() {
+    if !has_tool("g++") {
+        return;
+    }
+    let rules = r#"push(STR) token OPEN = "\"";
+token NAME = ([a-z])+;
+skip token WS = ([ ])+;
+mode STR {
+    token TEXT = ([^"])+;
+    pop token CLOSE = "\"";
+}
+"#;
+    let output = cpp_driver("cpp_modes", rules, &[], "ab \"cd ef\" gh");
+    assert_eq!(
+        output,
+        "NAME: ab\nOPEN: \"\nTEXT: cd ef\nCLOSE: \"\nNAME: gh\n"
+    );
+}
+
+#[test]
+fn cpp_and_java_in_one_run() {
+    let dir = workdir("cpp_and_java_in_one_run");
+    generate(&dir, KEYWORDS, &["-l", "cpp,java", "-q", "--driver"]);
+    for file in [
+        "lexer.h",
+        "lexer.cpp",
+        "main.cpp",
+        "Lexer.java",
+        "Main.java",
+    ] {
+        assert!(dir.join(file).exists(), "{} is missing", file);
+    }
+    if has_tool("g++") && has_tool("javac") {
+        let input = "if x else returns while";
+        let cpp = run_cpp(&dir, &["main.cpp", "lexer.cpp"], input);
+        let java = run_java(&dir, &["Main.java", "Lexer.java"], input);
+        assert_eq!(
+            cpp,
+            "IF: if\nNAME: x\nELSE: else\nNAME: returns\nWHILE: while\n"
+        );
+        assert_eq!(cpp, java);
+    }
+}
+
+#[test]
+fn cpp_utf16_surrogate_pairs() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_utf16_surrogate_pairs");
+    let rules = "token WORD = ([\\p{L}\\u{1F600}-\\u{1F64F}])+;\ntoken WS = ([ ])+;\n";
+    generate(&dir, rules, &["-l", "cpp", "-q", "--utf16"]);
+    let input: Vec<u8> = "ab\u{1F600}c d"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    fs::write(dir.join("input.bin"), input).unwrap();
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <fstream>
+#include <iostream>
+
+// Prints each token with its length in UTF-16 code units.
+void dump(Lexer &lexer)
+{
+    Token token;
+    do
+    {
+        std::u16string text = lexer.next(token);
+        std::cout << token_name(token) << " " << text.size() << "\n";
+    } while (token != Token::_EOF);
+}
+
+int main()
+{
+    std::ifstream file("input.bin", std::ios::binary);
+    Lexer stream(file);
+    dump(stream);
+    std::u16string text = u"ab\U0001F600c d";
+    Lexer buffer(text);
+    dump(buffer);
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let expected = "WORD 5\nWS 1\nWORD 1\n_EOF 0\n";
+    assert_eq!(
+        run_cpp(&dir, &["main.cpp", "lexer.cpp"], ""),
+        expected.repeat(2)
+    );
+}
+
+#[test]
+fn raw_bytes() {
+    let rules = "token HIGH = ([\\x80-\\xff])+;\ntoken LOW = ([a-z])+;\n";
+    // 0xff and a lone 0x80 are never valid UTF-8.
+    let input = [b'a', b'b', 0xff, 0x80, 0xc3, b'c'];
+    let expected = "LOW 2\nHIGH 3\nLOW 1\n_EOF 0\n";
+    if has_tool("g++") {
+        let dir = workdir("cpp_raw_bytes");
+        generate(&dir, rules, &["-l", "cpp", "-q", "--bytes"]);
+        fs::write(dir.join("input.bin"), input).unwrap();
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "lexer.h"
+#include <fstream>
+#include <iostream>
+
+int main()
+{
+    std::ifstream file("input.bin", std::ios::binary);
+    Lexer lexer(file);
+    Token token;
+    do
+    {
+        std::string text = lexer.next(token);
+        std::cout << token_name(token) << " " << text.size() << "\n";
+    } while (token != Token::_EOF);
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_cpp(&dir, &["main.cpp", "lexer.cpp"], ""), expected);
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_raw_bytes");
+        generate(&dir, rules, &["-l", "java", "-q", "--bytes"]);
+        fs::write(dir.join("input.bin"), input).unwrap();
+        fs::write(
+            dir.join("Main.java"),
+            r#"import java.io.FileInputStream;
+
+public class Main {
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(new FileInputStream("input.bin"));
+        Lexer.TextToken token;
+        do {
+            token = lexer.next();
+            System.out.println(token.getToken() + " " + token.getText().length());
+        } while (token.getToken() != Lexer.Token._EOF);
+    }
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_java(&dir, &["Main.java", "Lexer.java"], ""), expected);
+    }
+}
+
+#[test]
+fn strip_bom() {
+    let input = "ab cd\n";
+    let with_bom = format!("\u{feff}{}", input);
+    if has_tool("g++") {
+        let plain = cpp_driver("cpp_without_bom", WORDS, &["--strip-bom"], input);
+        let stripped = cpp_driver("cpp_strip_bom", WORDS, &["--strip-bom"], &with_bom);
+        assert_eq!(plain, "NAME: ab\nWS:  \nNAME: cd\nNL: \n\n");
+        assert_eq!(stripped, plain);
+        // Off by default, the BOM is unexpected input.
+        let kept = cpp_driver("cpp_keep_bom", WORDS, &[], &with_bom);
+        assert_ne!(kept, plain);
+    }
+    if has_tool("javac") {
+        let plain = java_driver("java_without_bom", WORDS, &["--strip-bom"], input);
+        let stripped = java_driver("java_strip_bom", WORDS, &["--strip-bom"], &with_bom);
+        assert_eq!(plain, "NAME: ab\nWS:  \nNAME: cd\nNL: \n\n");
+        assert_eq!(stripped, plain);
+    }
+}
+
+#[test]
+fn cpp_progress_after_malformed_utf8() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_progress_after_malformed_utf8");
+    generate(
+        &dir,
+        "token NAME = ([a-z])+;\nskip token WS = ([ ])+;\n",
+        &["-l", "cpp", "-q"],
+    );
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::string input("ab\xff" "cd \xe2" "ef ok");
+    Lexer lexer(input);
+    Token token;
+    // Bounded, so a lexer stuck on the bad bytes fails instead of hanging.
+    for (int i = 0; i < 20; i++)
+    {
+        std::string text = lexer.next(token);
+        std::cout << token_name(token) << " " << text.size() << "\n";
+        if (token == Token::_EOF)
+            break;
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    // Each bad byte is consumed as its own error, including a lead byte
+    // whose sequence is cut short, and the words around them still match.
+    assert_eq!(
+        output,
+        "NAME 2\n_ERR 1\nNAME 2\n_ERR 1\nNAME 2\nNAME 2\n_EOF 0\n"
+    );
+}
+
+#[test]
+fn cpp_lexers_share_no_state() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_lexers_share_no_state");
+    generate(&dir, KEYWORDS, &["-l", "cpp", "-q"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+#include <sstream>
+#include <thread>
+
+std::string lex_all(Lexer &lexer)
+{
+    std::string out;
+    Token token;
+    do
+    {
+        std::string text = lexer.next(token);
+        out += std::string(token_name(token)) + " " + text + "\n";
+    } while (token != Token::_EOF && token != Token::_ERR);
+    return out;
+}
+
+int main()
+{
+    std::string a_input("if else while");
+    std::istringstream b_input("return x if");
+    Lexer a(a_input);
+    Lexer b(b_input, 2);
+    Token a_token;
+    Token b_token;
+    do
+    {
+        std::string a_text = a.next(a_token);
+        std::string b_text = b.next(b_token);
+        std::cout << token_name(a_token) << " " << a_text << " | "
+                  << token_name(b_token) << " " << b_text << "\n";
+    } while (a_token != Token::_EOF || b_token != Token::_EOF);
+
+    std::string input("while returned else if");
+    Lexer reference(input);
+    std::string expected = lex_all(reference);
+    std::string results[4];
+    std::thread threads[4];
+    for (int i = 0; i < 4; i++)
+    {
+        threads[i] = std::thread([&input, &results, i]() {
+            for (int round = 0; round < 1000; round++)
+            {
+                Lexer lexer(input);
+                results[i] = lex_all(lexer);
+            }
+        });
+    }
+    for (int i = 0; i < 4; i++)
+    {
+        threads[i].join();
+        std::cout << (results[i] == expected ? "same" : "different") << "\n";
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(
+        output,
+        "IF if | RETURN return\nELSE else | NAME x\nWHILE while | IF if\n_EOF  | _EOF \nsame\nsame\nsame\nsame\n"
+    );
+}
+
+#[test]
+fn cpp_single_header() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_single_header");
+    generate(&dir, KEYWORDS, &["-l", "cpp", "-q", "--single-header"]);
+    assert!(dir.join("lexer.h").is_file() && !dir.join("lexer.cpp").exists());
+    fs::write(
+        dir.join("first.cpp"),
+        r#"#include "lexer.h"
+
+std::string first(const std::string &input)
+{
+    Lexer lexer(input);
+    Token token;
+    std::string text = lexer.next(token);
+    return std::string(token_name(token)) + " " + text;
+}
+"#,
+    )
+    .unwrap();
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+std::string first(const std::string &input);
+
+int main()
+{
+    std::string input("while x");
+    Lexer lexer(input);
+    Token token;
+    std::string text = lexer.next(token);
+    std::cout << first("return 1") << "\n" << token_name(token) << " " << text << "\n";
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "first.cpp"], "");
+    assert_eq!(output, "RETURN return\nWHILE while\n");
+}
+
+#[test]
+fn cpp_string_and_stream_agree() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_string_and_stream_agree");
+    generate(&dir, KEYWORDS, &["-l", "cpp", "-q"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+#include <iterator>
+#include <sstream>
+
+void dump(Lexer &lexer)
+{
+    Token token;
+    do
+    {
+        std::string text = lexer.next(token);
+        std::cout << token_name(token) << " " << text << "\n";
+    } while (token != Token::_EOF && token != Token::_ERR);
+}
+
+int main()
+{
+    std::string input(std::istreambuf_iterator<char>(std::cin), {});
+    std::istringstream stream(input);
+    // A small capacity makes the stream refill its buffer mid-token.
+    Lexer from_stream(stream, 4);
+    dump(from_stream);
+    std::cout << "--\n";
+    Lexer from_string(input);
+    dump(from_string);
+    Lexer from_span(input.data(), input.size());
+    std::cout << "--\n";
+    dump(from_span);
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(
+        &dir,
+        &["main.cpp", "lexer.cpp"],
+        "if returned else whilex return ?",
+    );
+    let runs: Vec<&str> = output.split("--\n").collect();
+    assert_eq!(
+        runs[0],
+        "IF if\nNAME returned\nELSE else\nNAME whilex\nRETURN return\n_ERR \n"
+    );
+    assert_eq!(runs[1], runs[0]);
+    assert_eq!(runs[2], runs[0]);
+}
+
+#[test]
+fn reset() {
+    // The first input is left half-read, so reset has to drop the buffered
+    // rest of it.
+    if has_tool("g++") {
+        let dir = workdir("cpp_reset");
+        generate(&dir, WORDS, &["-l", "cpp", "-q"]);
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "lexer.h"
+#include <iostream>
+#include <sstream>
+
+int main()
+{
+    std::istringstream first("abc de\n");
+    std::istringstream second("xy z");
+    Lexer lexer(first);
+    Token token;
+    std::string text = lexer.next(token);
+    std::cout << token_name(token) << ": " << text << "\n";
+    lexer.reset(second);
+    do
+    {
+        text = lexer.next(token);
+        std::cout << token_name(token) << ": " << text << "\n";
+    } while (token != Token::_EOF && token != Token::_ERR);
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+        assert_eq!(output, "NAME: abc\nNAME: xy\nWS:  \nNAME: z\n_EOF: \n");
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_reset");
+        generate(&dir, WORDS, &["-l", "java", "-q"]);
+        fs::write(
+            dir.join("Main.java"),
+            r#"import java.io.ByteArrayInputStream;
+import java.io.IOException;
+
+public class Main {
+    public static void main(String[] args) throws IOException {
+        Lexer lexer = new Lexer(new ByteArrayInputStream("abc de\n".getBytes()));
+        Lexer.TextToken token = lexer.next();
+        System.out.println(token.getToken() + ": " + token.getText());
+        lexer.reset(new ByteArrayInputStream("xy z".getBytes()));
+        for (Lexer.TextToken t : lexer) {
+            System.out.println(t.getToken() + ": " + t.getText());
+        }
+    }
+}
+"#,
+        )
+        .unwrap();
+        let output = run_java(&dir, &["Main.java", "Lexer.java"], "");
+        assert_eq!(output, "NAME: abc\nNAME: xy\nWS:  \nNAME: z\n");
+    }
+}
+
+#[test]
+fn cpp_line_start_anchor() {
+    if !has_tool("g++") {
+        return;
+    }
+    let rules = r##"token HEADING = ^"#" ([a-z])+;
+token HASH = "#";
+token NAME = ([a-z])+;
+token WS = ([ ])+;
+token NL = "\n";
+"##;
+    let output = cpp_driver("cpp_line_start_anchor", rules, &[], "#a b#c\n#d\n #e");
+    assert_eq!(
+        output,
+        "HEADING: #a\nWS:  \nNAME: b\nHASH: #\nNAME: c\nNL: \n\nHEADING: #d\nNL: \n\nWS:  \nHASH: #\nNAME: e\n"
+    );
+}
+
+#[test]
+fn no_dead_transitions() {
+    // Every state of (.)+ loops back, so only end of input leads to the trap.
+    let rules = "token ANY = (.)+;\n";
+    if has_tool("g++") {
+        let output = cpp_driver("cpp_no_dead_transitions", rules, &[], "ab c");
+        assert_eq!(output, "ANY: ab c\n");
+    }
+    if has_tool("javac") {
+        let output = java_driver("java_no_dead_transitions", rules, &[], "ab c");
+        assert_eq!(output, "ANY: ab c\n");
+    }
+}
+
+#[test]
+fn go_lexer() {
+    if !has_tool("go") {
+        return;
+    }
+    let dir = workdir("go_lexer");
+    generate(&dir, WORDS, &["-l", "go", "-q", "-o", "lexer"]);
+    fs::write(
+        dir.join("main.go"),
+        r#"package main
+
+import (
+	"bufio"
+	"fmt"
+	"os"
+
+	"example/lexer"
+)
+
+func main() {
+	l := lexer.NewLexer(bufio.NewReader(os.Stdin))
+	for {
+		token, text := l.Next()
+		if token == lexer.Token_EOF {
+			break
+		}
+		fmt.Printf("%s: %q\n", token, text)
+	}
+}
+"#,
+    )
+    .unwrap();
+    let output = run_go(&dir, "ab ?cd\n");
+    assert_eq!(
+        output,
+        "NAME: \"ab\"\nWS: \" \"\n_ERR: \"?\"\nNAME: \"cd\"\nNL: \"\\n\"\n"
+    );
+}
+
+#[test]
+fn iterators() {
+    if has_tool("rustc") {
+        let dir = workdir("rust_iterator");
+        generate(&dir, WORDS, &["-l", "rust", "-q"]);
+        fs::write(
+            dir.join("main.rs"),
+            r#"include!("lexer.rs");
+
+fn main() {
+    let tokens: Vec<(Token, String)> = Lexer::new("ab ?cd\n").collect();
+    println!("{:?}", tokens);
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            run_rust(&dir, &[], ""),
+            "[(NAME, \"ab\"), (WS, \" \"), (_ERR, \"?\"), (NAME, \"cd\"), (NL, \"\\n\")]\n"
+        );
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_iterator");
+        generate(&dir, WORDS, &["-l", "java", "-q"]);
+        fs::write(
+            dir.join("Main.java"),
+            r#"import java.io.ByteArrayInputStream;
+import java.util.ArrayList;
+import java.util.List;
+
+public class Main {
+    public static void main(String[] args) {
+        List<String> tokens = new ArrayList<>();
+        for (Lexer.TextToken token : new Lexer(new ByteArrayInputStream("ab cd\n?x".getBytes()))) {
+            tokens.add(token.getToken() + " " + token.getText().trim());
+        }
+        System.out.println(tokens);
+    }
+}
+"#,
+        )
+        .unwrap();
+        // Without recovery the error is yielded once and ends the iteration.
+        assert_eq!(
+            run_java(&dir, &["Main.java", "Lexer.java"], ""),
+            "[NAME ab, WS , NAME cd, NL , _ERR ]\n"
+        );
+    }
+}
+
+#[test]
+fn rust_include() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_include");
+    generate(&dir, WORDS, &["-l", "rust", "-q"]);
+    // The including module keeps its own items of the names the lexer
+    // uses internally.
+    fs::write(
+        dir.join("main.rs"),
+        r#"const TRAP: &str = "trap";
+const ALPHABET: &str = "alphabet";
+
+fn step() -> &'static str {
+    "step"
+}
+
+fn accept() -> &'static str {
+    "accept"
+}
+
+fn longest() -> &'static str {
+    "longest"
+}
+
+include!("lexer.rs");
+
+fn main() {
+    let input = std::io::read_to_string(std::io::stdin()).unwrap();
+    for (token, text) in Lexer::new(&input) {
+        println!("{}: {:?}", token.name(), text);
+    }
+    println!("{} {} {} {} {}", TRAP, ALPHABET, step(), accept(), longest());
+}
+"#,
+    )
+    .unwrap();
+    let output = run_rust(&dir, &[], "ab c\n");
+    assert_eq!(
+        output,
+        "NAME: \"ab\"\nWS: \" \"\nNAME: \"c\"\nNL: \"\\n\"\ntrap alphabet step accept longest\n"
+    );
+}
+
+#[test]
+fn rust_lex_trait() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_lex_trait");
+    generate(
+        &dir,
+        WORDS,
+        &["-l", "rust", "-q", "--lex-trait", "-o", "words"],
+    );
+    generate(
+        &dir,
+        NUMBERS,
+        &["-l", "rust", "-q", "--lex-trait", "-o", "numbers"],
+    );
+    fs::write(
+        dir.join("main.rs"),
+        r#"use parge::runtime::Lex;
+
+mod words {
+    include!("words/lexer.rs");
+}
+
+mod numbers {
+    include!("numbers/lexer.rs");
+}
+
+fn count(lexer: &mut dyn Lex) -> Vec<u32> {
+    let mut tokens = Vec::new();
+    while let Some((token, _)) = lexer.next() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn main() {
+    let mut lexers: Vec<Box<dyn Lex>> = vec![
+        Box::new(words::Lexer::new("ab c")),
+        Box::new(numbers::Lexer::new("1 22 333")),
+    ];
+    for lexer in &mut lexers {
+        println!("{:?}", count(lexer.as_mut()));
+    }
+    assert_eq!(words::Token::NAME as u32, 2);
+}
+"#,
+    )
+    .unwrap();
+    let args = common::extern_parge();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = run_rust(&dir, &args, "");
+    assert_eq!(output, "[2, 4, 2]\n[2, 2, 2]\n");
+}
+
+#[test]
+fn rust_serde() {
+    let dir = workdir("rust_serde");
+    generate(
+        &dir,
+        WORDS,
+        &["-l", "rust", "-q", "--serde", "--impl-display"],
+    );
+    fs::write(
+        dir.join("main.rs"),
+        r#"include!("lexer.rs");
+
+fn main() {
+    let input = std::io::read_to_string(std::io::stdin()).unwrap();
+    let tokens: Vec<(Token, String)> = Lexer::new(&input).collect();
+    let json = serde_json::to_string(&tokens).unwrap();
+    println!("{}", json);
+    let back: Vec<(Token, String)> = serde_json::from_str(&json).unwrap();
+    assert_eq!(back, tokens);
+    println!("{} {}", Token::NAME, Token::NL);
+}
+"#,
+    )
+    .unwrap();
+    let output = common::run_serde(&dir, "ab c\n");
+    assert_eq!(
+        output,
+        "[[\"NAME\",\"ab\"],[\"WS\",\" \"],[\"NAME\",\"c\"],[\"NL\",\"\\n\"]]\nNAME NL\n"
+    );
+}
+
+const COMPARISONS: &str = r#"token LT = "<";
+token GT = ">";
+token LE = "<=";
+token GE = ">=";
+token EQ = "=";
+equiv CMP = LT | GT | LE | GE;
+"#;
+
+#[test]
+fn rust_token_classes() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_token_classes");
+    generate(&dir, COMPARISONS, &["-l", "rust", "-q"]);
+    fs::write(
+        dir.join("main.rs"),
+        r#"include!("lexer.rs");
+
+fn main() {
+    for (token, _) in Lexer::new("<><=>==") {
+        println!("{} {:?}", token.name(), token.class());
+    }
+}
+"#,
+    )
+    .unwrap();
+    let output = run_rust(&dir, &[], "");
+    assert_eq!(output, "LT CMP\nGT CMP\nLE CMP\nGE CMP\nEQ _NONE\n");
+}
+
+#[test]
+fn python_token_classes() {
+    if !has_tool("python3") {
+        return;
+    }
+    let dir = workdir("python_token_classes");
+    generate(&dir, COMPARISONS, &["-l", "python", "-q"]);
+    fs::write(
+        dir.join("main.py"),
+        r#"from lexer import Lexer, Token
+
+for token in [Token.LT, Token.GT, Token.LE, Token.GE, Token.EQ]:
+    print(token.name, token.token_class().name)
+"#,
+    )
+    .unwrap();
+    let output = run_python(&dir, "");
+    assert_eq!(output, "LT CMP\nGT CMP\nLE CMP\nGE CMP\nEQ _NONE\n");
+}
+
+#[test]
+fn committed_states() {
+    let rules = "token NAME = ([a-z])+;\ntoken HASH = \"#\";\ntoken INCLUDE = \"#include\";\ntoken ARROW = \"=>>>\";\ntoken EQ = \"=\";\n";
+    // An unfinished "#include" falls back to what matched before.
+    let input = "#include#inc=>>>==>>>#";
+    let expected =
+        "INCLUDE: #include\nHASH: #\nNAME: inc\nARROW: =>>>\nEQ: =\nARROW: =>>>\nHASH: #\n";
+    if has_tool("g++") {
+        assert_eq!(
+            cpp_driver("cpp_committed_states", rules, &[], input),
+            expected
+        );
+        assert_eq!(
+            cpp_driver(
+                "cpp_committed_states_table_driven",
+                rules,
+                &["--table-driven"],
+                input
+            ),
+            expected
+        );
+
+        // Only the end of ARROW and of INCLUDE can't go on, so only they
+        // need no case of their own in the transition switch.
+        let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cpp_committed_states");
+        let code = fs::read_to_string(dir.join("lexer.cpp")).unwrap();
+        let lines: Vec<&str> = code.lines().map(str::trim).collect();
+        let lexer = parge::Lexer::from_source(rules).unwrap();
+        let has_case = |state: usize| {
+            let case = format!("case {}:", state);
+            lines
+                .windows(2)
+                .any(|pair| pair[0] == case && pair[1] == "switch (ach) {")
+        };
+        let committed = lexer.get_committed_states();
+        let finals: Vec<usize> = committed
+            .iter()
+            .copied()
+            .filter(|&state| !has_case(state))
+            .collect();
+        assert_eq!(finals.len(), 2);
+        assert!(committed.len() > finals.len());
+        assert!(lines.contains(&"found_pos = pos + chlen;"));
+    }
+    if has_tool("javac") {
+        assert_eq!(
+            java_driver("java_committed_states", rules, &[], input),
+            expected
+        );
+    }
+}
+
+#[test]
+fn peek_then_next() {
+    let expected = "peek NAME ab\npeek NAME ab\nnext NAME ab\npeek WS  \nnext WS  \n\
+                    next NAME cd\npeek _EOF \nnext _EOF \n";
+    if has_tool("g++") {
+        let dir = workdir("cpp_peek_then_next");
+        generate(&dir, WORDS, &["-l", "cpp", "-q", "--lookahead", "1"]);
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::string input = "ab cd";
+    Lexer lexer(input);
+    Token token;
+    auto peek = [&]() {
+        std::string text = lexer.peek(token);
+        std::cout << "peek " << token_name(token) << " " << text << "\n";
+    };
+    auto next = [&]() {
+        std::string text = lexer.next(token);
+        std::cout << "next " << token_name(token) << " " << text << "\n";
+    };
+    peek();
+    peek();
+    next();
+    peek();
+    next();
+    next();
+    peek();
+    next();
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_cpp(&dir, &["main.cpp", "lexer.cpp"], ""), expected);
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_peek_then_next");
+        generate(&dir, WORDS, &["-l", "java", "-q", "--lookahead", "1"]);
+        fs::write(
+            dir.join("Main.java"),
+            r#"import java.io.ByteArrayInputStream;
+
+public class Main {
+    static void print(String kind, Lexer.TextToken token) {
+        System.out.println(kind + " " + token.getToken() + " " + token.getText());
+    }
+
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(new ByteArrayInputStream("ab cd".getBytes("UTF-8")));
+        print("peek", lexer.peek());
+        print("peek", lexer.peek());
+        print("next", lexer.next());
+        print("peek", lexer.peek());
+        print("next", lexer.next());
+        print("next", lexer.next());
+        print("peek", lexer.peek());
+        print("next", lexer.next());
+    }
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_java(&dir, &["Main.java", "Lexer.java"], ""), expected);
+    }
+}
+
+#[test]
+fn cpp_peek_n() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_peek_n");
+    generate(&dir, WORDS, &["-l", "cpp", "-q", "--lookahead", "2"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::string input = "ab cd";
+    Lexer lexer(input);
+    Token token;
+    for (int round = 0; round < 2; round++)
+    {
+        for (auto &peeked : lexer.peek_n(2))
+        {
+            std::cout << "peek " << token_name(peeked.first) << " " << peeked.second << "\n";
+        }
+        for (int i = 0; i < 2; i++)
+        {
+            std::string text = lexer.next(token);
+            std::cout << "next " << token_name(token) << " " << text << "\n";
+        }
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(
+        output,
+        "peek NAME ab\npeek WS  \nnext NAME ab\nnext WS  \n\
+         peek NAME cd\npeek _EOF \nnext NAME cd\nnext _EOF \n"
+    );
+}
+
+#[test]
+fn java_peek_n() {
+    if !has_tool("javac") {
+        return;
+    }
+    let dir = workdir("java_peek_n");
+    generate(&dir, WORDS, &["-l", "java", "-q", "--lookahead", "2"]);
+    fs::write(
+        dir.join("Main.java"),
+        r#"import java.io.ByteArrayInputStream;
+
+public class Main {
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(new ByteArrayInputStream("ab cd".getBytes("UTF-8")));
+        for (int round = 0; round < 2; round++) {
+            for (Lexer.TextToken peeked : lexer.peekN(2)) {
+                System.out.println("peek " + peeked.getToken() + " " + peeked.getText());
+            }
+            for (int i = 0; i < 2; i++) {
+                Lexer.TextToken token = lexer.next();
+                System.out.println("next " + token.getToken() + " " + token.getText());
+            }
+        }
+        try {
+            lexer.peekN(3);
+        } catch (IllegalArgumentException e) {
+            System.out.println("too far");
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let output = run_java(&dir, &["Main.java", "Lexer.java"], "");
+    assert_eq!(
+        output,
+        "peek NAME ab\npeek WS  \nnext NAME ab\nnext WS  \n\
+         peek NAME cd\npeek _EOF \nnext NAME cd\nnext _EOF \ntoo far\n"
+    );
+}
+
+#[test]
+fn rust_reverse() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_reverse");
+    // After "aaa" the last "a" could start a token of its own or be the end
+    // of "aa", so reading backwards has to find where scanning began.
+    generate(
+        &dir,
+        r#"token AA = "aa";
+token A = "a";
+token NAME = "b" ([a-z])*;
+skip token WS = ([ ])+;
+"#,
+        &["-l", "rust", "-q", "--reverse"],
+    );
+    fs::write(
+        dir.join("main.rs"),
+        r#"include!("lexer.rs");
+
+fn main() {
+    let input = "aaa ba aaaa?a  bb ";
+    let mut lexer = Lexer::new(input);
+    let mut forward = Vec::new();
+    let mut ends = Vec::new();
+    loop {
+        let token = lexer.next();
+        if token.0 == Token::_EOF {
+            break;
+        }
+        forward.push(token);
+        ends.push(lexer.pos());
+    }
+    let mut lexer = Lexer::at(input, input.len());
+    let mut backward = Vec::new();
+    loop {
+        let token = lexer.prev();
+        if token.0 == Token::_EOF {
+            break;
+        }
+        backward.push(token);
+    }
+    backward.reverse();
+    assert_eq!(forward, backward);
+    for (i, &end) in ends.iter().enumerate() {
+        assert_eq!(Lexer::at(input, end).prev(), forward[i]);
+    }
+    let names: Vec<&str> = forward.iter().map(|(token, _)| token.name()).collect();
+    println!("{}", names.join(" "));
+}
+"#,
+    )
+    .unwrap();
+    let output = run_rust(&dir, &[], "");
+    assert_eq!(output, "AA A NAME AA AA _ERR A NAME\n");
+}
+
+#[test]
+fn rust_reverse_long_input() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_reverse_long_input");
+    // Every suffix of an identifier could start a NAME, so most steps back
+    // can't be decided locally and have to fall back to scanning forward.
+    generate(
+        &dir,
+        r#"token IF = "if";
+token NAME = ([a-z])+;
+token NUM = ([0-9])+;
+skip token WS = ([ ])+;
+"#,
+        &["-l", "rust", "-q", "--reverse"],
+    );
+    fs::write(
+        dir.join("main.rs"),
+        r#"include!("lexer.rs");
+
+fn main() {
+    let input = "if identifier 42 abcdefghij ifs ".repeat(20000);
+    let forward: Vec<(Token, String)> = Lexer::new(&input).collect();
+    let mut lexer = Lexer::at(&input, input.len());
+    let mut backward = Vec::new();
+    loop {
+        let token = lexer.prev();
+        if token.0 == Token::_EOF {
+            break;
+        }
+        backward.push(token);
+    }
+    backward.reverse();
+    assert_eq!(forward, backward);
+    // Stepping back after reading forward from the start.
+    let mut lexer = Lexer::at(&input, 0);
+    for _ in 0..3 {
+        lexer.next();
+    }
+    println!("{:?} {:?}", lexer.prev(), lexer.prev());
+    println!("{}", backward.len());
+}
+"#,
+    )
+    .unwrap();
+    let output = run_rust(&dir, &[], "");
+    assert_eq!(output, "(NUM, \"42\") (NAME, \"identifier\")\n100000\n");
+}
+
+#[test]
+fn cpp_validate_utf8() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_validate_utf8");
+    generate(&dir, WORDS, &["-l", "cpp", "-q", "--validate-utf8"]);
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    const char *inputs[] = {"ab \xc3\xa9 c\xff d", "ab \xc3\xa9 c", "ab \xe2\x82"};
+    for (const char *input : inputs)
+    {
+        std::string text = input;
+        Lexer lexer(text);
+        size_t offset = 0;
+        if (lexer.validate_utf8(offset))
+            std::cout << "valid\n";
+        else
+            std::cout << "invalid at " << offset << "\n";
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(output, "invalid at 7\nvalid\ninvalid at 3\n");
+}
+
+#[test]
+fn rust_combined() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_combined");
+    fs::write(dir.join("words.pgrules"), WORDS).unwrap();
+    fs::write(dir.join("numbers.pgrules"), NUMBERS).unwrap();
+    let output = parge(
+        &dir,
+        &[
+            "words.pgrules",
+            "-l",
+            "rust",
+            "-q",
+            "--combine",
+            "numbers.pgrules",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let generated = fs::read_to_string(dir.join("lexer.rs")).unwrap();
+    assert_eq!(generated.matches("ALPHABET: [(u32, u32);").count(), 1);
+    fs::write(
+        dir.join("main.rs"),
+        r#"include!("lexer.rs");
+
+fn main() {
+    let words: Vec<&str> = words::Lexer::new("ab 12").map(|(token, _)| token.name()).collect();
+    let numbers: Vec<&str> = numbers::Lexer::new("ab 12").map(|(token, _)| token.name()).collect();
+    println!("{}\n{}", words.join(" "), numbers.join(" "));
+}
+"#,
+    )
+    .unwrap();
+    let output = run_rust(&dir, &[], "");
+    assert_eq!(output, "NAME WS _ERR _ERR\n_ERR _ERR INT\n");
+}
+
+#[test]
+fn cpp_token_display() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_token_display");
+    generate(
+        &dir,
+        r#"token RPAREN = ")" display "')'";
+token LPAREN = "(";
+token NAME = ([a-z])+;
+"#,
+        &["-l", "cpp", "-q"],
+    );
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer.h"
+#include <iostream>
+
+int main()
+{
+    std::cout << token_display(Token::RPAREN) << "\n"
+              << token_display(Token::LPAREN) << "\n"
+              << token_display(Token::NAME) << "\n";
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp", "lexer.cpp"], "");
+    assert_eq!(output, "')'\n(\nNAME\n");
+}
+
+const STRINGS: &str = r#"token STR = "\"" ([^"])* "\"";
+token NL = "\r\n";
+"#;
+
+#[test]
+fn normalize_newlines() {
+    let input = "\"a\r\nb\"\r\n";
+    let raw = "STR: \"a\r\nb\"\nNL: \r\n\n";
+    let normalized = "STR: \"a\nb\"\nNL: \n\n";
+    if has_tool("g++") {
+        let output = cpp_driver("cpp_raw_newlines", STRINGS, &[], input);
+        assert_eq!(output, raw);
+        let output = cpp_driver(
+            "cpp_normalize_newlines",
+            STRINGS,
+            &["--normalize-newlines"],
+            input,
+        );
+        assert_eq!(output, normalized);
+    }
+    if has_tool("javac") {
+        let output = java_driver("java_raw_newlines", STRINGS, &[], input);
+        assert_eq!(output, raw);
+        let output = java_driver(
+            "java_normalize_newlines",
+            STRINGS,
+            &["--normalize-newlines"],
+            input,
+        );
+        assert_eq!(output, normalized);
+    }
+}
+
+#[test]
+fn skipped_tokens() {
+    let rules = "token ID = ([a-z])+;\nskip token WS = ([ \\t\\r\\n])+;\n";
+    let expected = "ID: a\nID: b\n";
+    if has_tool("g++") {
+        assert_eq!(
+            cpp_driver("cpp_skipped_tokens", rules, &[], "a  b"),
+            expected
+        );
+    }
+    if has_tool("javac") {
+        assert_eq!(
+            java_driver("java_skipped_tokens", rules, &[], "a  b"),
+            expected
+        );
+    }
+}
+
+#[test]
+fn cpp_tables_only() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_tables_only");
+    generate(&dir, WORDS, &["-l", "cpp", "-q", "--tables-only"]);
+    assert!(!dir.join("lexer.cpp").exists());
+    let tables = fs::read_to_string(dir.join("lexer_tables.h")).unwrap();
+    assert!(!tables.contains("next("));
+    // A driver written against the documented contract alone.
+    fs::write(
+        dir.join("main.cpp"),
+        r#"#include "lexer_tables.h"
+#include <iostream>
+#include <string>
+
+static size_t char_class(uint32_t ch)
+{
+    for (size_t i = 0; i < CLASS_COUNT; i++)
+        if (ALPHABET[i][0] <= ch && ch <= ALPHABET[i][1])
+            return i;
+    return CLASS_COUNT;
+}
+
+int main()
+{
+    std::string input = "ab c\n";
+    size_t pos = 0;
+    while (pos < input.size())
+    {
+        size_t state = INITIAL_STATE;
+        size_t end = pos;
+        int32_t token = -1;
+        for (size_t i = pos; i < input.size(); i++)
+        {
+            size_t cls = char_class((unsigned char)input[i]);
+            if (cls == CLASS_COUNT)
+                break;
+            state = TRANSITIONS[state][cls];
+            if (state == TRAP_STATE)
+                break;
+            if (ACCEPTS[state] >= 0)
+            {
+                token = ACCEPTS[state];
+                end = i + 1;
+            }
+        }
+        if (token < 0)
+            return 1;
+        std::cout << TOKEN_NAMES[token] << "\n";
+        pos = end;
+    }
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    let output = run_cpp(&dir, &["main.cpp"], "");
+    assert_eq!(output, "NAME\nWS\nNAME\nNL\n");
+}
+
+#[test]
+fn rust_lexes_input() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_lexes_input");
+    generate(&dir, WORDS, &["-l", "rust", "-q"]);
+    fs::write(
+        dir.join("main.rs"),
+        r#"include!("lexer.rs");
+
+fn main() {
+    let mut input = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input).unwrap();
+    let mut lexer = Lexer::new(&input);
+    loop {
+        let (token, text) = lexer.next();
+        println!("{}: {:?}", token.name(), text);
+        if token == Token::_EOF {
+            break;
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    let output = run_rust(&dir, &[], "ab\t\u{e9}c\n");
+    assert_eq!(
+        output,
+        "NAME: \"ab\"\nWS: \"\\t\"\n_ERR: \"\u{e9}\"\nNAME: \"c\"\nNL: \"\\n\"\n_EOF: \"\"\n"
+    );
+}
+
+#[test]
+fn python_lexes_input() {
+    if !has_tool("python3") {
+        return;
+    }
+    let dir = workdir("python_lexes_input");
+    generate(&dir, WORDS, &["-l", "python", "-q"]);
+    fs::write(
+        dir.join("main.py"),
+        r#"import sys
+
+from lexer import Lexer, Token
+
+lexer = Lexer(sys.stdin.read())
+while True:
+    token, text = lexer.next()
+    print(token.name, repr(text))
+    if token == Token._EOF:
+        break
+"#,
+    )
+    .unwrap();
+    let output = run_python(&dir, "ab\t\u{e9}c\n");
+    assert_eq!(
+        output,
+        "NAME 'ab'\nWS '\\t'\n_ERR '\u{e9}'\nNAME 'c'\nNL '\\n'\n_EOF ''\n"
+    );
+}
+
+#[test]
+fn js_lexes_input() {
+    if !has_tool("node") {
+        return;
+    }
+    let dir = workdir("js_lexes_input");
+    generate(&dir, WORDS, &["-l", "js,ts", "-q"]);
+    fs::write(
+        dir.join("main.mjs"),
+        r#"import { readFileSync } from "fs";
+import { Lexer, Token } from "./lexer.js";
+
+const lexer = new Lexer(readFileSync(0, "utf8"));
+while (true) {
+    const [token, text] = lexer.next();
+    console.log(token, JSON.stringify(text));
+    if (token === Token._EOF) {
+        break;
+    }
+}
+"#,
+    )
+    .unwrap();
+    // The astral character is one code point, so one error token.
+    let output = run_node(&dir, "ab\t\u{1f600}c\n");
+    assert_eq!(
+        output,
+        "NAME \"ab\"\nWS \"\\t\"\n_ERR \"\u{1f600}\"\nNAME \"c\"\nNL \"\\n\"\n_EOF \"\"\n"
+    );
+    let ts = fs::read_to_string(dir.join("lexer.ts")).unwrap();
+    assert!(ts.contains("export enum Token {"));
+    assert!(ts.contains("next(): [Token, string] {"));
+}
+
+#[test]
+fn c_lexes_input() {
+    if !has_tool("gcc") {
+        return;
+    }
+    let dir = workdir("c_lexes_input");
+    generate(&dir, WORDS, &["-l", "c", "-q"]);
+    fs::write(
+        dir.join("main.c"),
+        r#"#include "lexer.h"
+#include <stdio.h>
+
+int main(void)
+{
+    Lexer lexer;
+    lexer_init_file(&lexer, stdin);
+    while (1)
+    {
+        const char *text;
+        size_t len;
+        Token token = lexer_next(&lexer, &text, &len);
+        printf("%s: [%.*s]\n", token_name(token), (int)len, text);
+        if (token == TOKEN__EOF)
+            break;
+    }
+    lexer_free(&lexer);
+    return 0;
+}
+"#,
+    )
+    .unwrap();
+    run(
+        &dir,
+        "gcc",
+        &[
+            "-std=c99", "-Wall", "-Werror", "-o", "main", "main.c", "lexer.c",
+        ],
+        "",
+    );
+    let output = run(
+        &dir,
+        &dir.join("main").display().to_string(),
+        &[],
+        "ab\t\u{e9}c\n",
+    );
+    assert_eq!(
+        output,
+        "NAME: [ab]\nWS: [\t]\n_ERR: [\u{e9}]\nNAME: [c]\nNL: [\n]\n_EOF: []\n"
+    );
+}
+
+const WIDE: &str = r#"token WORD = ([\p{L}])+;
+token NUM = ([0-9])+;
+token WS = ([ ])+;
+"#;
+
+#[test]
+fn cpp_is_portable() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_is_portable");
+    generate(&dir, WIDE, &["-l", "cpp", "-q"]);
+    let source = fs::read_to_string(dir.join("lexer.cpp")).unwrap();
+    assert!(!source.contains(" ... "));
+    // Case ranges are a GCC extension, which -pedantic-errors rejects.
+    run(
+        &dir,
+        "g++",
+        &["-std=c++17", "-pedantic-errors", "-Wall", "-c", "lexer.cpp"],
+        "",
+    );
+}
+
+#[test]
+fn cpp_alphabet_classes() {
+    if !has_tool("g++") {
+        return;
+    }
+    let dir = workdir("cpp_alphabet_classes");
+    generate(&dir, WIDE, &["-l", "cpp", "-q", "--driver"]);
+    let source = fs::read_to_string(dir.join("lexer.cpp")).unwrap();
+    // Code points are only looked at once, to find their class; the DFA
+    // states switch on classes.
+    assert_eq!(source.matches("switch (ch)").count(), 1);
+    assert!(source.contains("int ach = Lexer::to_alphabet(ch);"));
+    assert!(source.contains("switch (ach)"));
+    let output = run_cpp(
+        &dir,
+        &["main.cpp", "lexer.cpp"],
+        "caf\u{e9} 42 \u{416}\u{4e2d}!",
+    );
+    assert_eq!(
+        output,
+        "WORD: caf\u{e9}\nWS:  \nNUM: 42\nWS:  \nWORD: \u{416}\u{4e2d}\n_ERR: \n"
+    );
+}
+
+#[test]
+fn positions() {
+    let input = "ab cd\n\nef\n  g";
+    let expected = "NAME 1:1 @0\nWS 1:3 @2\nNAME 1:4 @3\nNL 1:6 @5\nNL 2:1 @6\n\
+                    NAME 3:1 @7\nNL 3:3 @9\nWS 4:1 @10\nNAME 4:3 @12\n_EOF 4:4 @13\n";
+    if has_tool("g++") {
+        let dir = workdir("cpp_positions");
+        generate(&dir, WORDS, &["-l", "cpp", "-q", "--positions"]);
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "lexer.h"
+#include <iostream>
+#include <iterator>
+
+int main()
+{
+    std::string input(std::istreambuf_iterator<char>(std::cin), {});
+    Lexer lexer(input);
+    while (true)
+    {
+        TokenSpan span = lexer.next_span();
+        std::cout << token_name(span.kind) << " " << span.line << ":" << span.column
+                  << " @" << span.start << "\n";
+        if (span.kind == Token::_EOF)
+            break;
+    }
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_cpp(&dir, &["main.cpp", "lexer.cpp"], input), expected);
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_positions");
+        generate(&dir, WORDS, &["-l", "java", "-q", "--positions"]);
+        fs::write(
+            dir.join("Main.java"),
+            r#"public class Main {
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(System.in);
+        while (true) {
+            Lexer.TextToken token = lexer.next();
+            System.out.println(token.getToken() + " " + token.getLine() + ":"
+                    + token.getColumn() + " @" + token.getOffset());
+            if (token.getToken() == Lexer.Token._EOF) {
+                break;
+            }
+        }
+    }
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            run_java(&dir, &["Main.java", "Lexer.java"], input),
+            expected
+        );
+    }
+}
+
+#[test]
+fn span_fields() {
+    let input = "ab\ncd";
+    let expected = "NAME [ab] 0-2 1:1\nNL [\n] 2-3 1:3\nNAME [cd] 3-5 2:1\n_EOF [] 5-5 2:3\n";
+    if has_tool("g++") {
+        let dir = workdir("cpp_span_fields");
+        generate(&dir, WORDS, &["-l", "cpp", "-q", "--positions"]);
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "lexer.h"
+#include <iostream>
+#include <iterator>
+
+int main()
+{
+    std::string input(std::istreambuf_iterator<char>(std::cin), {});
+    Lexer lexer(input);
+    while (true)
+    {
+        TokenSpan span = lexer.next_span();
+        std::cout << token_name(span.kind) << " [" << span.text << "] " << span.start << "-"
+                  << span.end << " " << span.line << ":" << span.column << "\n";
+        if (span.kind == Token::_EOF)
+            break;
+    }
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(run_cpp(&dir, &["main.cpp", "lexer.cpp"], input), expected);
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_span_fields");
+        generate(&dir, WORDS, &["-l", "java", "-q", "--positions"]);
+        fs::write(
+            dir.join("Main.java"),
+            r#"public class Main {
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(System.in);
+        while (true) {
+            Lexer.TextToken token = lexer.next();
+            System.out.println(token.getToken() + " [" + token.getText() + "] "
+                    + token.getOffset() + "-" + token.getEnd() + " "
+                    + token.getLine() + ":" + token.getColumn());
+            if (token.getToken() == Lexer.Token._EOF) {
+                break;
+            }
+        }
+    }
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            run_java(&dir, &["Main.java", "Lexer.java"], input),
+            expected
+        );
+    }
+}
+
+// Checks that "start end" lines tile the input without gaps or overlaps.
+fn assert_spans_cover(output: &str, input: &str) {
+    let mut pos = 0;
+    for line in output.lines() {
+        let (start, end) = line.split_once(' ').unwrap();
+        let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+        assert_eq!(start, pos, "{}", output);
+        assert!(end >= start, "{}", output);
+        pos = end;
+    }
+    assert_eq!(pos, input.len(), "{}", output);
+}
+
+#[test]
+fn spans_cover_input() {
+    let input = "ab cd\n\nef\n  g";
+    if has_tool("g++") {
+        let dir = workdir("cpp_spans_cover_input");
+        generate(&dir, WORDS, &["-l", "cpp", "-q", "--positions"]);
+        fs::write(
+            dir.join("main.cpp"),
+            r#"#include "lexer.h"
+#include <iostream>
+#include <iterator>
+
+int main()
+{
+    std::string input(std::istreambuf_iterator<char>(std::cin), {});
+    Lexer lexer(input);
+    while (true)
+    {
+        TokenSpan span = lexer.next_span();
+        if (input.compare(span.start, span.end - span.start, span.text) != 0)
+            std::cout << "mismatch\n";
+        std::cout << span.start << " " << span.end << "\n";
+        if (span.kind == Token::_EOF)
+            break;
+    }
+    return 0;
+}
+"#,
+        )
+        .unwrap();
+        assert_spans_cover(&run_cpp(&dir, &["main.cpp", "lexer.cpp"], input), input);
+    }
+    if has_tool("javac") {
+        let dir = workdir("java_spans_cover_input");
+        generate(&dir, WORDS, &["-l", "java", "-q", "--positions"]);
+        fs::write(
+            dir.join("Main.java"),
+            r#"public class Main {
+    public static void main(String[] args) throws Exception {
+        Lexer lexer = new Lexer(System.in);
+        while (true) {
+            Lexer.TextToken token = lexer.next();
+            int start = token.getOffset();
+            System.out.println(start + " " + (start + token.getLength()));
+            if (token.getToken() == Lexer.Token._EOF) {
+                break;
+            }
+        }
+    }
+}
+"#,
+        )
+        .unwrap();
+        assert_spans_cover(&run_java(&dir, &["Main.java", "Lexer.java"], input), input);
+    }
+}
+
+const EXPRESSIONS: &str = r#"token NUM = ([0-9])+;
+token PLUS = "+";
+token STAR = "*";
+token LPAREN = "(";
+token RPAREN = ")";
+skip token WS = ([ ])+;
+nonterm Expr = head:Term (PLUS tail:Term)* -> Sum(head, tail);
+nonterm Term = head:Atom (STAR tail:Atom)* -> Product(head, tail);
+nonterm Atom = (value:NUM | (LPAREN inner:Expr RPAREN)) -> Atom(value, inner);
+"#;
+
+#[test]
+fn rust_ast() {
+    if !has_tool("rustc") {
+        return;
+    }
+    let dir = workdir("rust_ast");
+    generate(&dir, EXPRESSIONS, &["-l", "rust", "-q"]);
+    fs::write(
+        dir.join("main.rs"),
+        r#"mod ast;
+
+use ast::{Atom, Node, Product, Sum};
+
+fn number(text: &str) -> Product {
+    let atom = Atom {
+        value: Some(text.to_string()),
+        inner: None,
+    };
+    Product {
+        head: Some(Box::new(atom)),
+        tail: Vec::new(),
+    }
+}
+
+fn main() {
+    // 1 + (2)
+    let inner = Sum {
+        head: Some(Box::new(number("2"))),
+        tail: Vec::new(),
+    };
+    let paren = Atom {
+        value: None,
+        inner: Some(Box::new(inner)),
+    };
+    let sum = Sum {
+        head: Some(Box::new(number("1"))),
+        tail: vec![Product {
+            head: Some(Box::new(paren)),
+            tail: Vec::new(),
+        }],
+    };
+    let node: Node = sum.clone().into();
+    assert_eq!(node, Node::Sum(sum));
+    if let Node::Sum(sum) = node {
+        let atom = sum.tail[0].head.as_ref().unwrap();
+        let inner = atom.inner.as_ref().unwrap();
+        println!("{:?}", inner.head.as_ref().unwrap().head.as_ref().unwrap().value);
+    }
+}
+"#,
+    )
+    .unwrap();
+    assert_eq!(run_rust(&dir, &[], ""), "Some(\"2\")\n");
+}
+
+// Parses each input line with the generated Java parser, printing the tree
+// or "error".
+fn java_parse(test: &str, rules: &str, input: &str) -> String {
+    let dir = workdir(test);
+    generate(&dir, rules, &["-l", "java", "-q"]);
+    fs::write(
+        dir.join("Main.java"),
+        r#"import java.io.BufferedReader;
+import java.io.ByteArrayInputStream;
+import java.io.InputStreamReader;
+
+public class Main {
+    public static void main(String[] args) throws Exception {
+        BufferedReader lines = new BufferedReader(new InputStreamReader(System.in, "UTF-8"));
+        String line;
+        while ((line = lines.readLine()) != null) {
+            Lexer lexer = new Lexer(new ByteArrayInputStream(line.getBytes("UTF-8")));
+            try {
+                System.out.println(new Parser(lexer).parse());
+            } catch (Parser.ParseException e) {
+                System.out.println("error");
+            }
+        }
+    }
+}
+"#,
+    )
+    .unwrap();
+    run_java(&dir, &["Main.java", "Lexer.java", "Parser.java"], input)
+}
+
+#[test]
+fn java_parser_accepts_and_rejects() {
+    if !has_tool("javac") {
+        return;
+    }
+    let output = java_parse(
+        "java_parser_accepts_and_rejects",
+        EXPRESSIONS,
+        "2 * 3\n(1 + 2)\n1 +\n(1\n1 2\n",
+    );
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "Sum(head=Product(head=Atom(value=2, inner=null), tail=[Atom(value=3, inner=null)]), tail=[])",
+            "Sum(head=Product(head=Atom(value=null, inner=Sum(head=Product(head=Atom(value=1, inner=null), tail=[]), tail=[Product(head=Atom(value=2, inner=null), tail=[])])), tail=[]), tail=[])",
+            "error",
+            "error",
+            "error",
+        ]
+    );
+}
+
+#[test]
+fn java_parser_inline_literals() {
+    if !has_tool("javac") {
+        return;
+    }
+    let rules = r#"token NUM = ([0-9])+;
+skip token WS = ([ ])+;
+nonterm Add = l:NUM "+" r:NUM -> Add(l, r);
+"#;
+    let output = java_parse("java_parser_inline_literals", rules, "1 + 2\n1 2\n");
+    assert_eq!(output, "Add(l=1, r=2)\nerror\n");
+}
+
+#[test]
+fn java_parser_inline_attribute() {
+    if !has_tool("javac") {
+        return;
+    }
+    let rules = r#"token NUM = ([0-9])+;
+token PLUS = "+";
+token MINUS = "-";
+skip token WS = ([ ])+;
+nonterm Calc = l:NUM Op r:NUM -> Calc(l, r);
+@inline
+nonterm Op = (PLUS | MINUS) -> Op();
+"#;
+    let output = java_parse(
+        "java_parser_inline_attribute",
+        rules,
+        "1 + 2\n3 - 4\n1 * 2\n1 2\n",
+    );
+    assert_eq!(output, "Calc(l=1, r=2)\nCalc(l=3, r=4)\nerror\nerror\n");
+    // The call is replaced by the body, parseOp is still there for calls
+    // that keep the node.
+    let parser = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("java_parser_inline_attribute")
+        .join("Parser.java");
+    let parser = fs::read_to_string(parser).unwrap();
+    assert!(!parser.contains("this.parseOp();"), "{}", parser);
+    assert!(parser.contains("parseOp()"), "{}", parser);
+
+    let dir = workdir("java_parser_inline_binding");
+    fs::write(
+        dir.join("rules.pgrules"),
+        rules.replace("(PLUS | MINUS) -> Op()", "op:PLUS -> Op(op)"),
+    )
+    .unwrap();
+    let output = parge(&dir, &["rules.pgrules", "-l", "java", "-q"]);
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output)
+            .contains("'Op' is marked @inline, but binds variables its callers couldn't see"),
+        "{}",
+        stderr(&output)
+    );
+}
@@ -0,0 +1,166 @@
+use parge::{
+    codegen::{cpp, java},
+    parse_str, Element, Lexer, PargeError,
+};
+
+#[test]
+fn lexer_from_str() {
+    let grammar = parse_str("token A = \"ab\";\ntoken B = ([0-9])+;\n").unwrap();
+    assert_eq!(grammar.rules.len(), 2);
+    assert!(matches!(grammar.rules[0].element, Element::Group { .. }));
+
+    let lexer = Lexer::from_grammar(&grammar).unwrap();
+    let start = lexer.start_state();
+    assert_eq!(lexer.accept(start), None);
+
+    let after_a = lexer.step(start, 'a' as u32);
+    assert_ne!(after_a, lexer.get_trap());
+    assert_eq!(lexer.accept(after_a), None);
+    let after_ab = lexer.step(after_a, 'b' as u32);
+    assert_eq!(lexer.accept(after_ab).map(|name| name.as_str()), Some("A"));
+
+    let digit = lexer.step(start, '7' as u32);
+    assert_eq!(lexer.accept(digit).map(|name| name.as_str()), Some("B"));
+    assert_eq!(lexer.step(digit, '1' as u32), digit);
+
+    let accepting: Vec<&str> = lexer
+        .get_states()
+        .into_iter()
+        .flatten()
+        .map(|name| name.as_str())
+        .collect();
+    assert!(accepting.contains(&"A") && accepting.contains(&"B"));
+
+    let mut header = Vec::new();
+    cpp::gen_header_lexer(&lexer, &cpp::CppConfig::default(), &mut header).unwrap();
+    assert!(String::from_utf8(header).unwrap().contains("class Lexer"));
+}
+
+#[test]
+fn errors_are_typed() {
+    assert!(matches!(
+        parse_str("token A = \"a\"\n"),
+        Err(PargeError::Parse { .. })
+    ));
+    assert!(matches!(
+        parse_str("token A = \"a\";\ntoken A = \"b\";\n"),
+        Err(PargeError::DuplicateName(name)) if name == "A"
+    ));
+    match parse_str("token A = \"a\";\nnonterm S = A Q -> S();\n") {
+        Err(PargeError::UnresolvedReference(refs)) => {
+            assert_eq!(refs, [("Q".into(), "S".into())]);
+        }
+        other => panic!("expected an unresolved reference, got {:?}", other),
+    }
+
+    let grammar = parse_str("token A = ([a])*;\n").unwrap();
+    assert!(matches!(
+        Lexer::from_grammar(&grammar).err().unwrap(),
+        PargeError::EmptyMatch(name) if name == "A"
+    ));
+
+    let grammar =
+        parse_str("token KW = \"if\";\ntoken ID = ([a-z])+;\nassert exclusive KW, ID;\n").unwrap();
+    match Lexer::from_grammar(&grammar).err().unwrap() {
+        PargeError::AcceptConflict { tokens, example } => {
+            assert_eq!(tokens, ["KW", "ID"]);
+            assert_eq!(example, "if");
+        }
+        other => panic!("expected an accept conflict, got {:?}", other),
+    }
+
+    let lexer = Lexer::from_grammar(&parse_str("token A = \"a\";\n").unwrap()).unwrap();
+    assert!(matches!(
+        lexer.tokenize("aab"),
+        Err(PargeError::NoMatch { offset: 2, .. })
+    ));
+}
+
+#[test]
+fn lexer_from_source_and_file() {
+    let src = "token NUM = ([0-9])+;\ntoken PLUS = \"+\";\nskip token WS = \" \";\n";
+    let lexer = Lexer::from_source(src).unwrap();
+    let tokens = lexer.tokenize("1 + 22").unwrap();
+    let tokens: Vec<(&str, &str)> = tokens
+        .iter()
+        .map(|(token, text)| (token.as_str(), text.as_str()))
+        .collect();
+    assert_eq!(
+        tokens,
+        [("NUM", "1"), ("PLUS", "+"), ("NUM", "22"), ("_EOF", "")]
+    );
+
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("from_file.pgrules");
+    std::fs::write(&path, src).unwrap();
+    let from_file = Lexer::from_file(&path).unwrap();
+    assert_eq!(
+        from_file.tokenize("1 + 22").unwrap(),
+        lexer.tokenize("1 + 22").unwrap()
+    );
+
+    // The same validation as parse_str and from_grammar.
+    assert!(matches!(
+        Lexer::from_source("token A = \"a\"\n"),
+        Err(PargeError::Parse { .. })
+    ));
+    assert!(matches!(
+        Lexer::from_source("token A = ([a])*;\n"),
+        Err(PargeError::EmptyMatch(name)) if name == "A"
+    ));
+    assert!(Lexer::from_file(path.with_extension("missing")).is_err());
+}
+
+#[test]
+fn stepping_agrees_with_tokenize() {
+    let grammar =
+        parse_str("token ARROW = \"->\";\ntoken MINUS = \"-\";\ntoken NUM = ([0-9])+;\n").unwrap();
+    let lexer = Lexer::from_grammar(&grammar).unwrap();
+    let input = "12->-3-45";
+
+    // Longest match by hand: keep stepping until the trap, remembering the
+    // last accepting position.
+    let mut stepped = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        let mut state = lexer.start_state();
+        let mut found = None;
+        for (i, c) in input[pos..].char_indices() {
+            state = lexer.step(state, c as u32);
+            if state == lexer.get_trap() {
+                break;
+            }
+            if let Some(token) = lexer.accept(state) {
+                found = Some((token.clone(), pos + i + c.len_utf8()));
+            }
+        }
+        let (token, end) = found.unwrap();
+        stepped.push((token, input[pos..end].to_string()));
+        pos = end;
+    }
+    stepped.push((lexer.get_eof().clone(), String::new()));
+
+    assert_eq!(stepped, lexer.tokenize(input).unwrap());
+}
+
+#[test]
+fn generated_code_is_reproducible() {
+    let src = r#"token IF = "if";
+token ELSE = "else";
+token NAME = [a-zA-Z_] ([a-zA-Z0-9_])*;
+token NUM = ([0-9])+ ("." ([0-9])+)?;
+token OP = "+" | "-" | "*" | "/" | "==" | "=" | "<=" | "<";
+skip token WS = ([ \t\r\n])+;
+"#;
+    let generate = || {
+        let lexer = Lexer::from_source(src).unwrap();
+        let mut out = Vec::new();
+        cpp::gen_header_lexer(&lexer, &cpp::CppConfig::default(), &mut out).unwrap();
+        cpp::gen_body_lexer(&lexer, &cpp::CppConfig::default(), &mut out).unwrap();
+        java::gen_lexer(&lexer, &java::JavaConfig::default(), &mut out).unwrap();
+        out
+    };
+    let first = generate();
+    for _ in 0..10 {
+        assert!(generate() == first);
+    }
+}
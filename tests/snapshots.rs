@@ -0,0 +1,74 @@
+use std::{env, fs, path::Path};
+
+use parge::{
+    codegen::{cpp, go, java},
+    Lexer,
+};
+
+// The generated code of each fixture grammar is compared against the
+// snapshots committed next to it. Run with PARGE_UPDATE_SNAPSHOTS=1 to
+// rewrite them after an intended change to the output.
+const FIXTURES: [&str; 3] = ["ranges", "alternation", "repetition"];
+
+fn check(fixture: &str, extension: &str, generated: Vec<u8>) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.{}", fixture, extension));
+    let generated = String::from_utf8(generated).unwrap();
+    if env::var_os("PARGE_UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&path, &generated).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_default();
+    if generated != expected {
+        let diff = similar::TextDiff::from_lines(&expected, &generated);
+        panic!(
+            "{} doesn't match the generated code, rerun with PARGE_UPDATE_SNAPSHOTS=1 if this is intended\n{}",
+            path.display(),
+            diff.unified_diff().header("snapshot", "generated")
+        );
+    }
+}
+
+fn lexer(fixture: &str) -> Lexer {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.pgrules", fixture));
+    Lexer::from_source(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+#[test]
+fn cpp_header() {
+    for fixture in FIXTURES {
+        let mut out = Vec::new();
+        cpp::gen_header_lexer(&lexer(fixture), &cpp::CppConfig::default(), &mut out).unwrap();
+        check(fixture, "h", out);
+    }
+}
+
+#[test]
+fn cpp_body() {
+    for fixture in FIXTURES {
+        let mut out = Vec::new();
+        cpp::gen_body_lexer(&lexer(fixture), &cpp::CppConfig::default(), &mut out).unwrap();
+        check(fixture, "cpp", out);
+    }
+}
+
+#[test]
+fn java() {
+    for fixture in FIXTURES {
+        let mut out = Vec::new();
+        java::gen_lexer(&lexer(fixture), &java::JavaConfig::default(), &mut out).unwrap();
+        check(fixture, "java", out);
+    }
+}
+
+#[test]
+fn go() {
+    for fixture in FIXTURES {
+        let mut out = Vec::new();
+        go::gen_lexer(&lexer(fixture), &go::GoConfig::default(), &mut out).unwrap();
+        check(fixture, "go", out);
+    }
+}
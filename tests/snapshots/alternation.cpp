@@ -0,0 +1,660 @@
+#include "lexer.h"
+
+
+bool Lexer::fill(size_t n)
+{
+    if (!this->contents)
+    {
+        size_t count = n > this->buf.size() ? n - this->buf.size() : 0;
+        if (count > this->data_left)
+            count = this->data_left;
+        this->buf.append(this->data, count);
+        this->data += count;
+        this->data_left -= count;
+        return this->buf.size() >= n;
+    }
+    while (this->buf.size() < n)
+    {
+        int c = this->contents->get();
+        if (c == std::char_traits<char>::eof())
+            return false;
+        this->buf.push_back((char)c);
+    }
+    return true;
+}
+
+// taken from: https://github.com/skeeto/branchless-utf8
+uint32_t Lexer::next_chr(int *e, size_t pos, size_t &len)
+{
+    uint32_t ch = 0;
+    uint32_t *c = &ch;
+    static const char lengths[] = {
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 3, 3, 4, 0};
+    static const int masks[] = {0x00, 0x7f, 0x1f, 0x0f, 0x07};
+    static const uint32_t mins[] = {4194304, 0, 128, 2048, 65536};
+    static const int shiftc[] = {0, 18, 12, 6, 0};
+    static const int shifte[] = {0, 6, 4, 2, 0};
+
+    unsigned char s[4] = {0};
+    len = 0;
+    if (!this->fill(pos + 1))
+    {
+        *e = 0;
+        return 0;
+    }
+    s[0] = this->buf[pos];
+    len = lengths[s[0] >> 3];
+    if (!len)
+    {
+        /* Stray continuation or invalid lead byte. */
+        len = 1;
+        *e = 1;
+        return s[0];
+    }
+    this->fill(pos + len);
+    for (size_t i = 1; i < len && pos + i < this->buf.size(); i++)
+        s[i] = this->buf[pos + i];
+
+    /* Assume a four-byte character and load four bytes. Unused bits are
+     * shifted out.
+     */
+    *c = (uint32_t)(s[0] & masks[len]) << 18;
+    *c |= (uint32_t)(s[1] & 0x3f) << 12;
+    *c |= (uint32_t)(s[2] & 0x3f) << 6;
+    *c |= (uint32_t)(s[3] & 0x3f) << 0;
+    *c >>= shiftc[len];
+
+    /* Accumulate the various error conditions. */
+    *e = (*c < mins[len]) << 6;      // non-canonical encoding
+    *e |= ((*c >> 11) == 0x1b) << 7; // surrogate half?
+    *e |= (*c > 0x10FFFF) << 8;      // out of range?
+    *e |= (s[1] & 0xc0) >> 2;
+    *e |= (s[2] & 0xc0) >> 4;
+    *e |= (s[3]) >> 6;
+    *e ^= 0x2a; // top two bits of each tail byte correct?
+    *e >>= shifte[len];
+
+    return ch;
+}
+
+Lexer::Lexer(std::istream &contents, size_t capacity)
+{
+    this->buf.reserve(capacity);
+    this->reset(contents);
+}
+
+Lexer::Lexer(const char *data, size_t size)
+{
+    this->reset(data, size);
+}
+
+Lexer::Lexer(const std::string &text) : Lexer(text.data(), text.size())
+{
+}
+
+void Lexer::reset(std::istream &contents)
+{
+    this->contents = &contents;
+    this->data = nullptr;
+    this->data_left = 0;
+    this->clear();
+}
+
+void Lexer::reset(const char *data, size_t size)
+{
+    this->contents = nullptr;
+    this->data = data;
+    this->data_left = size;
+    this->clear();
+}
+
+void Lexer::reset(const std::string &text)
+{
+    this->reset(text.data(), text.size());
+}
+
+void Lexer::clear()
+{
+    this->buf.clear();
+}
+
+int Lexer::to_alphabet(uint32_t ch)
+{
+    switch (ch) {
+        case 0:
+            return 0;
+        case 32:
+            return 2;
+        case 43:
+            return 4;
+        case 44:
+            return 5;
+        case 45:
+            return 6;
+        case 61:
+            return 8;
+        case 97:
+            return 10;
+        case 101:
+            return 12;
+        case 102:
+            return 13;
+        case 103:
+            return 14;
+        case 104:
+            return 15;
+        case 105:
+            return 16;
+        case 108:
+            return 18;
+        case 115:
+            return 20;
+        case 119:
+            return 22;
+        case 122:
+            return 24;
+    }
+    if (ch >= 1 && ch <= 31) {
+        return 1;
+    } else if (ch >= 33 && ch <= 42) {
+        return 3;
+    } else if (ch >= 46 && ch <= 60) {
+        return 7;
+    } else if (ch >= 62 && ch <= 96) {
+        return 9;
+    } else if (ch >= 98 && ch <= 100) {
+        return 11;
+    } else if (ch >= 106 && ch <= 107) {
+        return 17;
+    } else if (ch >= 109 && ch <= 114) {
+        return 19;
+    } else if (ch >= 116 && ch <= 118) {
+        return 21;
+    } else if (ch >= 120 && ch <= 121) {
+        return 23;
+    } else if (ch >= 123 && ch <= 1114111) {
+        return 25;
+    } else {
+        return -1;
+    }
+}
+
+const char *token_name(Token token)
+{
+    switch (token)
+    {
+    case Token::KEYWORD:
+        return "KEYWORD";
+    case Token::NAME:
+        return "NAME";
+    case Token::OP:
+        return "OP";
+    case Token::WS:
+        return "WS";
+    case Token::_EOF:
+        return "_EOF";
+    case Token::_ERR:
+        return "_ERR";
+    }
+    return "";
+}
+
+std::string Lexer::next(Token &token)
+{
+    Token found = Token::_EOF;
+    size_t found_pos = 0;
+
+    size_t pos = 0;
+    size_t state = 0;
+    while (1)
+    {
+        if (state == 1) {
+            if (found == Token::_EOF) {
+                token = Token::_ERR;
+                return "";
+            }
+            std::string s = this->buf.substr(0, found_pos);
+            this->buf.erase(0, found_pos);
+            if (found == Token::WS) {
+                found = Token::_EOF;
+                found_pos = 0;
+                pos = 0;
+                state = 0;
+                continue;
+            }
+            token = found;
+            return s;
+        }
+
+        int error = 0;
+        size_t chlen = 0;
+        uint32_t ch = this->next_chr(&error, pos, chlen);
+        if (error) {
+            switch (state) {
+            case 2:
+                found_pos = pos;
+                found = Token::WS;
+                break;
+            case 3:
+                found_pos = pos;
+                found = Token::OP;
+                break;
+            case 4:
+                found_pos = pos;
+                found = Token::OP;
+                break;
+            case 5:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            case 6:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            case 7:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            case 8:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            case 9:
+                found_pos = pos;
+                found = Token::KEYWORD;
+                break;
+            case 10:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            case 11:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            case 12:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            case 13:
+                found_pos = pos;
+                found = Token::NAME;
+                break;
+            }
+            if (found != Token::_EOF) {
+                state = 1;
+                continue;
+            }
+            std::string s = this->buf.substr(0, pos + 1);
+            this->buf.erase(0, pos + 1);
+            token = Token::_ERR;
+            return s;
+        }
+
+        int ach = Lexer::to_alphabet(ch);
+
+        switch (state) {
+            case 0:
+                switch (ach) {
+                    default:
+                        state = 1;
+                        break;
+                    case 2:
+                        found_pos = pos + chlen;
+                        found = Token::WS;
+                        state = 1;
+                        break;
+                    case 4:
+                    case 6:
+                        found_pos = pos + chlen;
+                        found = Token::OP;
+                        state = 1;
+                        break;
+                    case 8:
+                        found_pos = pos + chlen;
+                        found = Token::OP;
+                        state = 4;
+                        break;
+                    case 10:
+                    case 11:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 12:
+                        state = 6;
+                        break;
+                    case 16:
+                        state = 10;
+                        break;
+                    case 22:
+                        state = 11;
+                        break;
+                }
+                break;
+            case 4:
+                switch (ach) {
+                    default:
+                        state = 1;
+                        break;
+                    case 8:
+                        found_pos = pos + chlen;
+                        found = Token::OP;
+                        state = 1;
+                        break;
+                }
+                break;
+            case 5:
+                switch (ach) {
+                    default:
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 16:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                }
+                break;
+            case 6:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 16:
+                    case 17:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 18:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 7;
+                        break;
+                }
+                break;
+            case 7:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 16:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 20:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 8;
+                        break;
+                }
+                break;
+            case 8:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 16:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 12:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 9;
+                        break;
+                }
+                break;
+            case 9:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::KEYWORD;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 16:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                }
+                break;
+            case 10:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 14:
+                    case 15:
+                    case 16:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 13:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 9;
+                        break;
+                }
+                break;
+            case 11:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 13:
+                    case 14:
+                    case 16:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 15:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 12;
+                        break;
+                }
+                break;
+            case 12:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 17:
+                    case 18:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 16:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 13;
+                        break;
+                }
+                break;
+            case 13:
+                switch (ach) {
+                    default:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 1;
+                        break;
+                    case 10:
+                    case 11:
+                    case 12:
+                    case 13:
+                    case 14:
+                    case 15:
+                    case 16:
+                    case 17:
+                    case 19:
+                    case 20:
+                    case 21:
+                    case 22:
+                    case 23:
+                    case 24:
+                        found_pos = pos + chlen;
+                        found = Token::NAME;
+                        state = 5;
+                        break;
+                    case 18:
+                        found_pos = pos;
+                        found = Token::NAME;
+                        state = 8;
+                        break;
+                }
+                break;
+        }
+
+        if (ch == 0)
+        {
+            if (found == Token::_EOF)
+            {
+                token = Token::_EOF;
+                return "";
+            }
+
+            std::string s = this->buf.substr(0, found_pos);
+            this->buf.erase(0, found_pos);
+            if (found == Token::WS) {
+                found = Token::_EOF;
+                found_pos = 0;
+                pos = 0;
+                state = 0;
+                continue;
+            }
+            token = found;
+            return s;
+        }
+
+        pos += chlen;
+    }
+}
\ No newline at end of file
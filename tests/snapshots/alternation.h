@@ -0,0 +1,44 @@
+#pragma once
+
+#include <cstdint>
+#include <string>
+#include <istream>
+
+enum class Token
+{
+    _EOF,
+    _ERR,
+    KEYWORD,
+    NAME,
+    OP,
+    WS
+};
+
+const char *token_name(Token token);
+
+// All mutable state lives in the instance, so separate lexers can be used
+// concurrently. A single lexer isn't safe to share between threads.
+class Lexer
+{
+private:
+    std::string buf;
+    std::istream *contents = nullptr;
+    const char *data = nullptr;
+    size_t data_left = 0;
+    void clear();
+    bool fill(size_t n);
+    uint32_t next_chr(int *err, size_t pos, size_t &len);
+    static int to_alphabet(uint32_t ch);
+
+public:
+    Lexer(std::istream &contents, size_t capacity = 4096);
+    // Lexes the text in place, which has to outlive the lexer.
+    Lexer(const char *data, size_t size);
+    explicit Lexer(const std::string &text);
+    explicit Lexer(std::string &&text) = delete;
+    void reset(std::istream &contents);
+    void reset(const char *data, size_t size);
+    void reset(const std::string &text);
+    void reset(std::string &&text) = delete;
+    std::string next(Token &token);
+};
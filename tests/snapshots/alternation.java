@@ -0,0 +1,566 @@
+import java.io.InputStream;
+import java.io.BufferedReader;
+import java.io.IOException;
+import java.io.InputStreamReader;
+import java.io.UncheckedIOException;
+import java.io.UnsupportedEncodingException;
+import java.util.Iterator;
+import java.util.NoSuchElementException;
+
+public class Lexer implements Iterable<Lexer.TextToken> {
+
+    private BufferedReader reader;
+    private final StringBuffer buf;
+
+    public Lexer(InputStream is) {
+        this(is, 4096);
+    }
+
+    public Lexer(InputStream is, int capacity) {
+        this.buf = new StringBuffer(capacity);
+        this.reset(is);
+    }
+
+    public void reset(InputStream is) {
+        BufferedReader reader = null;
+        try {
+            reader = new BufferedReader(new InputStreamReader(is, "utf-8"));
+        } catch (UnsupportedEncodingException e) {
+        }
+        this.reader = reader;
+        this.buf.setLength(0);
+    }
+
+    private int toAlphabet(int ch) {
+        switch (ch) {
+            case 0:
+                return 0;
+            case 32:
+                return 2;
+            case 43:
+                return 4;
+            case 44:
+                return 5;
+            case 45:
+                return 6;
+            case 61:
+                return 8;
+            case 97:
+                return 10;
+            case 101:
+                return 12;
+            case 102:
+                return 13;
+            case 103:
+                return 14;
+            case 104:
+                return 15;
+            case 105:
+                return 16;
+            case 108:
+                return 18;
+            case 115:
+                return 20;
+            case 119:
+                return 22;
+            case 122:
+                return 24;
+        }
+        if (ch >= 1 && ch <= 31) {
+            return 1;
+        } else if (ch >= 33 && ch <= 42) {
+            return 3;
+        } else if (ch >= 46 && ch <= 60) {
+            return 7;
+        } else if (ch >= 62 && ch <= 96) {
+            return 9;
+        } else if (ch >= 98 && ch <= 100) {
+            return 11;
+        } else if (ch >= 106 && ch <= 107) {
+            return 17;
+        } else if (ch >= 109 && ch <= 114) {
+            return 19;
+        } else if (ch >= 116 && ch <= 118) {
+            return 21;
+        } else if (ch >= 120 && ch <= 121) {
+            return 23;
+        } else if (ch >= 123 && ch <= 1114111) {
+            return 25;
+        } else {
+            return -1;
+        }
+    }
+
+    private int read() throws IOException {
+        int ch = this.reader.read();
+        if (ch != -1 && Character.isHighSurrogate((char) ch)) {
+            this.reader.mark(1);
+            int low = this.reader.read();
+            if (low != -1 && Character.isLowSurrogate((char) low)) {
+                return Character.toCodePoint((char) ch, (char) low);
+            }
+            this.reader.reset();
+        }
+        return ch;
+    }
+
+    public TextToken next() throws IOException {
+        Token found = null;
+        int found_pos = 0;
+
+        int pos = 0;
+        int state = 0;
+        while (true) {
+            if (state == 1) {
+                if (found == null) {
+                    return new TextToken(Token._ERR, "");
+                }
+                String s = this.buf.substring(0, found_pos);
+                this.buf.delete(0, found_pos);
+                if (found == Token.WS) {
+                    found = null;
+                    found_pos = 0;
+                    pos = 0;
+                    state = 0;
+                    continue;
+                }
+                return new TextToken(found, s);
+            }
+
+            int ch;
+            if (pos < this.buf.length()) {
+                ch = this.buf.codePointAt(pos);
+            } else {
+                ch = this.read();
+                if (ch != -1) this.buf.appendCodePoint(ch);
+            }
+            int ach = this.toAlphabet(ch);
+
+            switch (state) {
+                case 0:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 2:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.WS;
+                            state = 1;
+                            break;
+                        case 4:
+                        case 6:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.OP;
+                            state = 1;
+                            break;
+                        case 8:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.OP;
+                            state = 4;
+                            break;
+                        case 10:
+                        case 11:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 12:
+                            state = 6;
+                            break;
+                        case 16:
+                            state = 10;
+                            break;
+                        case 22:
+                            state = 11;
+                            break;
+                    }
+                    break;
+                case 4:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 8:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.OP;
+                            state = 1;
+                            break;
+                    }
+                    break;
+                case 5:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 16:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                    }
+                    break;
+                case 6:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 16:
+                        case 17:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 18:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 7;
+                            break;
+                    }
+                    break;
+                case 7:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 16:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 20:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 8;
+                            break;
+                    }
+                    break;
+                case 8:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 16:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 12:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 9;
+                            break;
+                    }
+                    break;
+                case 9:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.KEYWORD;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 16:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                    }
+                    break;
+                case 10:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 14:
+                        case 15:
+                        case 16:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 13:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 9;
+                            break;
+                    }
+                    break;
+                case 11:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 13:
+                        case 14:
+                        case 16:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 15:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 12;
+                            break;
+                    }
+                    break;
+                case 12:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 17:
+                        case 18:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 16:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 13;
+                            break;
+                    }
+                    break;
+                case 13:
+                    switch (ach) {
+                        default:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 1;
+                            break;
+                        case 10:
+                        case 11:
+                        case 12:
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 16:
+                        case 17:
+                        case 19:
+                        case 20:
+                        case 21:
+                        case 22:
+                        case 23:
+                        case 24:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NAME;
+                            state = 5;
+                            break;
+                        case 18:
+                            found_pos = pos;
+                            found = Token.NAME;
+                            state = 8;
+                            break;
+                    }
+                    break;
+            }
+
+            if (ch == -1)
+            {
+                if (found == null)
+                {
+                    return new TextToken(Token._EOF, "");
+                }
+
+                String s = this.buf.substring(0, found_pos);
+                this.buf.delete(0, found_pos);
+                if (found == Token.WS) {
+                    found = null;
+                    found_pos = 0;
+                    pos = 0;
+                    state = 0;
+                    continue;
+                }
+                return new TextToken(found, s);
+            }
+
+            pos += Character.charCount(ch);
+        }
+    }
+
+    @Override
+    public Iterator<TextToken> iterator() {
+        return new Iterator<TextToken>() {
+            private TextToken pending;
+            private boolean failed;
+
+            @Override
+            public boolean hasNext() {
+                if (this.failed) {
+                    return false;
+                }
+                if (this.pending == null) {
+                    try {
+                        this.pending = Lexer.this.next();
+                    } catch (IOException e) {
+                        throw new UncheckedIOException(e);
+                    }
+                }
+                return this.pending.getToken() != Token._EOF;
+            }
+
+            @Override
+            public TextToken next() {
+                if (!this.hasNext()) {
+                    throw new NoSuchElementException();
+                }
+                TextToken token = this.pending;
+                this.pending = null;
+                this.failed = token.getToken() == Token._ERR;
+                return token;
+            }
+        };
+    }
+
+    public static class TextToken {
+        private final Token token;
+        private final String text;
+
+        public TextToken(Token token, String text) {
+            this.token = token;
+            this.text = text;
+        }
+
+        public Token getToken() {
+            return this.token;
+        }
+
+        public String getText() {
+            return this.text;
+        }
+    }
+
+
+    public static enum Token {
+        _EOF,
+        _ERR,
+        KEYWORD,
+        NAME,
+        OP,
+        WS,
+        ;
+    }
+
+}
\ No newline at end of file
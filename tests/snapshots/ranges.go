@@ -0,0 +1,174 @@
+package lexer
+
+import (
+	"io"
+	"sort"
+)
+
+type Token int
+
+const (
+	Token_EOF Token = iota
+	Token_ERR
+	TokenDIGIT
+	TokenHEX
+	TokenLOWER
+	TokenNL
+	TokenOTHER
+)
+
+var tokenNames = [...]string{
+	"_EOF",
+	"_ERR",
+	"DIGIT",
+	"HEX",
+	"LOWER",
+	"NL",
+	"OTHER",
+}
+
+func (t Token) String() string {
+	return tokenNames[t]
+}
+
+const trap = 2
+
+var alphabet = []rune{
+	0,
+	9,
+	10,
+	47,
+	48,
+	56,
+	57,
+	64,
+	65,
+	69,
+	70,
+	96,
+	97,
+	101,
+	102,
+	119,
+	120,
+	121,
+	122,
+	1114111,
+}
+
+var transitions = [][]int{
+	{1, 1, 3, 1, 4, 7, 7, 1, 1, 1, 1, 1, 8, 8, 8, 8, 8, 8, 8, 1},
+	{2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2},
+	{2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2},
+	{2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2},
+	{2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 5, 2, 2, 2},
+	{2, 2, 2, 2, 6, 6, 6, 2, 6, 6, 6, 2, 6, 6, 6, 2, 2, 2, 2, 2},
+	{2, 2, 2, 2, 6, 6, 6, 2, 6, 6, 6, 2, 6, 6, 6, 2, 2, 2, 2, 2},
+	{2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2},
+	{2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2},
+}
+
+var accepts = []Token{
+	-1,
+	TokenOTHER,
+	-1,
+	TokenNL,
+	TokenDIGIT,
+	-1,
+	TokenHEX,
+	TokenDIGIT,
+	TokenLOWER,
+}
+
+var skipped = [...]bool{
+	false,
+	false,
+	false,
+	false,
+	false,
+	false,
+	false,
+}
+
+func toAlphabet(ch rune) int {
+	return sort.Search(len(alphabet), func(i int) bool { return alphabet[i] >= ch })
+}
+
+type Lexer struct {
+	reader io.RuneReader
+	buf    []rune
+	eof    bool
+	err    error
+}
+
+func NewLexer(reader io.RuneReader) *Lexer {
+	return &Lexer{reader: reader}
+}
+
+// Err returns the first read error other than io.EOF, after which Next
+// reports _EOF.
+func (l *Lexer) Err() error {
+	return l.err
+}
+
+func (l *Lexer) peek(pos int) (rune, bool) {
+	for pos >= len(l.buf) && !l.eof {
+		ch, _, err := l.reader.ReadRune()
+		if err != nil {
+			if err != io.EOF {
+				l.err = err
+			}
+			l.eof = true
+			break
+		}
+		l.buf = append(l.buf, ch)
+	}
+	if pos < len(l.buf) {
+		return l.buf[pos], true
+	}
+	return 0, false
+}
+
+func (l *Lexer) take(n int) string {
+	text := string(l.buf[:n])
+	l.buf = l.buf[n:]
+	return text
+}
+
+func (l *Lexer) longest(pos int) (Token, int, bool) {
+	state := 0
+	found, end, ok := Token_EOF, 0, false
+	for {
+		ch, more := l.peek(pos)
+		if !more {
+			break
+		}
+		state = transitions[state][toAlphabet(ch)]
+		if state == trap {
+			break
+		}
+		pos++
+		if accepts[state] >= 0 {
+			found, end, ok = accepts[state], pos, true
+		}
+	}
+	return found, end, ok
+}
+
+func (l *Lexer) Next() (Token, string) {
+	for {
+		if _, more := l.peek(0); !more {
+			return Token_EOF, ""
+		}
+		token, end, ok := l.longest(0)
+		if !ok {
+			end = 1
+			return Token_ERR, l.take(end)
+		}
+		text := l.take(end)
+		if skipped[token] {
+			continue
+		}
+		return token, text
+	}
+}
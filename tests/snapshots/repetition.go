@@ -0,0 +1,162 @@
+package lexer
+
+import (
+	"io"
+	"sort"
+)
+
+type Token int
+
+const (
+	Token_EOF Token = iota
+	Token_ERR
+	TokenNUM
+	TokenWORD
+	TokenWS
+)
+
+var tokenNames = [...]string{
+	"_EOF",
+	"_ERR",
+	"NUM",
+	"WORD",
+	"WS",
+}
+
+func (t Token) String() string {
+	return tokenNames[t]
+}
+
+const trap = 1
+
+var alphabet = []rune{
+	0,
+	8,
+	9,
+	10,
+	31,
+	32,
+	45,
+	46,
+	47,
+	48,
+	56,
+	57,
+	96,
+	97,
+	98,
+	121,
+	122,
+	1114111,
+}
+
+var transitions = [][]int{
+	{1, 1, 2, 2, 1, 2, 1, 1, 1, 3, 3, 3, 1, 6, 6, 6, 6, 1},
+	{1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1},
+	{1, 1, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1},
+	{1, 1, 1, 1, 1, 1, 1, 4, 1, 3, 3, 3, 1, 1, 1, 1, 1, 1},
+	{1, 1, 1, 1, 1, 1, 1, 1, 1, 5, 5, 5, 1, 1, 1, 1, 1, 1},
+	{1, 1, 1, 1, 1, 1, 1, 1, 1, 5, 5, 5, 1, 1, 1, 1, 1, 1},
+	{1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 6, 6, 1, 1, 1},
+}
+
+var accepts = []Token{
+	-1,
+	-1,
+	TokenWS,
+	TokenNUM,
+	-1,
+	TokenNUM,
+	TokenWORD,
+}
+
+var skipped = [...]bool{
+	false,
+	false,
+	false,
+	false,
+	true,
+}
+
+func toAlphabet(ch rune) int {
+	return sort.Search(len(alphabet), func(i int) bool { return alphabet[i] >= ch })
+}
+
+type Lexer struct {
+	reader io.RuneReader
+	buf    []rune
+	eof    bool
+	err    error
+}
+
+func NewLexer(reader io.RuneReader) *Lexer {
+	return &Lexer{reader: reader}
+}
+
+// Err returns the first read error other than io.EOF, after which Next
+// reports _EOF.
+func (l *Lexer) Err() error {
+	return l.err
+}
+
+func (l *Lexer) peek(pos int) (rune, bool) {
+	for pos >= len(l.buf) && !l.eof {
+		ch, _, err := l.reader.ReadRune()
+		if err != nil {
+			if err != io.EOF {
+				l.err = err
+			}
+			l.eof = true
+			break
+		}
+		l.buf = append(l.buf, ch)
+	}
+	if pos < len(l.buf) {
+		return l.buf[pos], true
+	}
+	return 0, false
+}
+
+func (l *Lexer) take(n int) string {
+	text := string(l.buf[:n])
+	l.buf = l.buf[n:]
+	return text
+}
+
+func (l *Lexer) longest(pos int) (Token, int, bool) {
+	state := 0
+	found, end, ok := Token_EOF, 0, false
+	for {
+		ch, more := l.peek(pos)
+		if !more {
+			break
+		}
+		state = transitions[state][toAlphabet(ch)]
+		if state == trap {
+			break
+		}
+		pos++
+		if accepts[state] >= 0 {
+			found, end, ok = accepts[state], pos, true
+		}
+	}
+	return found, end, ok
+}
+
+func (l *Lexer) Next() (Token, string) {
+	for {
+		if _, more := l.peek(0); !more {
+			return Token_EOF, ""
+		}
+		token, end, ok := l.longest(0)
+		if !ok {
+			end = 1
+			return Token_ERR, l.take(end)
+		}
+		text := l.take(end)
+		if skipped[token] {
+			continue
+		}
+		return token, text
+	}
+}
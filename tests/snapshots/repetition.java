@@ -0,0 +1,312 @@
+import java.io.InputStream;
+import java.io.BufferedReader;
+import java.io.IOException;
+import java.io.InputStreamReader;
+import java.io.UncheckedIOException;
+import java.io.UnsupportedEncodingException;
+import java.util.Iterator;
+import java.util.NoSuchElementException;
+
+public class Lexer implements Iterable<Lexer.TextToken> {
+
+    private BufferedReader reader;
+    private final StringBuffer buf;
+
+    public Lexer(InputStream is) {
+        this(is, 4096);
+    }
+
+    public Lexer(InputStream is, int capacity) {
+        this.buf = new StringBuffer(capacity);
+        this.reset(is);
+    }
+
+    public void reset(InputStream is) {
+        BufferedReader reader = null;
+        try {
+            reader = new BufferedReader(new InputStreamReader(is, "utf-8"));
+        } catch (UnsupportedEncodingException e) {
+        }
+        this.reader = reader;
+        this.buf.setLength(0);
+    }
+
+    private int toAlphabet(int ch) {
+        switch (ch) {
+            case 0:
+                return 0;
+            case 9:
+                return 2;
+            case 10:
+                return 3;
+            case 32:
+                return 5;
+            case 46:
+                return 7;
+            case 47:
+                return 8;
+            case 48:
+                return 9;
+            case 57:
+                return 11;
+            case 97:
+                return 13;
+            case 98:
+                return 14;
+            case 122:
+                return 16;
+        }
+        if (ch >= 1 && ch <= 8) {
+            return 1;
+        } else if (ch >= 11 && ch <= 31) {
+            return 4;
+        } else if (ch >= 33 && ch <= 45) {
+            return 6;
+        } else if (ch >= 49 && ch <= 56) {
+            return 10;
+        } else if (ch >= 58 && ch <= 96) {
+            return 12;
+        } else if (ch >= 99 && ch <= 121) {
+            return 15;
+        } else if (ch >= 123 && ch <= 1114111) {
+            return 17;
+        } else {
+            return -1;
+        }
+    }
+
+    private int read() throws IOException {
+        int ch = this.reader.read();
+        if (ch != -1 && Character.isHighSurrogate((char) ch)) {
+            this.reader.mark(1);
+            int low = this.reader.read();
+            if (low != -1 && Character.isLowSurrogate((char) low)) {
+                return Character.toCodePoint((char) ch, (char) low);
+            }
+            this.reader.reset();
+        }
+        return ch;
+    }
+
+    public TextToken next() throws IOException {
+        Token found = null;
+        int found_pos = 0;
+
+        int pos = 0;
+        int state = 0;
+        while (true) {
+            if (state == 1) {
+                if (found == null) {
+                    return new TextToken(Token._ERR, "");
+                }
+                String s = this.buf.substring(0, found_pos);
+                this.buf.delete(0, found_pos);
+                if (found == Token.WS) {
+                    found = null;
+                    found_pos = 0;
+                    pos = 0;
+                    state = 0;
+                    continue;
+                }
+                return new TextToken(found, s);
+            }
+
+            int ch;
+            if (pos < this.buf.length()) {
+                ch = this.buf.codePointAt(pos);
+            } else {
+                ch = this.read();
+                if (ch != -1) this.buf.appendCodePoint(ch);
+            }
+            int ach = this.toAlphabet(ch);
+
+            switch (state) {
+                case 0:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 2:
+                        case 3:
+                        case 5:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.WS;
+                            state = 2;
+                            break;
+                        case 9:
+                        case 10:
+                        case 11:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NUM;
+                            state = 3;
+                            break;
+                        case 13:
+                        case 14:
+                        case 15:
+                        case 16:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.WORD;
+                            state = 6;
+                            break;
+                    }
+                    break;
+                case 2:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 2:
+                        case 3:
+                        case 5:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.WS;
+                            state = 2;
+                            break;
+                    }
+                    break;
+                case 3:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 9:
+                        case 10:
+                        case 11:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NUM;
+                            state = 3;
+                            break;
+                        case 7:
+                            state = 4;
+                            break;
+                    }
+                    break;
+                case 4:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 9:
+                        case 10:
+                        case 11:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NUM;
+                            state = 5;
+                            break;
+                    }
+                    break;
+                case 5:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 9:
+                        case 10:
+                        case 11:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.NUM;
+                            state = 5;
+                            break;
+                    }
+                    break;
+                case 6:
+                    switch (ach) {
+                        default:
+                            state = 1;
+                            break;
+                        case 13:
+                        case 14:
+                            found_pos = pos + Character.charCount(ch);
+                            found = Token.WORD;
+                            state = 6;
+                            break;
+                    }
+                    break;
+            }
+
+            if (ch == -1)
+            {
+                if (found == null)
+                {
+                    return new TextToken(Token._EOF, "");
+                }
+
+                String s = this.buf.substring(0, found_pos);
+                this.buf.delete(0, found_pos);
+                if (found == Token.WS) {
+                    found = null;
+                    found_pos = 0;
+                    pos = 0;
+                    state = 0;
+                    continue;
+                }
+                return new TextToken(found, s);
+            }
+
+            pos += Character.charCount(ch);
+        }
+    }
+
+    @Override
+    public Iterator<TextToken> iterator() {
+        return new Iterator<TextToken>() {
+            private TextToken pending;
+            private boolean failed;
+
+            @Override
+            public boolean hasNext() {
+                if (this.failed) {
+                    return false;
+                }
+                if (this.pending == null) {
+                    try {
+                        this.pending = Lexer.this.next();
+                    } catch (IOException e) {
+                        throw new UncheckedIOException(e);
+                    }
+                }
+                return this.pending.getToken() != Token._EOF;
+            }
+
+            @Override
+            public TextToken next() {
+                if (!this.hasNext()) {
+                    throw new NoSuchElementException();
+                }
+                TextToken token = this.pending;
+                this.pending = null;
+                this.failed = token.getToken() == Token._ERR;
+                return token;
+            }
+        };
+    }
+
+    public static class TextToken {
+        private final Token token;
+        private final String text;
+
+        public TextToken(Token token, String text) {
+            this.token = token;
+            this.text = text;
+        }
+
+        public Token getToken() {
+            return this.token;
+        }
+
+        public String getText() {
+            return this.text;
+        }
+    }
+
+
+    public static enum Token {
+        _EOF,
+        _ERR,
+        NUM,
+        WORD,
+        WS,
+        ;
+    }
+
+}
\ No newline at end of file